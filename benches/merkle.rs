@@ -0,0 +1,28 @@
+//! What appending a leaf costs `MerkleBuilder`, against building the tree afresh.
+//! Run with `cargo bench --features test-utilities --bench merkle`.
+
+use bitcoin::crypto::hash::tests::generate_random_hash;
+use bitcoin::crypto::hash::H256;
+use bitcoin::crypto::merkle::{MerkleBuilder, MerkleTree};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+fn append_leaf(c: &mut Criterion) {
+    let data: Vec<H256> = (0..5001).map(|_| generate_random_hash()).collect();
+    // a builder that last built the tree without the newest leaf
+    let primed = || {
+        let mut builder = MerkleBuilder::new();
+        builder.root(&data[..5000]);
+        builder
+    };
+    let mut group = c.benchmark_group("root of 5001 leaves");
+    group.bench_function("incremental rebuild", |b| b.iter_batched(
+        primed,
+        |mut builder| builder.root(&data),
+        BatchSize::SmallInput,
+    ));
+    group.bench_function("full rebuild", |b| b.iter(|| MerkleTree::new(&data).root()));
+    group.finish();
+}
+
+criterion_group!(benches, append_leaf);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+//! What the signature cache saves when a block's transactions were verified before.
+//! Run with `cargo bench --features test-utilities --bench sig_cache`.
+
+use bitcoin::address::{get_deterministic_keypair, H160};
+use bitcoin::crypto::hash::{Hashable, H256};
+use bitcoin::sig_cache::SigCache;
+use bitcoin::transaction::{verify_batch, ChainId, RawTransaction, SignedTransaction};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ring::signature::KeyPair;
+use std::sync::atomic::AtomicBool;
+
+/// Transfers from the first ICO account, signed for the default chain
+fn transfers(count: u32) -> Vec<SignedTransaction> {
+    let key = get_deterministic_keypair(0);
+    let from_addr = H160::from_pubkey(key.public_key().as_ref());
+    (1..=count).map(|nonce| {
+        let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 1)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+        SignedTransaction::from_raw(raw, &key, &ChainId::default())
+    }).collect()
+}
+
+fn revalidation(c: &mut Criterion) {
+    let txs = transfers(1000);
+    let chain = ChainId::default();
+    let mut group = c.benchmark_group("1000 transactions");
+    group.sample_size(20);
+    group.bench_function("verifying", |b| b.iter_batched(
+        SigCache::default,
+        |cache| assert_eq!(verify_batch(&txs, &chain, &cache, &AtomicBool::new(false)), Ok(())),
+        BatchSize::SmallInput,
+    ));
+    let warm = SigCache::default();
+    assert_eq!(verify_batch(&txs, &chain, &warm, &AtomicBool::new(false)), Ok(()));
+    group.bench_function("re-verifying through the cache", |b| b.iter(|| {
+        assert_eq!(verify_batch(&txs, &chain, &warm, &AtomicBool::new(false)), Ok(()));
+    }));
+    // the least a lookup can cost, as each one hashes the transaction
+    group.bench_function("hashing alone", |b| b.iter(|| txs.iter().map(|tx| tx.hash()).collect::<Vec<H256>>()));
+    group.finish();
+}
+
+criterion_group!(benches, revalidation);
+criterion_main!(benches);
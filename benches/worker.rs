@@ -0,0 +1,91 @@
+//! What answering `GetBlocks` and checking a received block cost the network worker.
+//! Run with `cargo bench --features test-utilities --bench worker`.
+
+use bitcoin::address::{get_deterministic_keypair, H160};
+use bitcoin::block::test::generate_mined_block;
+use bitcoin::block::Block;
+use bitcoin::blockchain::Blockchain;
+use bitcoin::crypto::hash::{Hashable, H256};
+use bitcoin::crypto::merkle::MerkleTree;
+use bitcoin::network::message::Message;
+use bitcoin::sig_cache::SigCache;
+use bitcoin::transaction::{verify_batch, ChainId, RawTransaction, SignedTransaction};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ring::signature::KeyPair;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// A chain of `count` mined blocks on top of genesis, each with `txs` copies of its coinbase
+fn insert_chain(blockchain: &mut Blockchain, count: usize, txs: usize) -> Vec<H256> {
+    let mut parent = blockchain.tip();
+    (0..count).map(|_| {
+        let mut block = generate_mined_block(&parent);
+        block.content.transactions = vec![block.content.transactions[0].clone(); txs];
+        parent = block.hash();
+        blockchain.insert(&block);
+        parent
+    }).collect()
+}
+
+/// A mined block of `count` transfers from ICO account 0, committing to the state it leads to
+fn transfer_block(blockchain: &Blockchain, count: u32) -> Block {
+    let key = get_deterministic_keypair(0);
+    let from_addr = H160::from_pubkey(key.public_key().as_ref());
+    let mut block = generate_mined_block(&blockchain.tip());
+    block.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, 1)];
+    block.content.transactions.extend((1..=count).map(|nonce| {
+        let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 10)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+        SignedTransaction::from_raw(raw, &key, &ChainId::default())
+    }));
+    block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+    block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+    while block.hash() > block.header.difficulty {
+        block.header.nonce = rand::random();
+    }
+    block
+}
+
+fn serve_get_blocks(c: &mut Criterion) {
+    let mut blockchain = Blockchain::new();
+    let hashes = insert_chain(&mut blockchain, 100, 500);
+    let mut group = c.benchmark_group("serve 100 blocks of 500 transactions");
+    group.sample_size(20);
+    // the blocks shared with the blockchain, as the worker answers
+    group.bench_function("shared", |b| b.iter(|| {
+        let blocks: Vec<Arc<Block>> = hashes.iter().filter_map(|hash| blockchain.get_block(hash)).collect();
+        bincode::serialize(&Message::Blocks(blocks)).unwrap()
+    }));
+    group.bench_function("deep copy alone", |b| b.iter(|| {
+        hashes.iter().filter_map(|hash| blockchain.get_block(hash)).map(|block| Block::clone(&block)).collect::<Vec<Block>>()
+    }));
+    group.finish();
+}
+
+fn verify_received_block(c: &mut Criterion) {
+    let blockchain = Blockchain::new();
+    let block = transfer_block(&blockchain, 500);
+    let txs = &block.content.transactions[1..];
+    let chain = ChainId::default();
+    let mut group = c.benchmark_group("check a block of 500 transactions");
+    group.sample_size(10);
+    group.bench_function("sequential verification", |b| b.iter(|| {
+        assert!(txs.iter().all(|tx| tx.verify(&chain)));
+    }));
+    group.bench_function("batch verification", |b| b.iter(|| {
+        assert_eq!(verify_batch(txs, &chain, &SigCache::default(), &AtomicBool::new(false)), Ok(()));
+    }));
+    // what the worker does while holding the blockchain lock
+    group.bench_function("state check and insert", |b| b.iter_batched(
+        Blockchain::new,
+        |mut blockchain| {
+            assert!(blockchain.state_validity_check(&block).is_ok());
+            blockchain.insert(&block);
+            blockchain
+        },
+        BatchSize::LargeInput,
+    ));
+    group.finish();
+}
+
+criterion_group!(benches, serve_get_blocks, verify_received_block);
+criterion_main!(benches);
@@ -2,7 +2,7 @@ use ring::signature::Ed25519KeyPair;
 use serde::{Serialize,Deserialize};
 
 /// A 160-bit public address.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct H160([u8; 20]); 
 
 impl std::fmt::Display for H160 {
@@ -58,6 +58,21 @@ impl std::convert::From<[u8; 20]> for H160 {
     }
 }
 
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    /// Parse an address from 40 hex digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| format!("invalid address {}: {}", s, e))?;
+        if bytes.len() != 20 {
+            return Err(format!("invalid address {}: expected 20 bytes", s));
+        }
+        let mut buffer: [u8; 20] = [0; 20];
+        buffer.copy_from_slice(&bytes);
+        Ok(buffer.into())
+    }
+}
+
 pub fn get_deterministic_keypair(nonce: u8) -> Ed25519KeyPair {
     let mut seed = [0u8; 32];
     seed[0] = nonce;
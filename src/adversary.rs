@@ -0,0 +1,183 @@
+//! Adversarial mining strategies, only used for robustness experiments.
+//! Compiled in with the `adversary` feature.
+
+use crate::crypto::hash::H256;
+use std::str::FromStr;
+
+/// How an adversarial miner decides when to publish the blocks it mined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Keep mined blocks private until there are `k` of them, then release them all at once
+    Withhold(usize),
+    /// Release a private block only when a competing block at the same height is heard
+    Selfish,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    /// Parse `withhold:<k>` or `selfish`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "selfish" {
+            return Ok(Strategy::Selfish);
+        }
+        match s.strip_prefix("withhold:") {
+            Some(k) => match k.parse::<usize>() {
+                Ok(k) if k > 0 => Ok(Strategy::Withhold(k)),
+                _ => Err(format!("invalid withhold count: {}", k)),
+            },
+            None => Err(format!("unknown adversary strategy: {}", s)),
+        }
+    }
+}
+
+/// State of an adversarial miner: the blocks it keeps private and what its releases caused
+pub struct Adversary {
+    strategy: Strategy,
+    /// Mined but unreleased blocks as (hash, height), in mining order
+    private: Vec<(H256, u64)>,
+    /// Every release, one batch of hashes per release
+    pub releases: Vec<Vec<H256>>,
+    /// Received blocks that were knocked off the longest chain by a release
+    pub stale_from_release: Vec<H256>,
+}
+
+impl Adversary {
+    pub fn new(strategy: Strategy) -> Self {
+        Adversary {
+            strategy,
+            private: Vec::new(),
+            releases: Vec::new(),
+            stale_from_release: Vec::new(),
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Record a block we just mined (and inserted locally); returns the hashes to release now
+    pub fn on_mined(&mut self, hash: H256, height: u64) -> Vec<H256> {
+        self.private.push((hash, height));
+        match self.strategy {
+            Strategy::Withhold(k) if self.private.len() >= k => self.release_up_to(u64::MAX),
+            _ => vec![],
+        }
+    }
+
+    /// Record a competing block received at `height`; returns the hashes to release now
+    pub fn on_competing_block(&mut self, height: u64) -> Vec<H256> {
+        match self.strategy {
+            Strategy::Selfish if self.private.iter().any(|(_, h)| *h == height) => {
+                self.release_up_to(height)
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Heights of the private blocks that are still withheld
+    pub fn private_heights(&self) -> Vec<u64> {
+        self.private.iter().map(|(_, h)| *h).collect()
+    }
+
+    /// Release every private block at or below `height` (its private ancestors must go too)
+    fn release_up_to(&mut self, height: u64) -> Vec<H256> {
+        let (released, kept): (Vec<_>, Vec<_>) = self.private.drain(..).partition(|(_, h)| *h <= height);
+        self.private = kept;
+        let released: Vec<H256> = released.into_iter().map(|(hash, _)| hash).collect();
+        if !released.is_empty() {
+            self.releases.push(released.clone());
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::blockchain::{BlockOrigin, Blockchain};
+    use crate::crypto::hash::Hashable;
+
+    #[test]
+    fn parse_strategy() {
+        assert_eq!("selfish".parse::<Strategy>(), Ok(Strategy::Selfish));
+        assert_eq!("withhold:3".parse::<Strategy>(), Ok(Strategy::Withhold(3)));
+        assert!("withhold:0".parse::<Strategy>().is_err());
+        assert!("honest".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn withhold_2_releases_two_blocks_together() {
+        let mut adversary_node = Blockchain::new();
+        adversary_node.adversary = Some(Adversary::new(Strategy::Withhold(2)));
+        let genesis_hash = adversary_node.tip();
+
+        let block_1 = generate_random_block(&genesis_hash);
+        adversary_node.insert(&block_1);
+        assert!(adversary_node.adversary_on_mined(&block_1.hash()).unwrap().is_empty());
+        // private blocks are still part of the local chain view
+        assert_eq!(adversary_node.tip(), block_1.hash());
+
+        let block_2 = generate_random_block(&block_1.hash());
+        adversary_node.insert(&block_2);
+        let released = adversary_node.adversary_on_mined(&block_2.hash()).unwrap();
+        assert_eq!(released, vec![block_1.hash(), block_2.hash()]);
+        assert_eq!(adversary_node.adversary.as_ref().unwrap().releases.len(), 1);
+    }
+
+    #[test]
+    fn honest_node_reorgs_on_release() {
+        let mut adversary_node = Blockchain::new();
+        adversary_node.adversary = Some(Adversary::new(Strategy::Withhold(2)));
+        let mut honest_node = Blockchain::new();
+        let genesis_hash = honest_node.tip();
+
+        // the honest network finds a block and the adversary hears about it
+        let honest_block = generate_random_block(&genesis_hash);
+        honest_node.insert(&honest_block);
+        honest_node.hash_to_origin.insert(honest_block.hash(), BlockOrigin::Mined);
+        adversary_node.insert(&honest_block);
+        adversary_node.hash_to_origin.insert(honest_block.hash(), BlockOrigin::Received { delay_ms: 0, from: "127.0.0.1:6000".parse().unwrap() });
+
+        // the adversary mines a private branch of two blocks
+        let block_1 = generate_random_block(&genesis_hash);
+        adversary_node.insert(&block_1);
+        assert!(adversary_node.adversary_on_mined(&block_1.hash()).unwrap().is_empty());
+        let block_2 = generate_random_block(&block_1.hash());
+        adversary_node.insert(&block_2);
+        let released = adversary_node.adversary_on_mined(&block_2.hash()).unwrap();
+        assert_eq!(released.len(), 2);
+
+        // the honest node receives the release and switches over
+        for hash in released {
+            honest_node.insert(&adversary_node.get_block(&hash).unwrap());
+        }
+        assert_eq!(honest_node.tip(), block_2.hash());
+        assert_eq!(
+            adversary_node.adversary.as_ref().unwrap().stale_from_release,
+            vec![honest_block.hash()]
+        );
+    }
+
+    #[test]
+    fn selfish_releases_on_competing_height() {
+        let mut adversary_node = Blockchain::new();
+        adversary_node.adversary = Some(Adversary::new(Strategy::Selfish));
+        let genesis_hash = adversary_node.tip();
+
+        let private_block = generate_random_block(&genesis_hash);
+        adversary_node.insert(&private_block);
+        assert!(adversary_node.adversary_on_mined(&private_block.hash()).unwrap().is_empty());
+
+        let competing_block = generate_random_block(&genesis_hash);
+        adversary_node.insert(&competing_block);
+        adversary_node.hash_to_origin.insert(competing_block.hash(), BlockOrigin::Received { delay_ms: 0, from: "127.0.0.1:6000".parse().unwrap() });
+        let released = adversary_node.adversary_on_received(&competing_block.hash());
+        assert_eq!(released, vec![private_block.hash()]);
+        assert_eq!(
+            adversary_node.adversary.as_ref().unwrap().stale_from_release,
+            vec![competing_block.hash()]
+        );
+    }
+}
@@ -2,11 +2,17 @@ use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::mempool::Mempool;
+use crate::wallet::WalletManager;
+use crate::snapshot::{ConsistentView, SnapshotCoordinator};
+use crate::address::H160;
 
 use log::info;
 use std::collections::HashMap;
 use std::thread;
+use std::sync::{Arc, Mutex};
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -15,6 +21,9 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    mempool: Arc<Mutex<Mempool>>,
+    wallets: Arc<Mutex<WalletManager>>,
+    snapshots: SnapshotCoordinator,
 }
 
 #[derive(Serialize)]
@@ -23,6 +32,68 @@ struct ApiResponse {
     message: String,
 }
 
+/// Which tip and mempool generation an answer reflects, so clients can detect races
+#[derive(Serialize)]
+struct ViewToken {
+    tip: String,
+    height: u64,
+    mempool_generation: u64,
+}
+
+impl From<ConsistentView> for ViewToken {
+    fn from(view: ConsistentView) -> Self {
+        ViewToken {
+            tip: view.tip.to_string(),
+            height: view.height,
+            mempool_generation: view.mempool_generation,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PendingTransactions {
+    view: ViewToken,
+    transactions: Vec<String>,
+}
+
+/// What an account can spend as of the tip, and the coinbase rewards it has to wait for
+#[derive(Serialize)]
+struct Balance {
+    view: ViewToken,
+    balance: u64,
+    immature: u64,
+}
+
+#[derive(Serialize)]
+struct PeerLatency {
+    peer: String,
+    rtt_ms: f64,
+}
+
+#[derive(Serialize)]
+struct ConnectedPeer {
+    id: String,
+    peer: String,
+    direction: String,
+    connected_secs: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Milliseconds since its last message, if any
+    last_seen_ms: Option<u128>,
+}
+
+#[derive(Serialize)]
+struct DroppedMessages {
+    peer: String,
+    dropped: u64,
+}
+
+#[derive(Serialize)]
+struct WalletInfo {
+    name: String,
+    address: String,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -36,22 +107,40 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
+        mempool: &Arc<Mutex<Mempool>>,
+        wallets: &Arc<Mutex<WalletManager>>,
+        snapshots: &SnapshotCoordinator,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            mempool: Arc::clone(mempool),
+            wallets: Arc::clone(wallets),
+            snapshots: snapshots.clone(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let mempool = Arc::clone(&server.mempool);
+                let wallets = Arc::clone(&server.wallets);
+                let snapshots = server.snapshots.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -91,10 +180,162 @@ impl Server {
                             miner.exit();
                             respond_result!(req, true, "ok");
                         }
+                        "/report" => {
+                            match miner.report() {
+                                Some(report) => respond_json!(req, report),
+                                None => respond_result!(req, false, "no report yet, exit the miner first"),
+                            }
+                        }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            network.ping_all();
                             respond_result!(req, true, "ok");
                         }
+                        "/network/latencies" => {
+                            let latencies: Vec<_> = network.peer_latencies().into_iter()
+                                .map(|(addr, rtt)| PeerLatency { peer: addr.to_string(), rtt_ms: rtt.as_secs_f64() * 1000.0 })
+                                .collect();
+                            respond_json!(req, latencies);
+                        }
+                        "/network/peers" => {
+                            let peers: Vec<_> = network.peers().into_iter()
+                                .map(|info| ConnectedPeer {
+                                    id: info.id.to_string(),
+                                    peer: info.addr.to_string(),
+                                    direction: format!("{:?}", info.direction),
+                                    connected_secs: info.connected_for.as_secs(),
+                                    bytes_sent: info.bytes_sent,
+                                    bytes_received: info.bytes_received,
+                                    last_seen_ms: info.last_seen.map(|ago| ago.as_millis()),
+                                })
+                                .collect();
+                            respond_json!(req, peers);
+                        }
+                        "/network/queue" => {
+                            respond_json!(req, network.queue_stats());
+                        }
+                        "/network/stats" => {
+                            respond_json!(req, network.net_stats());
+                        }
+                        "/network/stats/reset" => {
+                            network.reset_net_stats();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/dropped-messages" => {
+                            let dropped: Vec<_> = network.dropped_messages().into_iter()
+                                .map(|(addr, dropped)| DroppedMessages { peer: addr.to_string(), dropped })
+                                .collect();
+                            respond_json!(req, dropped);
+                        }
+                        "/mempool/by-address" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let pending = snapshots.with_both(|_, mempool, view| PendingTransactions {
+                                view: view.into(),
+                                transactions: mempool.by_address(&address).into_iter()
+                                    .map(|tx| tx.txid().to_string())
+                                    .collect(),
+                            });
+                            respond_json!(req, pending);
+                        }
+                        "/balance" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let balance = snapshots.with_both(|blockchain, _, view| Balance {
+                                view: view.into(),
+                                balance: blockchain.balance_of(&address),
+                                immature: blockchain.immature_balance_of(&address),
+                            });
+                            respond_json!(req, balance);
+                        }
+                        "/wallets" if *req.method() == Method::Get => {
+                            let wallets = wallets.lock().unwrap();
+                            let list: Vec<_> = wallets.list().into_iter()
+                                .map(|(name, address)| WalletInfo { name, address: address.to_string() })
+                                .collect();
+                            respond_json!(req, list);
+                        }
+                        "/wallets" if *req.method() == Method::Post => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let name = match params.get("name") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing name");
+                                    return;
+                                }
+                            };
+                            match wallets.lock().unwrap().generate(name) {
+                                Ok(address) => respond_result!(req, true, address),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        path if path.starts_with("/wallets/") && path.ends_with("/send")
+                            && *req.method() == Method::Post => {
+                            let name = &path["/wallets/".len()..path.len() - "/send".len()];
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let to = match params.get("to").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing to: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing to");
+                                    return;
+                                }
+                            };
+                            let value = match params.get("value").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing value: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing value");
+                                    return;
+                                }
+                            };
+                            let fee = match params.get("fee").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing fee: {}", e));
+                                    return;
+                                }
+                                None => 0,
+                            };
+                            let result = wallets.lock().unwrap().send(name, to, value, fee, &mempool);
+                            match result {
+                                Ok(transaction) => {
+                                    let hash = transaction.txid();
+                                    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -1,7 +1,10 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::blockchain::TxApplyError;
+use std::collections::HashMap;
 // use crate::transaction::RawTransaction;
-use crate::transaction::SignedTransaction;
+use crate::transaction::{SignedTransaction, MAX_DATA_SIZE};
 
 /// The block header
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -11,6 +14,24 @@ pub struct Header {
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root: H256,
+    /// Hash of the state after this block, or all zeros if its transactions don't apply
+    pub state_root: H256,
+}
+
+impl Header {
+    /// The bytes hashed, independent of how bincode is configured: `parent`, then `nonce` as a
+    /// little-endian u32, `difficulty`, `timestamp` as a little-endian u128, `merkle_root` and
+    /// `state_root`, hashes as their 32 raw bytes; 148 bytes in all
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(148);
+        bytes.extend_from_slice(self.parent.as_ref());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend_from_slice(self.difficulty.as_ref());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(self.merkle_root.as_ref());
+        bytes.extend_from_slice(self.state_root.as_ref());
+        bytes
+    }
 }
 
 /// Transactions contained in a block
@@ -20,6 +41,13 @@ pub struct Content {
     pub transactions: Vec<SignedTransaction>,
 }
 
+impl Content {
+    /// Total serialized size of the transactions, in bytes
+    pub fn size(&self) -> usize {
+        self.transactions.iter().map(SignedTransaction::size).sum()
+    }
+}
+
 /// A block in the blockchain
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
@@ -27,10 +55,17 @@ pub struct Block {
     pub content: Content,
 }
 
+/// The most transactions a valid block may contain
+pub const MAX_TRANSACTIONS_PER_BLOCK: usize = 4096;
+/// The most bytes of transactions, coinbase included, a valid block may contain
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024;
+/// Coins created by each block's coinbase, on top of the fees of the block's transactions
+pub const BLOCK_REWARD: u64 = 50;
+
 /// Returns the default difficulty, which is a big-endian 32-byte integer.
 /// - Note: a valid block must satisfy that `block.hash() <= difficulty`.
 ///   In other words, the _smaller_ the `difficulty`, the harder it actually is to mine a block!
-fn default_difficulty() -> [u8; 32] {
+pub fn default_difficulty() -> [u8; 32] {
     let mut difficulty = [0u8; 32];
     difficulty[0] = 1;
     difficulty
@@ -39,18 +74,82 @@ fn default_difficulty() -> [u8; 32] {
 impl Block {
     /// Construct the (totally deterministic) genesis block
     pub fn genesis() -> Block {
+        Block::genesis_with(default_difficulty().into(), 0)
+    }
+
+    /// Construct a genesis block with the given difficulty and timestamp; deterministic as well,
+    /// so every node built with the same parameters agrees on its hash
+    pub fn genesis_with(difficulty: H256, timestamp: u128) -> Block {
         let transactions: Vec<SignedTransaction> = vec![];
         let header = Header {
             parent: Default::default(),
             nonce: 0,
-            difficulty: default_difficulty().into(),
-            timestamp: 0,
+            difficulty,
+            timestamp,
             merkle_root: Default::default(),
+            // genesis commits to no state: the ICO comes from the genesis config
+            state_root: Default::default(),
         };
         let content = Content { transactions };
         Block { header, content }
     }
 
+    /// Check that the header's merkle root commits to the transactions in the content.
+    /// A block without transactions, like genesis, has the all-zero root.
+    pub fn verify_merkle_root(&self) -> bool {
+        if self.content.transactions.is_empty() {
+            return self.header.merkle_root == H256::default();
+        }
+        MerkleTree::new(&self.content.transactions).root() == self.header.merkle_root
+    }
+
+    /// Check that the first transaction, and only it, is a coinbase, paying at most the block
+    /// reward plus the fees of the other transactions across its outputs. On failure, the index of the offending
+    /// transaction and why.
+    pub fn check_coinbase(&self) -> Result<(), (usize, TxApplyError)> {
+        let transactions = &self.content.transactions;
+        let coinbase = match transactions.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err((0, TxApplyError::MissingCoinbase)),
+        };
+        if let Some(i) = transactions[1..].iter().position(|tx| tx.is_coinbase()) {
+            return Err((i + 1, TxApplyError::MisplacedCoinbase));
+        }
+        let limit = transactions[1..].iter()
+            .try_fold(BLOCK_REWARD, |limit, tx| limit.checked_add(tx.raw.fee))
+            .ok_or((0, TxApplyError::Overflow))?;
+        let value = coinbase.raw.total_value().ok_or((0, TxApplyError::Overflow))?;
+        if value > limit {
+            return Err((0, TxApplyError::CoinbaseTooLarge { value, limit }));
+        }
+        Ok(())
+    }
+
+    /// Index of the first transaction whose nonce does not directly follow the previous one
+    /// from the same sender in this block, e.g. a repeated or skipped nonce. Needs no state,
+    /// so it also works when the parent's state is unknown.
+    pub fn nonce_conflict(&self) -> Option<usize> {
+        let mut last_nonce = HashMap::new();
+        for (i, tx) in self.content.transactions.iter().enumerate() {
+            if let Some(last) = last_nonce.insert(tx.raw.from_addr, tx.raw.nonce) {
+                if last.checked_add(1) != Some(tx.raw.nonce) {
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// Index of the first transaction that expired before `height`, the height of this block
+    pub fn expired_transaction(&self, height: u64) -> Option<usize> {
+        self.content.transactions.iter().position(|tx| tx.raw.is_expired_at(height))
+    }
+
+    /// Index of the first transaction carrying more than `MAX_DATA_SIZE` bytes of data
+    pub fn oversized_data(&self) -> Option<usize> {
+        self.content.transactions.iter().position(|tx| tx.raw.data.len() > MAX_DATA_SIZE)
+    }
+
     /// Obtain the block size in bytes
     pub fn size(&self) -> usize {
         bincode::serialize(&self).unwrap().len()
@@ -60,8 +159,7 @@ impl Block {
 impl Hashable for Header {
     /// Hash the block header using SHA256.
     fn hash(&self) -> H256 {
-        let bytes = bincode::serialize(&self).unwrap();
-        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+        ring::digest::digest(&ring::digest::SHA256, &self.canonical_bytes()).into()
     }
 }
 
@@ -72,23 +170,129 @@ impl Hashable for Block {
     }
 }
 
-// #[cfg(any(test, test_utilities))]
-// pub mod test {
-//     use super::*;
-//     use crate::crypto::hash::H256;
-//     use crate::crypto::merkle::MerkleTree;
-
-//     pub fn generate_random_block(parent: &H256) -> Block {
-//         let transactions: Vec<RawTransaction> = vec![Default::default()];
-//         let root = MerkleTree::new(&transactions).root();
-//         let header = Header {
-//             parent: *parent,
-//             nonce: rand::random(),
-//             difficulty: default_difficulty().into(),
-//             timestamp: rand::random(),
-//             merkle_root: root,
-//         };
-//         let content = Content { transactions };
-//         Block { header, content }
-//     }
-// }
+#[cfg(any(test, feature = "test-utilities"))]
+pub mod test {
+    use super::*;
+    use crate::crypto::hash::H256;
+    use crate::crypto::merkle::MerkleTree;
+
+    /// A block whose only transaction is a coinbase paying nothing; it claims height 0, as the
+    /// parent's height is unknown here
+    pub fn generate_random_block(parent: &H256) -> Block {
+        let transactions = vec![SignedTransaction::coinbase(Default::default(), 0, 0)];
+        let root = MerkleTree::new(&transactions).root();
+        let header = Header {
+            parent: *parent,
+            nonce: rand::random(),
+            difficulty: default_difficulty().into(),
+            timestamp: rand::random(),
+            merkle_root: root,
+            state_root: Default::default(),
+        };
+        let content = Content { transactions };
+        Block { header, content }
+    }
+
+    /// Like `generate_random_block`, but timestamped now and with a nonce satisfying the default difficulty
+    pub fn generate_mined_block(parent: &H256) -> Block {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        // strictly increasing, so that a chain mined within a millisecond still passes the
+        // median time past check
+        static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let last = LAST_TIMESTAMP.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1))).unwrap();
+        let mut block = generate_random_block(parent);
+        block.header.timestamp = now.max(last + 1) as u128;
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = rand::random();
+        }
+        block
+    }
+
+    #[test]
+    fn header_canonical_bytes_golden_vector() {
+        let header = Header {
+            parent: [0x01; 32].into(),
+            nonce: 0xdeadbeef,
+            difficulty: [0x02; 32].into(),
+            timestamp: 1_600_000_000_000,
+            merkle_root: [0x03; 32].into(),
+            state_root: [0x04; 32].into(),
+        };
+        assert_eq!(
+            hex::encode(header.canonical_bytes()),
+            concat!(
+                "0101010101010101010101010101010101010101010101010101010101010101", "efbeadde",
+                "0202020202020202020202020202020202020202020202020202020202020202", "00806e87740100000000000000000000",
+                "0303030303030303030303030303030303030303030303030303030303030303",
+                "0404040404040404040404040404040404040404040404040404040404040404",
+            )
+        );
+        assert_eq!(hex::encode(header.hash()), "7a4a9b2641580ee7d8f1bf5a89b133f8f83c35d6117d337c104e86a1e3ca7e7b");
+    }
+
+    #[test]
+    fn merkle_root_must_match_transactions() {
+        use crate::address::get_deterministic_keypair;
+        use crate::transaction::{ChainId, RawTransaction};
+
+        let block = generate_mined_block(&Default::default());
+        assert!(block.verify_merkle_root());
+        assert!(Block::genesis().verify_merkle_root());
+
+        let mut swapped = block.clone();
+        let raw = RawTransaction { nonce: 1, outputs: vec![(Default::default(), 100)], ..Default::default() };
+        swapped.content.transactions[0] = SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default());
+        // the header, and so the hash and PoW, are unchanged
+        assert_eq!(swapped.hash(), block.hash());
+        assert!(!swapped.verify_merkle_root());
+
+        let mut emptied = block;
+        emptied.content.transactions.clear();
+        assert!(!emptied.verify_merkle_root());
+    }
+
+    #[test]
+    fn coinbase_rules() {
+        use crate::address::get_deterministic_keypair;
+        use crate::blockchain::TxApplyError;
+        use crate::transaction::{ChainId, RawTransaction};
+
+        let miner = [7; 20].into();
+        let raw = RawTransaction { nonce: 1, outputs: vec![(Default::default(), 100)], fee: 20, ..Default::default() };
+        let paying = SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default());
+        let mut block = generate_random_block(&Default::default());
+        block.content.transactions = vec![SignedTransaction::coinbase(miner, BLOCK_REWARD + 20, 1), paying.clone()];
+        assert_eq!(block.check_coinbase(), Ok(()));
+
+        block.content.transactions[0] = SignedTransaction::coinbase(miner, BLOCK_REWARD + 21, 1);
+        assert_eq!(block.check_coinbase(), Err((0, TxApplyError::CoinbaseTooLarge { value: BLOCK_REWARD + 21, limit: BLOCK_REWARD + 20 })));
+
+        block.content.transactions = vec![SignedTransaction::coinbase(miner, 1, 1), SignedTransaction::coinbase(miner, 1, 1)];
+        assert_eq!(block.check_coinbase(), Err((1, TxApplyError::MisplacedCoinbase)));
+        block.content.transactions = vec![paying, SignedTransaction::coinbase(miner, 1, 1)];
+        assert_eq!(block.check_coinbase(), Err((0, TxApplyError::MissingCoinbase)));
+        block.content.transactions.clear();
+        assert_eq!(block.check_coinbase(), Err((0, TxApplyError::MissingCoinbase)));
+    }
+
+    #[test]
+    fn nonces_must_follow_within_a_block() {
+        use crate::address::get_deterministic_keypair;
+        use crate::transaction::{ChainId, RawTransaction};
+
+        // only the sender matters here, not who signed
+        let transaction = |sender: u8, nonce: u32| {
+            let raw = RawTransaction { from_addr: [sender; 20].into(), nonce, outputs: vec![(Default::default(), 1)], ..Default::default() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let mut block = generate_random_block(&Default::default());
+        block.content.transactions = vec![transaction(0, 4), transaction(1, 1), transaction(0, 5), transaction(1, 2)];
+        assert_eq!(block.nonce_conflict(), None);
+        block.content.transactions.push(transaction(0, 5));
+        assert_eq!(block.nonce_conflict(), Some(4));
+        block.content.transactions[4] = transaction(1, 4);
+        assert_eq!(block.nonce_conflict(), Some(4));
+    }
+}
@@ -1,219 +1,3923 @@
 use ring::signature::KeyPair;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::{debug, error};
+use std::fmt;
 
 use crate::address::{get_deterministic_keypair, H160};
-use crate::block::Block;
+#[cfg(feature = "adversary")]
+use crate::adversary::Adversary;
+use crate::block::{default_difficulty, Block, Header};
 use crate::crypto::hash::{H256, Hashable};
-use std::collections::HashMap; 
+use crate::mempool::Mempool;
+use crate::report::{ChainSummary, DelayStats};
+use crate::sig_cache::SigCache;
+use serde::{Serialize, Deserialize, Deserializer, Serializer};
+use crate::transaction::{ChainId, SignedTransaction, MAX_DATA_SIZE};
+use crate::transaction_generator::Stamp;
+use crate::validation::{RejectReason, ValidationStats, MAX_OUTPUTS_PER_TRANSACTION, MAX_TRANSACTION_SIZE};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Clone)]
+/// How many blocks the orphan buffer holds by default
+pub const DEFAULT_ORPHAN_LIMIT: usize = 1024;
+/// How many insertions happen between two checkpoints to disk
+pub const CHECKPOINT_INTERVAL: usize = 100;
+/// How far ahead of our clock a block's timestamp may be by default
+pub const DEFAULT_MAX_FUTURE_DRIFT: Duration = Duration::from_secs(120);
+/// How many ancestors the median timestamp is taken over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How many heights below the tip the state deltas are kept for
+pub const STATE_DELTA_WINDOW: u64 = 128;
+/// Full states are kept for blocks at multiples of this height
+pub const STATE_SNAPSHOT_INTERVAL: u64 = 128;
+
+impl Hashable for State {
+    /// Hash the accounts sorted by address, so equal states hash equally
+    /// however their accounts were inserted
+    fn hash(&self) -> H256 {
+        let mut accounts: Vec<(&H160, &(u32, u64))> = self.map.iter().collect();
+        accounts.sort_unstable_by_key(|(address, _)| **address);
+        // rewards waiting to mature count too, once there are any
+        let bytes = if self.immature.is_empty() {
+            bincode::serialize(&accounts).unwrap()
+        } else {
+            bincode::serialize(&(&accounts, &self.immature)).unwrap()
+        };
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
+/// Why a transaction could not be applied to a `State`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxApplyError {
+    BadSignature,
+    /// The public key does not belong to the sending address
+    WrongOwner,
+    /// The nonce is not the one after the sender's
+    BadNonce { expected: u32, got: u32 },
+    /// The balance does not cover the `cost`, the value plus the fee
+    InsufficientBalance { balance: u64, cost: u64 },
+    /// A balance or nonce would overflow
+    Overflow,
+    /// The sender already used the highest nonce, so it can send no more transactions
+    NonceExhausted,
+    /// The first transaction is not a coinbase
+    MissingCoinbase,
+    /// A coinbase other than the first transaction
+    MisplacedCoinbase,
+    /// The coinbase pays more than the block reward plus the fees
+    CoinbaseTooLarge { value: u64, limit: u64 },
+    /// The coinbase's nonce is not the block's height
+    CoinbaseHeight { expected: u32, got: u32 },
+}
+
+impl fmt::Display for TxApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxApplyError::BadSignature => write!(f, "invalid signature"),
+            TxApplyError::WrongOwner => write!(f, "public key does not match the sender"),
+            TxApplyError::BadNonce { expected, got } => write!(f, "nonce {} instead of {}", got, expected),
+            TxApplyError::InsufficientBalance { balance, cost } => {
+                write!(f, "sending {} with a balance of {}", cost, balance)
+            }
+            TxApplyError::Overflow => write!(f, "balance or nonce overflow"),
+            TxApplyError::NonceExhausted => write!(f, "sender has used up its nonces"),
+            TxApplyError::MissingCoinbase => write!(f, "not a coinbase"),
+            TxApplyError::MisplacedCoinbase => write!(f, "coinbase after the first transaction"),
+            TxApplyError::CoinbaseTooLarge { value, limit } => {
+                write!(f, "coinbase of {} exceeds the reward and fees of {}", value, limit)
+            }
+            TxApplyError::CoinbaseHeight { expected, got } => {
+                write!(f, "coinbase for height {} instead of {}", got, expected)
+            }
+        }
+    }
+}
+
+/// The nonce and balance of an account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountInfo {
+    /// Nonce of the last transaction sent from the account
+    pub nonce: u32,
+    pub balance: u64,
+}
+
+/// What applying a block changed in a `State`: the previous account of every address it
+/// touched, `None` for the ones it created, the rewards it made spendable, and the height at
+/// which those of its coinbase mature with how many there are
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateDelta {
+    previous: Vec<(H160, Option<(u32, u64)>)>,
+    matured: BTreeMap<u64, Vec<(H160, u64)>>,
+    immature_at: Option<(u64, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct State {
     map: HashMap<H160, (u32, u64)>, // (nonce, balance)
+    /// Coinbase rewards not spendable yet, by the height of the first block that may spend them
+    immature: BTreeMap<u64, Vec<(H160, u64)>>,
+    /// How many blocks a coinbase reward waits before it can be spent; 0 for right away
+    coinbase_maturity: u64,
 }
 
 impl State {
     /// Initial coin offering; generate an initial state.
     fn ico() -> Self {
-        let mut state = HashMap::new();
-        // give the i-th account 1000 * (10 - i) coins, i = 0, 1, 2, ..., 9
-        for i in 0..10 {
-            let pair = get_deterministic_keypair(i);
-            let address = H160::from_pubkey(pair.public_key().as_ref());
-            let balance: u64 = 1000 * ((10 - i) as u64);
-            let nonce: u32 = 0;
-            state.insert(address, (nonce, balance));
+        State::ico_from_config(&IcoConfig::default().allocations())
+    }
+
+    /// Initial coin offering giving each address its balance; an address listed twice gets both
+    pub fn ico_from_config(alloc: &[(H160, u64)]) -> State {
+        let mut map: HashMap<H160, (u32, u64)> = HashMap::new();
+        for (address, balance) in alloc {
+            let account = map.entry(*address).or_insert((0, 0));
+            account.1 = account.1.saturating_add(*balance);
         }
-        State { map: state }
+        State { map, immature: BTreeMap::new(), coinbase_maturity: 0 }
+    }
+
+    /// This state, with the coinbase rewards of the blocks applied to it only spendable
+    /// `maturity` blocks later
+    pub fn with_coinbase_maturity(mut self, maturity: u64) -> State {
+        self.coinbase_maturity = maturity;
+        self
+    }
+
+    pub fn coinbase_maturity(&self) -> u64 {
+        self.coinbase_maturity
+    }
+
+    /// Sum of the coinbase rewards to `address` that are not spendable yet
+    pub fn immature_balance(&self, address: &H160) -> u64 {
+        self.immature.values().flatten()
+            .filter(|(to, _)| to == address)
+            .map(|(_, value)| value)
+            .sum()
     }
 
     pub fn get(&self, address: &H160) -> Option<&(u32, u64)> {
         self.map.get(address)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&H160, &(u32, u64))> {
+        self.map.iter()
+    }
+
+    /// Every account's balance, richest first; equal balances are ordered by address
+    pub fn accounts_sorted_by_balance(&self) -> Vec<(H160, u64)> {
+        let mut accounts: Vec<(H160, u64)> = self.map.iter().map(|(address, &(_, balance))| (*address, balance)).collect();
+        accounts.sort_unstable_by(|(a, a_balance), (b, b_balance)| b_balance.cmp(a_balance).then(a.cmp(b)));
+        accounts
+    }
+
+    /// Sum of all balances and immature rewards, which only coinbases raise. Panics if it does
+    /// not fit in a u64, which blocks can't cause, nor a genesis config read with `from_json_file`.
+    pub fn total_supply(&self) -> u64 {
+        self.map.values().map(|&(_, balance)| balance)
+            .chain(self.immature.values().flatten().map(|&(_, value)| value))
+            .try_fold(0u64, |total, value| total.checked_add(value))
+            .expect("total supply overflows u64")
+    }
+
+    /// Like `get`, with named fields
+    pub fn account(&self, address: &H160) -> Option<AccountInfo> {
+        self.map.get(address).map(|&(nonce, balance)| AccountInfo { nonce, balance })
+    }
+
     pub fn update(&mut self, address: H160, nonce: u32, balance: u64) {
         self.map.insert(address, (nonce, balance));
     }
 
+    /// Apply a transaction: check it, then move the value, take the fee, which the block's
+    /// coinbase may claim, and bump the sender's nonce. The state is left unchanged if any
+    /// check fails. A zero-value transfer to an unknown address does not create an account for it.
+    pub fn apply_transaction(&mut self, tx: &SignedTransaction, chain: &ChainId) -> Result<(), TxApplyError> {
+        if !tx.verify_signature(chain) {
+            return Err(TxApplyError::BadSignature);
+        }
+        self.apply_presigned_transaction(tx)
+    }
+
+    /// Like `apply_transaction`, trusting that the signature was already verified
+    pub fn apply_presigned_transaction(&mut self, tx: &SignedTransaction) -> Result<(), TxApplyError> {
+        let raw = &tx.raw;
+        if !tx.verify_owner() {
+            return Err(TxApplyError::WrongOwner);
+        }
+        let sender = self.account(&raw.from_addr).unwrap_or_default();
+        let expected = sender.nonce.checked_add(1).ok_or(TxApplyError::NonceExhausted)?;
+        if raw.nonce != expected {
+            return Err(TxApplyError::BadNonce { expected, got: raw.nonce });
+        }
+        let cost = raw.total_value().and_then(|value| value.checked_add(raw.fee)).ok_or(TxApplyError::Overflow)?;
+        let sender_balance = sender.balance.checked_sub(cost)
+            .ok_or(TxApplyError::InsufficientBalance { balance: sender.balance, cost })?;
+        let mut accounts = HashMap::new();
+        accounts.insert(raw.from_addr, (expected, sender_balance));
+        let accounts = self.credited(accounts, &raw.outputs)?;
+        self.map.extend(accounts);
+        Ok(())
+    }
+
+    /// `accounts`, which take precedence over this state's, after crediting `outputs` in order.
+    /// A zero-value output to an unknown address creates no account.
+    fn credited(&self, mut accounts: HashMap<H160, (u32, u64)>, outputs: &[(H160, u64)]) -> Result<HashMap<H160, (u32, u64)>, TxApplyError> {
+        for (address, value) in outputs {
+            let (nonce, balance) = match accounts.get(address).or_else(|| self.map.get(address)) {
+                Some(account) => *account,
+                None if *value == 0 => continue,
+                None => (0, 0),
+            };
+            let balance = balance.checked_add(*value).ok_or(TxApplyError::Overflow)?;
+            accounts.insert(*address, (nonce, balance));
+        }
+        Ok(accounts)
+    }
+
+    /// The state after crediting a block's coinbase and applying its other transactions in
+    /// order, so a transaction may depend on an earlier one in the same block. With a coinbase
+    /// maturity, the coinbase is only credited that many blocks later, and the rewards maturing
+    /// at the block's height, the coinbase's nonce, are credited first instead. All or nothing:
+    /// on failure, the index of the first transaction that could not be applied, and why.
+    pub fn apply_block(&self, block: &Block, chain: &ChainId) -> Result<State, (usize, TxApplyError)> {
+        let mut state = self.clone();
+        state.apply_block_in_place(block, chain)?;
+        Ok(state)
+    }
+
+    /// Like `apply_block`, but changing this state and returning what to `revert` to undo it.
+    /// On failure, the state is left unchanged.
+    pub fn apply_block_in_place(&mut self, block: &Block, chain: &ChainId) -> Result<StateDelta, (usize, TxApplyError)> {
+        self.apply_block_checking(block, Some(chain))
+    }
+
+    /// Like `apply_block_in_place`, trusting that the signatures were already verified,
+    /// e.g. with `verify_batch`
+    pub fn apply_presigned_block_in_place(&mut self, block: &Block) -> Result<StateDelta, (usize, TxApplyError)> {
+        self.apply_block_checking(block, None)
+    }
+
+    /// Checks signatures for `chain`, if given
+    fn apply_block_checking(&mut self, block: &Block, chain: Option<&ChainId>) -> Result<StateDelta, (usize, TxApplyError)> {
+        block.check_coinbase()?;
+        let mut delta = StateDelta::default();
+        let mut touched = HashSet::new();
+        let coinbase = &block.content.transactions[0].raw;
+        let height = u64::from(coinbase.nonce);
+        let mut credits: Vec<(H160, u64)> = self.immature.range(..=height).flat_map(|(_, credits)| credits.iter().copied()).collect();
+        if self.coinbase_maturity == 0 {
+            credits.extend(coinbase.outputs.iter().copied());
+        }
+        let rewarded = self.credited(HashMap::new(), &credits).map_err(|e| (0, e))?;
+        for address in rewarded.keys() {
+            touched.insert(*address);
+            delta.previous.push((*address, self.map.get(address).copied()));
+        }
+        self.map.extend(rewarded);
+        let immature = self.immature.split_off(&(height + 1));
+        delta.matured = std::mem::replace(&mut self.immature, immature);
+        let rewards: Vec<(H160, u64)> = coinbase.outputs.iter().copied().filter(|(_, value)| *value > 0).collect();
+        if self.coinbase_maturity > 0 && !rewards.is_empty() {
+            let at = height + self.coinbase_maturity;
+            delta.immature_at = Some((at, rewards.len()));
+            self.immature.entry(at).or_default().extend(rewards);
+        }
+        for (i, tx) in block.content.transactions.iter().enumerate().skip(1) {
+            let addresses = std::iter::once(&tx.raw.from_addr).chain(tx.raw.outputs.iter().map(|(address, _)| address));
+            for address in addresses {
+                if touched.insert(*address) {
+                    delta.previous.push((*address, self.map.get(address).copied()));
+                }
+            }
+            let applied = match chain {
+                Some(chain) => self.apply_transaction(tx, chain),
+                None => self.apply_presigned_transaction(tx),
+            };
+            if let Err(e) = applied {
+                self.revert(&delta);
+                return Err((i, e));
+            }
+        }
+        Ok(delta)
+    }
+
+    /// Undo the block whose application produced `delta`
+    pub fn revert(&mut self, delta: &StateDelta) {
+        for (address, previous) in &delta.previous {
+            match previous {
+                Some(account) => self.map.insert(*address, *account),
+                None => self.map.remove(address),
+            };
+        }
+        if let Some((at, count)) = delta.immature_at {
+            let rewards = self.immature.get_mut(&at).expect("reverting a block not applied");
+            rewards.truncate(rewards.len() - count);
+            if rewards.is_empty() {
+                self.immature.remove(&at);
+            }
+        }
+        for (at, rewards) in &delta.matured {
+            self.immature.entry(*at).or_default().extend(rewards.iter().copied());
+        }
+    }
+
+    /// Remove the accounts that are indistinguishable from absent ones (no balance, nonce 0)
+    /// and return how many were removed. Drained accounts that have sent something are kept:
+    /// their nonce is what stops their old transactions from being replayed once they are
+    /// refilled, so it must never restart at 0. Since `apply_transaction` never creates such
+    /// accounts, states built by applying blocks are unaffected and hash the same on every node.
+    pub fn compact(&mut self) -> usize {
+        let before = self.map.len();
+        self.map.retain(|_, account| *account != (0, 0));
+        before - self.map.len()
+    }
+
+    /// The accounts as pretty JSON, sorted by address so equal states export identically
+    pub fn export_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(s: &str) -> Result<State, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+
+    /// Every account on which the two states disagree, sorted by address
+    pub fn diff(&self, other: &State) -> Vec<StateDiffEntry> {
+        let mut entries: Vec<StateDiffEntry> = self.map.keys().chain(other.map.keys())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|address| self.map.get(address) != other.map.get(address))
+            .map(|address| StateDiffEntry {
+                address: *address,
+                ours: self.account(address),
+                theirs: other.account(address),
+            })
+            .collect();
+        entries.sort_unstable_by_key(|entry| entry.address);
+        entries
+    }
+
     // other methods...
 }
 
+/// An account on which two states disagree; `None` where a state does not have it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateDiffEntry {
+    pub address: H160,
+    pub ours: Option<AccountInfo>,
+    pub theirs: Option<AccountInfo>,
+}
+
+/// One account of a serialized `State`
+#[derive(Serialize, Deserialize)]
+struct StateEntry {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    address: H160,
+    nonce: u32,
+    balance: u64,
+}
+
+/// A coinbase reward of a serialized `State`, spendable from the block at `height` on
+#[derive(Serialize, Deserialize)]
+struct ImmatureEntry {
+    height: u64,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    address: H160,
+    value: u64,
+}
+
+/// A serialized `State`
+#[derive(Serialize, Deserialize)]
+struct StateEntries {
+    accounts: Vec<StateEntry>,
+    immature: Vec<ImmatureEntry>,
+    coinbase_maturity: u64,
+}
+
+/// A state serializes as its accounts sorted by address, then its immature rewards in the order
+/// they mature, so the output is byte-stable
+impl Serialize for State {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut accounts: Vec<StateEntry> = self.map.iter()
+            .map(|(address, &(nonce, balance))| StateEntry { address: *address, nonce, balance })
+            .collect();
+        accounts.sort_unstable_by_key(|entry| entry.address);
+        let immature = self.immature.iter()
+            .flat_map(|(&height, rewards)| rewards.iter().map(move |&(address, value)| ImmatureEntry { height, address, value }))
+            .collect();
+        StateEntries { accounts, immature, coinbase_maturity: self.coinbase_maturity }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = StateEntries::deserialize(deserializer)?;
+        let mut map = HashMap::new();
+        for entry in entries.accounts {
+            if map.insert(entry.address, (entry.nonce, entry.balance)).is_some() {
+                return Err(serde::de::Error::custom(format!("duplicate account {}", entry.address)));
+            }
+        }
+        let mut immature: BTreeMap<u64, Vec<(H160, u64)>> = BTreeMap::new();
+        for entry in entries.immature {
+            immature.entry(entry.height).or_default().push((entry.address, entry.value));
+        }
+        Ok(State { map, immature, coinbase_maturity: entries.coinbase_maturity })
+    }
+}
+
 /// Whether the block is mined or received from the network
 pub enum BlockOrigin {
     Mined,
-    Received{delay_ms: u128},
+    Received{delay_ms: u128, from: SocketAddr},
+}
+
+/// Parameters every node of a network must agree on, as they determine the genesis block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisConfig {
+    /// Mining target of the genesis block and every block after it, as 64 hex digits
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub difficulty: H256,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// The accounts funded at genesis, ten deterministic ones if left out
+    #[serde(default)]
+    pub ico: IcoConfig,
+    /// How many blocks a coinbase reward waits before it can be spent, none if left out
+    #[serde(default)]
+    pub coinbase_maturity: u64,
+}
+
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        GenesisConfig { difficulty: default_difficulty().into(), timestamp: 0, ico: IcoConfig::default(), coinbase_maturity: 0 }
+    }
+}
+
+/// The accounts funded at genesis
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IcoConfig {
+    pub accounts: Vec<IcoAccount>,
+}
+
+/// An account funded at genesis, given by its address or by the index of its deterministic key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum IcoAccount {
+    Address {
+        /// 40 hex digits
+        #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+        address: H160,
+        balance: u64,
+    },
+    KeyIndex { key_index: u8, balance: u64 },
+}
+
+impl Default for IcoConfig {
+    /// The i-th deterministic account gets 1000 * (10 - i) coins, i = 0, 1, 2, ..., 9
+    fn default() -> Self {
+        let accounts = (0..10)
+            .map(|i| IcoAccount::KeyIndex { key_index: i, balance: 1000 * (10 - i as u64) })
+            .collect();
+        IcoConfig { accounts }
+    }
+}
+
+impl IcoConfig {
+    /// Each funded address with its balance
+    pub fn allocations(&self) -> Vec<(H160, u64)> {
+        self.accounts.iter().map(|account| match account {
+            IcoAccount::Address { address, balance } => (*address, *balance),
+            IcoAccount::KeyIndex { key_index, balance } => {
+                let pair = get_deterministic_keypair(*key_index);
+                (H160::from_pubkey(pair.public_key().as_ref()), *balance)
+            }
+        }).collect()
+    }
+}
+
+impl GenesisConfig {
+    /// Read a config from a JSON file. The ICO must not allocate more than a u64 holds in total.
+    pub fn from_json_file(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("error reading genesis config {}: {}", path.display(), e))?;
+        let config: GenesisConfig = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("invalid genesis config {}: {}", path.display(), e))?;
+        config.ico.allocations().iter().try_fold(0u64, |total, (_, balance)| total.checked_add(*balance))
+            .ok_or(format!("invalid genesis config {}: the ICO allocates more than {} in total", path.display(), u64::MAX))?;
+        Ok(config)
+    }
+}
+
+fn serialize_hex<S: Serializer, T: fmt::Display>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+fn deserialize_hex<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// What `Blockchain::save` writes to disk; the indices are rebuilt on load
+#[derive(Serialize, Deserialize)]
+struct StoredBlockchain {
+    genesis: Block,
+    genesis_state: State,
+    /// Every block but genesis, parents before children
+    blocks: Vec<BlockEntry>,
+    orphans: Vec<Arc<Block>>,
+    tip: H256,
+    /// States of the pruned blocks that have one, which their headers alone cannot rebuild
+    pruned_states: Vec<(H256, State)>,
+}
+
+/// Why a block from the network was refused by `Blockchain::try_insert`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError {
+    /// The block's ancestry at `height` is not the checkpointed `expected` block
+    CheckpointMismatch { height: u64, expected: H256 },
+    /// Making the block the tip would detach `depth` blocks, more than `limit`
+    ReorgTooDeep { depth: u64, limit: u64 },
+    /// The header commits to `found` rather than the hash of the state after the block
+    StateRootMismatch { expected: H256, found: H256 },
+    /// Transaction `index` was only valid until `valid_until`, before the block's `height`
+    ExpiredTransaction { index: usize, valid_until: u64, height: u64 },
+    /// Transaction `index` carries `size` bytes of data, more than `MAX_DATA_SIZE`
+    DataTooLarge { index: usize, size: usize },
+    /// Transaction `index` does not apply on top of the parent's state
+    InvalidTransaction { index: usize, error: TxApplyError },
+}
+
+impl InsertError {
+    /// Whether the block is invalid on any chain, rather than refused where this node stands
+    pub fn is_invalid(&self) -> bool {
+        matches!(self,
+            InsertError::StateRootMismatch { .. } | InsertError::ExpiredTransaction { .. }
+                | InsertError::DataTooLarge { .. } | InsertError::InvalidTransaction { .. })
+    }
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InsertError::CheckpointMismatch { height, expected } => {
+                write!(f, "ancestry does not include checkpoint {} at height {}", expected, height)
+            }
+            InsertError::ReorgTooDeep { depth, limit } => {
+                write!(f, "reorg of depth {} exceeds the limit of {}", depth, limit)
+            }
+            InsertError::StateRootMismatch { expected, found } => {
+                write!(f, "state root {} instead of {}", found, expected)
+            }
+            InsertError::ExpiredTransaction { index, valid_until, height } => {
+                write!(f, "transaction {} valid until height {} included at height {}", index, valid_until, height)
+            }
+            InsertError::DataTooLarge { index, size } => {
+                write!(f, "transaction {} carries {} bytes of data, over the limit of {}", index, size, MAX_DATA_SIZE)
+            }
+            InsertError::InvalidTransaction { index, error } => write!(f, "transaction {} does not apply: {}", index, error),
+        }
+    }
+}
+
+/// A broken invariant found by `Blockchain::audit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditError {
+    /// A non-genesis block whose parent is not in the blockchain
+    MissingParent { hash: H256, parent: H256 },
+    /// A block whose recorded height is not its parent's height plus one
+    WrongHeight { hash: H256, height: u64, expected: u64 },
+    /// Some block is higher than the tip
+    TipNotHighest { tip_height: u64, max_height: u64 },
+    /// A block stored under a key other than its own hash
+    HashMismatch { key: H256, hash: H256 },
+    /// An orphan whose parent is already in the blockchain
+    OrphanWithKnownParent { hash: H256, parent: H256 },
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditError::MissingParent { hash, parent } => {
+                write!(f, "block {} has unknown parent {}", hash, parent)
+            }
+            AuditError::WrongHeight { hash, height, expected } => {
+                write!(f, "block {} is at height {} instead of {}", hash, height, expected)
+            }
+            AuditError::TipNotHighest { tip_height, max_height } => {
+                write!(f, "tip is at height {} but a block is at height {}", tip_height, max_height)
+            }
+            AuditError::HashMismatch { key, hash } => {
+                write!(f, "block {} is stored under {}", hash, key)
+            }
+            AuditError::OrphanWithKnownParent { hash, parent } => {
+                write!(f, "orphan {} has known parent {}", hash, parent)
+            }
+        }
+    }
+}
+
+/// What inserting a block did to the blockchain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The block became the tip, on top of the previous one
+    ExtendedTip,
+    /// The block went on a branch other than the longest chain
+    SideChain,
+    /// The block became the tip of another branch, detaching `depth` blocks from the longest chain
+    Reorged { depth: u64 },
+    /// The block was already in the blockchain; nothing changed
+    Duplicate,
+}
+
+impl InsertOutcome {
+    /// Whether the tip moved
+    pub fn moved_tip(&self) -> bool {
+        matches!(self, InsertOutcome::ExtendedTip | InsertOutcome::Reorged { .. })
+    }
+}
+
+/// What `Blockchain::insert_recursively` did besides inserting the block itself
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// What each inserted block did, in insertion order
+    pub outcomes: Vec<(H256, InsertOutcome)>,
+    /// Buffered orphans inserted because their missing ancestor arrived
+    pub orphans_resolved: usize,
+    /// Blocks refused by `try_insert`, with the reason; their buffered descendants are dropped
+    pub rejected: Vec<(H256, InsertError)>,
+    /// The peers that sent orphans refused as invalid once their parent arrived
+    pub invalid_from: Vec<SocketAddr>,
+}
+
+/// A source of the current time in milliseconds, replaceable so tests can control time
+pub trait Clock: Send {
+    fn now_ms(&self) -> u128;
+}
+
+/// The system clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u128 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+    }
+}
+
+/// How long a block remained the tip of the longest chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TipDuration {
+    pub hash: H256,
+    pub duration_ms: u128,
+    /// Whether the block was displaced by a reorg rather than extended
+    pub displaced: bool,
+}
+
+/// A reorg deep enough to set off the alarm; see `Blockchain::set_reorg_alarm`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorgEvent {
+    pub common_ancestor: H256,
+    /// Blocks taken off the longest chain, in order of height
+    pub detached: Vec<H256>,
+    /// Blocks now on the longest chain in their place, in order of height
+    pub attached: Vec<H256>,
+    /// When the reorg happened, by the blockchain's clock
+    pub timestamp_ms: u128,
+}
+
+/// A block in the blockchain, possibly with its body pruned away
+#[derive(Serialize, Deserialize, Clone)]
+enum BlockEntry {
+    Full(Arc<Block>),
+    HeaderOnly(Header),
+}
+
+impl BlockEntry {
+    fn header(&self) -> &Header {
+        match self {
+            BlockEntry::Full(block) => &block.header,
+            BlockEntry::HeaderOnly(header) => header,
+        }
+    }
+
+    /// The whole block, unless its body was pruned
+    fn block(&self) -> Option<&Arc<Block>> {
+        match self {
+            BlockEntry::Full(block) => Some(block),
+            BlockEntry::HeaderOnly(_) => None,
+        }
+    }
+}
+
+/// A block waiting in the orphan buffer for its parent
+struct Orphan {
+    block: Arc<Block>,
+    buffered_at: Instant,
+    /// Order in which orphans were buffered, used to evict the oldest
+    seq: u64,
+    /// When and from whom it arrived, recorded in `hash_to_origin` only once it is inserted
+    origin: Option<BlockOrigin>,
 }
 
 pub struct Blockchain {
-    hash_to_block: HashMap<H256, Block>,
+    hash_to_block: HashMap<H256, BlockEntry>,
     hash_to_height: HashMap<H256, u64>,
+    /// The i-th entry is the hash of the block at height i along the longest chain
+    height_to_canonical_hash: Vec<H256>,
+    hash_to_children: HashMap<H256, Vec<H256>>,
+    /// Txid of every transaction on the longest chain but the coinbases, mapped to the block containing it
+    tx_to_block: HashMap<H256, H256>,
+    /// Blocks whose transactions, and all their ancestors', could be applied
+    stateful: HashSet<H256>,
+    /// State of the tip, if it is stateful
+    tip_state: Option<State>,
+    /// How the blocks of the last `STATE_DELTA_WINDOW` heights changed their parent's state
+    hash_to_delta: HashMap<H256, StateDelta>,
+    /// Full states of genesis, the stateful blocks at multiples of `STATE_SNAPSHOT_INTERVAL` and
+    /// the highest pruned block
+    state_snapshots: HashMap<H256, State>,
     tip: H256,
     difficulty: H256,
-    orphan_buffer: HashMap<H256, Vec<Block>>,
+    /// Parentless blocks keyed by their parent's hash
+    orphan_buffer: HashMap<H256, Vec<Orphan>>,
+    orphan_limit: usize,
+    /// Sequence number of the next block added to the orphan buffer
+    next_orphan_seq: u64,
+    /// Where to checkpoint the blockchain, if anywhere
+    checkpoint_path: Option<PathBuf>,
+    inserts_since_checkpoint: usize,
+    /// Blocks pinned as final, by height; not to be confused with checkpoints to disk
+    finalized: BTreeMap<u64, H256>,
+    max_reorg_depth: Option<u64>,
+    max_future_drift: Duration,
+    /// Notified of every new tip; see `subscribe_tip`
+    tip_subscribers: Vec<Sender<H256>>,
+    /// Minimum depth and destination of reorg alarms
+    reorg_alarm: Option<(u64, Sender<ReorgEvent>)>,
     // below are used for experiments:
     pub hash_to_origin: HashMap<H256, BlockOrigin>,
+    clock: Box<dyn Clock>,
+    /// When the current tip became the tip, by `clock`
+    tip_since: u128,
+    tip_durations: Vec<TipDuration>,
+    validation_stats: ValidationStats,
+    /// The lowest fee admitted into the mempool
+    min_fee: u64,
+    /// Shared with the threads verifying transactions without holding the blockchain
+    sig_cache: Arc<SigCache>,
+    #[cfg(feature = "adversary")]
+    pub adversary: Option<Adversary>,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain, only containing the default genesis block
     pub fn new() -> Self {
-        let genesis_block = Block::genesis();
+        Blockchain::from_genesis_config(&GenesisConfig::default())
+    }
+
+    /// Create a new blockchain, only containing the genesis block described by `config`
+    pub fn from_genesis_config(config: &GenesisConfig) -> Self {
+        Blockchain::from_genesis(
+            Block::genesis_with(config.difficulty, config.timestamp as u128),
+            State::ico_from_config(&config.ico.allocations()).with_coinbase_maturity(config.coinbase_maturity),
+        )
+    }
+
+    /// Create a new blockchain, only containing a genesis block with the given parameters
+    pub fn new_with_genesis(difficulty: H256, timestamp: u128) -> Self {
+        Blockchain::from_genesis(Block::genesis_with(difficulty, timestamp), State::ico())
+    }
+
+    fn from_genesis(genesis_block: Block, genesis_state: State) -> Self {
         let genesis_hash = genesis_block.hash();
         let genesis_difficulty = genesis_block.header.difficulty;
         let mut hash_to_block = HashMap::new();
-        hash_to_block.insert(genesis_hash, genesis_block);
+        hash_to_block.insert(genesis_hash, BlockEntry::Full(Arc::new(genesis_block)));
         let mut hash_to_height = HashMap::new();
         hash_to_height.insert(genesis_hash, 0);
+        let mut stateful = HashSet::new();
+        stateful.insert(genesis_hash);
+        let mut state_snapshots = HashMap::new();
+        state_snapshots.insert(genesis_hash, genesis_state.clone());
         Blockchain {
             hash_to_block,
             hash_to_height,
+            height_to_canonical_hash: vec![genesis_hash],
+            hash_to_children: HashMap::new(),
+            tx_to_block: HashMap::new(),
+            stateful,
+            tip_state: Some(genesis_state),
+            hash_to_delta: HashMap::new(),
+            state_snapshots,
             tip: genesis_hash,
             difficulty: genesis_difficulty,
             orphan_buffer: HashMap::new(),
+            orphan_limit: DEFAULT_ORPHAN_LIMIT,
+            next_orphan_seq: 0,
+            checkpoint_path: None,
+            inserts_since_checkpoint: 0,
+            finalized: BTreeMap::new(),
+            max_reorg_depth: None,
+            max_future_drift: DEFAULT_MAX_FUTURE_DRIFT,
+            tip_subscribers: Vec::new(),
+            reorg_alarm: None,
             hash_to_origin: HashMap::new(),
+            clock: Box::new(SystemClock),
+            tip_since: SystemClock.now_ms(),
+            tip_durations: Vec::new(),
+            validation_stats: ValidationStats::default(),
+            min_fee: 0,
+            sig_cache: Arc::new(SigCache::default()),
+            #[cfg(feature = "adversary")]
+            adversary: None,
         }
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
-        let parent_hash = block.header.parent;
-        let parent_height = *self.hash_to_height.get(&parent_hash).unwrap();
-        let height = parent_height + 1;
-        let block_hash = block.hash();
-        self.hash_to_block.insert(block_hash, block.clone());
-        self.hash_to_height.insert(block_hash, height);
-        if height > *self.hash_to_height.get(&self.tip).unwrap() {
-            self.tip = block_hash;
-        }
+    /// Replace the clock timing how long blocks remain the tip
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.tip_since = clock.now_ms();
+        self.clock = clock;
     }
 
-    /// Get the last block's hash of the longest chain
-    pub fn tip(&self) -> H256 {
-        self.tip
+    /// Create a new blockchain whose orphan buffer holds at most `limit` blocks
+    pub fn with_orphan_limit(limit: usize) -> Self {
+        let mut blockchain = Blockchain::new();
+        blockchain.orphan_limit = limit;
+        blockchain
     }
 
-    /// Get all the blocks' hashes along the longest chain
-    pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        let mut curr_hash = self.tip;
-        let mut hashes_backward = vec![curr_hash];
-        while *self.hash_to_height.get(&curr_hash).unwrap() > 0 { // while not genesis
-            curr_hash = self.hash_to_block.get(&curr_hash).unwrap().header.parent;
-            hashes_backward.push(curr_hash);
-        }
-        hashes_backward.into_iter().rev().collect()
+    /// Pin `hash` as the final block at `height`: from now on `try_insert` refuses any block
+    /// at or above that height whose ancestry does not include it
+    pub fn add_checkpoint(&mut self, height: u64, hash: H256) {
+        self.finalized.insert(height, hash);
     }
 
-    pub fn get_block(&self, hash: &H256) -> &Block {
-        self.hash_to_block.get(hash).unwrap()
+    /// Refuse any reorg that detaches more than `depth` blocks from the longest chain
+    pub fn set_max_reorg_depth(&mut self, depth: Option<u64>) {
+        self.max_reorg_depth = depth;
     }
 
-    pub fn contains_block(&self, hash: &H256) -> bool {
-        self.hash_to_block.contains_key(hash)
+    /// Accept blocks timestamped at most `drift` ahead of our clock
+    pub fn set_max_future_drift(&mut self, drift: Duration) {
+        self.max_future_drift = drift;
     }
 
-    /// Check if a block is consistent with PoW
-    pub fn pow_validity_check(&self, block: &Block) -> bool {
-        block.hash() <= block.header.difficulty && block.header.difficulty == self.difficulty
+    pub fn max_future_drift(&self) -> Duration {
+        self.max_future_drift
     }
 
-    /// Check if a block's parent is in the blockchain
-    pub fn parent_check(&self, block: &Block) -> bool {
-        self.contains_block(&block.header.parent)
+    /// Check that a block is not timestamped too far in the future and, if its parent is known,
+    /// that it is later than the median timestamp of the last `MEDIAN_TIME_SPAN` ancestors
+    pub fn timestamp_validity_check(&self, block: &Block, now_ms: u128) -> bool {
+        if block.header.timestamp > now_ms + self.max_future_drift.as_millis() {
+            return false;
+        }
+        match self.median_time_past(&block.header.parent, MEDIAN_TIME_SPAN) {
+            Some(median) => block.header.timestamp > median,
+            None => true, // an orphan, checked again once its parent arrives
+        }
     }
 
-    /// Add a PoW valid, parentless block to the orphan buffer
-    pub fn add_to_orphan_buffer(&mut self, block: &Block) {
-        self.orphan_buffer.entry(block.header.parent).or_insert(vec![]).push(block.clone());
+    /// The earliest timestamp a child of `parent` may have
+    pub fn min_timestamp_after(&self, parent: &H256) -> u128 {
+        self.median_time_past(parent, MEDIAN_TIME_SPAN).map_or(0, |median| median + 1)
     }
 
-    /// Insert a PoW valid, parentful block into the blockchain, and recursively do all its children.
-    /// `out_hashes` is used to store the hashes of all the blocks inserted.
-    pub fn insert_recursively(&mut self, block: &Block, out_hashes: &mut Vec<H256>) {
-        if self.contains_block(&block.hash()) {
-            return;  // redundant item, skip
+    /// Median timestamp of `hash` and its ancestors, up to `window` blocks; with an even count
+    /// near genesis, the lower of the two middle ones. `None` if `hash` is unknown or `window` is 0.
+    pub fn median_time_past(&self, hash: &H256, window: usize) -> Option<u128> {
+        if !self.contains_block(hash) || window == 0 {
+            return None;
+        }
+        let mut timestamps = Vec::with_capacity(window);
+        timestamps.extend(self.iter_from(hash).take(window).map(|(_, header)| header.timestamp));
+        timestamps.sort_unstable();
+        Some(timestamps[(timestamps.len() - 1) / 2])
+    }
+
+    /// The state after a block, if its transactions and all its ancestors' could be applied.
+    /// Rebuilt on demand: from the tip state, reverting recent blocks of the longest chain,
+    /// or from the nearest snapshot, applying the blocks after it again. `None` as well if
+    /// that needs a pruned body.
+    pub fn state_at(&self, hash: &H256) -> Option<State> {
+        if !self.stateful.contains(hash) {
+            return None;
         }
-        self.insert(block);
-        out_hashes.push(block.hash());
-        if self.orphan_buffer.contains_key(&block.hash()) {
-            for child in self.orphan_buffer.remove(&block.hash()).unwrap() {
-                self.insert_recursively(&child, out_hashes);
+        // the blocks to apply on top of the starting state, newest first
+        let mut path = Vec::new();
+        let mut current = *hash;
+        let mut state = loop {
+            if let Some(state) = self.rewind_tip_state(&current) {
+                break state;
             }
+            if let Some(snapshot) = self.state_snapshots.get(&current) {
+                break snapshot.clone();
+            }
+            path.push(current);
+            current = self.hash_to_block[&current].header().parent;
+        };
+        for hash in path.iter().rev() {
+            state.apply_presigned_block_in_place(self.hash_to_block[hash].block()?).ok()?;
         }
+        Some(state)
     }
 
-    pub fn block_count(&self) -> usize {
-        self.hash_to_block.len()
+    /// The tip state rolled back to `hash`, if it is on the longest chain and recent enough
+    /// for the deltas to still be there
+    fn rewind_tip_state(&self, hash: &H256) -> Option<State> {
+        if !self.is_in_longest_chain(hash) {
+            return None;
+        }
+        let undone = &self.height_to_canonical_hash[self.hash_to_height[hash] as usize + 1..];
+        if !undone.iter().all(|hash| self.hash_to_delta.contains_key(hash)) {
+            return None;
+        }
+        let mut state = self.tip_state.clone()?;
+        for hash in undone.iter().rev() {
+            state.revert(&self.hash_to_delta[hash]);
+        }
+        Some(state)
     }
 
-    pub fn average_block_size(&self) -> usize {
-        self.hash_to_block.values().map(|block| block.size()).sum::<usize>() / self.block_count()
+    /// An account as of the tip, or as of its latest ancestor with a state if the tip has none
+    pub fn account_info(&self, address: &H160) -> Option<AccountInfo> {
+        match &self.tip_state {
+            Some(state) => state.account(address),
+            None => self.latest_state()?.account(address),
+        }
     }
 
-    pub fn block_delays_ms(&self) -> Vec<u128> {
-        let mut delays: Vec<_> = self.hash_to_origin.values().filter_map(|origin| {
-            match origin {
-                BlockOrigin::Mined => None,
-                BlockOrigin::Received{delay_ms} => Some(*delay_ms),
+    /// Admit transactions into the mempool, in order, unless they are oversized, already
+    /// pending or confirmed, coinbases, badly signed, pay less than the minimum fee, or can't apply to
+    /// the latest state. A nonce beyond
+    /// the next one is fine, as it may follow transactions still pending. Rejections are
+    /// counted in `validation_stats`.
+    pub fn admit_transactions(&mut self, mempool: &mut Mempool, transactions: Vec<SignedTransaction>) -> Vec<Result<(), RejectReason>> {
+        let latest;
+        let state = match &self.tip_state {
+            Some(state) => Some(state),
+            None => {
+                latest = self.latest_state();
+                latest.as_ref()
+            }
+        };
+        let chain = self.chain_id();
+        let results: Vec<Result<(), RejectReason>> = transactions.into_iter().map(|tx| {
+            if tx.size() > MAX_TRANSACTION_SIZE {
+                return Err(RejectReason::Oversized);
+            }
+            if tx.raw.outputs.len() > MAX_OUTPUTS_PER_TRANSACTION {
+                return Err(RejectReason::TooManyOutputs);
+            }
+            if tx.raw.data.len() > MAX_DATA_SIZE {
+                return Err(RejectReason::DataTooLarge);
+            }
+            if mempool.get_transaction(&tx.txid()).is_some() || self.tx_to_block.contains_key(&tx.txid()) {
+                return Err(RejectReason::Duplicate);
+            }
+            if tx.is_coinbase() {
+                return Err(RejectReason::BadCoinbase);
+            }
+            if !self.sig_cache.verify_signature(&tx, &chain) {
+                return Err(RejectReason::BadSignature);
+            }
+            if !tx.verify_owner() {
+                return Err(RejectReason::WrongOwner);
+            }
+            let sender = state.and_then(|state| state.account(&tx.raw.from_addr)).unwrap_or_default();
+            if tx.raw.nonce <= sender.nonce {
+                return Err(RejectReason::BadNonce);
+            }
+            if tx.raw.fee < self.min_fee {
+                return Err(RejectReason::FeeTooLow);
+            }
+            if mempool.conflicting(&tx).is_some_and(|pending| !mempool.can_replace(pending, &tx)) {
+                return Err(RejectReason::ReplacementUnderpriced);
+            }
+            // it must still fit in the next block
+            if tx.raw.is_expired_at(self.tip_height() + 1) {
+                return Err(RejectReason::Expired);
+            }
+            match tx.raw.total_value().and_then(|value| value.checked_add(tx.raw.fee)) {
+                None => return Err(RejectReason::Overflow),
+                Some(cost) if cost > sender.balance => return Err(RejectReason::InsufficientBalance),
+                Some(_) => {}
             }
+            mempool.insert(tx);
+            Ok(())
         }).collect();
-        delays.sort();
-        delays
+        for reason in results.iter().filter_map(|result| result.err()) {
+            self.validation_stats.mempool.record(reason);
+        }
+        results
     }
-}
 
-// #[cfg(any(test, test_utilities))]
-// mod tests {
-//     use super::*;
-//     use crate::block::test::generate_random_block;
-//     use crate::crypto::hash::Hashable;
+    /// Count a transaction that made a block invalid
+    pub fn record_block_reject(&mut self, reason: RejectReason) {
+        self.validation_stats.blocks.record(reason);
+    }
 
-//     #[test]
-//     fn insert_one() {
-//         let mut blockchain = Blockchain::new();
-//         let genesis_hash = blockchain.tip();
-//         let block = generate_random_block(&genesis_hash);
-//         blockchain.insert(&block);
-//         assert_eq!(blockchain.tip(), block.hash());
+    pub fn validation_stats(&self) -> ValidationStats {
+        self.validation_stats
+    }
 
-//     }
-    
-//     #[test]
-//     fn mp1_insert_chain() {
-//         let mut blockchain = Blockchain::new();
-//         let genesis_hash = blockchain.tip();
-//         let mut block = generate_random_block(&genesis_hash);
-//         blockchain.insert(&block);
-//         assert_eq!(blockchain.tip(), block.hash());
-//         for _ in 0..50 {
-//             let h = block.hash();
-//             block = generate_random_block(&h);
-//             blockchain.insert(&block);
-//             assert_eq!(blockchain.tip(), block.hash());
-//         }
-//     }
-
-//     #[test]
-//     fn mp1_insert_3_fork_and_back() {
-//         let mut blockchain = Blockchain::new();
-//         let genesis_hash = blockchain.tip();
-//         let block_1 = generate_random_block(&genesis_hash);
-//         blockchain.insert(&block_1);
-//         assert_eq!(blockchain.tip(), block_1.hash());
-//         let block_2 = generate_random_block(&block_1.hash());
-//         blockchain.insert(&block_2);
-//         assert_eq!(blockchain.tip(), block_2.hash());
-//         let block_3 = generate_random_block(&block_2.hash());
-//         blockchain.insert(&block_3);
-//         assert_eq!(blockchain.tip(), block_3.hash());
-//         let fork_block_1 = generate_random_block(&block_2.hash());
-//         blockchain.insert(&fork_block_1);
-//         assert_eq!(blockchain.tip(), block_3.hash());
-//         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-//         blockchain.insert(&fork_block_2);
-//         assert_eq!(blockchain.tip(), fork_block_2.hash());
-//         let block_4 = generate_random_block(&block_3.hash());
-//         blockchain.insert(&block_4);
-//         assert_eq!(blockchain.tip(), fork_block_2.hash());
-//         let block_5 = generate_random_block(&block_4.hash());
-//         blockchain.insert(&block_5);
-//         assert_eq!(blockchain.tip(), block_5.hash());
-//     }
-// }
+    /// The cache of signature verifications for this blockchain's transactions
+    pub fn sig_cache(&self) -> Arc<SigCache> {
+        Arc::clone(&self.sig_cache)
+    }
+
+    /// Set the lowest fee a transaction must pay to be admitted into the mempool
+    pub fn set_min_fee(&mut self, min_fee: u64) {
+        self.min_fee = min_fee;
+    }
+
+    /// The state of the tip, or of its latest ancestor with a state if it has none
+    pub fn latest_state(&self) -> Option<State> {
+        let latest = self.height_to_canonical_hash.iter().rev()
+            .find(|hash| self.stateful.contains(hash))?;
+        self.state_at(latest)
+    }
+
+    /// Balance of an account as of the tip, 0 if it does not exist; see `account_info`.
+    /// Only what it can spend: coinbase rewards count once they are mature.
+    pub fn balance_of(&self, address: &H160) -> u64 {
+        self.account_info(address).map_or(0, |account| account.balance)
+    }
+
+    /// Coinbase rewards to an account, as of the tip, that it cannot spend yet
+    pub fn immature_balance_of(&self, address: &H160) -> u64 {
+        match &self.tip_state {
+            Some(state) => state.immature_balance(address),
+            None => self.latest_state().map_or(0, |state| state.immature_balance(address)),
+        }
+    }
+
+    /// Nonce of the last transaction sent from an account as of the tip, 0 if it does not exist
+    pub fn nonce_of(&self, address: &H160) -> u32 {
+        self.account_info(address).map_or(0, |account| account.nonce)
+    }
+
+    /// Hash of the state after a block, if it is known; see `state_at`
+    pub fn state_hash(&self, hash: &H256) -> Option<H256> {
+        self.state_at(hash).map(|state| state.hash())
+    }
+
+    /// Check that the transactions of a block apply on top of its parent's state.
+    /// Passes if the parent's state is unknown, as for orphans.
+    /// Signatures are not checked here, nor anywhere the blockchain applies blocks: callers
+    /// verify them before taking the blockchain lock, with `verify_batch`.
+    pub fn state_validity_check(&self, block: &Block) -> Result<(), (usize, TxApplyError)> {
+        self.apply_on_parent(block).map(|_| ())
+    }
+
+    /// Check that the coinbase, if the block starts with one, has the block's height as its
+    /// nonce. Passes if the parent is unknown.
+    fn coinbase_height_check(&self, block: &Block) -> Result<(), (usize, TxApplyError)> {
+        let expected = match self.hash_to_height.get(&block.header.parent) {
+            Some(height) => (height + 1) as u32,
+            None => return Ok(()),
+        };
+        match block.content.transactions.first() {
+            Some(coinbase) if coinbase.is_coinbase() && coinbase.raw.nonce != expected => {
+                Err((0, TxApplyError::CoinbaseHeight { expected, got: coinbase.raw.nonce }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The state after a block and how the block changed its parent's, or `None` if the
+    /// parent's state is unknown; fails if the block's transactions or coinbase are invalid
+    fn apply_on_parent(&self, block: &Block) -> Result<Option<(State, StateDelta)>, (usize, TxApplyError)> {
+        let mut state = match self.state_at(&block.header.parent) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        self.coinbase_height_check(block)?;
+        let delta = state.apply_presigned_block_in_place(block)?;
+        Ok(Some((state, delta)))
+    }
+
+    /// The state after a block and how the block changed its parent's: `None` if the
+    /// parent's state is unknown or the block's transactions or coinbase are invalid
+    fn post_state(&self, block: &Block) -> Option<(State, StateDelta)> {
+        self.apply_on_parent(block).ok().flatten()
+    }
+
+    /// The state root a block must commit to: the hash of the state after it. `None` if there
+    /// is no such state, the parent's being unknown or the block's transactions not applying;
+    /// `try_insert` then expects the zero root of a stateless block, or refuses the block.
+    pub fn expected_state_root(&self, block: &Block) -> Option<H256> {
+        self.post_state(block).map(|(state, _)| state.hash())
+    }
+
+    /// Insert a block from the network, unless it conflicts with a checkpoint, would cause
+    /// a reorg deeper than the limit or commits to the wrong state. The parent must be in the blockchain.
+    pub fn try_insert(&mut self, block: &Block) -> Result<InsertOutcome, InsertError> {
+        let state = self.check_insert(block)?;
+        if self.contains_block(&block.hash()) {
+            return Ok(InsertOutcome::Duplicate);
+        }
+        let outcome = self.insert_validated(BlockEntry::Full(Arc::new(block.clone())), state);
+        self.debug_audit();
+        Ok(outcome)
+    }
+
+    /// The checks of `try_insert`; returns the state after the block, computed along the way
+    fn check_insert(&self, block: &Block) -> Result<Option<(State, StateDelta)>, InsertError> {
+        let parent_hash = block.header.parent;
+        let height = self.hash_to_height[&parent_hash] + 1;
+        for (&checkpoint_height, &expected) in self.finalized.range(..=height) {
+            let ancestor = if checkpoint_height == height {
+                block.hash()
+            } else {
+                self.ancestor_at(&parent_hash, checkpoint_height).unwrap()
+            };
+            if ancestor != expected {
+                return Err(InsertError::CheckpointMismatch { height: checkpoint_height, expected });
+            }
+        }
+        if let Some(index) = block.expired_transaction(height) {
+            let valid_until = block.content.transactions[index].raw.valid_until_block;
+            return Err(InsertError::ExpiredTransaction { index, valid_until, height });
+        }
+        if let Some(index) = block.oversized_data() {
+            return Err(InsertError::DataTooLarge { index, size: block.content.transactions[index].raw.data.len() });
+        }
+        if let Some(limit) = self.max_reorg_depth {
+            if height > self.tip_height() {
+                let depth = self.tip_height() - self.common_ancestor_height(&parent_hash);
+                if depth > limit {
+                    return Err(InsertError::ReorgTooDeep { depth, limit });
+                }
+            }
+        }
+        // a block whose parent has no known state can only be stateless itself
+        let state = self.apply_on_parent(block)
+            .map_err(|(index, error)| InsertError::InvalidTransaction { index, error })?;
+        let expected = state.as_ref().map_or(H256::default(), |(state, _)| state.hash());
+        if block.header.state_root != expected {
+            return Err(InsertError::StateRootMismatch { expected, found: block.header.state_root });
+        }
+        Ok(state)
+    }
+
+    /// The ancestor of `hash` at `height` (the block itself at its own height)
+    fn ancestor_at(&self, hash: &H256, height: u64) -> Option<H256> {
+        let mut hash = *hash;
+        let mut current = self.get_height(&hash)?;
+        if height > current {
+            return None;
+        }
+        while current > height {
+            // once on the longest chain, the canonical index has the answer
+            if self.height_to_canonical_hash.get(current as usize) == Some(&hash) {
+                return Some(self.height_to_canonical_hash[height as usize]);
+            }
+            hash = self.hash_to_block[&hash].header().parent;
+            current -= 1;
+        }
+        Some(hash)
+    }
+
+    /// Insert a block into blockchain
+    pub fn insert(&mut self, block: &Block) -> InsertOutcome {
+        if self.contains_block(&block.hash()) {
+            return InsertOutcome::Duplicate;
+        }
+        let outcome = self.insert_entry(BlockEntry::Full(Arc::new(block.clone())));
+        self.debug_audit();
+        outcome
+    }
+
+    /// Insert a block, or the header of a pruned one
+    fn insert_entry(&mut self, entry: BlockEntry) -> InsertOutcome {
+        let state = entry.block().and_then(|block| self.post_state(block));
+        self.insert_validated(entry, state)
+    }
+
+    /// Mark a pruned block stateful again with its saved state, which its header cannot rebuild
+    fn restore_pruned_state(&mut self, hash: H256, state: State) {
+        self.stateful.insert(hash);
+        if self.tip == hash {
+            self.tip_state = Some(state.clone());
+        }
+        self.state_snapshots.insert(hash, state);
+    }
+
+    /// Insert a block, or the header of a pruned one, whose state was already computed
+    fn insert_validated(&mut self, entry: BlockEntry, state: Option<(State, StateDelta)>) -> InsertOutcome {
+        let parent_hash = entry.header().parent;
+        let parent_height = *self.hash_to_height.get(&parent_hash).unwrap();
+        let height = parent_height + 1;
+        let block_hash = entry.header().hash();
+        if self.hash_to_block.contains_key(&block_hash) {
+            return InsertOutcome::Duplicate;
+        }
+        let state = state.map(|(state, delta)| {
+            self.stateful.insert(block_hash);
+            self.hash_to_delta.insert(block_hash, delta);
+            if height.is_multiple_of(STATE_SNAPSHOT_INTERVAL) {
+                self.state_snapshots.insert(block_hash, state.clone());
+            }
+            state
+        });
+        self.hash_to_block.insert(block_hash, entry);
+        self.hash_to_height.insert(block_hash, height);
+        self.hash_to_children.entry(parent_hash).or_default().push(block_hash);
+        let outcome = if height <= *self.hash_to_height.get(&self.tip).unwrap() {
+            InsertOutcome::SideChain
+        } else if parent_hash == self.tip {
+            InsertOutcome::ExtendedTip
+        } else {
+            InsertOutcome::Reorged { depth: self.tip_height() - self.common_ancestor_height(&parent_hash) }
+        };
+        if outcome.moved_tip() {
+            let old_tip = self.tip;
+            let now = self.clock.now_ms();
+            self.tip_durations.push(TipDuration {
+                hash: old_tip,
+                duration_ms: now.saturating_sub(self.tip_since),
+                displaced: outcome != InsertOutcome::ExtendedTip,
+            });
+            self.tip_since = now;
+            self.tip = block_hash;
+            self.tip_state = state;
+            let hash_to_height = &self.hash_to_height;
+            self.hash_to_delta.retain(|hash, _| hash_to_height[hash] + STATE_DELTA_WINDOW > height);
+            self.update_canonical_hashes(&old_tip);
+            self.notify_tip();
+            if let InsertOutcome::Reorged { depth } = outcome {
+                self.sound_reorg_alarm(&old_tip, depth, now);
+            }
+        }
+        self.inserts_since_checkpoint += 1;
+        if self.inserts_since_checkpoint >= CHECKPOINT_INTERVAL {
+            self.checkpoint();
+        }
+        outcome
+    }
+
+    /// Height of the common ancestor of `hash` and the tip
+    fn common_ancestor_height(&self, hash: &H256) -> u64 {
+        self.hash_to_height[&self.common_ancestor(hash, &self.tip).unwrap()]
+    }
+
+    /// Get a channel receiving the hash of every new tip. The channel holds a single
+    /// notification, and further ones are dropped until it is read: subscribers should
+    /// treat a notification as "the tip moved" and look up the current tip themselves.
+    pub fn subscribe_tip(&mut self) -> Receiver<H256> {
+        let (sender, receiver) = channel::bounded(1);
+        self.tip_subscribers.push(sender);
+        receiver
+    }
+
+    /// Send a `ReorgEvent` to `sender` whenever a reorg detaches at least `min_depth` blocks,
+    /// replacing any previous alarm. Events are dropped, not waited for, if the channel is full.
+    pub fn set_reorg_alarm(&mut self, min_depth: u64, sender: Sender<ReorgEvent>) {
+        self.reorg_alarm = Some((min_depth, sender));
+    }
+
+    /// Fire the reorg alarm, if set and the reorg from `old_tip` to the tip was deep enough
+    fn sound_reorg_alarm(&mut self, old_tip: &H256, depth: u64, now: u128) {
+        let min_depth = match &self.reorg_alarm {
+            Some((min_depth, _)) => *min_depth,
+            None => return,
+        };
+        if depth < min_depth {
+            return;
+        }
+        let common_ancestor = self.common_ancestor(old_tip, &self.tip).unwrap();
+        let branch = |tip: &H256| {
+            let mut hashes: Vec<H256> = self.iter_from(tip)
+                .take_while(|(hash, _)| **hash != common_ancestor)
+                .map(|(hash, _)| *hash)
+                .collect();
+            hashes.reverse();
+            hashes
+        };
+        let event = ReorgEvent {
+            common_ancestor,
+            detached: branch(old_tip),
+            attached: branch(&self.tip),
+            timestamp_ms: now,
+        };
+        let (_, sender) = self.reorg_alarm.as_ref().unwrap();
+        if let Err(TrySendError::Disconnected(_)) = sender.try_send(event) {
+            self.reorg_alarm = None;
+        }
+    }
+
+    /// Tell the subscribers about the new tip, never blocking and forgetting the ones that left
+    fn notify_tip(&mut self) {
+        let tip = self.tip;
+        self.tip_subscribers.retain(|subscriber| {
+            !matches!(subscriber.try_send(tip), Err(TrySendError::Disconnected(_)))
+        });
+    }
+
+    /// Point the canonical chain at the new tip, rewriting the heights taken over from the old branch
+    fn update_canonical_hashes(&mut self, old_tip: &H256) {
+        let fork_point = self.common_ancestor(old_tip, &self.tip).unwrap();
+        let fork_height = self.hash_to_height[&fork_point];
+        let new_branch: Vec<H256> = self.iter_from(&self.tip)
+            .take_while(|(hash, _)| **hash != fork_point)
+            .map(|(hash, _)| *hash)
+            .collect();
+        let old_branch: Vec<H256> = self.iter_from(old_tip)
+            .take_while(|(hash, _)| **hash != fork_point)
+            .map(|(hash, _)| *hash)
+            .collect();
+        // pruned bodies are only ever on the longest chain, far below any reorg
+        for hash in old_branch {
+            if let Some(block) = self.hash_to_block[&hash].block() {
+                for tx in &block.content.transactions {
+                    if self.tx_to_block.get(&tx.txid()) == Some(&hash) {
+                        self.tx_to_block.remove(&tx.txid());
+                    }
+                }
+            }
+        }
+        for hash in new_branch.iter().rev() {
+            if let Some(block) = self.hash_to_block[hash].block() {
+                for tx in block.content.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                    self.tx_to_block.insert(tx.txid(), *hash);
+                }
+            }
+        }
+        self.height_to_canonical_hash.truncate(fork_height as usize + 1);
+        self.height_to_canonical_hash.extend(new_branch.into_iter().rev());
+    }
+
+    /// Find the lowest common ancestor of two blocks (or `None` if either is unknown).
+    /// A block counts as its own ancestor.
+    pub fn common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        let mut a = *a;
+        let mut b = *b;
+        let mut a_height = self.get_height(&a)?;
+        let mut b_height = self.get_height(&b)?;
+        // first bring both to the same height, then walk back in lockstep
+        while a_height > b_height {
+            a = self.hash_to_block[&a].header().parent;
+            a_height -= 1;
+        }
+        while b_height > a_height {
+            b = self.hash_to_block[&b].header().parent;
+            b_height -= 1;
+        }
+        while a != b {
+            a = self.hash_to_block[&a].header().parent;
+            b = self.hash_to_block[&b].header().parent;
+        }
+        Some(a)
+    }
+
+    /// How many blocks `a` and `b` are each above their common ancestor
+    pub fn fork_depth(&self, a: &H256, b: &H256) -> Option<(u64, u64)> {
+        let ancestor_height = self.hash_to_height[&self.common_ancestor(a, b)?];
+        Some((self.hash_to_height[a] - ancestor_height, self.hash_to_height[b] - ancestor_height))
+    }
+
+    /// Save all blocks (only headers of pruned ones), the orphan buffer and the tip to a file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut hashes: Vec<&H256> = self.hash_to_height.iter()
+            .filter(|(_, height)| **height > 0) // genesis is recreated on load
+            .map(|(hash, _)| hash)
+            .collect();
+        hashes.sort_by_key(|hash| self.hash_to_height[*hash]);
+        let stored = StoredBlockchain {
+            genesis: Block::clone(self.hash_to_block[&self.genesis_hash()].block().unwrap()),
+            genesis_state: self.state_snapshots[&self.genesis_hash()].clone(),
+            blocks: hashes.into_iter().map(|hash| self.hash_to_block[hash].clone()).collect(),
+            orphans: self.orphan_buffer.values().flatten().map(|orphan| Arc::clone(&orphan.block)).collect(),
+            tip: self.tip,
+            pruned_states: self.state_snapshots.iter()
+                .filter(|(hash, _)| matches!(self.hash_to_block[*hash], BlockEntry::HeaderOnly(_)))
+                .map(|(hash, state)| (*hash, state.clone()))
+                .collect(),
+        };
+        let bytes = bincode::serialize(&stored).map_err(io::Error::other)?;
+        // write to a temporary file first, so a crash never leaves a half-written checkpoint
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Load a blockchain saved by `save`, rebuilding all indices by inserting the blocks again
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let stored: StoredBlockchain = bincode::deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut blockchain = Blockchain::from_genesis(stored.genesis, stored.genesis_state);
+        let mut heights = HashMap::new();
+        heights.insert(blockchain.genesis_hash(), 0);
+        let mut parents = HashMap::new();
+        for entry in &stored.blocks {
+            let (hash, parent) = (entry.header().hash(), entry.header().parent);
+            match heights.get(&parent) {
+                Some(height) => heights.insert(hash, height + 1),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("block {} has no parent", hash))),
+            };
+            parents.insert(hash, parent);
+        }
+        // among equally long branches, keep the tip we had before: replaying its branch first at
+        // each height makes it the tip, with its state, as the first block to reach a height is
+        let mut tip_branch = HashSet::new();
+        let mut current = stored.tip;
+        while let Some(parent) = parents.get(&current) {
+            tip_branch.insert(current);
+            current = *parent;
+        }
+        let mut entries = stored.blocks;
+        entries.sort_by_key(|entry| {
+            let hash = entry.header().hash();
+            (heights[&hash], !tip_branch.contains(&hash))
+        });
+        let mut pruned_states: HashMap<H256, State> = stored.pruned_states.into_iter().collect();
+        for entry in entries {
+            let hash = entry.header().hash();
+            blockchain.insert_entry(entry);
+            if let Some(state) = pruned_states.remove(&hash) {
+                blockchain.restore_pruned_state(hash, state);
+            }
+        }
+        for block in stored.orphans {
+            blockchain.add_to_orphan_buffer(block);
+        }
+        if blockchain.tip != stored.tip {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "tip is not the end of a longest chain"));
+        }
+        blockchain.inserts_since_checkpoint = 0;
+        // replaying the saved blocks says nothing about how long they were tips
+        blockchain.tip_durations.clear();
+        Ok(blockchain)
+    }
+
+    /// Save to `path` every `CHECKPOINT_INTERVAL` insertions and whenever `checkpoint` is called
+    pub fn set_checkpoint_path(&mut self, path: &Path) {
+        self.checkpoint_path = Some(path.to_path_buf());
+    }
+
+    /// Save to the checkpoint path, if there is one
+    pub fn checkpoint(&mut self) {
+        self.inserts_since_checkpoint = 0;
+        if let Some(path) = &self.checkpoint_path {
+            match self.save(path) {
+                Ok(()) => debug!("Blockchain checkpointed to {}", path.display()),
+                Err(e) => error!("Error checkpointing blockchain to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Get the genesis block's hash
+    pub fn genesis_hash(&self) -> H256 {
+        self.height_to_canonical_hash[0]
+    }
+
+    /// The chain transactions must be signed for
+    pub fn chain_id(&self) -> ChainId {
+        ChainId(self.genesis_hash())
+    }
+
+    /// Get the last block's hash of the longest chain
+    pub fn tip(&self) -> H256 {
+        self.tip
+    }
+
+    /// Get all the blocks' hashes along the longest chain
+    pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
+        self.iter_longest_chain().map(|(hash, _)| *hash).collect()
+    }
+
+    /// Iterate over the headers along the longest chain, from genesis to tip
+    pub fn iter_longest_chain(&self) -> impl Iterator<Item = (&H256, &Header)> {
+        self.height_to_canonical_hash.iter().map(move |hash| (hash, self.hash_to_block[hash].header()))
+    }
+
+    /// Iterate over the headers of `start` and its ancestors, from `start` back to genesis.
+    /// `start` need not be on the longest chain; the iterator is empty if it is unknown.
+    pub fn iter_from(&self, start: &H256) -> impl Iterator<Item = (&H256, &Header)> {
+        let mut next = self.hash_to_block.get_key_value(start);
+        std::iter::from_fn(move || {
+            let (hash, entry) = next?;
+            next = if self.hash_to_height[hash] > 0 { // while not genesis
+                self.hash_to_block.get_key_value(&entry.header().parent)
+            } else {
+                None
+            };
+            Some((hash, entry.header()))
+        })
+    }
+
+    /// Get a block by its hash (or `None` if it is not in the blockchain or its body was pruned).
+    /// Blocks are shared, so this does not copy the block.
+    pub fn get_block(&self, hash: &H256) -> Option<Arc<Block>> {
+        self.hash_to_block.get(hash)?.block().cloned()
+    }
+
+    /// Get a block's header by its hash, even if its body was pruned
+    pub fn get_header(&self, hash: &H256) -> Option<&Header> {
+        self.hash_to_block.get(hash).map(|entry| entry.header())
+    }
+
+    /// Whether the body of a known block was pruned
+    pub fn is_pruned(&self, hash: &H256) -> bool {
+        matches!(self.hash_to_block.get(hash), Some(BlockEntry::HeaderOnly(_)))
+    }
+
+    /// Get the height of a block by its hash (or `None` if it is not in the blockchain)
+    pub fn get_height(&self, hash: &H256) -> Option<u64> {
+        self.hash_to_height.get(hash).copied()
+    }
+
+    /// Get the height of the last block of the longest chain
+    pub fn tip_height(&self) -> u64 {
+        self.hash_to_height[&self.tip]
+    }
+
+    /// Get the block at `height` along the longest chain
+    /// (or `None` if the chain is not that long or the block's body was pruned)
+    pub fn get_block_by_height(&self, height: u64) -> Option<Arc<Block>> {
+        let hash = self.height_to_canonical_hash.get(height as usize)?;
+        self.get_block(hash)
+    }
+
+    /// Find a transaction on the longest chain by its txid;
+    /// returns the hash and height of the block containing it
+    pub fn find_transaction(&self, tx_hash: &H256) -> Option<(H256, u64)> {
+        let block_hash = self.tx_to_block.get(tx_hash)?;
+        Some((*block_hash, self.hash_to_height[block_hash]))
+    }
+
+    /// Check if a block is on the longest chain
+    pub fn is_in_longest_chain(&self, hash: &H256) -> bool {
+        match self.hash_to_height.get(hash) {
+            Some(height) => self.height_to_canonical_hash.get(*height as usize) == Some(hash),
+            None => false,
+        }
+    }
+
+    /// Summarize the longest chain for a peer to find where our chains diverge:
+    /// the tip, then the ancestors 1, 2, 4, 8, ... blocks back, ending with genesis
+    pub fn block_locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut height = self.tip_height();
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.height_to_canonical_hash[height as usize]);
+            height = height.saturating_sub(step);
+            if locator.len() > 1 {
+                step *= 2;
+            }
+        }
+        locator.push(self.height_to_canonical_hash[0]);
+        locator
+    }
+
+    /// Hashes of up to `limit` blocks of the longest chain following the first locator entry
+    /// that is on it, in order of height
+    pub fn blocks_after_locator(&self, locator: &[H256], limit: usize) -> Vec<H256> {
+        // genesis is always shared, even if the locator does not end with it
+        let start = locator.iter()
+            .find(|hash| self.is_in_longest_chain(hash))
+            .map_or(0, |hash| self.hash_to_height[hash]);
+        self.height_to_canonical_hash.iter()
+            .skip(start as usize + 1)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Hashes of up to `limit` blocks of the longest chain after `since`, in order of height.
+    /// Returns `None` if `since` is unknown, and nothing if it is the tip or was knocked off
+    /// the longest chain: a consumer seeing that should restart from a block locator.
+    pub fn blocks_since(&self, since: &H256, limit: usize) -> Option<Vec<H256>> {
+        let height = self.get_height(since)?;
+        if !self.is_in_longest_chain(since) {
+            return Some(vec![]);
+        }
+        Some(self.height_to_canonical_hash[height as usize + 1..].iter().take(limit).cloned().collect())
+    }
+
+    /// Number of confirmations of a block, counting the block itself.
+    /// Returns `None` if the block is unknown or not on the longest chain.
+    pub fn confirmations(&self, hash: &H256) -> Option<u64> {
+        if !self.is_in_longest_chain(hash) {
+            return None;
+        }
+        Some(self.tip_height() - self.hash_to_height[hash] + 1)
+    }
+
+    /// Number of confirmations of the block containing a transaction,
+    /// or `None` if the transaction is not on the longest chain
+    pub fn tx_confirmations(&self, tx_hash: &H256) -> Option<u64> {
+        let (block_hash, _) = self.find_transaction(tx_hash)?;
+        self.confirmations(&block_hash)
+    }
+
+    pub fn contains_block(&self, hash: &H256) -> bool {
+        self.hash_to_block.contains_key(hash)
+    }
+
+    /// Get the difficulty every block must satisfy
+    pub fn difficulty(&self) -> H256 {
+        self.difficulty
+    }
+
+    /// Check if a block is consistent with PoW
+    pub fn pow_validity_check(&self, block: &Block) -> bool {
+        block.hash() <= block.header.difficulty && block.header.difficulty == self.difficulty
+    }
+
+    /// Check if a block's parent is in the blockchain
+    pub fn parent_check(&self, block: &Block) -> bool {
+        self.contains_block(&block.header.parent)
+    }
+
+    /// Add a PoW valid, parentless block to the orphan buffer,
+    /// evicting the oldest orphans if the buffer is full
+    pub fn add_to_orphan_buffer(&mut self, block: Arc<Block>) {
+        self.buffer_orphan(block, None);
+    }
+
+    /// Like `add_to_orphan_buffer`, keeping the block's origin until it is inserted,
+    /// so orphans that never are don't count towards the block delays
+    pub fn add_received_orphan(&mut self, block: Arc<Block>, origin: BlockOrigin) {
+        self.buffer_orphan(block, Some(origin));
+    }
+
+    fn buffer_orphan(&mut self, block: Arc<Block>, origin: Option<BlockOrigin>) {
+        let hash = block.hash();
+        if let Some(siblings) = self.orphan_buffer.get(&block.header.parent) {
+            if siblings.iter().any(|orphan| orphan.block.hash() == hash) {
+                return;  // redundant item, skip
+            }
+        }
+        while self.orphan_count() >= self.orphan_limit.max(1) {
+            self.evict_oldest_orphan();
+        }
+        let parent = block.header.parent;
+        let orphan = Orphan { block, buffered_at: Instant::now(), seq: self.next_orphan_seq, origin };
+        self.next_orphan_seq += 1;
+        self.orphan_buffer.entry(parent).or_default().push(orphan);
+    }
+
+    /// Number of blocks in the orphan buffer
+    pub fn orphan_count(&self) -> usize {
+        self.orphan_buffer.values().map(|orphans| orphans.len()).sum()
+    }
+
+    /// Drop the orphans buffered longer than `age` ago; returns how many were dropped
+    pub fn prune_orphans_older_than(&mut self, age: Duration) -> usize {
+        let now = Instant::now();
+        let before = self.orphan_count();
+        for orphans in self.orphan_buffer.values_mut() {
+            orphans.retain(|orphan| now.duration_since(orphan.buffered_at) <= age);
+        }
+        self.orphan_buffer.retain(|_, orphans| !orphans.is_empty());
+        before - self.orphan_count()
+    }
+
+    fn evict_oldest_orphan(&mut self) {
+        let oldest = self.orphan_buffer.iter()
+            .flat_map(|(parent, orphans)| orphans.iter().enumerate().map(move |(i, orphan)| (orphan.seq, *parent, i)))
+            .min_by_key(|(seq, _, _)| *seq);
+        if let Some((_, parent, i)) = oldest {
+            let orphans = self.orphan_buffer.get_mut(&parent).unwrap();
+            orphans.remove(i);
+            if orphans.is_empty() {
+                self.orphan_buffer.remove(&parent);
+            }
+        }
+    }
+
+    /// Insert a PoW valid, parentful block into the blockchain, then all its buffered descendants.
+    /// `out_hashes` is used to store the hashes of all the blocks inserted, each before its children.
+    pub fn insert_recursively(&mut self, block: Arc<Block>, out_hashes: &mut Vec<H256>) -> Resolution {
+        let mut resolution = Resolution::default();
+        let root = block.hash();
+        // an explicit stack rather than recursion, so long chains of orphans can't overflow the stack
+        let mut stack = vec![(block, None)];
+        let mut visited = HashSet::new();
+        while let Some((block, origin)) = stack.pop() {
+            let hash = block.hash();
+            if !visited.insert(hash) || self.contains_block(&hash) {
+                continue;  // redundant item, skip
+            }
+            let state = match self.check_insert(&block) {
+                Ok(state) => state,
+                Err(e) => {
+                    self.orphan_buffer.remove(&hash);
+                    if let (true, Some(BlockOrigin::Received { from, .. })) = (e.is_invalid(), origin) {
+                        resolution.invalid_from.push(from);
+                    }
+                    resolution.rejected.push((hash, e));
+                    continue;
+                }
+            };
+            let outcome = self.insert_validated(BlockEntry::Full(block), state);
+            resolution.outcomes.push((hash, outcome));
+            if let Some(origin) = origin {
+                self.hash_to_origin.entry(hash).or_insert(origin);
+            }
+            if hash != root {
+                resolution.orphans_resolved += 1;
+            }
+            out_hashes.push(hash);
+            if let Some(orphans) = self.orphan_buffer.remove(&hash) {
+                // reversed, so the first buffered child is inserted first
+                stack.extend(orphans.into_iter().rev().map(|orphan| (orphan.block, orphan.origin)));
+            }
+        }
+        self.debug_audit();
+        resolution
+    }
+
+    /// Check the invariants between the indices, returning every violation found
+    pub fn audit(&self) -> Result<(), Vec<AuditError>> {
+        let mut errors = Vec::new();
+        let genesis_hash = self.genesis_hash();
+        for (key, entry) in &self.hash_to_block {
+            let hash = entry.header().hash();
+            if hash != *key {
+                errors.push(AuditError::HashMismatch { key: *key, hash });
+            }
+            if *key == genesis_hash {
+                continue;
+            }
+            let parent = entry.header().parent;
+            match (self.hash_to_height.get(key), self.hash_to_height.get(&parent)) {
+                (_, None) => errors.push(AuditError::MissingParent { hash: *key, parent }),
+                (Some(&height), Some(&parent_height)) if height != parent_height + 1 => {
+                    errors.push(AuditError::WrongHeight { hash: *key, height, expected: parent_height + 1 });
+                }
+                _ => {}
+            }
+        }
+        let max_height = self.hash_to_height.values().copied().max().unwrap_or(0);
+        let tip_height = self.tip_height();
+        if tip_height < max_height {
+            errors.push(AuditError::TipNotHighest { tip_height, max_height });
+        }
+        for (parent, orphans) in &self.orphan_buffer {
+            if self.contains_block(parent) {
+                for orphan in orphans {
+                    errors.push(AuditError::OrphanWithKnownParent { hash: orphan.block.hash(), parent: *parent });
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Panic on any broken invariant, in debug builds and with the `strict-audit` feature
+    fn debug_audit(&self) {
+        if cfg!(any(debug_assertions, feature = "strict-audit")) {
+            if let Err(errors) = self.audit() {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                panic!("blockchain audit failed: {}", errors.join("; "));
+            }
+        }
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.hash_to_block.len()
+    }
+
+    /// Average size of the blocks whose bodies were not pruned
+    pub fn average_block_size(&self) -> usize {
+        let sizes: Vec<usize> = self.hash_to_block.values()
+            .filter_map(|entry| entry.block())
+            .map(|block| block.size())
+            .collect();
+        // genesis is never pruned
+        sizes.iter().sum::<usize>() / sizes.len()
+    }
+
+    /// Number of blocks not on the longest chain
+    pub fn stale_block_count(&self) -> usize {
+        self.block_count() - self.height_to_canonical_hash.len()
+    }
+
+    /// Number of blocks with more than one child
+    pub fn fork_count(&self) -> usize {
+        self.hash_to_children.values().filter(|children| children.len() > 1).count()
+    }
+
+    /// For every branch leaving the longest chain, count how many blocks its longest path has;
+    /// maps each branch length to the number of branches that long
+    pub fn fork_length_histogram(&self) -> HashMap<u64, usize> {
+        let mut histogram = HashMap::new();
+        for (height, hash) in self.height_to_canonical_hash.iter().enumerate() {
+            let children = match self.hash_to_children.get(hash) {
+                Some(children) => children,
+                None => continue,
+            };
+            for child in children {
+                if self.height_to_canonical_hash.get(height + 1) == Some(child) {
+                    continue;
+                }
+                *histogram.entry(self.subtree_depth(child)).or_insert(0) += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Drop the bodies of the longest chain's blocks below `height`, keeping their headers so the
+    /// chain can still be traversed and extended, and remove the stale branches ending below it.
+    /// Genesis and the tip are never pruned. Returns how many blocks were pruned or removed.
+    pub fn prune_below(&mut self, height: u64) -> usize {
+        let height = height.min(self.tip_height());
+        // the blocks left with bodies rebuild their states from the highest pruned one's
+        if height > 1 {
+            let boundary = self.height_to_canonical_hash[height as usize - 1];
+            if let Some(state) = self.state_at(&boundary) {
+                self.state_snapshots.insert(boundary, state);
+            }
+        }
+        let mut pruned = 0;
+        for h in 0..height {
+            let hash = self.height_to_canonical_hash[h as usize];
+            // stale branches growing past `height` may still become the longest chain
+            let stale: Vec<H256> = self.children(&hash).iter()
+                .filter(|child| !self.is_in_longest_chain(child))
+                .filter(|child| self.hash_to_height[*child] + self.subtree_depth(child) <= height)
+                .cloned()
+                .collect();
+            for child in stale {
+                pruned += self.remove_subtree(&child);
+            }
+            if h == 0 {
+                continue;
+            }
+            let entry = self.hash_to_block.get_mut(&hash).unwrap();
+            if let BlockEntry::Full(block) = entry {
+                *entry = BlockEntry::HeaderOnly(block.header.clone());
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    /// Remove a block that is not on the longest chain, with all its descendants;
+    /// returns how many blocks were removed
+    fn remove_subtree(&mut self, hash: &H256) -> usize {
+        let parent = self.hash_to_block[hash].header().parent;
+        if let Some(siblings) = self.hash_to_children.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+        }
+        let mut removed = 0;
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            self.hash_to_block.remove(&hash);
+            self.hash_to_height.remove(&hash);
+            self.stateful.remove(&hash);
+            self.hash_to_delta.remove(&hash);
+            self.state_snapshots.remove(&hash);
+            if let Some(children) = self.hash_to_children.remove(&hash) {
+                stack.extend(children);
+            }
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Children of a block, in the order they were inserted
+    pub fn children(&self, hash: &H256) -> &[H256] {
+        self.hash_to_children.get(hash).map_or(&[], |children| children.as_slice())
+    }
+
+    /// Number of blocks descending from `hash`, counting `hash` itself (0 if it is unknown)
+    pub fn subtree_size(&self, hash: &H256) -> usize {
+        if !self.contains_block(hash) {
+            return 0;
+        }
+        let mut size = 0;
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            size += 1;
+            stack.extend_from_slice(self.children(&hash));
+        }
+        size
+    }
+
+    /// Blocks without children, i.e. the tips of all competing branches
+    pub fn leaves(&self) -> Vec<H256> {
+        self.hash_to_block.keys()
+            .filter(|hash| self.children(hash).is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Number of blocks on the longest path from `hash` down to a leaf, counting `hash` itself
+    fn subtree_depth(&self, hash: &H256) -> u64 {
+        let mut depth = 0;
+        let mut level = vec![*hash];
+        while !level.is_empty() {
+            depth += 1;
+            level = level.iter()
+                .filter_map(|hash| self.hash_to_children.get(hash))
+                .flatten()
+                .cloned()
+                .collect();
+        }
+        depth
+    }
+
+    /// Render the block tree as a Graphviz DOT digraph: canonical blocks are filled blue,
+    /// stale ones gray, the tip is drawn as a double octagon, and edges point to parents
+    pub fn to_dot(&self) -> String {
+        let mut hashes: Vec<&H256> = self.hash_to_block.keys().collect();
+        hashes.sort_by_key(|hash| (self.hash_to_height[*hash], **hash));
+        let mut dot = String::from("digraph blockchain {\n    rankdir=RL;\n    node [style=filled];\n");
+        for hash in &hashes {
+            let txs = match self.hash_to_block[*hash].block() {
+                Some(block) => format!("{} txs", block.content.transactions.len()),
+                None => "pruned".to_string(),
+            };
+            let color = if self.is_in_longest_chain(hash) { "lightblue" } else { "lightgray" };
+            let shape = if **hash == self.tip { "doubleoctagon" } else { "box" };
+            dot += &format!(
+                "    \"{}\" [label=\"{}\\nheight {}\\n{}\", fillcolor={}, shape={}];\n",
+                hash, &hash.to_string()[..8], self.hash_to_height[*hash], txs, color, shape,
+            );
+        }
+        for hash in &hashes {
+            if self.hash_to_height[*hash] > 0 {
+                dot += &format!("    \"{}\" -> \"{}\";\n", hash, self.hash_to_block[*hash].header().parent);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// Write `to_dot` to a file
+    pub fn write_dot(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_dot())
+    }
+
+    /// Summarize every block of the longest chain, for post-processing
+    pub fn export_json(&self) -> ChainSummary {
+        ChainSummary::collect(self)
+    }
+
+    /// Write `export_json` to a file
+    pub fn export_json_to_file(&self, path: &Path) -> io::Result<()> {
+        self.export_json().write_json(path)
+    }
+
+    pub fn block_delays_ms(&self) -> Vec<u128> {
+        let mut delays: Vec<_> = self.hash_to_origin.values().filter_map(|origin| {
+            match origin {
+                BlockOrigin::Mined => None,
+                BlockOrigin::Received{delay_ms, ..} => Some(*delay_ms),
+            }
+        }).collect();
+        delays.sort();
+        delays
+    }
+
+    /// Summary statistics of `block_delays_ms`
+    pub fn block_delay_stats(&self) -> DelayStats {
+        DelayStats::from_sorted(&self.block_delays_ms())
+    }
+
+    /// Time from a generated transaction's stamp to the timestamp of the block of the longest
+    /// chain that includes it, for each stamped transaction there, sorted. Blocks whose body was
+    /// pruned are skipped, as are transactions stamped after their block's time.
+    pub fn confirmation_latencies_ms(&self) -> Vec<u128> {
+        let mut latencies: Vec<u128> = self.height_to_canonical_hash.iter()
+            .filter_map(|hash| self.hash_to_block[hash].block())
+            .flat_map(|block| {
+                let timestamp = block.header.timestamp;
+                block.content.transactions.iter().skip(1)
+                    .filter_map(|tx| Stamp::parse(&tx.raw.data))
+                    .filter_map(move |stamp| timestamp.checked_sub(stamp.sent_ms as u128))
+            })
+            .collect();
+        latencies.sort_unstable();
+        latencies
+    }
+
+    /// Summary statistics of `confirmation_latencies_ms`
+    pub fn confirmation_latency_stats(&self) -> DelayStats {
+        DelayStats::from_sorted(&self.confirmation_latencies_ms())
+    }
+
+    /// Mean time between consecutive blocks of the longest chain, from their header timestamps;
+    /// genesis is left out, as its timestamp is fixed. `None` with fewer than two other blocks.
+    pub fn mean_interblock_time_ms(&self) -> Option<f64> {
+        let blocks = self.tip_height();
+        if blocks < 2 {
+            return None;
+        }
+        let timestamp = |height: u64| self.hash_to_block[&self.height_to_canonical_hash[height as usize]].header().timestamp;
+        let first = timestamp(1) as f64;
+        let last = timestamp(blocks) as f64;
+        Some((last - first) / (blocks - 1) as f64)
+    }
+
+    /// Every block that stopped being the tip, and for how long it was, in order
+    pub fn tip_durations(&self) -> &[TipDuration] {
+        &self.tip_durations
+    }
+
+    /// Mean, minimum and maximum time in ms that a block remained the tip, extended or displaced;
+    /// all zero if the tip never moved
+    pub fn tip_duration_stats(&self) -> (f64, u128, u128) {
+        let durations: Vec<u128> = self.tip_durations.iter().map(|tip| tip.duration_ms).collect();
+        if durations.is_empty() {
+            return (0.0, 0, 0);
+        }
+        let mean = durations.iter().sum::<u128>() as f64 / durations.len() as f64;
+        (mean, *durations.iter().min().unwrap(), *durations.iter().max().unwrap())
+    }
+
+    /// The delays of the blocks received from each peer, sorted
+    pub fn delays_by_peer(&self) -> HashMap<SocketAddr, Vec<u128>> {
+        let mut delays: HashMap<SocketAddr, Vec<u128>> = HashMap::new();
+        for origin in self.hash_to_origin.values() {
+            if let BlockOrigin::Received{delay_ms, from} = origin {
+                delays.entry(*from).or_default().push(*delay_ms);
+            }
+        }
+        for peer_delays in delays.values_mut() {
+            peer_delays.sort();
+        }
+        delays
+    }
+
+    /// Number of distinct transactions confirmed on the longest chain, coinbases left out
+    pub fn canonical_transaction_count(&self) -> usize {
+        self.tx_to_block.len()
+    }
+
+    /// Transactions a canonical block is credited with: each confirmed transaction counts
+    /// once, in the block `tx_to_block` records for it. `None` if the body was pruned.
+    fn confirmed_transactions_in(&self, hash: &H256) -> Option<usize> {
+        let block = self.hash_to_block[hash].block()?;
+        Some(block.content.transactions.iter()
+            .filter(|tx| self.tx_to_block.get(&tx.txid()) == Some(hash))
+            .count())
+    }
+
+    /// How many blocks of the longest chain (genesis and pruned blocks left out) confirmed
+    /// each number of transactions
+    pub fn transactions_per_block_histogram(&self) -> HashMap<usize, usize> {
+        let mut histogram = HashMap::new();
+        for hash in &self.height_to_canonical_hash[1..] {
+            if let Some(count) = self.confirmed_transactions_in(hash) {
+                *histogram.entry(count).or_insert(0) += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Transactions per second confirmed by the blocks of the longest chain timestamped within
+    /// `window_secs` before the tip, which counts as inside the window
+    pub fn confirmed_tps(&self, window_secs: u64) -> f64 {
+        if window_secs == 0 {
+            return 0.0;
+        }
+        let tip_timestamp = self.hash_to_block[&self.tip].header().timestamp;
+        let start = tip_timestamp.saturating_sub(window_secs as u128 * 1000);
+        let confirmed: usize = self.height_to_canonical_hash[1..].iter().rev()
+            .take_while(|hash| self.hash_to_block[*hash].header().timestamp > start)
+            .filter_map(|hash| self.confirmed_transactions_in(hash))
+            .sum();
+        confirmed as f64 / window_secs as f64
+    }
+}
+
+#[cfg(feature = "adversary")]
+impl Blockchain {
+    /// Hand a block we just mined to the adversary, if any.
+    /// Returns `None` for an honest node, otherwise the hashes to broadcast now.
+    pub fn adversary_on_mined(&mut self, hash: &H256) -> Option<Vec<H256>> {
+        let height = *self.hash_to_height.get(hash).unwrap();
+        let released = self.adversary.as_mut()?.on_mined(*hash, height);
+        self.attribute_release(&released);
+        Some(released)
+    }
+
+    /// Tell the adversary, if any, about a block received from the network.
+    /// Returns the hashes to broadcast now.
+    pub fn adversary_on_received(&mut self, hash: &H256) -> Vec<H256> {
+        let height = *self.hash_to_height.get(hash).unwrap();
+        let released = match self.adversary.as_mut() {
+            Some(adversary) => adversary.on_competing_block(height),
+            None => return vec![],
+        };
+        self.attribute_release(&released);
+        released
+    }
+
+    /// Blame the received blocks at the released heights for being stale
+    fn attribute_release(&mut self, released: &[H256]) {
+        let heights: Vec<u64> = released.iter().map(|hash| self.hash_to_height[hash]).collect();
+        let stale: Vec<H256> = self.hash_to_height.iter()
+            .filter(|(hash, height)| heights.contains(height) && !released.contains(hash))
+            .filter(|(hash, _)| matches!(self.hash_to_origin.get(hash), Some(BlockOrigin::Received{..})))
+            .map(|(hash, _)| *hash)
+            .collect();
+        if let Some(adversary) = self.adversary.as_mut() {
+            adversary.stale_from_release.extend(stale);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::block::BLOCK_REWARD;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::crypto::key_pair;
+    use crate::transaction::{RawTransaction, SignedTransaction};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A transaction from the i-th ICO account
+    fn ico_transaction(i: u8, to: H160, value: u64, nonce: u32) -> SignedTransaction {
+        let key = get_deterministic_keypair(i);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        SignedTransaction::from_raw(RawTransaction { from_addr, outputs: vec![(to, value)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() }, &key, &ChainId::default())
+    }
+
+    fn ico_address(i: u8) -> H160 {
+        H160::from_pubkey(get_deterministic_keypair(i).public_key().as_ref())
+    }
+
+    #[test]
+    fn apply_transaction() {
+        let mut state = State::ico();
+        // the ICO only funds accounts 0 to 9
+        let (alice, bob, carol) = (ico_address(0), ico_address(1), ico_address(10));
+        state.apply_transaction(&ico_transaction(0, bob, 300, 1), &ChainId::default()).unwrap();
+        assert_eq!(state.get(&alice), Some(&(1, 9700)));
+        assert_eq!(state.get(&bob), Some(&(0, 9300)));
+        // receivers are created as needed
+        state.apply_transaction(&ico_transaction(0, carol, 700, 2), &ChainId::default()).unwrap();
+        assert_eq!(state.get(&alice), Some(&(2, 9000)));
+        assert_eq!(state.get(&carol), Some(&(0, 700)));
+    }
+
+    #[test]
+    fn apply_self_transfer() {
+        let mut state = State::ico();
+        let alice = ico_address(0);
+        state.apply_transaction(&ico_transaction(0, alice, 10000, 1), &ChainId::default()).unwrap();
+        assert_eq!(state.get(&alice), Some(&(1, 10000)));
+        assert_eq!(
+            state.apply_transaction(&ico_transaction(0, alice, 10001, 2), &ChainId::default()),
+            Err(TxApplyError::InsufficientBalance { balance: 10000, cost: 10001 })
+        );
+    }
+
+    #[test]
+    fn apply_transaction_with_several_outputs() {
+        let mut state = State::ico();
+        let (alice, bob, carol) = (ico_address(0), ico_address(1), ico_address(10));
+        let key = get_deterministic_keypair(0);
+        let paying = |outputs: Vec<(H160, u64)>, nonce: u32| {
+            let raw = RawTransaction { from_addr: alice, outputs, fee: 5, nonce, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &key, &ChainId::default())
+        };
+        // a repeated recipient is credited for each output, and paying the sender back is allowed
+        let tx = paying(vec![(bob, 100), (carol, 50), (bob, 25), (alice, 1000)], 1);
+        state.apply_transaction(&tx, &ChainId::default()).unwrap();
+        assert_eq!(state.get(&alice), Some(&(1, 9820)));
+        assert_eq!(state.get(&bob), Some(&(0, 9125)));
+        assert_eq!(state.get(&carol), Some(&(0, 50)));
+        assert_eq!(state.total_supply(), State::ico().total_supply() - 5);
+
+        // outputs whose sum overflows are rejected without touching the state
+        let before = state.clone();
+        let tx = paying(vec![(bob, u64::MAX), (carol, 1)], 2);
+        assert_eq!(state.apply_transaction(&tx, &ChainId::default()), Err(TxApplyError::Overflow));
+        assert_eq!(state, before);
+    }
+
+    /// A block at `height` on top of `parent` with the given transactions, after a coinbase paying nothing
+    fn block_with_transactions(parent: &H256, height: u64, transactions: Vec<SignedTransaction>) -> Block {
+        let mut block = generate_random_block(parent);
+        block.content.transactions = vec![SignedTransaction::coinbase(H160::default(), 0, height)];
+        block.content.transactions.extend(transactions);
+        block
+    }
+
+    /// A block of only a coinbase, which `try_insert` accepts on top of `parent` in `blockchain`
+    fn valid_block(blockchain: &Blockchain, parent: &H256) -> Block {
+        let mut block = block_with_transactions(parent, blockchain.get_height(parent).unwrap() + 1, vec![]);
+        block.header.state_root = blockchain.expected_state_root(&block).unwrap_or_default();
+        block
+    }
+
+    #[test]
+    fn apply_block_in_order() {
+        let state = State::ico();
+        let (alice, bob) = (ico_address(0), ico_address(1));
+        let genesis_hash = Blockchain::new().tip();
+        // the second transaction spends what the first one received
+        let block = block_with_transactions(&genesis_hash, 1, vec![
+            ico_transaction(1, alice, 9000, 1),
+            ico_transaction(0, bob, 15000, 1),
+            ico_transaction(0, bob, 4000, 2),
+        ]);
+        let after = state.apply_block(&block, &ChainId::default()).unwrap();
+        assert_eq!(after.get(&alice), Some(&(2, 0)));
+        assert_eq!(after.get(&bob), Some(&(1, 19000)));
+
+        // swapped, alice's nonces are out of order
+        let swapped = block_with_transactions(&genesis_hash, 1, vec![
+            ico_transaction(0, bob, 1000, 2),
+            ico_transaction(0, bob, 1000, 1),
+        ]);
+        assert_eq!(state.apply_block(&swapped, &ChainId::default()).err(), Some((1, TxApplyError::BadNonce { expected: 1, got: 2 })));
+    }
+
+    #[test]
+    fn state_hash_ignores_insertion_order() {
+        let accounts: Vec<(H160, u64)> = (0..20).map(|i| (ico_address(i), 100 * i as u64)).collect();
+        let forward = State::ico_from_config(&accounts);
+        let mut backward = State::ico_from_config(&[]);
+        for (address, balance) in accounts.iter().rev() {
+            backward.update(*address, 0, *balance);
+        }
+        assert_eq!(forward.hash(), backward.hash());
+        backward.update(ico_address(3), 1, 300);
+        assert_ne!(forward.hash(), backward.hash());
+
+        let blockchain = Blockchain::new();
+        assert_eq!(blockchain.state_hash(&blockchain.genesis_hash()), Some(State::ico().hash()));
+        assert_eq!(blockchain.state_hash(&generate_random_hash()), None);
+    }
+
+    #[test]
+    fn apply_block_is_all_or_nothing() {
+        let state = State::ico();
+        let (alice, bob) = (ico_address(0), ico_address(1));
+        let genesis_hash = Blockchain::new().tip();
+        let block = block_with_transactions(&genesis_hash, 1, vec![
+            ico_transaction(0, bob, 1000, 1),
+            ico_transaction(0, bob, 1000, 2),
+            ico_transaction(0, bob, 1000, 2),
+        ]);
+        assert_eq!(state.apply_block(&block, &ChainId::default()).err(), Some((3, TxApplyError::BadNonce { expected: 3, got: 2 })));
+        assert_eq!(state.get(&alice), Some(&(0, 10000)));
+        assert_eq!(state.get(&bob), Some(&(0, 9000)));
+    }
+
+    #[test]
+    fn states_are_tracked_per_block() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let bob = ico_address(1);
+        let valid = block_with_transactions(&genesis_hash, 1, vec![ico_transaction(0, bob, 1000, 1)]);
+        assert_eq!(blockchain.state_validity_check(&valid), Ok(()));
+        blockchain.insert(&valid);
+        assert_eq!(blockchain.state_at(&valid.hash()).unwrap().get(&bob), Some(&(0, 10000)));
+
+        // replaying the same transaction is invalid, and so is anything built on top of it
+        let replay = block_with_transactions(&valid.hash(), 2, vec![ico_transaction(0, bob, 1000, 1)]);
+        assert_eq!(blockchain.state_validity_check(&replay), Err((1, TxApplyError::BadNonce { expected: 2, got: 1 })));
+        blockchain.insert(&replay);
+        assert!(blockchain.state_at(&replay.hash()).is_none());
+        let child = block_with_transactions(&replay.hash(), 3, vec![]);
+        assert_eq!(blockchain.state_validity_check(&child), Ok(()));
+        blockchain.insert(&child);
+        assert!(blockchain.state_at(&child.hash()).is_none());
+    }
+
+    #[test]
+    fn transfers_conserve_the_total_supply() {
+        let mut state = State::ico();
+        assert_eq!(state.total_supply(), 55000);
+        let genesis_hash = Blockchain::new().tip();
+        // account 9 ends up with as much as account 8, and account 10 is created
+        let block = block_with_transactions(&genesis_hash, 1, vec![
+            ico_transaction(0, ico_address(9), 1000, 1),
+            ico_transaction(0, ico_address(10), 2000, 2),
+            ico_transaction(3, ico_address(1), 7000, 1),
+            ico_transaction(1, ico_address(1), 500, 1),
+        ]);
+        state.apply_block_in_place(&block, &ChainId::default()).unwrap();
+        assert_eq!(state.total_supply(), 55000);
+        assert_eq!(state.iter().count(), 11);
+
+        let sorted = state.accounts_sorted_by_balance();
+        let balances: Vec<u64> = sorted.iter().map(|(_, balance)| *balance).collect();
+        assert_eq!(balances, vec![16000, 8000, 7000, 6000, 5000, 4000, 3000, 2000, 2000, 2000, 0]);
+        assert_eq!(sorted[0].0, ico_address(1));
+        // ties are broken by address, whatever order the accounts are stored in
+        let mut tied: Vec<H160> = [ico_address(8), ico_address(9), ico_address(10)].to_vec();
+        tied.sort();
+        assert_eq!(sorted[7..10].iter().map(|(address, _)| *address).collect::<Vec<_>>(), tied);
+        assert_eq!(state.clone().accounts_sorted_by_balance(), sorted);
+    }
+
+    #[test]
+    fn drained_accounts_keep_their_nonce() {
+        let genesis_hash = Blockchain::new().tip();
+        let block = block_with_transactions(&genesis_hash, 1, vec![ico_transaction(9, ico_address(0), 1000, 1)]);
+        let mut state = State::ico().apply_block(&block, &ChainId::default()).unwrap();
+        assert_eq!(state.account(&ico_address(9)), Some(AccountInfo { nonce: 1, balance: 0 }));
+        assert_eq!(state.compact(), 0);
+
+        // once refilled, the drained account can't replay its old transaction
+        let refill = block_with_transactions(&block.hash(), 2, vec![ico_transaction(0, ico_address(9), 1000, 1)]);
+        state.apply_block_in_place(&refill, &ChainId::default()).unwrap();
+        assert_eq!(
+            state.apply_transaction(&ico_transaction(9, ico_address(0), 1000, 1), &ChainId::default()),
+            Err(TxApplyError::BadNonce { expected: 2, got: 1 })
+        );
+        state.apply_transaction(&ico_transaction(9, ico_address(0), 1000, 2), &ChainId::default()).unwrap();
+        assert_eq!(state.account(&ico_address(9)), Some(AccountInfo { nonce: 2, balance: 0 }));
+    }
+
+    #[test]
+    fn compact_removes_only_empty_accounts() {
+        let mut state = State::ico();
+        // a zero-value transfer does not create the receiver
+        state.apply_transaction(&ico_transaction(0, ico_address(10), 0, 1), &ChainId::default()).unwrap();
+        assert_eq!(state.get(&ico_address(10)), None);
+        let hash = state.hash();
+
+        state.update(ico_address(10), 0, 0);
+        state.update(ico_address(11), 0, 0);
+        assert_ne!(state.hash(), hash);
+        assert_eq!(state.compact(), 2);
+        assert_eq!(state.hash(), hash);
+        assert_eq!(state.iter().count(), 10);
+    }
+
+    #[test]
+    fn state_json_round_trip() {
+        let genesis_hash = Blockchain::new().tip();
+        let block = block_with_transactions(&genesis_hash, 1, vec![ico_transaction(0, ico_address(10), 2500, 1)]);
+        let state = State::ico().apply_block(&block, &ChainId::default()).unwrap();
+        let json = state.export_json();
+        let restored = State::from_json(&json).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(restored.export_json(), json);
+        assert_eq!(restored.hash(), state.hash());
+        // the persisted chain stores states with bincode
+        let bytes = bincode::serialize(&state).unwrap();
+        assert_eq!(bincode::deserialize::<State>(&bytes).unwrap(), state);
+
+        let duplicated = format!(r#"{{"accounts": [{{"address": "{0}", "nonce": 0, "balance": 1}}, {{"address": "{0}", "nonce": 0, "balance": 2}}], "immature": [], "coinbase_maturity": 0}}"#, ico_address(0));
+        assert!(State::from_json(&duplicated).is_err());
+    }
+
+    #[test]
+    fn state_diff_reports_every_discrepancy() {
+        let genesis_hash = Blockchain::new().tip();
+        let ours = State::ico();
+        assert!(ours.diff(&ours.clone()).is_empty());
+        let block = block_with_transactions(&genesis_hash, 1, vec![ico_transaction(0, ico_address(10), 2500, 1)]);
+        let theirs = ours.apply_block(&block, &ChainId::default()).unwrap();
+
+        let diff = ours.diff(&theirs);
+        let mut expected = vec![
+            StateDiffEntry {
+                address: ico_address(0),
+                ours: Some(AccountInfo { nonce: 0, balance: 10000 }),
+                theirs: Some(AccountInfo { nonce: 1, balance: 7500 }),
+            },
+            StateDiffEntry {
+                address: ico_address(10),
+                ours: None,
+                theirs: Some(AccountInfo { nonce: 0, balance: 2500 }),
+            },
+        ];
+        expected.sort_unstable_by_key(|entry| entry.address);
+        assert_eq!(diff, expected);
+        assert_eq!(theirs.diff(&ours)[0].ours, expected[0].theirs);
+    }
+
+    #[test]
+    fn apply_then_revert_is_identity() {
+        let mut state = State::ico();
+        let original = state.clone();
+        let (alice, bob, carol) = (ico_address(0), ico_address(1), ico_address(10));
+        let genesis_hash = Blockchain::new().tip();
+        let block = block_with_transactions(&genesis_hash, 1, vec![
+            ico_transaction(0, carol, 1000, 1),
+            ico_transaction(0, bob, 500, 2),
+            ico_transaction(1, alice, 200, 1),
+        ]);
+        let delta = state.apply_block_in_place(&block, &ChainId::default()).unwrap();
+        assert_eq!(state.get(&carol), Some(&(0, 1000)));
+        state.revert(&delta);
+        assert_eq!(state, original);
+        // carol did not exist before the block, and no longer does
+        assert_eq!(state.get(&carol), None);
+
+        // a failing block leaves no trace either
+        let failing = block_with_transactions(&genesis_hash, 1, vec![
+            ico_transaction(0, carol, 1000, 1),
+            ico_transaction(0, carol, 1000, 1),
+        ]);
+        assert!(state.apply_block_in_place(&failing, &ChainId::default()).is_err());
+        assert_eq!(state, original);
+    }
+
+    /// A block on top of `parent` moving `value` from the i-th ICO account to the j-th,
+    /// with a state root matching `blockchain`
+    fn transfer_block(blockchain: &Blockchain, parent: &H256, i: u8, j: u8, value: u64) -> Block {
+        let nonce = blockchain.state_at(parent).unwrap().get(&ico_address(i)).map_or(0, |account| account.0) + 1;
+        let height = blockchain.get_height(parent).unwrap() + 1;
+        let mut block = block_with_transactions(parent, height, vec![ico_transaction(i, ico_address(j), value, nonce)]);
+        block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+        block
+    }
+
+    #[test]
+    fn state_at_follows_reorgs() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main_1 = transfer_block(&blockchain, &genesis_hash, 0, 1, 1000);
+        blockchain.try_insert(&main_1).unwrap();
+        let main_2 = transfer_block(&blockchain, &main_1.hash(), 0, 1, 1000);
+        blockchain.try_insert(&main_2).unwrap();
+        let fork_1 = transfer_block(&blockchain, &genesis_hash, 0, 2, 3000);
+        blockchain.try_insert(&fork_1).unwrap();
+        let fork_2 = transfer_block(&blockchain, &fork_1.hash(), 2, 0, 1);
+        blockchain.try_insert(&fork_2).unwrap();
+        let fork_3 = transfer_block(&blockchain, &fork_2.hash(), 2, 0, 1);
+        assert_eq!(blockchain.try_insert(&fork_3), Ok(InsertOutcome::Reorged { depth: 2 }));
+
+        let balance = |hash: &H256, i: u8| blockchain.state_at(hash).unwrap().get(&ico_address(i)).unwrap().1;
+        assert_eq!(balance(&fork_3.hash(), 0), 7002);
+        assert_eq!(balance(&fork_3.hash(), 2), 10998);
+        // the detached branch and the common ancestor are still there
+        assert_eq!(balance(&main_2.hash(), 0), 8000);
+        assert_eq!(balance(&main_2.hash(), 1), 11000);
+        assert_eq!(blockchain.state_at(&genesis_hash), Some(State::ico()));
+        assert_eq!(blockchain.state_hash(&fork_3.hash()), Some(fork_3.header.state_root));
+    }
+
+    #[test]
+    fn balances_follow_the_longest_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let (alice, bob) = (ico_address(0), ico_address(1));
+        assert_eq!(blockchain.balance_of(&alice), 10000);
+        assert_eq!(blockchain.nonce_of(&alice), 0);
+        assert_eq!(blockchain.account_info(&ico_address(10)), None);
+        assert_eq!(blockchain.balance_of(&ico_address(10)), 0);
+
+        let main_1 = transfer_block(&blockchain, &genesis_hash, 0, 1, 1000);
+        blockchain.try_insert(&main_1).unwrap();
+        assert_eq!(blockchain.account_info(&alice), Some(AccountInfo { nonce: 1, balance: 9000 }));
+        assert_eq!(blockchain.balance_of(&bob), 10000);
+
+        let fork_1 = transfer_block(&blockchain, &genesis_hash, 1, 0, 50);
+        blockchain.try_insert(&fork_1).unwrap();
+        assert_eq!(blockchain.balance_of(&alice), 9000);
+        let fork_2 = transfer_block(&blockchain, &fork_1.hash(), 1, 0, 50);
+        blockchain.try_insert(&fork_2).unwrap();
+        assert_eq!(blockchain.account_info(&alice), Some(AccountInfo { nonce: 0, balance: 10100 }));
+        assert_eq!(blockchain.account_info(&bob), Some(AccountInfo { nonce: 2, balance: 8900 }));
+
+        // a tip without a state answers as of its latest ancestor with one
+        blockchain.insert(&generate_random_block(&fork_2.hash()));
+        assert_eq!(blockchain.balance_of(&alice), 10100);
+    }
+
+    /// A block on top of `parent` whose coinbase pays the block reward to `miner`, then the
+    /// given transactions, committing to its state if it has one
+    fn block_paying(blockchain: &Blockchain, parent: &H256, miner: H160, transactions: Vec<SignedTransaction>) -> Block {
+        let height = blockchain.get_height(parent).unwrap() + 1;
+        let mut block = block_with_transactions(parent, height, transactions);
+        block.content.transactions[0] = SignedTransaction::coinbase(miner, BLOCK_REWARD, height);
+        block.header.state_root = blockchain.expected_state_root(&block).unwrap_or_default();
+        block
+    }
+
+    #[test]
+    fn coinbase_reward_is_spendable_once_mature() {
+        let config = GenesisConfig { coinbase_maturity: 2, ..GenesisConfig::default() };
+        let mut blockchain = Blockchain::from_genesis_config(&config);
+        let (miner, other) = (ico_address(10), ico_address(11));
+        let rewarded = block_paying(&blockchain, &blockchain.tip(), miner, vec![]);
+        blockchain.try_insert(&rewarded).unwrap();
+        assert_eq!(blockchain.balance_of(&miner), 0);
+        assert_eq!(blockchain.immature_balance_of(&miner), BLOCK_REWARD);
+        assert_eq!(blockchain.latest_state().unwrap().total_supply(), State::ico().total_supply() + BLOCK_REWARD);
+
+        // neither the mempool nor a block at height 2 takes a spend of the reward
+        let spend = ico_transaction(10, ico_address(0), 10, 1);
+        assert_eq!(blockchain.admit_transactions(&mut Mempool::new(), vec![spend.clone()]), vec![Err(RejectReason::InsufficientBalance)]);
+        let early = block_paying(&blockchain, &rewarded.hash(), other, vec![spend.clone()]);
+        assert_eq!(blockchain.try_insert(&early), Err(InsertError::InvalidTransaction {
+            index: 1,
+            error: TxApplyError::InsufficientBalance { balance: 0, cost: 10 },
+        }));
+        let waiting = block_paying(&blockchain, &rewarded.hash(), other, vec![]);
+        blockchain.try_insert(&waiting).unwrap();
+        assert_eq!(blockchain.balance_of(&miner), 0);
+
+        // two blocks later, it is spendable from the start of the block
+        let mature = block_paying(&blockchain, &waiting.hash(), other, vec![spend]);
+        blockchain.try_insert(&mature).unwrap();
+        assert_eq!(blockchain.account_info(&miner), Some(AccountInfo { nonce: 1, balance: BLOCK_REWARD - 10 }));
+        assert_eq!(blockchain.immature_balance_of(&miner), 0);
+        assert_eq!(blockchain.immature_balance_of(&other), 2 * BLOCK_REWARD);
+
+        // immature rewards survive a round trip to disk
+        let state = blockchain.latest_state().unwrap();
+        let bytes = bincode::serialize(&state).unwrap();
+        assert_eq!(bincode::deserialize::<State>(&bytes).unwrap(), state);
+    }
+
+    #[test]
+    fn reorg_takes_back_an_immature_reward() {
+        let config = GenesisConfig { coinbase_maturity: 2, ..GenesisConfig::default() };
+        let mut blockchain = Blockchain::from_genesis_config(&config);
+        let genesis_hash = blockchain.tip();
+        let miner = ico_address(10);
+        let rewarded = block_paying(&blockchain, &genesis_hash, miner, vec![]);
+        blockchain.try_insert(&rewarded).unwrap();
+        assert_eq!(blockchain.immature_balance_of(&miner), BLOCK_REWARD);
+
+        // a longer branch without the block
+        let fork_1 = block_paying(&blockchain, &genesis_hash, H160::default(), vec![]);
+        blockchain.try_insert(&fork_1).unwrap();
+        let fork_2 = block_paying(&blockchain, &fork_1.hash(), H160::default(), vec![]);
+        blockchain.try_insert(&fork_2).unwrap();
+        assert_eq!(blockchain.tip(), fork_2.hash());
+        assert_eq!(blockchain.immature_balance_of(&miner), 0);
+        assert_eq!(blockchain.balance_of(&miner), 0);
+
+        // back on the branch with the reward, which has matured by its third block
+        let main_2 = block_paying(&blockchain, &rewarded.hash(), H160::default(), vec![]);
+        blockchain.try_insert(&main_2).unwrap();
+        let main_3 = block_paying(&blockchain, &main_2.hash(), H160::default(), vec![]);
+        blockchain.try_insert(&main_3).unwrap();
+        assert_eq!(blockchain.tip(), main_3.hash());
+        assert_eq!(blockchain.balance_of(&miner), BLOCK_REWARD);
+        assert_eq!(blockchain.immature_balance_of(&miner), 0);
+
+        // and undoing the blocks of the branch undoes the maturing too
+        let mut state = blockchain.state_at(&rewarded.hash()).unwrap();
+        let before = state.clone();
+        let deltas: Vec<StateDelta> = [&main_2, &main_3].iter()
+            .map(|block| state.apply_presigned_block_in_place(block).unwrap())
+            .collect();
+        assert_eq!(state.hash(), main_3.header.state_root);
+        for delta in deltas.iter().rev() {
+            state.revert(delta);
+        }
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn state_beyond_the_delta_window() {
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        let mut hashes = vec![parent];
+        for i in 0..300 {
+            let block = transfer_block(&blockchain, &parent, (i % 3) as u8, 5, 1);
+            blockchain.try_insert(&block).unwrap();
+            parent = block.hash();
+            hashes.push(parent);
+        }
+        assert!(blockchain.hash_to_delta.len() as u64 <= STATE_DELTA_WINDOW);
+        assert_eq!(blockchain.state_snapshots.len(), 3);
+        // rebuilt from the snapshots at heights 0 and 128, and from the tip
+        for &height in &[10, 140, 290] {
+            let state = blockchain.state_at(&hashes[height]).unwrap();
+            assert_eq!(state.get(&ico_address(5)), Some(&(0, 5000 + height as u64)));
+            assert_eq!(state.get(&ico_address(0)), Some(&(height.div_ceil(3) as u32, 10000 - height.div_ceil(3) as u64)));
+        }
+    }
+
+    #[test]
+    fn state_root_must_match() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let bob = ico_address(1);
+        let mut block = block_with_transactions(&genesis_hash, 1, vec![ico_transaction(0, bob, 1000, 1)]);
+        let expected = blockchain.expected_state_root(&block).unwrap();
+        assert_eq!(blockchain.try_insert(&block), Err(InsertError::StateRootMismatch { expected, found: H256::default() }));
+        assert!(!blockchain.contains_block(&block.hash()));
+
+        block.header.state_root = expected;
+        assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+        assert_eq!(blockchain.state_hash(&block.hash()), Some(expected));
+        assert_eq!(blockchain.state_at(&block.hash()).unwrap().get(&bob), Some(&(0, 10000)));
+
+        // transactions that don't apply have no state to commit to, not even the zero root
+        let replay = block_with_transactions(&block.hash(), 2, vec![ico_transaction(0, bob, 1000, 1)]);
+        assert_eq!(blockchain.expected_state_root(&replay), None);
+        assert_eq!(
+            blockchain.try_insert(&replay),
+            Err(InsertError::InvalidTransaction { index: 1, error: TxApplyError::BadNonce { expected: 2, got: 1 } })
+        );
+        assert!(!blockchain.contains_block(&replay.hash()));
+    }
+
+    #[test]
+    fn apply_transaction_failures() {
+        let mut state = State::ico();
+        let (alice, bob) = (ico_address(0), ico_address(1));
+
+        let mut tampered = ico_transaction(0, bob, 300, 1);
+        tampered.raw.outputs[0].1 = 3000;
+        assert_eq!(state.apply_transaction(&tampered, &ChainId::default()), Err(TxApplyError::BadSignature));
+
+        // bob signs a transaction spending alice's coins
+        let key = get_deterministic_keypair(1);
+        let stolen = SignedTransaction::from_raw(RawTransaction { from_addr: alice, outputs: vec![(bob, 300)], fee: 0, nonce: 1, valid_until_block: 0, data: Vec::new() }, &key, &ChainId::default());
+        assert_eq!(state.apply_transaction(&stolen, &ChainId::default()), Err(TxApplyError::WrongOwner));
+
+        assert_eq!(state.apply_transaction(&ico_transaction(0, bob, 300, 2), &ChainId::default()), Err(TxApplyError::BadNonce { expected: 1, got: 2 }));
+        assert_eq!(state.apply_transaction(&ico_transaction(0, bob, 300, 0), &ChainId::default()), Err(TxApplyError::BadNonce { expected: 1, got: 0 }));
+        assert_eq!(
+            state.apply_transaction(&ico_transaction(0, bob, 10001, 1), &ChainId::default()),
+            Err(TxApplyError::InsufficientBalance { balance: 10000, cost: 10001 })
+        );
+
+        state.update(bob, 0, u64::MAX);
+        assert_eq!(state.apply_transaction(&ico_transaction(0, bob, 1, 1), &ChainId::default()), Err(TxApplyError::Overflow));
+        // an account that used the last nonce can send nothing more
+        state.update(alice, u32::MAX, 10000);
+        assert_eq!(state.apply_transaction(&ico_transaction(0, bob, 1, 0), &ChainId::default()), Err(TxApplyError::NonceExhausted));
+
+        // nothing was applied
+        assert_eq!(state.get(&alice), Some(&(u32::MAX, 10000)));
+        assert_eq!(state.get(&bob), Some(&(0, u64::MAX)));
+    }
+
+    #[test]
+    fn fees_are_debited_and_burned() {
+        let mut state = State::ico();
+        let (alice, bob) = (ico_address(0), ico_address(1));
+        let key = get_deterministic_keypair(0);
+        let transfer = |value, fee, nonce| {
+            SignedTransaction::from_raw(RawTransaction { from_addr: alice, outputs: vec![(bob, value)], fee, nonce, valid_until_block: 0, data: Vec::new() }, &key, &ChainId::default())
+        };
+
+        // the balance covers the value but not the fee
+        assert_eq!(
+            state.apply_transaction(&transfer(10000, 1, 1), &ChainId::default()),
+            Err(TxApplyError::InsufficientBalance { balance: 10000, cost: 10001 })
+        );
+        assert_eq!(state.apply_transaction(&transfer(1, u64::MAX, 1), &ChainId::default()), Err(TxApplyError::Overflow));
+        assert_eq!(state, State::ico());
+
+        state.apply_transaction(&transfer(9000, 1000, 1), &ChainId::default()).unwrap();
+        assert_eq!(state.get(&alice), Some(&(1, 0)));
+        assert_eq!(state.get(&bob), Some(&(0, 18000)));
+        assert_eq!(state.total_supply(), State::ico().total_supply() - 1000);
+
+        // a self-transfer only pays the fee
+        let key = get_deterministic_keypair(1);
+        let raw = RawTransaction { from_addr: bob, outputs: vec![(bob, 500)], fee: 10, nonce: 1, valid_until_block: 0, data: Vec::new() };
+        state.apply_transaction(&SignedTransaction::from_raw(raw, &key, &ChainId::default()), &ChainId::default()).unwrap();
+        assert_eq!(state.get(&bob), Some(&(1, 17990)));
+    }
+
+    #[test]
+    fn coinbase_must_commit_to_the_height() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let miner = ico_address(20);
+        let mut block = block_with_transactions(&genesis_hash, 2, vec![]);
+        block.content.transactions[0] = SignedTransaction::coinbase(miner, BLOCK_REWARD, 2);
+        assert_eq!(blockchain.state_validity_check(&block), Err((0, TxApplyError::CoinbaseHeight { expected: 1, got: 2 })));
+
+        block.content.transactions[0] = SignedTransaction::coinbase(miner, BLOCK_REWARD, 1);
+        block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+        assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+        assert_eq!(blockchain.balance_of(&miner), BLOCK_REWARD);
+        // coinbases are not indexed as transactions
+        assert_eq!(blockchain.canonical_transaction_count(), 0);
+    }
+
+    #[test]
+    fn expiry_height_is_inclusive() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let expiring = |nonce, valid_until_block| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 10)], fee: 0, nonce, valid_until_block, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+
+        // valid until height 1: still fine in the block at height 1
+        let mut block_1 = block_with_transactions(&genesis_hash, 1, vec![expiring(1, 1)]);
+        block_1.header.state_root = blockchain.expected_state_root(&block_1).unwrap();
+        assert_eq!(blockchain.try_insert(&block_1), Ok(InsertOutcome::ExtendedTip));
+
+        // but not at height 2
+        let mut block_2 = block_with_transactions(&block_1.hash(), 2, vec![expiring(2, 1)]);
+        block_2.header.state_root = blockchain.expected_state_root(&block_2).unwrap();
+        assert_eq!(
+            blockchain.try_insert(&block_2),
+            Err(InsertError::ExpiredTransaction { index: 1, valid_until: 1, height: 2 })
+        );
+        block_2.content.transactions[1] = expiring(2, 0);
+        block_2.header.state_root = blockchain.expected_state_root(&block_2).unwrap();
+        assert_eq!(blockchain.try_insert(&block_2), Ok(InsertOutcome::ExtendedTip));
+    }
+
+    #[test]
+    fn hand_built_blocks_with_extreme_values_are_rejected() {
+        let blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let coinbase_paying = |outputs: Vec<(H160, u64)>| {
+            let mut coinbase = SignedTransaction::coinbase(H160::default(), 0, 1);
+            coinbase.raw.outputs = outputs;
+            coinbase
+        };
+        let mut block = block_with_transactions(&genesis_hash, 1, vec![]);
+        block.content.transactions[0] = coinbase_paying(vec![(ico_address(20), u64::MAX)]);
+        assert_eq!(
+            blockchain.state_validity_check(&block),
+            Err((0, TxApplyError::CoinbaseTooLarge { value: u64::MAX, limit: BLOCK_REWARD }))
+        );
+        block.content.transactions[0] = coinbase_paying(vec![(ico_address(20), u64::MAX), (ico_address(21), 1)]);
+        assert_eq!(blockchain.state_validity_check(&block), Err((0, TxApplyError::Overflow)));
+
+        // fees adding up past u64::MAX
+        let with_fee = |i: u8, fee: u64| {
+            let raw = RawTransaction { from_addr: ico_address(i), outputs: vec![], fee, nonce: 1, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(i), &ChainId::default())
+        };
+        let block = block_with_transactions(&genesis_hash, 1, vec![with_fee(0, u64::MAX), with_fee(1, 1)]);
+        assert_eq!(blockchain.state_validity_check(&block), Err((0, TxApplyError::Overflow)));
+
+        // a credit pushing the recipient past u64::MAX
+        let state = State::ico_from_config(&[(ico_address(0), u64::MAX - 5), (ico_address(1), 10)]);
+        let block = block_with_transactions(&genesis_hash, 1, vec![ico_transaction(1, ico_address(0), 10, 1)]);
+        assert_eq!(state.apply_block(&block, &ChainId::default()).err(), Some((1, TxApplyError::Overflow)));
+        // and an output summing past it, however rich the sender
+        let raw = RawTransaction {
+            from_addr: ico_address(0), outputs: vec![(ico_address(2), u64::MAX - 5), (ico_address(2), 6)],
+            fee: 0, nonce: 1, valid_until_block: 0, data: Vec::new(),
+        };
+        let tx = SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default());
+        let block = block_with_transactions(&genesis_hash, 1, vec![tx]);
+        assert_eq!(state.apply_block(&block, &ChainId::default()).err(), Some((1, TxApplyError::Overflow)));
+    }
+
+    /// A transfer from the first ICO account carrying `data`
+    fn with_data(nonce: u32, data: Vec<u8>) -> SignedTransaction {
+        let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 10)], fee: 0, nonce, valid_until_block: 0, data };
+        SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+    }
+
+    #[test]
+    fn oversized_data_invalidates_the_block() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut block = block_with_transactions(&genesis_hash, 1, vec![with_data(1, vec![0; MAX_DATA_SIZE + 1])]);
+        block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+        assert_eq!(blockchain.try_insert(&block), Err(InsertError::DataTooLarge { index: 1, size: MAX_DATA_SIZE + 1 }));
+        block.content.transactions[1] = with_data(1, vec![0; MAX_DATA_SIZE]);
+        block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+        assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+    }
+
+    #[test]
+    fn confirmation_latency_from_stamps() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let stamp = |sent_ms| Stamp { generator: 7, sent_ms }.to_bytes();
+        assert_eq!(Stamp::parse(&stamp(9_000)), Some(Stamp { generator: 7, sent_ms: 9_000 }));
+
+        // unstamped and foreign data is ignored
+        let mut block_1 = block_with_transactions(&genesis_hash, 1, vec![
+            with_data(1, stamp(9_000)), with_data(2, Vec::new()), with_data(3, b"hello".to_vec()),
+        ]);
+        block_1.header.timestamp = 10_000;
+        block_1.header.state_root = blockchain.expected_state_root(&block_1).unwrap();
+        assert_eq!(blockchain.try_insert(&block_1), Ok(InsertOutcome::ExtendedTip));
+        // a stamp later than the block, from a skewed clock, is left out
+        let mut block_2 = block_with_transactions(&block_1.hash(), 2, vec![
+            with_data(4, stamp(10_000)), with_data(5, stamp(7_500)), with_data(6, stamp(13_000)),
+        ]);
+        block_2.header.timestamp = 12_500;
+        block_2.header.state_root = blockchain.expected_state_root(&block_2).unwrap();
+        assert_eq!(blockchain.try_insert(&block_2), Ok(InsertOutcome::ExtendedTip));
+
+        assert_eq!(blockchain.confirmation_latencies_ms(), vec![1_000, 2_500, 5_000]);
+        let stats = blockchain.confirmation_latency_stats();
+        assert_eq!((stats.count, stats.median, stats.max), (3, 2_500, 5_000));
+    }
+
+    #[test]
+    fn insert_one() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+
+    }
+    
+    #[test]
+    fn mp1_insert_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+        for _ in 0..50 {
+            let h = block.hash();
+            block = generate_random_block(&h);
+            blockchain.insert(&block);
+            assert_eq!(blockchain.tip(), block.hash());
+        }
+    }
+
+    #[test]
+    fn mp1_insert_3_fork_and_back() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        assert_eq!(blockchain.tip(), block_1.hash());
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        assert_eq!(blockchain.tip(), block_2.hash());
+        let block_3 = generate_random_block(&block_2.hash());
+        blockchain.insert(&block_3);
+        assert_eq!(blockchain.tip(), block_3.hash());
+        let fork_block_1 = generate_random_block(&block_2.hash());
+        blockchain.insert(&fork_block_1);
+        assert_eq!(blockchain.tip(), block_3.hash());
+        let fork_block_2 = generate_random_block(&fork_block_1.hash());
+        blockchain.insert(&fork_block_2);
+        assert_eq!(blockchain.tip(), fork_block_2.hash());
+        let block_4 = generate_random_block(&block_3.hash());
+        blockchain.insert(&block_4);
+        assert_eq!(blockchain.tip(), fork_block_2.hash());
+        let block_5 = generate_random_block(&block_4.hash());
+        blockchain.insert(&block_5);
+        assert_eq!(blockchain.tip(), block_5.hash());
+    }
+
+    #[test]
+    fn get_block_present() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.get_block(&block.hash()).unwrap().hash(), block.hash());
+        assert_eq!(blockchain.get_height(&block.hash()), Some(1));
+        assert_eq!(blockchain.tip_height(), 1);
+    }
+
+    #[test]
+    fn get_block_absent() {
+        let blockchain = Blockchain::new();
+        let hash = generate_random_hash();
+        assert!(blockchain.get_block(&hash).is_none());
+        assert_eq!(blockchain.get_height(&hash), None);
+    }
+
+    #[test]
+    fn get_block_genesis() {
+        let blockchain = Blockchain::new();
+        let genesis_hash = Block::genesis().hash();
+        assert_eq!(blockchain.get_block(&genesis_hash).unwrap().hash(), genesis_hash);
+        assert_eq!(blockchain.get_height(&genesis_hash), Some(0));
+        assert_eq!(blockchain.tip_height(), 0);
+    }
+
+    #[test]
+    fn get_block_by_height_after_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        assert_eq!(blockchain.get_block_by_height(0).unwrap().hash(), genesis_hash);
+        assert_eq!(blockchain.get_block_by_height(2).unwrap().hash(), block_2.hash());
+        assert!(blockchain.get_block_by_height(3).is_none());
+
+        // a fork from genesis overtakes the original chain
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_2);
+        assert_eq!(blockchain.get_block_by_height(2).unwrap().hash(), block_2.hash());
+        let fork_3 = generate_random_block(&fork_2.hash());
+        blockchain.insert(&fork_3);
+        assert_eq!(blockchain.tip(), fork_3.hash());
+        assert_eq!(blockchain.get_block_by_height(0).unwrap().hash(), genesis_hash);
+        assert_eq!(blockchain.get_block_by_height(1).unwrap().hash(), fork_1.hash());
+        assert_eq!(blockchain.get_block_by_height(2).unwrap().hash(), fork_2.hash());
+        assert_eq!(blockchain.get_block_by_height(3).unwrap().hash(), fork_3.hash());
+        assert!(blockchain.get_block_by_height(4).is_none());
+    }
+
+    #[test]
+    fn iterate_longest_chain_and_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        let fork_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&fork_2);
+
+        let canonical: Vec<H256> = blockchain.iter_longest_chain().map(|(hash, _)| *hash).collect();
+        assert_eq!(canonical, vec![genesis_hash, block_1.hash(), block_2.hash()]);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), canonical);
+
+        // walking back from a block off the longest chain still ends at genesis
+        let from_fork: Vec<H256> = blockchain.iter_from(&fork_2.hash()).map(|(hash, _)| *hash).collect();
+        assert_eq!(from_fork, vec![fork_2.hash(), block_1.hash(), genesis_hash]);
+        assert_eq!(blockchain.iter_from(&generate_random_hash()).count(), 0);
+    }
+
+    #[test]
+    fn common_ancestor_of_deep_forks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut trunk = generate_random_block(&genesis_hash);
+        blockchain.insert(&trunk);
+        for _ in 0..5 {
+            trunk = generate_random_block(&trunk.hash());
+            blockchain.insert(&trunk);
+        }
+        let fork_point = trunk.hash();
+
+        // one branch 20 blocks deep, another 7 blocks deep
+        let mut branch_a = fork_point;
+        for _ in 0..20 {
+            let block = generate_random_block(&branch_a);
+            blockchain.insert(&block);
+            branch_a = block.hash();
+        }
+        let mut branch_b = fork_point;
+        for _ in 0..7 {
+            let block = generate_random_block(&branch_b);
+            blockchain.insert(&block);
+            branch_b = block.hash();
+        }
+
+        assert_eq!(blockchain.common_ancestor(&branch_a, &branch_b), Some(fork_point));
+        assert_eq!(blockchain.common_ancestor(&branch_b, &branch_a), Some(fork_point));
+        assert_eq!(blockchain.fork_depth(&branch_a, &branch_b), Some((20, 7)));
+        assert_eq!(blockchain.common_ancestor(&branch_a, &fork_point), Some(fork_point));
+        assert_eq!(blockchain.fork_depth(&branch_a, &branch_a), Some((0, 0)));
+        assert_eq!(blockchain.common_ancestor(&branch_a, &genesis_hash), Some(genesis_hash));
+        assert_eq!(blockchain.common_ancestor(&branch_a, &generate_random_hash()), None);
+        assert_eq!(blockchain.fork_depth(&generate_random_hash(), &branch_b), None);
+    }
+
+    #[test]
+    fn stale_and_fork_statistics() {
+        // genesis - a1 - a2 - a3 - a4     (longest chain)
+        //             \- b2 - b3
+        //                  \- c3
+        //              a2 - d3
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&a1);
+        let a2 = generate_random_block(&a1.hash());
+        blockchain.insert(&a2);
+        let a3 = generate_random_block(&a2.hash());
+        blockchain.insert(&a3);
+        let a4 = generate_random_block(&a3.hash());
+        blockchain.insert(&a4);
+        let b2 = generate_random_block(&a1.hash());
+        blockchain.insert(&b2);
+        let b3 = generate_random_block(&b2.hash());
+        blockchain.insert(&b3);
+        let c3 = generate_random_block(&b2.hash());
+        blockchain.insert(&c3);
+        let d3 = generate_random_block(&a2.hash());
+        blockchain.insert(&d3);
+
+        assert_eq!(blockchain.stale_block_count(), 4);
+        assert_eq!(blockchain.fork_count(), 3); // a1, a2 and b2
+        let mut expected = HashMap::new();
+        expected.insert(2, 1); // b2 - b3 (or b2 - c3)
+        expected.insert(1, 1); // d3
+        assert_eq!(blockchain.fork_length_histogram(), expected);
+    }
+
+    #[test]
+    fn find_transaction_follows_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key = key_pair::random();
+        let raw = RawTransaction { outputs: vec![(Default::default(), 7)], nonce: 1, ..Default::default() };
+        let tx = SignedTransaction::from_raw(raw, &key, &ChainId::default());
+
+        let mut block_1 = generate_random_block(&genesis_hash);
+        block_1.content.transactions.push(tx.clone());
+        blockchain.insert(&block_1);
+        assert_eq!(blockchain.find_transaction(&tx.txid()), Some((block_1.hash(), 1)));
+
+        // the same transaction confirmed again on a fork that becomes the longest chain
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        let mut fork_2 = generate_random_block(&fork_1.hash());
+        fork_2.content.transactions.push(tx.clone());
+        blockchain.insert(&fork_2);
+        assert_eq!(blockchain.find_transaction(&tx.txid()), Some((fork_2.hash(), 2)));
+
+        // a fork without the transaction takes over
+        let other_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&other_2);
+        let other_3 = generate_random_block(&other_2.hash());
+        blockchain.insert(&other_3);
+        assert_eq!(blockchain.find_transaction(&tx.txid()), None);
+        assert_eq!(blockchain.find_transaction(&generate_random_hash()), None);
+    }
+
+    #[test]
+    fn confirmations_across_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tx = SignedTransaction::from_raw(RawTransaction { nonce: 1, ..Default::default() }, &key_pair::random(), &ChainId::default());
+        let mut block_1 = generate_random_block(&genesis_hash);
+        block_1.content.transactions.push(tx.clone());
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        assert!(blockchain.is_in_longest_chain(&block_1.hash()));
+        assert_eq!(blockchain.confirmations(&block_1.hash()), Some(2));
+        assert_eq!(blockchain.confirmations(&block_2.hash()), Some(1));
+        assert_eq!(blockchain.confirmations(&genesis_hash), Some(3));
+        assert_eq!(blockchain.tx_confirmations(&tx.txid()), Some(2));
+
+        // a longer fork from genesis takes over
+        let mut parent = genesis_hash;
+        for _ in 0..3 {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        assert!(!blockchain.is_in_longest_chain(&block_1.hash()));
+        assert_eq!(blockchain.confirmations(&block_1.hash()), None);
+        assert_eq!(blockchain.tx_confirmations(&tx.txid()), None);
+        assert_eq!(blockchain.confirmations(&parent), Some(1));
+        assert_eq!(blockchain.confirmations(&generate_random_hash()), None);
+    }
+
+    #[test]
+    fn orphan_buffer_is_bounded() {
+        let mut blockchain = Blockchain::with_orphan_limit(3);
+        let genesis_hash = blockchain.tip();
+        let mut scratch = Blockchain::new();
+        let parents: Vec<Block> = (0..5).map(|_| valid_block(&scratch, &genesis_hash)).collect();
+        let children: Vec<Block> = parents.iter()
+            .map(|parent| {
+                scratch.insert(parent);
+                valid_block(&scratch, &parent.hash())
+            })
+            .collect();
+        for child in &children {
+            blockchain.add_to_orphan_buffer(Arc::new(child.clone()));
+        }
+        blockchain.add_to_orphan_buffer(Arc::new(children[4].clone()));
+        assert_eq!(blockchain.orphan_count(), 3);
+
+        // the two oldest orphans were evicted, the rest still resolve
+        let mut inserted = vec![];
+        blockchain.insert_recursively(Arc::new(parents[0].clone()), &mut inserted);
+        assert_eq!(inserted, vec![parents[0].hash()]);
+        inserted.clear();
+        blockchain.insert_recursively(Arc::new(parents[4].clone()), &mut inserted);
+        assert_eq!(inserted, vec![parents[4].hash(), children[4].hash()]);
+        assert_eq!(blockchain.orphan_count(), 2);
+    }
+
+    #[test]
+    fn prune_old_orphans() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let parent = generate_random_block(&genesis_hash);
+        blockchain.add_to_orphan_buffer(Arc::new(generate_random_block(&parent.hash())));
+        assert_eq!(blockchain.prune_orphans_older_than(Duration::from_secs(60)), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(blockchain.prune_orphans_older_than(Duration::from_millis(10)), 1);
+        assert_eq!(blockchain.orphan_count(), 0);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_2);
+        let orphan = generate_random_block(&generate_random_hash());
+        blockchain.add_to_orphan_buffer(Arc::new(orphan.clone()));
+
+        let path = std::env::temp_dir().join(format!("blockchain-test-{}.bin", rand::random::<u64>()));
+        blockchain.save(&path).unwrap();
+        let loaded = Blockchain::load(&path).unwrap();
+        assert_eq!(loaded.tip(), block_2.hash());
+        assert_eq!(loaded.block_count(), 5);
+        assert_eq!(loaded.orphan_count(), 1);
+        for block in &[&block_1, &block_2, &fork_1, &fork_2] {
+            assert_eq!(loaded.get_height(&block.hash()), blockchain.get_height(&block.hash()));
+        }
+        assert_eq!(loaded.all_blocks_in_longest_chain(), blockchain.all_blocks_in_longest_chain());
+
+        // a truncated file is an error, not a panic
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(Blockchain::load(&path).is_err());
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(Blockchain::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_keeps_the_tip_among_tied_branches_with_its_state() {
+        let blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let main_1 = transfer_block(&blockchain, &genesis_hash, 0, 1, 1000);
+        let fork_1 = transfer_block(&blockchain, &genesis_hash, 0, 2, 3000);
+        // either branch may be the tip, whatever order the saved blocks come in
+        for (first, second) in &[(&main_1, &fork_1), (&fork_1, &main_1)] {
+            let mut blockchain = Blockchain::new();
+            let mut tips = Vec::new();
+            for block_1 in &[first, second] {
+                blockchain.try_insert(block_1).unwrap();
+                let block_2 = transfer_block(&blockchain, &block_1.hash(), 0, 3, 10);
+                blockchain.try_insert(&block_2).unwrap();
+                tips.push(block_2.hash());
+            }
+            assert_eq!(blockchain.tip(), tips[0]);
+
+            let path = std::env::temp_dir().join(format!("blockchain-test-{}.bin", rand::random::<u64>()));
+            blockchain.save(&path).unwrap();
+            let mut loaded = Blockchain::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.tip(), tips[0]);
+            assert_eq!(loaded.all_blocks_in_longest_chain(), blockchain.all_blocks_in_longest_chain());
+            assert_eq!(loaded.state_hash(&loaded.tip()), blockchain.state_hash(&blockchain.tip()));
+            let next = transfer_block(&blockchain, &tips[0], 0, 1, 10);
+            assert_eq!(loaded.try_insert(&next), Ok(InsertOutcome::ExtendedTip));
+        }
+    }
+
+    #[test]
+    fn checkpoint_rejects_longer_attacker_branch() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        blockchain.add_checkpoint(1, block_1.hash());
+
+        // a private branch forking at genesis, released once it is longer
+        let mut attacker = Vec::new();
+        let mut parent = genesis_hash;
+        for _ in 0..3 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            attacker.push(block);
+        }
+        let mut inserted = Vec::new();
+        let rejected = blockchain.insert_recursively(Arc::new(attacker[0].clone()), &mut inserted).rejected;
+        assert_eq!(rejected, vec![(attacker[0].hash(), InsertError::CheckpointMismatch { height: 1, expected: block_1.hash() })]);
+        assert!(inserted.is_empty());
+        assert_eq!(blockchain.tip(), block_2.hash());
+
+        // blocks extending the checkpointed chain are still accepted
+        let block_3 = generate_random_block(&block_2.hash());
+        assert_eq!(blockchain.try_insert(&block_3), Ok(InsertOutcome::ExtendedTip));
+        assert_eq!(blockchain.tip(), block_3.hash());
+    }
+
+    #[test]
+    fn checkpoint_rejects_buffered_descendants() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        blockchain.add_checkpoint(1, block_1.hash());
+        let fork_1 = generate_random_block(&genesis_hash);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.add_to_orphan_buffer(Arc::new(fork_2.clone()));
+
+        let mut inserted = Vec::new();
+        let rejected = blockchain.insert_recursively(Arc::new(fork_1.clone()), &mut inserted).rejected;
+        assert_eq!(rejected.len(), 1);
+        assert!(!blockchain.contains_block(&fork_2.hash()));
+        assert_eq!(blockchain.orphan_count(), 0);
+    }
+
+    #[test]
+    fn deep_reorg_is_refused() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_max_reorg_depth(Some(1));
+        let genesis_hash = blockchain.tip();
+        let block_1 = valid_block(&blockchain, &genesis_hash);
+        blockchain.try_insert(&block_1).unwrap();
+        let block_2 = valid_block(&blockchain, &block_1.hash());
+        blockchain.try_insert(&block_2).unwrap();
+
+        // forking at block_1 detaches one block: allowed
+        let fork_2 = valid_block(&blockchain, &block_1.hash());
+        blockchain.try_insert(&fork_2).unwrap();
+        let fork_3 = valid_block(&blockchain, &fork_2.hash());
+        blockchain.try_insert(&fork_3).unwrap();
+        assert_eq!(blockchain.tip(), fork_3.hash());
+
+        // forking at genesis would detach three blocks: the side branch is kept until it would win
+        let mut parent = genesis_hash;
+        for _ in 0..3 {
+            let block = valid_block(&blockchain, &parent);
+            parent = block.hash();
+            blockchain.try_insert(&block).unwrap();
+        }
+        let block = valid_block(&blockchain, &parent);
+        assert_eq!(blockchain.try_insert(&block), Err(InsertError::ReorgTooDeep { depth: 3, limit: 1 }));
+        assert_eq!(blockchain.tip(), fork_3.hash());
+    }
+
+    /// A chain of blocks on top of genesis with the given timestamps, returning the last hash
+    fn chain_with_timestamps(blockchain: &mut Blockchain, timestamps: &[u128]) -> H256 {
+        let mut parent = blockchain.tip();
+        for &timestamp in timestamps {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = timestamp;
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        parent
+    }
+
+    #[test]
+    fn timestamp_too_far_in_future() {
+        let mut blockchain = Blockchain::new();
+        let now = 1_000_000;
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = now + DEFAULT_MAX_FUTURE_DRIFT.as_millis();
+        assert!(blockchain.timestamp_validity_check(&block, now));
+        block.header.timestamp += 1;
+        assert!(!blockchain.timestamp_validity_check(&block, now));
+        // also for orphans, whose ancestors are unknown
+        block.header.parent = generate_random_hash();
+        assert!(!blockchain.timestamp_validity_check(&block, now));
+        blockchain.set_max_future_drift(Duration::from_secs(3600));
+        assert!(blockchain.timestamp_validity_check(&block, now));
+    }
+
+    #[test]
+    fn timestamp_not_after_median() {
+        let mut blockchain = Blockchain::new();
+        // the last 11 blocks have timestamps 10..=20 out of order, so the median is 15
+        let timestamps = [1, 2, 20, 11, 19, 12, 18, 13, 17, 14, 16, 15, 10];
+        let tip = chain_with_timestamps(&mut blockchain, &timestamps);
+        let mut block = generate_random_block(&tip);
+        block.header.timestamp = 15;
+        assert!(!blockchain.timestamp_validity_check(&block, 100));
+        block.header.timestamp = 16;
+        assert!(blockchain.timestamp_validity_check(&block, 100));
+        assert_eq!(blockchain.min_timestamp_after(&tip), 16);
+    }
+
+    #[test]
+    fn median_time_past() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.median_time_past(&genesis_hash, MEDIAN_TIME_SPAN), Some(0));
+        assert_eq!(blockchain.median_time_past(&genesis_hash, 0), None);
+        assert_eq!(blockchain.median_time_past(&generate_random_hash(), MEDIAN_TIME_SPAN), None);
+
+        let tip = chain_with_timestamps(&mut blockchain, &[30, 10, 50, 20, 40]);
+        // genesis and all five blocks: 0, 10, 20, 30, 40, 50
+        assert_eq!(blockchain.median_time_past(&tip, MEDIAN_TIME_SPAN), Some(20));
+        // only the last three: 50, 20, 40
+        assert_eq!(blockchain.median_time_past(&tip, 3), Some(40));
+        assert_eq!(blockchain.median_time_past(&tip, 1), Some(40));
+    }
+
+    #[test]
+    fn timestamp_near_genesis() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        // only genesis (timestamp 0) to take the median over
+        let mut block = generate_random_block(&genesis_hash);
+        block.header.timestamp = 0;
+        assert!(!blockchain.timestamp_validity_check(&block, 100));
+        block.header.timestamp = 1;
+        assert!(blockchain.timestamp_validity_check(&block, 100));
+
+        // three ancestors: genesis, 50 and 40, so the median is 40
+        let tip = chain_with_timestamps(&mut blockchain, &[50, 40]);
+        let mut block = generate_random_block(&tip);
+        block.header.timestamp = 40;
+        assert!(!blockchain.timestamp_validity_check(&block, 100));
+        block.header.timestamp = 41;
+        assert!(blockchain.timestamp_validity_check(&block, 100));
+    }
+
+    #[test]
+    fn locator_is_exponentially_spaced() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.block_locator(), vec![genesis_hash]);
+        let mut parent = genesis_hash;
+        for _ in 0..20 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            blockchain.insert(&block);
+        }
+        let heights: Vec<u64> = blockchain.block_locator().iter()
+            .map(|hash| blockchain.get_height(hash).unwrap())
+            .collect();
+        assert_eq!(heights, vec![20, 19, 18, 16, 12, 4, 0]);
+    }
+
+    #[test]
+    fn locator_finds_fork_point() {
+        let mut ours = Blockchain::new();
+        let mut theirs = Blockchain::new();
+        let mut parent = ours.tip();
+        for _ in 0..300 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            ours.insert(&block);
+            theirs.insert(&block);
+        }
+        let fork_point = parent;
+        for (blockchain, length) in [(&mut ours, 200), (&mut theirs, 250)] {
+            let mut parent = fork_point;
+            for _ in 0..length {
+                let block = generate_random_block(&parent);
+                parent = block.hash();
+                blockchain.insert(&block);
+            }
+        }
+
+        // they send us their locator; the last entry before the fork is at height 550 - 256 = 294
+        let locator = theirs.block_locator();
+        assert!(locator.contains(&ours.get_block_by_height(294).unwrap().hash()));
+        let hashes = ours.blocks_after_locator(&locator, 1000);
+        assert_eq!(ours.get_height(&hashes[0]), Some(295));
+        assert!(hashes.contains(&ours.get_block_by_height(301).unwrap().hash()));
+        assert_eq!(*hashes.last().unwrap(), ours.tip());
+        // and the other way around, capped by the limit
+        let hashes = theirs.blocks_after_locator(&ours.block_locator(), 100);
+        assert_eq!(hashes.len(), 100);
+        assert!(theirs.get_height(&hashes[0]).unwrap() <= 301);
+    }
+
+    #[test]
+    fn dot_of_forked_tree() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+
+        let dot = blockchain.to_dot();
+        assert!(dot.starts_with("digraph blockchain {"));
+        for (child, parent) in &[(&block_1, genesis_hash), (&block_2, block_1.hash()), (&fork_1, genesis_hash)] {
+            assert!(dot.contains(&format!("\"{}\" -> \"{}\";", child.hash(), parent)));
+        }
+        assert_eq!(dot.matches(" -> ").count(), 3);
+        assert_eq!(dot.matches("doubleoctagon").count(), 1);
+        let tip_line = dot.lines().find(|line| line.contains("doubleoctagon")).unwrap();
+        assert!(tip_line.contains(&block_2.hash().to_string()));
+        assert_eq!(dot.matches("lightgray").count(), 1);
+    }
+
+    #[test]
+    fn genesis_from_config() {
+        let config: GenesisConfig = serde_json::from_str(
+            r#"{"difficulty": "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", "timestamp": 1600000000000}"#
+        ).unwrap();
+        let genesis_hash = Blockchain::from_genesis_config(&config).genesis_hash();
+        assert_eq!(Blockchain::from_genesis_config(&config.clone()).genesis_hash(), genesis_hash);
+        assert_eq!(Blockchain::new_with_genesis(config.difficulty, 1600000000000).genesis_hash(), genesis_hash);
+
+        let easier = GenesisConfig { difficulty: [0xff; 32].into(), ..config.clone() };
+        let blockchain = Blockchain::from_genesis_config(&easier);
+        assert_ne!(blockchain.genesis_hash(), genesis_hash);
+        assert_eq!(blockchain.difficulty(), [0xff; 32].into());
+        assert_eq!(Blockchain::new().genesis_hash(), Block::genesis().hash());
+
+        let bad: Result<GenesisConfig, _> = serde_json::from_str(r#"{"difficulty": "00ff", "timestamp": 0}"#);
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn ico_from_config() {
+        let merchant = ico_address(20);
+        let config: GenesisConfig = serde_json::from_str(&format!(
+            r#"{{"difficulty": "{}", "timestamp": 0, "ico": {{"accounts": [
+                {{"address": "{}", "balance": 500}},
+                {{"key_index": 3, "balance": 70}},
+                {{"key_index": 4, "balance": 0}}
+            ]}}}}"#,
+            H256::from(default_difficulty()), merchant
+        )).unwrap();
+        let blockchain = Blockchain::from_genesis_config(&config);
+        let state = blockchain.state_at(&blockchain.genesis_hash()).unwrap();
+        assert_eq!(state.get(&merchant), Some(&(0, 500)));
+        assert_eq!(state.get(&ico_address(3)), Some(&(0, 70)));
+        assert_eq!(state.get(&ico_address(4)), Some(&(0, 0)));
+        assert_eq!(state.get(&ico_address(0)), None);
+        // the same config, the same state
+        let again = Blockchain::from_genesis_config(&config.clone());
+        assert_eq!(again.state_at(&again.genesis_hash()), Some(state.clone()));
+
+        // without an ICO section, the ten deterministic accounts are funded as before
+        let config: GenesisConfig = serde_json::from_str(&format!(
+            r#"{{"difficulty": "{}", "timestamp": 0}}"#, H256::from(default_difficulty())
+        )).unwrap();
+        let blockchain = Blockchain::from_genesis_config(&config);
+        assert_eq!(blockchain.state_at(&blockchain.genesis_hash()), Some(State::ico()));
+        assert_eq!(State::ico().get(&ico_address(9)), Some(&(0, 1000)));
+    }
+
+    #[test]
+    fn genesis_config_allocating_more_than_a_u64_is_refused() {
+        let path = std::env::temp_dir().join(format!("genesis-test-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, format!(
+            r#"{{"difficulty": "{}", "timestamp": 0, "ico": {{"accounts": [
+                {{"key_index": 0, "balance": {}}},
+                {{"key_index": 1, "balance": 1}}
+            ]}}}}"#,
+            H256::from(default_difficulty()), u64::MAX
+        )).unwrap();
+        let result = GenesisConfig::from_json_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().contains("allocates more than"));
+    }
+
+    #[test]
+    fn save_and_load_keep_custom_genesis() {
+        let mut blockchain = Blockchain::new_with_genesis([0xff; 32].into(), 42);
+        let block = generate_random_block(&blockchain.genesis_hash());
+        blockchain.insert(&block);
+        let path = std::env::temp_dir().join(format!("blockchain-test-{}.bin", rand::random::<u64>()));
+        blockchain.save(&path).unwrap();
+        let loaded = Blockchain::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.genesis_hash(), blockchain.genesis_hash());
+        assert_eq!(loaded.state_at(&loaded.genesis_hash()), blockchain.state_at(&blockchain.genesis_hash()));
+        assert_eq!(loaded.tip(), block.hash());
+    }
+
+    #[test]
+    fn children_and_leaves() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_2);
+
+        assert_eq!(blockchain.children(&genesis_hash), &[block_1.hash(), fork_1.hash()]);
+        assert_eq!(blockchain.children(&block_1.hash()), &[block_2.hash()]);
+        assert!(blockchain.children(&block_2.hash()).is_empty());
+        assert!(blockchain.children(&generate_random_hash()).is_empty());
+        assert_eq!(blockchain.subtree_size(&genesis_hash), 5);
+        assert_eq!(blockchain.subtree_size(&fork_1.hash()), 2);
+        assert_eq!(blockchain.subtree_size(&generate_random_hash()), 0);
+
+        let mut leaves = blockchain.leaves();
+        leaves.sort();
+        let mut expected = vec![block_2.hash(), fork_2.hash()];
+        expected.sort();
+        assert_eq!(leaves, expected);
+    }
+
+    #[test]
+    fn tip_notifications() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tip_updates = blockchain.subscribe_tip();
+        let dropped = blockchain.subscribe_tip();
+        drop(dropped);
+
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        assert_eq!(tip_updates.try_recv(), Ok(block_1.hash()));
+        assert_eq!(blockchain.tip_subscribers.len(), 1);
+
+        // a fork that is not longer leaves the tip alone
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        assert!(tip_updates.try_recv().is_err());
+
+        // one that is longer moves it
+        let fork_2 = generate_random_block(&fork_1.hash());
+        blockchain.insert(&fork_2);
+        assert_eq!(tip_updates.try_recv(), Ok(fork_2.hash()));
+
+        // a slow subscriber never blocks insertion, it just misses intermediate tips
+        let mut parent = fork_2.hash();
+        for _ in 0..3 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            blockchain.insert(&block);
+        }
+        assert_eq!(tip_updates.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn delay_stats_and_interblock_time() {
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.block_delay_stats().count, 0);
+        assert_eq!(blockchain.mean_interblock_time_ms(), None);
+
+        let from: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut parent = blockchain.tip();
+        for (i, delay_ms) in [40, 10, 30, 20].iter().enumerate() {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = 1000 + 600 * i as u128;
+            parent = block.hash();
+            blockchain.insert(&block);
+            blockchain.hash_to_origin.insert(parent, BlockOrigin::Received { delay_ms: *delay_ms, from });
+        }
+        let stats = blockchain.block_delay_stats();
+        assert_eq!((stats.count, stats.median, stats.min, stats.max), (4, 20, 10, 40));
+        assert_eq!(stats.mean, 25.0);
+        assert_eq!(blockchain.mean_interblock_time_ms(), Some(600.0));
+    }
+
+    #[test]
+    fn long_orphan_chain_resolves_iteratively() {
+        let mut blockchain = Blockchain::with_orphan_limit(20_000);
+        let genesis_hash = blockchain.tip();
+        // each block commits to the state after it, applied along the way
+        let child = |state: &mut State, parent: &H256, height: u64| {
+            let mut block = block_with_transactions(parent, height, vec![]);
+            state.apply_presigned_block_in_place(&block).unwrap();
+            block.header.state_root = state.hash();
+            block
+        };
+        let mut state = blockchain.state_at(&genesis_hash).unwrap();
+        let ancestor = child(&mut state, &genesis_hash, 1);
+        let mut parent = ancestor.hash();
+        let mut orphans = Vec::new();
+        let mut first_orphan_state = None;
+        for height in 2..10_002 {
+            let block = child(&mut state, &parent, height);
+            parent = block.hash();
+            orphans.push(block);
+            first_orphan_state.get_or_insert_with(|| state.clone());
+        }
+        // a sibling branch off the first orphan, buffered after its sibling
+        let sibling = child(&mut first_orphan_state.unwrap(), &orphans[0].hash(), 3);
+        for block in orphans.iter().rev() {
+            blockchain.add_to_orphan_buffer(Arc::new(block.clone()));
+        }
+        blockchain.add_to_orphan_buffer(Arc::new(sibling.clone()));
+
+        let mut inserted = Vec::new();
+        let resolution = blockchain.insert_recursively(Arc::new(ancestor.clone()), &mut inserted);
+        assert_eq!(resolution.orphans_resolved, 10_001);
+        assert!(resolution.rejected.is_empty());
+        assert_eq!(blockchain.tip(), parent);
+        assert_eq!(blockchain.tip_height(), 10_001);
+        assert_eq!(blockchain.orphan_count(), 0);
+        // every block comes before its children, and the whole main branch before the sibling
+        assert_eq!(inserted[0], ancestor.hash());
+        assert_eq!(inserted[1], orphans[0].hash());
+        assert_eq!(inserted[10_000], parent);
+        assert_eq!(inserted[10_001], sibling.hash());
+
+        // inserting it again does nothing
+        let resolution = blockchain.insert_recursively(Arc::new(ancestor.clone()), &mut inserted);
+        assert_eq!(resolution.orphans_resolved, 0);
+        assert_eq!(inserted.len(), 10_002);
+    }
+
+    #[test]
+    fn prune_bodies_and_stale_branches() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut chain = vec![genesis_hash];
+        for _ in 0..10 {
+            let block = generate_random_block(chain.last().unwrap());
+            chain.push(block.hash());
+            blockchain.insert(&block);
+        }
+        // a stale branch ending below the pruning height, and one growing past it
+        let short_fork = generate_random_block(&chain[2]);
+        blockchain.insert(&short_fork);
+        let mut long_fork = vec![chain[3]];
+        for _ in 0..4 {
+            let block = generate_random_block(long_fork.last().unwrap());
+            long_fork.push(block.hash());
+            blockchain.insert(&block);
+        }
+
+        // bodies at heights 1 to 5 and the short fork
+        assert_eq!(blockchain.prune_below(6), 6);
+        assert!(!blockchain.contains_block(&short_fork.hash()));
+        assert_eq!(blockchain.children(&chain[2]), &[chain[3]]);
+        assert!(long_fork.iter().all(|hash| blockchain.contains_block(hash)));
+        assert!(blockchain.is_pruned(&chain[5]));
+        assert!(blockchain.get_block(&chain[5]).is_none());
+        assert_eq!(blockchain.get_header(&chain[5]).unwrap().parent, chain[4]);
+        assert!(blockchain.get_block(&chain[6]).is_some());
+        assert!(blockchain.get_block(&genesis_hash).is_some());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), chain);
+        assert_eq!(blockchain.prune_below(6), 0);
+
+        // the chain is still extended and saved normally
+        let block = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip_height(), 11);
+        let path = std::env::temp_dir().join(format!("blockchain-test-{}.bin", rand::random::<u64>()));
+        blockchain.save(&path).unwrap();
+        let loaded = Blockchain::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.tip(), block.hash());
+        assert!(loaded.is_pruned(&chain[5]));
+    }
+
+    #[test]
+    fn pruned_chain_keeps_its_state_across_save_and_load() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..6 {
+            let block = transfer_block(&blockchain, &blockchain.tip(), 0, 1, 100);
+            blockchain.try_insert(&block).unwrap();
+        }
+        blockchain.prune_below(4);
+        let path = std::env::temp_dir().join(format!("blockchain-test-{}.bin", rand::random::<u64>()));
+        blockchain.save(&path).unwrap();
+        let mut loaded = Blockchain::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_pruned(&loaded.all_blocks_in_longest_chain()[3]));
+        assert_eq!(loaded.state_hash(&loaded.tip()), blockchain.state_hash(&blockchain.tip()));
+
+        // the next block, committing to the state after it, is accepted
+        let next = transfer_block(&blockchain, &blockchain.tip(), 0, 1, 100);
+        assert_eq!(loaded.try_insert(&next), Ok(InsertOutcome::ExtendedTip));
+        blockchain.try_insert(&next).unwrap();
+        assert_eq!(loaded.state_hash(&next.hash()), blockchain.state_hash(&next.hash()));
+    }
+
+    fn audited_chain() -> (Blockchain, Vec<Block>) {
+        let mut blockchain = Blockchain::new();
+        let mut blocks = vec![];
+        let mut parent = blockchain.tip();
+        for _ in 0..3 {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        assert_eq!(blockchain.audit(), Ok(()));
+        (blockchain, blocks)
+    }
+
+    #[test]
+    fn audit_reports_missing_parent() {
+        let (mut blockchain, blocks) = audited_chain();
+        blockchain.hash_to_height.remove(&blocks[1].hash());
+        let errors = blockchain.audit().unwrap_err();
+        assert!(errors.contains(&AuditError::MissingParent { hash: blocks[2].hash(), parent: blocks[1].hash() }));
+    }
+
+    #[test]
+    fn audit_reports_wrong_height() {
+        let (mut blockchain, blocks) = audited_chain();
+        blockchain.hash_to_height.insert(blocks[1].hash(), 5);
+        let errors = blockchain.audit().unwrap_err();
+        assert!(errors.contains(&AuditError::WrongHeight { hash: blocks[1].hash(), height: 5, expected: 2 }));
+    }
+
+    #[test]
+    fn audit_reports_tip_not_highest() {
+        let (mut blockchain, blocks) = audited_chain();
+        blockchain.tip = blocks[0].hash();
+        assert_eq!(blockchain.audit(), Err(vec![AuditError::TipNotHighest { tip_height: 1, max_height: 3 }]));
+    }
+
+    #[test]
+    fn audit_reports_hash_mismatch() {
+        let (mut blockchain, blocks) = audited_chain();
+        let entry = blockchain.hash_to_block.remove(&blocks[2].hash()).unwrap();
+        let key = generate_random_hash();
+        blockchain.hash_to_block.insert(key, entry);
+        let errors = blockchain.audit().unwrap_err();
+        assert!(errors.contains(&AuditError::HashMismatch { key, hash: blocks[2].hash() }));
+    }
+
+    #[test]
+    fn audit_reports_orphan_with_known_parent() {
+        let (mut blockchain, blocks) = audited_chain();
+        let orphan = generate_random_block(&blocks[2].hash());
+        // skipping insert_recursively, which would have resolved it
+        blockchain.add_to_orphan_buffer(Arc::new(orphan.clone()));
+        assert_eq!(
+            blockchain.audit(),
+            Err(vec![AuditError::OrphanWithKnownParent { hash: orphan.hash(), parent: blocks[2].hash() }])
+        );
+    }
+
+    #[test]
+    fn confirmed_transaction_stats() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key = key_pair::random();
+        let transaction = |nonce| SignedTransaction::from_raw(RawTransaction { nonce, ..Default::default() }, &key, &ChainId::default());
+        let block_with = |parent: &H256, timestamp: u128, transactions: Vec<SignedTransaction>| {
+            let mut block = generate_random_block(parent);
+            block.header.timestamp = timestamp;
+            block.content.transactions = transactions;
+            block
+        };
+
+        // a stale block holding a transaction that the longest chain confirms too
+        let stale = block_with(&genesis_hash, 1_000, vec![transaction(1), transaction(9)]);
+        let block_1 = block_with(&genesis_hash, 1_000, vec![transaction(1), transaction(2)]);
+        let block_2 = block_with(&block_1.hash(), 2_000, vec![SignedTransaction::coinbase(H160::default(), 0, 2)]);
+        let block_3 = block_with(&block_2.hash(), 3_000, vec![transaction(3), transaction(4), transaction(5)]);
+        let block_4 = block_with(&block_3.hash(), 4_000, vec![transaction(6), transaction(2)]);
+        blockchain.insert(&stale);
+        for block in &[&block_1, &block_2, &block_3, &block_4] {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), block_4.hash());
+
+        // transactions 1 to 6: the stale one, the repeated one and the coinbase don't count
+        assert_eq!(blockchain.canonical_transaction_count(), 6);
+        // transaction 2 is credited to block 4, where it was last confirmed
+        let expected: HashMap<usize, usize> = [(1, 1), (0, 1), (3, 1), (2, 1)].iter().cloned().collect();
+        assert_eq!(blockchain.transactions_per_block_histogram(), expected);
+        // blocks 3 and 4 are within 2 seconds of the tip, block 2 is just outside
+        assert_eq!(blockchain.confirmed_tps(2), 2.5);
+        assert_eq!(blockchain.confirmed_tps(10), 0.6);
+        assert_eq!(blockchain.confirmed_tps(0), 0.0);
+    }
+
+    #[test]
+    fn insert_outcomes() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        assert_eq!(blockchain.insert(&block_1), InsertOutcome::ExtendedTip);
+        let block_2 = generate_random_block(&block_1.hash());
+        assert_eq!(blockchain.insert(&block_2), InsertOutcome::ExtendedTip);
+        assert_eq!(blockchain.insert(&block_2), InsertOutcome::Duplicate);
+        assert_eq!(blockchain.block_count(), 3);
+
+        // a fork from genesis catches up without overtaking, then does
+        let fork_1 = generate_random_block(&genesis_hash);
+        assert_eq!(blockchain.insert(&fork_1), InsertOutcome::SideChain);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        assert_eq!(blockchain.insert(&fork_2), InsertOutcome::SideChain);
+        let fork_3 = generate_random_block(&fork_2.hash());
+        assert_eq!(blockchain.insert(&fork_3), InsertOutcome::Reorged { depth: 2 });
+        assert_eq!(blockchain.tip(), fork_3.hash());
+
+        // a duplicate of a stale block changes nothing either
+        assert_eq!(blockchain.insert(&block_1), InsertOutcome::Duplicate);
+        assert_eq!(blockchain.children(&genesis_hash).len(), 2);
+    }
+
+    #[test]
+    fn insert_recursively_reports_outcomes() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let mut scratch = Blockchain::new();
+        let fork_1 = valid_block(&scratch, &genesis_hash);
+        scratch.insert(&fork_1);
+        let fork_2 = valid_block(&scratch, &fork_1.hash());
+        blockchain.add_to_orphan_buffer(Arc::new(fork_2.clone()));
+        let resolution = blockchain.insert_recursively(Arc::new(fork_1.clone()), &mut vec![]);
+        assert_eq!(resolution.outcomes, vec![
+            (fork_1.hash(), InsertOutcome::SideChain),
+            (fork_2.hash(), InsertOutcome::Reorged { depth: 1 }),
+        ]);
+    }
+
+    #[test]
+    fn blocks_since() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        let block_2 = generate_random_block(&block_1.hash());
+        let block_3 = generate_random_block(&block_2.hash());
+        for block in &[&block_1, &block_2, &block_3] {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.blocks_since(&genesis_hash, 10), Some(vec![block_1.hash(), block_2.hash(), block_3.hash()]));
+        assert_eq!(blockchain.blocks_since(&block_1.hash(), 1), Some(vec![block_2.hash()]));
+        assert_eq!(blockchain.blocks_since(&block_3.hash(), 10), Some(vec![]));
+        assert_eq!(blockchain.blocks_since(&generate_random_hash(), 10), None);
+
+        // block 2 gets reorged off the longest chain
+        let fork_2 = generate_random_block(&block_1.hash());
+        let fork_3 = generate_random_block(&fork_2.hash());
+        let fork_4 = generate_random_block(&fork_3.hash());
+        for block in &[&fork_2, &fork_3, &fork_4] {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.blocks_since(&block_2.hash(), 10), Some(vec![]));
+        assert_eq!(blockchain.blocks_since(&block_1.hash(), 10), Some(vec![fork_2.hash(), fork_3.hash(), fork_4.hash()]));
+    }
+
+    /// A clock that only moves when told to
+    struct ManualClock(Arc<AtomicU64>);
+
+    impl Clock for ManualClock {
+        fn now_ms(&self) -> u128 {
+            self.0.load(Ordering::SeqCst) as u128
+        }
+    }
+
+    #[test]
+    fn tip_durations() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let time = Arc::new(AtomicU64::new(1_000));
+        blockchain.set_clock(Box::new(ManualClock(Arc::clone(&time))));
+        assert_eq!(blockchain.tip_duration_stats(), (0.0, 0, 0));
+
+        let block_1 = generate_random_block(&genesis_hash);
+        time.store(1_400, Ordering::SeqCst);
+        blockchain.insert(&block_1);
+        let fork_1 = generate_random_block(&genesis_hash);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        time.store(1_500, Ordering::SeqCst);
+        blockchain.insert(&fork_1);
+        time.store(2_000, Ordering::SeqCst);
+        blockchain.insert(&fork_2);
+        let block_3 = generate_random_block(&fork_2.hash());
+        time.store(2_100, Ordering::SeqCst);
+        blockchain.insert(&block_3);
+
+        assert_eq!(blockchain.tip_durations(), &[
+            TipDuration { hash: genesis_hash, duration_ms: 400, displaced: false },
+            TipDuration { hash: block_1.hash(), duration_ms: 600, displaced: true },
+            TipDuration { hash: fork_2.hash(), duration_ms: 100, displaced: false },
+        ][..]);
+        assert_eq!(blockchain.tip_duration_stats(), (1100.0 / 3.0, 100, 600));
+    }
+
+    #[test]
+    fn reorg_alarm() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let (sender, receiver) = channel::unbounded();
+        blockchain.set_reorg_alarm(3, sender);
+
+        let branch = |blockchain: &mut Blockchain, parent: &H256, count: usize| {
+            let mut parent = *parent;
+            let mut hashes = vec![];
+            for _ in 0..count {
+                let block = generate_random_block(&parent);
+                parent = block.hash();
+                hashes.push(parent);
+                blockchain.insert(&block);
+            }
+            hashes
+        };
+        let main = branch(&mut blockchain, &genesis_hash, 3);
+        // a reorg of depth 1 stays below the threshold
+        let short_fork = branch(&mut blockchain, &main[1], 2);
+        assert_eq!(blockchain.tip(), short_fork[1]);
+        assert!(receiver.try_recv().is_err());
+
+        // a reorg of depth 3, taking over everything above the first block
+        let long_fork = branch(&mut blockchain, &main[0], 4);
+        assert_eq!(blockchain.tip(), long_fork[3]);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.common_ancestor, main[0]);
+        assert_eq!(event.detached, vec![main[1], short_fork[0], short_fork[1]]);
+        assert_eq!(event.attached, long_fork);
+        assert!(receiver.try_recv().is_err());
+    }
+}
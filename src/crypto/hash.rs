@@ -87,6 +87,21 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    /// Parse a hash from 64 hex digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| format!("invalid hash {}: {}", s, e))?;
+        if bytes.len() != 32 {
+            return Err(format!("invalid hash {}: expected 32 bytes", s));
+        }
+        let mut buffer: [u8; 32] = [0; 32];
+        buffer.copy_from_slice(&bytes);
+        Ok(buffer.into())
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -107,7 +122,7 @@ impl PartialOrd for H256 {
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub mod tests {
     use super::H256;
     use rand::Rng;
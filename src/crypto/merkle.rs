@@ -115,6 +115,65 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
     *root == curr_hash
 }
 
+/// Computes Merkle roots (identical to `MerkleTree::new(data).root()`) for a list of items
+/// that changes little between calls, e.g. the miner's block template. The hashes of every
+/// level are kept, and only the nodes above changed leaves are recomputed.
+#[derive(Debug, Default)]
+pub struct MerkleBuilder {
+    /// `levels[0]` holds the leaf hashes, each next level their parents, the last one the root
+    levels: Vec<Vec<H256>>,
+}
+
+impl MerkleBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn root<T>(&mut self, data: &[T]) -> H256 where T: Hashable, {
+        assert!(!data.is_empty());
+        let leaves: Vec<H256> = data.iter().map(|item| item.hash()).collect();
+        let old_leaves = self.levels.first().map_or(&[][..], |level| &level[..]);
+        let mut dirty: Vec<usize> = (0..leaves.len())
+            .filter(|&i| old_leaves.get(i) != Some(&leaves[i]))
+            .collect();
+        if dirty.len() > leaves.len() / 4 + 1 {
+            // too much changed for the cached hashes to help
+            self.levels.clear();
+        }
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let depth = levels.len() - 1;
+            let level = &levels[depth];
+            let old_level_len = self.levels.get(depth).map_or(0, |old| old.len());
+            let old_parents = self.levels.get(depth + 1).map_or(&[][..], |old| &old[..]);
+            let parent_count = level.len().div_ceil(2);
+
+            let mut dirty_parents: Vec<usize> = dirty.iter().map(|i| i / 2).collect();
+            if level.len() != old_level_len {
+                // the last pair may have gained or lost its duplicated node
+                dirty_parents.push(parent_count - 1);
+            }
+            dirty_parents.sort_unstable();
+            dirty_parents.dedup();
+
+            let parents: Vec<H256> = (0..parent_count).map(|j| {
+                match old_parents.get(j) {
+                    Some(old) if dirty_parents.binary_search(&j).is_err() => *old,
+                    _ => {
+                        let left = &level[2 * j];
+                        hash_children(left, level.get(2 * j + 1).unwrap_or(left))
+                    }
+                }
+            }).collect();
+            levels.push(parents);
+            dirty = dirty_parents;
+        }
+        self.levels = levels;
+        self.levels.last().unwrap()[0]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::crypto::hash::H256;
@@ -201,4 +260,35 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn builder_matches_tree_over_random_edits() {
+        use crate::crypto::hash::tests::generate_random_hash;
+        use rand::Rng;
+
+        let mut rng = rand::thread_rng();
+        let mut builder = MerkleBuilder::new();
+        let mut data: Vec<H256> = vec![generate_random_hash()];
+        for _ in 0..500 {
+            match rng.gen_range(0, 5) {
+                0 => data.push(generate_random_hash()),
+                1 => {
+                    let i = rng.gen_range(0, data.len());
+                    data[i] = generate_random_hash();
+                }
+                2 if data.len() > 1 => {
+                    data.pop();
+                }
+                3 => {
+                    let count = rng.gen_range(1, 40);
+                    data.extend((0..count).map(|_| generate_random_hash()));
+                }
+                _ => {
+                    let i = rng.gen_range(0, data.len());
+                    data.insert(i, generate_random_hash());
+                }
+            }
+            assert_eq!(builder.root(&data), MerkleTree::new(&data).root());
+        }
+    }
 }
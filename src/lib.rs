@@ -0,0 +1,21 @@
+#[cfg(test)]
+#[macro_use]
+extern crate hex_literal;
+
+pub mod api;
+pub mod block;
+pub mod blockchain;
+pub mod crypto;
+pub mod miner;
+pub mod network;
+pub mod transaction;
+pub mod address;
+#[cfg(feature = "adversary")]
+pub mod adversary;
+pub mod mempool;
+pub mod report;
+pub mod sig_cache;
+pub mod snapshot;
+pub mod transaction_generator;
+pub mod validation;
+pub mod wallet;
@@ -1,31 +1,21 @@
-#[cfg(test)]
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod block;
-pub mod blockchain;
-pub mod crypto;
-pub mod miner;
-pub mod network;
-pub mod transaction;
-pub mod address;
-pub mod mempool;
-pub mod transaction_generator;
-
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
-use api::Server as ApiServer;
-use mempool::Mempool;
-use network::{server, worker};
+use log::{error, info, warn};
+#[cfg(feature = "adversary")]
+use bitcoin::adversary;
+use bitcoin::api::Server as ApiServer;
+use bitcoin::mempool::Mempool;
+use bitcoin::wallet::WalletManager;
+use bitcoin::network::peer_manager::RateLimits;
+use bitcoin::network::{server, worker};
+use bitcoin::{miner, snapshot, transaction_generator};
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
 
 use std::sync::{Arc, Mutex};
-use crate::blockchain::Blockchain;
+use bitcoin::blockchain::{Blockchain, GenesisConfig};
 
 fn main() {
     // parse command line arguments
@@ -37,6 +27,28 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg max_frame_size: --("max-frame-size") [BYTES] "Sets the largest message a peer may send before it is disconnected, 4 MiB by default and at most 16 MiB")
+     (@arg no_compression: --("no-compression") "Neither sends nor reads compressed messages, which are otherwise used with peers supporting them for messages over 4 KiB")
+     (@arg p2p_queue: --("p2p-queue") [INT] "Sets how many received messages may wait for the workers before announcements are dropped and peers are read no further, 4096 by default")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the blockchain is saved and reloaded from")
+     (@arg genesis: --genesis [FILE] "Reads the genesis block parameters from this JSON file")
+     (@arg report: --report [FILE] "Writes the experiment report as JSON to this file when the miner exits")
+     (@arg keystore: --keystore [DIR] "Sets the directory holding this node's wallet keys")
+     (@arg max_reorg_depth: --("max-reorg-depth") [INT] "Refuses reorgs that detach more than this many blocks")
+     (@arg reward_address: --("reward-address") [ADDRESS] "Sets the address the coinbases of mined blocks pay, in hex; by default, rewards go to the zero address nobody owns")
+     (@arg min_fee: --("min-fee") [INT] "Sets the lowest fee a transaction from a peer must pay to enter the mempool")
+     (@arg rbf_increment: --("rbf-increment") [INT] "Sets by how much a transaction's fee must exceed the pending one with the same sender and nonce to replace it, 1 by default")
+     (@arg ban_duration: --("ban-duration") [SECS] "Sets for how many seconds a misbehaving peer stays banned, an hour by default")
+     (@arg peer_message_rate: --("peer-message-rate") [INT] "Sets how many messages per second a peer may send before the excess is dropped or held back, 500 by default")
+     (@arg peer_byte_rate: --("peer-byte-rate") [BYTES] "Sets how many bytes per second a peer may send before the excess is dropped or held back, 8 MiB by default")
+     (@arg outbound_peers: --("outbound-peers") [INT] "Sets how many peers to keep connected to from the addresses peers gossip, 8 by default")
+     (@arg tip_check_interval: --("tip-check-interval") [SECS] "Sets how often to ask peers for their tips, to catch up on blocks whose announcements were missed; 30 by default, 0 to never")
+    (@arg local_addrs: --("gossip-local-addrs") "Gossips loopback and private addresses too, for experiments on one host")
+     (@arg max_block_size: --("max-block-size") [BYTES] "Sets the most bytes of transactions in a mined block, 65536 by default and at most")
+     (@arg reorg_alarm: --("reorg-alarm") [INT] "Warns about every reorg detaching at least this many blocks")
+     (@arg adversary: --adversary [STRATEGY] "Runs an adversarial miner (withhold:<k> or selfish); needs the `adversary` feature")
+     (@arg link_conditions: --("link-conditions") [CONDITIONS] "Delays and drops what is sent to every peer, as <delay_ms>:<jitter_ms>:<loss_rate>; needs the `emulation` feature")
+     (@arg emulation_seed: --("emulation-seed") [INT] "Seeds the delays and drops of --link-conditions, 0 by default")
     )
     .get_matches();
 
@@ -64,18 +76,180 @@ fn main() {
             process::exit(1);
         });
 
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::unbounded();
+    // create channels between server and worker, bounded so that a flood cannot exhaust memory
+    let queue_capacity = match matches.value_of("p2p_queue") {
+        Some(capacity) => capacity.parse::<usize>().map_err(|e| e.to_string())
+            .and_then(|capacity| if capacity == 0 { Err("must be positive".to_string()) } else { Ok(capacity) })
+            .unwrap_or_else(|e| {
+                error!("Error parsing P2P queue capacity: {}", e);
+                process::exit(1);
+            }),
+        None => server::DEFAULT_MESSAGE_QUEUE_CAPACITY,
+    };
+    let (msg_tx, msg_rx) = channel::bounded(queue_capacity);
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    // create the p2p server, started once the blockchain is loaded
+    let (mut server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    if let Some(duration) = matches.value_of("ban_duration") {
+        let duration = duration.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+        server.set_ban_duration(time::Duration::from_secs(duration));
+    }
+    let mut rate_limits = RateLimits::default();
+    if let Some(rate) = matches.value_of("peer_message_rate") {
+        rate_limits.messages_per_sec = rate.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing peer message rate: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(rate) = matches.value_of("peer_byte_rate") {
+        rate_limits.bytes_per_sec = rate.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing peer byte rate: {}", e);
+            process::exit(1);
+        });
+    }
+    server.set_rate_limits(rate_limits);
+    if let Some(size) = matches.value_of("max_frame_size") {
+        let size = size.parse::<usize>().map_err(|e| e.to_string())
+            .and_then(|size| server_ctx.set_max_frame_size(size));
+        if let Err(e) = size {
+            error!("Error setting max frame size: {}", e);
+            process::exit(1);
+        }
+    }
+
+    server_ctx.set_compression(!matches.is_present("no_compression"));
 
     // create the Blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let genesis_config = match matches.value_of("genesis") {
+        Some(path) => GenesisConfig::from_json_file(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading genesis config: {}", e);
+            process::exit(1);
+        }),
+        None => GenesisConfig::default(),
+    };
+    let mut blockchain = match matches.value_of("data_dir") {
+        Some(dir) => {
+            let dir = std::path::Path::new(dir);
+            std::fs::create_dir_all(dir).unwrap_or_else(|e| {
+                error!("Error creating data directory {}: {}", dir.display(), e);
+                process::exit(1);
+            });
+            let path = dir.join("blockchain.bin");
+            let mut blockchain = if path.exists() {
+                Blockchain::load(&path).unwrap_or_else(|e| {
+                    error!("Error loading blockchain from {}: {}", path.display(), e);
+                    process::exit(1);
+                })
+            } else {
+                Blockchain::from_genesis_config(&genesis_config)
+            };
+            let expected = Blockchain::from_genesis_config(&genesis_config);
+            let expected_genesis = expected.genesis_hash();
+            if blockchain.genesis_hash() != expected_genesis
+                || blockchain.state_at(&expected_genesis) != expected.state_at(&expected_genesis) {
+                error!("Blockchain in {} was created from a different genesis config", dir.display());
+                process::exit(1);
+            }
+            info!("Blockchain has {} blocks, tip at height {}", blockchain.block_count(), blockchain.tip_height());
+            blockchain.set_checkpoint_path(&path);
+            blockchain
+        }
+        None => Blockchain::from_genesis_config(&genesis_config),
+    };
+    if let Some(depth) = matches.value_of("max_reorg_depth") {
+        let depth = depth.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing max reorg depth: {}", e);
+            process::exit(1);
+        });
+        blockchain.set_max_reorg_depth(Some(depth));
+    }
+    if let Some(min_fee) = matches.value_of("min_fee") {
+        let min_fee = min_fee.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing min fee: {}", e);
+            process::exit(1);
+        });
+        blockchain.set_min_fee(min_fee);
+    }
+    if let Some(depth) = matches.value_of("reorg_alarm") {
+        let depth = depth.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing reorg alarm depth: {}", e);
+            process::exit(1);
+        });
+        let (alarm_tx, alarm_rx) = channel::unbounded();
+        blockchain.set_reorg_alarm(depth, alarm_tx);
+        thread::spawn(move || {
+            for event in alarm_rx {
+                warn!("!!! Reorg of depth {} at {} ms: detached {:?}, attached {:?}, common ancestor {}",
+                    event.detached.len(), event.timestamp_ms, event.detached, event.attached, event.common_ancestor);
+            }
+        });
+    }
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
+    // start the p2p server, greeting each new peer with our tip
+    {
+        let blockchain = Arc::clone(&blockchain);
+        let server = server.clone();
+        server_ctx.set_on_connect(move |peer| worker::send_version(peer, &blockchain.lock().unwrap(), &server));
+    }
+    server_ctx.start().unwrap();
+
+    // set up the adversarial miner, for experiments only
+    if let Some(strategy) = matches.value_of("adversary") {
+        #[cfg(feature = "adversary")]
+        {
+            let strategy = strategy.parse::<adversary::Strategy>().unwrap_or_else(|e| {
+                error!("Error parsing adversary strategy: {}", e);
+                process::exit(1);
+            });
+            info!("Running as an adversarial miner with strategy {:?}", strategy);
+            blockchain.lock().unwrap().adversary = Some(adversary::Adversary::new(strategy));
+        }
+        #[cfg(not(feature = "adversary"))]
+        {
+            error!("Adversary strategy {} requires building with the `adversary` feature", strategy);
+            process::exit(1);
+        }
+    }
+
+    // emulate slow and lossy links, for propagation experiments only
+    if let Some(conditions) = matches.value_of("link_conditions") {
+        #[cfg(feature = "emulation")]
+        {
+            let conditions = conditions.parse::<bitcoin::network::emulation::LinkConditions>().unwrap_or_else(|e| {
+                error!("Error parsing link conditions: {}", e);
+                process::exit(1);
+            });
+            if let Some(seed) = matches.value_of("emulation_seed") {
+                let seed = seed.parse::<u64>().unwrap_or_else(|e| {
+                    error!("Error parsing emulation seed: {}", e);
+                    process::exit(1);
+                });
+                server.seed_link_emulation(seed);
+            }
+            info!("Emulating links with {:?}", conditions);
+            server.set_default_link_conditions(Some(conditions));
+        }
+        #[cfg(not(feature = "emulation"))]
+        {
+            error!("Link conditions {} require building with the `emulation` feature", conditions);
+            process::exit(1);
+        }
+    }
 
     // create the Mempool
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let mut mempool = Mempool::new();
+    if let Some(increment) = matches.value_of("rbf_increment") {
+        let increment = increment.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing RBF increment: {}", e);
+            process::exit(1);
+        });
+        mempool.set_replacement_fee_increment(increment);
+    }
+    let mempool = Arc::new(Mutex::new(mempool));
 
     // start the worker
     let p2p_workers = matches
@@ -86,21 +260,52 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = worker::new(
+    let mut worker_ctx = worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &blockchain,
         &mempool, // pass the mempool to the worker
     );
-    worker_ctx.start();
+    if let Some(target) = matches.value_of("outbound_peers") {
+        let target = target.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+        worker_ctx.set_outbound_target(target);
+    }
+    if let Some(interval) = matches.value_of("tip_check_interval") {
+        let interval = interval.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing tip check interval: {}", e);
+            process::exit(1);
+        });
+        worker_ctx.set_tip_check_interval(time::Duration::from_secs(interval));
+    }
+    worker_ctx.set_allow_local_addrs(matches.is_present("local_addrs"));
+    let workers = worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner) = miner::new(
+    let reward_address = matches.value_of("reward_address").map_or(Ok(Default::default()), str::parse).unwrap_or_else(|e| {
+        error!("Error parsing reward address: {}", e);
+        process::exit(1);
+    });
+    let (mut miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
         &mempool, // pass the mempool to the miner
+        reward_address,
+        matches.value_of("report").map(std::path::PathBuf::from),
     );
+    if let Some(size) = matches.value_of("max_block_size") {
+        let size = size.parse::<usize>().map_err(|e| e.to_string())
+            .and_then(|size| miner_ctx.set_max_block_size(size));
+        if let Err(e) = size {
+            error!("Error setting max block size: {}", e);
+            process::exit(1);
+        }
+    }
+    // stopping the miner stops the network workers too
+    miner_ctx.set_workers(workers);
     miner_ctx.start();
 
     // Generate a key pair
@@ -114,49 +319,42 @@ fn main() {
         &mempool,
         &blockchain,
         controlled_keypair,
+        // the P2P port tells apart the nodes of a local experiment
+        p2p_addr.port() as u32,
     );
     
     transaction_generator.start();
 
-    // connect to known peers
+    // connect to known peers, and again whenever they drop; blocks and transactions follow
+    // from the handshake
     if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
+        for peer in known_peers {
+            match peer.parse::<net::SocketAddr>() {
+                Ok(addr) => server.connect_persistent(addr),
+                Err(e) => error!("Error parsing peer address {}: {}", peer, e),
             }
-        });
+        }
     }
 
 
+    // load the wallets
+    let wallets = match matches.value_of("keystore") {
+        Some(dir) => WalletManager::load(std::path::Path::new(dir), &blockchain).unwrap_or_else(|e| {
+            error!("Error loading wallets: {}", e);
+            process::exit(1);
+        }),
+        None => WalletManager::new(&blockchain),
+    };
+    let wallets = Arc::new(Mutex::new(wallets));
+
     // start the API server
     ApiServer::start(
         api_addr,
         &miner,
         &server,
+        &mempool,
+        &wallets,
+        &snapshot::SnapshotCoordinator::new(&blockchain, &mempool),
     );
 
     loop {
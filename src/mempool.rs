@@ -1,50 +1,253 @@
 use crate::transaction::SignedTransaction as Transaction;
 use std::collections::HashMap;
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::H256;
+use crate::address::H160;
+
+/// By how much a transaction's fee must exceed the pending one it replaces, unless configured
+pub const DEFAULT_REPLACEMENT_FEE_INCREMENT: u64 = 1;
+
+/// Split `hashes` into announcements of at most `batch_size` each
+pub fn batches(hashes: &[H256], batch_size: usize) -> Vec<Vec<H256>> {
+    hashes.chunks(batch_size).map(|batch| batch.to_vec()).collect()
+}
 
 /// Store all the received valid transactions which have not been included in the blockchain yet.
 pub struct Mempool {
     // TODO Optional: you may use other data structures if you wish.
     hash_to_transaction: HashMap<H256, Transaction>,
+    /// The txid of the pending transaction for each sender and nonce
+    sender_nonce_to_hash: HashMap<(H160, u32), H256>,
+    replacement_fee_increment: u64,
+    /// Bumped on every change, so readers can tell which version of the mempool they saw
+    generation: u64,
 }
 
 impl Mempool {
     pub fn new() -> Self {
         Mempool {
             hash_to_transaction: HashMap::new(),
+            sender_nonce_to_hash: HashMap::new(),
+            replacement_fee_increment: DEFAULT_REPLACEMENT_FEE_INCREMENT,
+            generation: 0,
         }
     }
 
-    /// Get a transaction from the mempool by hash (or `None` if it does not exist)
+    /// Set by how much a transaction's fee must exceed the pending one it replaces
+    pub fn set_replacement_fee_increment(&mut self, increment: u64) {
+        self.replacement_fee_increment = increment;
+    }
+
+    /// Get the pending transaction with the same sender and nonce as `transaction`, if any
+    pub fn conflicting(&self, transaction: &Transaction) -> Option<&Transaction> {
+        self.sender_nonce_to_hash.get(&(transaction.raw.from_addr, transaction.raw.nonce))
+            .and_then(|hash| self.hash_to_transaction.get(hash))
+    }
+
+    /// Whether `replacement` pays enough more than `pending` to take its place
+    pub fn can_replace(&self, pending: &Transaction, replacement: &Transaction) -> bool {
+        pending.raw.fee.checked_add(self.replacement_fee_increment)
+            .is_some_and(|required| replacement.raw.fee >= required)
+    }
+
+    /// Get a transaction from the mempool by txid (or `None` if it does not exist)
     pub fn get_transaction(&self, hash: &H256) -> Option<&Transaction> {
         self.hash_to_transaction.get(hash)
     }
 
-    /// Insert a transaction into the mempool
+    /// Insert a transaction into the mempool, keyed by its txid; coinbases only belong in blocks
+    /// and are ignored. Of two copies of a transaction signed differently, the first seen stays.
+    /// A transaction with the same sender and nonce as a pending one replaces it if it pays
+    /// enough more (see `can_replace`), and is dropped otherwise.
     pub fn insert(&mut self, transaction: Transaction) {
-        // (Make sure you have implemented the `Hashable` trait for `SignedTransaction`, or there will be an error):
-        let hash = transaction.raw.hash();
-        self.hash_to_transaction.insert(hash, transaction);
+        if transaction.is_coinbase() {
+            return;
+        }
+        let txid = transaction.txid();
+        if self.hash_to_transaction.contains_key(&txid) {
+            return;
+        }
+        if let Some(pending) = self.conflicting(&transaction) {
+            if !self.can_replace(pending, &transaction) {
+                return;
+            }
+            let replaced = pending.txid();
+            self.hash_to_transaction.remove(&replaced);
+        }
+        self.sender_nonce_to_hash.insert((transaction.raw.from_addr, transaction.raw.nonce), txid);
+        self.hash_to_transaction.insert(txid, transaction);
+        self.generation += 1;
     }
 
     /// Remove a transaction from the mempool by its hash
     pub fn remove(&mut self, hash: &H256) {
-        self.hash_to_transaction.remove(hash);
+        if let Some(transaction) = self.hash_to_transaction.remove(hash) {
+            self.sender_nonce_to_hash.remove(&(transaction.raw.from_addr, transaction.raw.nonce));
+            self.generation += 1;
+        }
     }
 
     /// Remove a random transaction from the mempool and return it (or `None` if it is empty)
     pub fn pop(&mut self) -> Option<Transaction> {
-        let hash = self.hash_to_transaction.keys().next().cloned();
-        if let Some(hash) = hash {
-            self.hash_to_transaction.remove(&hash)
-        } else {
-            None
-        }
+        let hash = self.hash_to_transaction.keys().next().cloned()?;
+        let transaction = self.hash_to_transaction.get(&hash).cloned();
+        self.remove(&hash);
+        transaction
+    }
+    /// Get up to `limit` transactions to put in a block, leaving them in the mempool
+    pub fn select(&self, limit: usize) -> Vec<Transaction> {
+        self.hash_to_transaction.values().take(limit).cloned().collect()
     }
+
     /// Get the keys of hash_to_transaction
     pub fn get_keys(&self) -> Vec<H256> {
         self.hash_to_transaction.keys().cloned().collect()
     }
-        
+
+    /// Get the highest nonce among the pending transactions sent from `address`
+    pub fn max_nonce_of(&self, address: &H160) -> Option<u32> {
+        self.hash_to_transaction.values()
+            .filter(|tx| tx.raw.from_addr == *address)
+            .map(|tx| tx.raw.nonce)
+            .max()
+    }
+
+    /// Get the hashes to announce to a peer asking for our mempool, at most `limit` of them,
+    /// split into batches of at most `batch_size`
+    pub fn announcement_batches(&self, limit: usize, batch_size: usize) -> Vec<Vec<H256>> {
+        let hashes: Vec<H256> = self.hash_to_transaction.keys().take(limit).cloned().collect();
+        batches(&hashes, batch_size)
+    }
+
+    /// How many times the mempool has changed since it was created
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Get the pending transactions sent from `address`, in nonce order
+    pub fn by_address(&self, address: &H160) -> Vec<&Transaction> {
+        let mut transactions: Vec<&Transaction> = self.hash_to_transaction.values()
+            .filter(|tx| tx.raw.from_addr == *address)
+            .collect();
+        transactions.sort_by_key(|tx| tx.raw.nonce);
+        transactions
+    }
+
+    /// Remove the transactions that can no longer be included in a block at `height`;
+    /// returns how many were removed
+    pub fn evict_expired(&mut self, height: u64) -> usize {
+        let before = self.hash_to_transaction.len();
+        self.hash_to_transaction.retain(|_, tx| !tx.raw.is_expired_at(height));
+        let hash_to_transaction = &self.hash_to_transaction;
+        self.sender_nonce_to_hash.retain(|_, hash| hash_to_transaction.contains_key(hash));
+        let evicted = before - self.hash_to_transaction.len();
+        if evicted > 0 {
+            self.generation += 1;
+        }
+        evicted
+    }
+
     // TODO Optional: you may want to add more methods here...
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::get_deterministic_keypair;
+    use crate::transaction::{ChainId, RawTransaction, SignedTransaction};
+
+    fn generate_transactions(count: u32) -> Vec<Transaction> {
+        let key = get_deterministic_keypair(0);
+        (1..=count).map(|nonce| {
+            let raw = RawTransaction { nonce, outputs: vec![(Default::default(), 1)], ..Default::default() };
+            SignedTransaction::from_raw(raw, &key, &ChainId::default())
+        }).collect()
+    }
+
+    #[test]
+    fn restarted_node_backfills_from_peer() {
+        let mut peer = Mempool::new();
+        for tx in generate_transactions(25) {
+            peer.insert(tx);
+        }
+        // the restarted node only kept a few transactions
+        let mut restarted = Mempool::new();
+        for tx in generate_transactions(3) {
+            restarted.insert(tx);
+        }
+
+        // GetMempool -> NewTransactionHashes batches -> GetTransactions for the unknown ones
+        let batches = peer.announcement_batches(100, 10);
+        assert_eq!(batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(), vec![10, 10, 5]);
+        for batch in batches {
+            let missing: Vec<H256> = batch.into_iter()
+                .filter(|hash| restarted.get_transaction(hash).is_none())
+                .collect();
+            for hash in missing {
+                restarted.insert(peer.get_transaction(&hash).unwrap().clone());
+            }
+        }
+        let mut expected = peer.get_keys();
+        let mut actual = restarted.get_keys();
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn first_seen_copy_wins() {
+        let original = generate_transactions(1).pop().unwrap();
+        let mut resigned = original.clone();
+        resigned.signature = SignedTransaction::from_raw(original.raw.clone(), &get_deterministic_keypair(1), &ChainId::default()).signature;
+        assert_eq!(resigned.txid(), original.txid());
+        assert_ne!(resigned.wtxid(), original.wtxid());
+
+        let mut mempool = Mempool::new();
+        mempool.insert(original.clone());
+        let generation = mempool.generation();
+        mempool.insert(resigned);
+        assert_eq!(mempool.generation(), generation);
+        assert_eq!(mempool.get_keys(), vec![original.txid()]);
+        assert_eq!(mempool.get_transaction(&original.txid()).unwrap().signature, original.signature);
+    }
+
+    #[test]
+    fn replacement_needs_the_fee_increment() {
+        let with_fee = |fee: u64| {
+            let raw = RawTransaction { nonce: 1, fee, outputs: vec![(Default::default(), 1)], ..Default::default() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let mut mempool = Mempool::new();
+        mempool.set_replacement_fee_increment(10);
+        mempool.insert(with_fee(5));
+        // a lower, equal or barely higher fee does not replace the pending transaction
+        for fee in [0, 5, 14] {
+            mempool.insert(with_fee(fee));
+            assert_eq!(mempool.get_keys(), vec![with_fee(5).txid()]);
+        }
+        mempool.insert(with_fee(15));
+        assert_eq!(mempool.get_keys(), vec![with_fee(15).txid()]);
+        assert_eq!(mempool.conflicting(&with_fee(5)).unwrap().txid(), with_fee(15).txid());
+        // the replaced transaction cannot come back
+        mempool.insert(with_fee(5));
+        assert_eq!(mempool.get_keys(), vec![with_fee(15).txid()]);
+        // removing the replacement frees its nonce
+        mempool.remove(&with_fee(15).txid());
+        assert!(mempool.conflicting(&with_fee(5)).is_none());
+        mempool.insert(with_fee(5));
+        assert_eq!(mempool.get_keys(), vec![with_fee(5).txid()]);
+        // a fee that cannot be outbid is never replaced
+        let mut mempool = Mempool::new();
+        mempool.insert(with_fee(u64::MAX));
+        assert!(!mempool.can_replace(&with_fee(u64::MAX), &with_fee(u64::MAX)));
+    }
+
+    #[test]
+    fn announcement_is_capped() {
+        let mut mempool = Mempool::new();
+        for tx in generate_transactions(25) {
+            mempool.insert(tx);
+        }
+        let batches = mempool.announcement_batches(12, 10);
+        assert_eq!(batches.iter().map(|batch| batch.len()).sum::<usize>(), 12);
+    }
+}
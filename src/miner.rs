@@ -1,6 +1,7 @@
 use crate::network::server::Handle as ServerHandle;
+use crate::network::worker::Handle as WorkerHandle;
 
-use log::info;
+use log::{debug, error, info};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
@@ -8,15 +9,25 @@ use std::time;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, State};
 // use crate::transaction::RawTransaction;
-// use crate::transaction::SignedTransaction;
-use crate::crypto::merkle::MerkleTree;
-use crate::block::{Block, Header, Content};
-use crate::crypto::hash::Hashable;
+use crate::transaction::{ChainId, SignedTransaction, MAX_DATA_SIZE};
+use crate::address::H160;
+use std::collections::HashMap;
+use crate::crypto::merkle::MerkleBuilder;
+use crate::block::{Block, Header, Content, BLOCK_REWARD, MAX_BLOCK_SIZE, MAX_TRANSACTIONS_PER_BLOCK};
+use crate::crypto::hash::{H256, Hashable};
 use crate::network::message::Message;
 use crate::blockchain::BlockOrigin;
 use crate::mempool::Mempool;
+use crate::report::ExperimentReport;
+use crate::sig_cache::SigCache;
+use std::path::PathBuf;
+
+/// If set, the miner writes the block tree as Graphviz DOT to this file on exit
+const DOT_PATH_VAR: &str = "BLOCKCHAIN_DOT";
+/// If set, the miner writes a JSON summary of the longest chain to this file on exit
+const CHAIN_JSON_PATH_VAR: &str = "BLOCKCHAIN_JSON";
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
@@ -36,23 +47,41 @@ pub struct Context {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    /// Reuses the merkle hashes of the previous block template
+    merkle_builder: MerkleBuilder,
+    /// The block being mined, rebuilt whenever the tip moves
+    template: Option<Block>,
+    tip_updates: Receiver<H256>,
+    /// Where the coinbases of mined blocks pay
+    reward_address: H160,
+    /// Most bytes of transactions in a mined block, at most `MAX_BLOCK_SIZE`
+    max_block_size: usize,
     // For experiments:
     total_blocks_mined: u64,
     start_time: Option<SystemTime>,
+    report: Arc<Mutex<Option<ExperimentReport>>>,
+    report_path: Option<PathBuf>,
+    /// The network workers, stopped along with the miner
+    workers: Option<WorkerHandle>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    /// The experiment report, assembled when the miner exits
+    report: Arc<Mutex<Option<ExperimentReport>>>,
 }
 
 pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    reward_address: H160,
+    report_path: Option<PathBuf>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let report = Arc::new(Mutex::new(None));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -60,13 +89,22 @@ pub fn new(
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        merkle_builder: MerkleBuilder::new(),
+        template: None,
+        tip_updates: blockchain.lock().unwrap().subscribe_tip(),
+        reward_address,
+        max_block_size: MAX_BLOCK_SIZE,
 
         total_blocks_mined: 0,
         start_time: None,
+        report: Arc::clone(&report),
+        report_path,
+        workers: None,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        report,
     };
 
     (ctx, handle)
@@ -83,9 +121,28 @@ impl Handle {
             .unwrap();
     }
 
+    /// Get the experiment report, once the miner has exited
+    pub fn report(&self) -> Option<ExperimentReport> {
+        self.report.lock().unwrap().clone()
+    }
 }
 
 impl Context {
+    /// Fill mined blocks with at most `size` bytes of transactions; larger than `MAX_BLOCK_SIZE`,
+    /// peers would reject them
+    pub fn set_max_block_size(&mut self, size: usize) -> Result<(), String> {
+        if size > MAX_BLOCK_SIZE {
+            return Err(format!("block size {} exceeds the limit of {}", size, MAX_BLOCK_SIZE));
+        }
+        self.max_block_size = size;
+        Ok(())
+    }
+
+    /// Shut down the network workers when the miner exits, so that the node stops cleanly
+    pub fn set_workers(&mut self, workers: WorkerHandle) {
+        self.workers = Some(workers);
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("miner".to_string())
@@ -101,6 +158,7 @@ impl Context {
             ControlSignal::Exit => {
                 info!("Miner shutting down");
                 self.operating_state = OperatingState::ShutDown;
+                self.blockchain.lock().unwrap().checkpoint();
 
                 // print mining stats if the miner started:
                 if let Some(start_time) = self.start_time {
@@ -110,10 +168,76 @@ impl Context {
                         self.total_blocks_mined, seconds_spent, mining_rate);
                     let blockchain = self.blockchain.lock().unwrap();
                     info!("Blockchain has {} blocks in total", blockchain.block_count());
-                    let longest_chain = blockchain.all_blocks_in_longest_chain();
-                    info!("Longest chain {:?} has {} blocks", longest_chain, longest_chain.len());
+                    info!("{} blocks are stale, {} blocks have forks", blockchain.stale_block_count(), blockchain.fork_count());
+                    info!("Longest chain has {} blocks, tip is {:?}", blockchain.tip_height() + 1, blockchain.tip());
+                    for (hash, _) in blockchain.iter_longest_chain() {
+                        match blockchain.get_block(hash) {
+                            Some(block) => debug!("Longest chain block {:?} with {} transactions", hash, block.content.transactions.len()),
+                            None => debug!("Longest chain block {:?} with pruned body", hash),
+                        }
+                    }
                     info!("Average block size is {} bytes", blockchain.average_block_size());
-                    info!("Delays in ms for each block (raw data): {:?}", blockchain.block_delays_ms());
+                    info!("Block delays in ms: {:?}", blockchain.block_delay_stats());
+                    info!("Confirmation latencies of generated transactions in ms: {:?}", blockchain.confirmation_latency_stats());
+                    debug!("Delays in ms for each block (raw data): {:?}", blockchain.block_delays_ms());
+                    if let Some(interval) = blockchain.mean_interblock_time_ms() {
+                        info!("Mean time between blocks of the longest chain is {:.1} ms", interval);
+                    }
+                    info!("{} transactions confirmed on the longest chain, {:.2} per second",
+                        blockchain.canonical_transaction_count(), blockchain.confirmed_tps(seconds_spent.ceil() as u64));
+                    let (mean, min, max) = blockchain.tip_duration_stats();
+                    let displaced = blockchain.tip_durations().iter().filter(|tip| tip.displaced).count();
+                    info!("Blocks remained the tip for {:.1} ms on average (min {} ms, max {} ms); {} of {} were displaced by a reorg",
+                        mean, min, max, displaced, blockchain.tip_durations().len());
+                    let validation_stats = blockchain.validation_stats();
+                    info!("Transactions rejected from the mempool: {}", validation_stats.mempool);
+                    info!("Transactions rejected in blocks: {}", validation_stats.blocks);
+                    let sig_cache_stats = blockchain.sig_cache().stats();
+                    info!("Signature cache: {} hits, {} misses", sig_cache_stats.hits, sig_cache_stats.misses);
+                    if let Some(state) = blockchain.latest_state() {
+                        info!("Total supply is {}; richest accounts: {:?}",
+                            state.total_supply(), &state.accounts_sorted_by_balance()[..state.iter().count().min(10)]);
+                    }
+                    for (peer, delays) in blockchain.delays_by_peer() {
+                        let mean = delays.iter().sum::<u128>() as f64 / delays.len() as f64;
+                        info!("Mean delay of the {} blocks from peer {} is {:.1} ms", delays.len(), peer, mean);
+                    }
+                    #[cfg(feature = "adversary")]
+                    if let Some(adversary) = &blockchain.adversary {
+                        info!("Adversary released {} batches, causing {} stale blocks: {:?}",
+                            adversary.releases.len(), adversary.stale_from_release.len(), adversary.stale_from_release);
+                    }
+
+                    let net_stats = self.server.net_stats();
+                    info!("Messages by type:\n{}", net_stats);
+
+                    let mempool = self.mempool.lock().unwrap();
+                    let report = ExperimentReport::collect(seconds_spent, self.total_blocks_mined, &blockchain, &mempool, self.server.traffic().snapshot(), net_stats);
+                    if let Some(path) = &self.report_path {
+                        match report.write_json(path) {
+                            Ok(()) => info!("Experiment report written to {}", path.display()),
+                            Err(e) => error!("Error writing experiment report to {}: {}", path.display(), e),
+                        }
+                    }
+                    *self.report.lock().unwrap() = Some(report);
+                    if let Some(path) = std::env::var_os(DOT_PATH_VAR) {
+                        let path = PathBuf::from(path);
+                        match blockchain.write_dot(&path) {
+                            Ok(()) => info!("Block tree written to {}", path.display()),
+                            Err(e) => error!("Error writing block tree to {}: {}", path.display(), e),
+                        }
+                    }
+                    if let Some(path) = std::env::var_os(CHAIN_JSON_PATH_VAR) {
+                        let path = PathBuf::from(path);
+                        match blockchain.export_json_to_file(&path) {
+                            Ok(()) => info!("Longest chain summary written to {}", path.display()),
+                            Err(e) => error!("Error writing longest chain summary to {}: {}", path.display(), e),
+                        }
+                    }
+                }
+                // with the blockchain unlocked, for the workers to finish the messages at hand
+                if let Some(workers) = self.workers.take() {
+                    workers.shutdown();
                 }
             }
             ControlSignal::Start(i) => {
@@ -164,55 +288,271 @@ impl Context {
                 let mut blockchain = self.blockchain.lock().unwrap();
                 let mut mempool = self.mempool.lock().unwrap();
 
-                let parent = blockchain.tip();
-                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                let difficulty = blockchain.get_block(&parent).header.difficulty;
-
-                let mut transactions = vec![];
-
-                // Select random transactions from the mempool
-                while let Some(tx) = mempool.pop() {
-                    transactions.push(tx);
-                    // Set a block size limit if necessary, e.g., max 10 transactions
-                    if transactions.len() >= 10 {
-                        break;
+                // a new tip, from us or from a peer, makes the current template stale
+                if self.tip_updates.try_iter().count() > 0 {
+                    self.template = None;
+                    let evicted = mempool.evict_expired(blockchain.tip_height() + 1);
+                    if evicted > 0 {
+                        debug!("Evicted {} expired transactions from the mempool", evicted);
                     }
                 }
-
-                // Make sure transactions is not empty
-                if transactions.is_empty() {
-                    transactions = vec![Default::default()]; 
-                }
-
-                let merkle_root = MerkleTree::new(&transactions).root();
-                let nonce = rand::random();
-        
-                let header = Header {
-                    parent,
-                    nonce,
-                    difficulty,
-                    timestamp,
-                    merkle_root, 
+                let mut block = match self.template.take() {
+                    Some(block) => block,
+                    None => build_template(&mut self.merkle_builder, &blockchain, &mempool, self.reward_address, self.max_block_size),
                 };
-                let content = Content { transactions: transactions.clone() };
-                let block = Block { header, content };
+                let parent = block.header.parent;
+                let difficulty = block.header.difficulty;
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                // blocks mined within the same millisecond must still pass peers' median-time check
+                block.header.timestamp = timestamp.max(blockchain.min_timestamp_after(&parent));
+                block.header.nonce = rand::random();
 
                 if block.hash() <= difficulty {
                     info!("A block is mined ");
                     blockchain.insert(&block);
+                    for tx in &block.content.transactions {
+                        mempool.remove(&tx.txid());
+                    }
 
                     self.total_blocks_mined += 1;
-                    self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
                     blockchain.hash_to_origin.insert(block.hash(), BlockOrigin::Mined);
 
+                    let announce = to_announce(&mut blockchain, block.hash());
+                    if !announce.is_empty() {
+                        let state_hash = blockchain.state_hash(&block.hash()).filter(|_| announce.contains(&block.hash()));
+                        // a block of our own goes out compactly, its transactions likely gossiped already
+                        match Message::compact_block(&block).filter(|_| announce == [block.hash()]) {
+                            Some(compact) => self.server.broadcast(compact),
+                            None => self.server.broadcast(Message::NewBlockHashes(announce)),
+                        }
+                        if let Some(state_hash) = state_hash {
+                            self.server.broadcast(Message::StateHash(block.hash(), state_hash));
+                        }
+                    }
+
                 } else {
                     info!("Block {} not mined", block.hash());
-                    // Add transactions back to the mempool
-                    for tx in transactions {
-                        mempool.insert(tx);
-                    }
+                    self.template = Some(block);
                 }
             }
         }
     }
 }
+
+/// Assemble a block on top of the current tip with transactions from the mempool; they stay
+/// in the mempool until the block is mined. The coinbase pays the block reward and the fees
+/// to `reward_address`. Only the nonce and timestamp change between attempts, until the tip moves.
+/// The transactions, coinbase included, take up at most `max_block_size` bytes.
+/// The blocks to broadcast once `mined` is inserted: itself, unless an adversarial miner
+/// decides when to publish its blocks
+#[cfg(feature = "adversary")]
+fn to_announce(blockchain: &mut Blockchain, mined: H256) -> Vec<H256> {
+    blockchain.adversary_on_mined(&mined).unwrap_or_else(|| vec![mined])
+}
+
+#[cfg(not(feature = "adversary"))]
+fn to_announce(_blockchain: &mut Blockchain, mined: H256) -> Vec<H256> {
+    vec![mined]
+}
+
+fn build_template(merkle_builder: &mut MerkleBuilder, blockchain: &Blockchain, mempool: &Mempool, reward_address: H160, max_block_size: usize) -> Block {
+    let parent = blockchain.tip();
+    let difficulty = blockchain.get_header(&parent).unwrap().difficulty;
+
+    let height = blockchain.tip_height() + 1;
+    // the coinbase's size does not depend on the fees it collects
+    let budget = max_block_size.saturating_sub(SignedTransaction::coinbase(reward_address, 0, height).size());
+    let selected = select_transactions(mempool, blockchain.state_at(&parent), &blockchain.chain_id(), &blockchain.sig_cache(), height, budget);
+    let fees = selected.iter().try_fold(BLOCK_REWARD, |total, tx| total.checked_add(tx.raw.fee));
+    // a selection whose fees overflow can't be mined; settle for the reward alone
+    let (reward, selected) = match fees {
+        Some(reward) => (reward, selected),
+        None => (BLOCK_REWARD, Vec::new()),
+    };
+    let coinbase = SignedTransaction::coinbase(reward_address, reward, height);
+    let transactions: Vec<SignedTransaction> = std::iter::once(coinbase).chain(selected).collect();
+
+    let merkle_root = merkle_builder.root(&transactions);
+    let header = Header {
+        parent,
+        nonce: 0,
+        difficulty,
+        timestamp: 0,
+        merkle_root,
+        state_root: Default::default(),
+    };
+    let content = Content { transactions };
+    let mut block = Block { header, content };
+    block.header.state_root = blockchain.expected_state_root(&block).unwrap_or_default();
+    block
+}
+
+/// Pick mempool transactions totalling at most `budget` bytes that apply in order on top of
+/// `state`, carry no more data than allowed and have not expired at `height`, the height of the block, so a mined block never
+/// has a nonce conflict. If the state is unknown, only keep verified transactions with each
+/// sender's nonces consecutive, as the network checks then. Signatures go through `sig_cache`,
+/// so those verified at admission are not verified again.
+fn select_transactions(mempool: &Mempool, mut state: Option<State>, chain: &ChainId, sig_cache: &SigCache, height: u64, budget: usize) -> Vec<SignedTransaction> {
+    // one slot goes to the coinbase
+    let limit = MAX_TRANSACTIONS_PER_BLOCK - 1;
+    let mut remaining = budget;
+    let mut pending = mempool.select(usize::MAX);
+    pending.retain(|tx| !tx.raw.is_expired_at(height) && tx.raw.data.len() <= MAX_DATA_SIZE);
+    pending.sort_unstable_by_key(|tx| (tx.raw.nonce, tx.raw.from_addr));
+    let mut selected = Vec::new();
+    let mut last_nonce: HashMap<H160, u32> = HashMap::new();
+    // a transaction may only apply once an earlier pick funds its sender, hence several passes
+    loop {
+        let selected_before = selected.len();
+        pending.retain(|tx| {
+            if selected.len() >= limit || tx.size() > remaining {
+                return true;
+            }
+            let applies = sig_cache.verify_signature(tx, chain) && match &mut state {
+                Some(state) => state.apply_presigned_transaction(tx).is_ok(),
+                None => tx.verify_owner() && last_nonce.get(&tx.raw.from_addr)
+                    .is_none_or(|last| last.checked_add(1) == Some(tx.raw.nonce)),
+            };
+            if applies {
+                remaining -= tx.size();
+                last_nonce.insert(tx.raw.from_addr, tx.raw.nonce);
+                selected.push(tx.clone());
+            }
+            !applies
+        });
+        if selected.len() == selected_before || selected.len() >= limit {
+            return selected;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::get_deterministic_keypair;
+    use crate::blockchain::InsertOutcome;
+    use crate::transaction::RawTransaction;
+    use ring::signature::KeyPair;
+
+    fn transaction(from: u8, to: u8, value: u64, nonce: u32) -> SignedTransaction {
+        let key = get_deterministic_keypair(from);
+        let raw = RawTransaction {
+            from_addr: H160::from_pubkey(key.public_key().as_ref()),
+            outputs: vec![(H160::from_pubkey(get_deterministic_keypair(to).public_key().as_ref()), value)],
+            fee: 0,
+            nonce,
+            valid_until_block: 0,
+            data: Vec::new(),
+        };
+        SignedTransaction::from_raw(raw, &key, &ChainId::default())
+    }
+
+    #[test]
+    fn template_transactions_apply_in_order() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        // a conflicting nonce the mempool drops, a nonce chain, a gap and a sender only funded by
+        // another transaction
+        for tx in [
+            transaction(0, 1, 100, 2), transaction(0, 1, 100, 1), transaction(0, 2, 100, 1),
+            transaction(1, 0, 50, 3), transaction(10, 0, 100, 1), transaction(0, 10, 100, 3),
+        ] {
+            mempool.insert(tx);
+        }
+        let tip = blockchain.tip();
+        let selected = select_transactions(&mempool, blockchain.state_at(&tip), &blockchain.chain_id(), &blockchain.sig_cache(), 1, MAX_BLOCK_SIZE);
+        assert_eq!(selected.len(), 4);
+
+        let mut merkle_builder = MerkleBuilder::new();
+        let block = build_template(&mut merkle_builder, &blockchain, &mempool, Default::default(), MAX_BLOCK_SIZE);
+        assert_eq!(block.nonce_conflict(), None);
+        assert!(blockchain.state_validity_check(&block).is_ok());
+        assert_ne!(block.header.state_root, H256::default());
+
+        // without a state, the nonce chains are still kept consecutive, whatever they start at
+        let selected = select_transactions(&mempool, None, &blockchain.chain_id(), &blockchain.sig_cache(), 1, MAX_BLOCK_SIZE);
+        assert_eq!(selected.len(), 5);
+        assert_eq!(selected.iter().filter(|tx| tx.raw.nonce == 1).count(), 2);
+    }
+
+    #[test]
+    fn replaced_transaction_is_never_mined() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let stuck = transaction(0, 1, 100, 1);
+        let mut bumped = stuck.clone();
+        bumped.raw.fee = 5;
+        let bumped = SignedTransaction::from_raw(bumped.raw, &get_deterministic_keypair(0), &ChainId::default());
+        mempool.insert(stuck.clone());
+        mempool.insert(bumped.clone());
+        // the stuck transaction is relayed back after being replaced
+        mempool.insert(stuck.clone());
+
+        let mut merkle_builder = MerkleBuilder::new();
+        let block = build_template(&mut merkle_builder, &blockchain, &mempool, Default::default(), MAX_BLOCK_SIZE);
+        let txids: Vec<H256> = block.content.transactions.iter().map(|tx| tx.txid()).collect();
+        assert!(txids.contains(&bumped.txid()));
+        assert!(!txids.contains(&stuck.txid()));
+        let selected = select_transactions(&mempool, None, &blockchain.chain_id(), &blockchain.sig_cache(), 1, MAX_BLOCK_SIZE);
+        assert!(selected.iter().all(|tx| tx.txid() != stuck.txid()));
+    }
+
+    #[test]
+    fn template_skips_expired_transactions() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let mut expired = transaction(0, 1, 100, 1);
+        expired.raw.valid_until_block = 1;
+        mempool.insert(SignedTransaction::from_raw(expired.raw, &get_deterministic_keypair(0), &ChainId::default()));
+        mempool.insert(transaction(1, 0, 100, 1));
+        let tip = blockchain.tip();
+        assert_eq!(select_transactions(&mempool, blockchain.state_at(&tip), &blockchain.chain_id(), &blockchain.sig_cache(), 1, MAX_BLOCK_SIZE).len(), 2);
+        let selected = select_transactions(&mempool, blockchain.state_at(&tip), &blockchain.chain_id(), &blockchain.sig_cache(), 2, MAX_BLOCK_SIZE);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].raw.valid_until_block, 0);
+    }
+
+    #[test]
+    fn template_stays_within_the_byte_budget() {
+        let blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        for nonce in 1..=100 {
+            mempool.insert(transaction(0, 1, 1, nonce));
+        }
+        let tx_size = transaction(0, 1, 1, 1).size();
+        let mut merkle_builder = MerkleBuilder::new();
+        for &max_block_size in &[2000, 5000] {
+            let block = build_template(&mut merkle_builder, &blockchain, &mempool, Default::default(), max_block_size);
+            let size = block.content.size();
+            // just under the budget: one more transaction would not fit
+            assert!(size <= max_block_size && size + tx_size > max_block_size);
+            assert!(blockchain.state_validity_check(&block).is_ok());
+        }
+        // the whole mempool fits in a default block
+        let block = build_template(&mut merkle_builder, &blockchain, &mempool, Default::default(), MAX_BLOCK_SIZE);
+        assert_eq!(block.content.transactions.len(), 101);
+    }
+
+    #[test]
+    fn coinbase_pays_reward_and_fees() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let miner = H160::from_pubkey(get_deterministic_keypair(20).public_key().as_ref());
+        let mut merkle_builder = MerkleBuilder::new();
+
+        // an empty mempool still yields a valid block, paying the reward alone
+        let block = build_template(&mut merkle_builder, &blockchain, &mempool, miner, MAX_BLOCK_SIZE);
+        assert_eq!(block.content.transactions.len(), 1);
+        assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+        assert_eq!(blockchain.balance_of(&miner), BLOCK_REWARD);
+
+        let mut paying = transaction(0, 1, 100, 1);
+        paying.raw.fee = 7;
+        mempool.insert(SignedTransaction::from_raw(paying.raw, &get_deterministic_keypair(0), &ChainId::default()));
+        let block = build_template(&mut merkle_builder, &blockchain, &mempool, miner, MAX_BLOCK_SIZE);
+        assert_eq!(block.content.transactions[0].raw.total_value(), Some(BLOCK_REWARD + 7));
+        assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+        assert_eq!(blockchain.balance_of(&miner), 2 * BLOCK_REWARD + 7);
+        assert_eq!(blockchain.latest_state().unwrap().total_supply(), 55000 + 2 * BLOCK_REWARD);
+    }
+}
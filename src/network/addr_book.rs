@@ -0,0 +1,202 @@
+//! Addresses of peers we heard of, to connect to when we have too few outbound peers, so that
+//! the topology is not fixed by the peers given at startup and a partition can heal.
+
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Most addresses kept; those learned beyond it are dropped
+pub const MAX_ADDRS: usize = 1000;
+/// Wait after a first failed attempt to connect, doubled with each failure after it
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(10);
+/// Longest wait between two attempts at an address
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(1800);
+/// Failed attempts in a row after which an address is forgotten
+pub const MAX_FAILURES: u32 = 10;
+
+struct Entry {
+    failures: u32,
+    /// When the address may be tried again
+    retry_at: Instant,
+}
+
+#[derive(Default)]
+pub struct AddrBook {
+    entries: HashMap<SocketAddr, Entry>,
+    /// Addresses never to learn again, such as our own
+    ignored: HashSet<SocketAddr>,
+    /// Keep loopback and private addresses, for experiments run on one host or network
+    allow_local: bool,
+}
+
+impl AddrBook {
+    pub fn new(allow_local: bool) -> Self {
+        AddrBook { allow_local, ..Self::default() }
+    }
+
+    pub fn set_allow_local(&mut self, allow_local: bool) {
+        self.allow_local = allow_local;
+    }
+
+    /// Learn `addr` unless known, ignored, unroutable or the book is full. Returns true if added.
+    pub fn add(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.entries.len() >= MAX_ADDRS || self.entries.contains_key(&addr) || self.ignored.contains(&addr) {
+            return false;
+        }
+        if (!self.allow_local && !is_routable(&addr)) || addr.port() == 0 {
+            return false;
+        }
+        self.entries.insert(addr, Entry { failures: 0, retry_at: now });
+        true
+    }
+
+    /// Forget `addr` and never learn it again
+    pub fn ignore(&mut self, addr: SocketAddr) {
+        self.entries.remove(&addr);
+        self.ignored.insert(addr);
+    }
+
+    /// Record a successful connection to `addr`, learning it if it was not known
+    pub fn connected(&mut self, addr: SocketAddr, now: Instant) {
+        match self.entries.get_mut(&addr) {
+            Some(entry) => *entry = Entry { failures: 0, retry_at: now },
+            None => {
+                self.add(addr, now);
+            }
+        }
+    }
+
+    /// Record a failed attempt to connect to `addr`, putting off the next one
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let entry = match self.entries.get_mut(&addr) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.failures += 1;
+        if entry.failures >= MAX_FAILURES {
+            self.entries.remove(&addr);
+            return;
+        }
+        let backoff = RETRY_BACKOFF.checked_mul(1 << (entry.failures - 1)).unwrap_or(MAX_RETRY_BACKOFF);
+        entry.retry_at = now + backoff.min(MAX_RETRY_BACKOFF);
+    }
+
+    /// Up to `count` addresses to try connecting to as of `now`, at random, leaving out `exclude`
+    pub fn select(&self, count: usize, exclude: &[SocketAddr], now: Instant) -> Vec<SocketAddr> {
+        let candidates: Vec<SocketAddr> = self.entries.iter()
+            .filter(|(addr, entry)| entry.retry_at <= now && !exclude.contains(addr))
+            .map(|(addr, _)| *addr)
+            .collect();
+        candidates.choose_multiple(&mut rand::thread_rng(), count).cloned().collect()
+    }
+
+    /// Up to `count` known addresses at random, to answer a `GetAddr` with
+    pub fn sample(&self, count: usize) -> Vec<SocketAddr> {
+        let addrs: Vec<SocketAddr> = self.entries.keys().cloned().collect();
+        addrs.choose_multiple(&mut rand::thread_rng(), count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Whether `addr` could be reached from another host on the internet
+pub fn is_routable(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_private() || ip.is_link_local()
+                || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast())
+        }
+        IpAddr::V6(ip) => {
+            // unique local fc00::/7 and link-local fe80::/10
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(port: u16) -> SocketAddr {
+        SocketAddr::from(([8, 8, 8, 8], port))
+    }
+
+    #[test]
+    fn only_routable_addresses_are_learned_once() {
+        let mut book = AddrBook::new(false);
+        let now = Instant::now();
+        assert!(book.add(public(6000), now));
+        assert!(!book.add(public(6000), now));
+        for local in &["127.0.0.1:6000", "10.0.0.1:6000", "192.168.1.1:6000", "0.0.0.0:6000", "[::1]:6000", "[fe80::1]:6000"] {
+            assert!(!book.add(local.parse().unwrap(), now), "{}", local);
+        }
+        assert!(!book.add(public(0), now));
+        assert_eq!(book.len(), 1);
+
+        // unless experimenting locally
+        book.set_allow_local(true);
+        assert!(book.add("127.0.0.1:6000".parse().unwrap(), now));
+
+        book.ignore(public(6000));
+        assert!(!book.add(public(6000), now));
+        assert_eq!(book.sample(10), vec!["127.0.0.1:6000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn failed_addresses_back_off_then_are_forgotten() {
+        let mut book = AddrBook::new(false);
+        let start = Instant::now();
+        book.add(public(6000), start);
+        assert_eq!(book.select(8, &[], start), vec![public(6000)]);
+
+        book.failed(public(6000), start);
+        assert!(book.select(8, &[], start + RETRY_BACKOFF - Duration::from_millis(1)).is_empty());
+        assert_eq!(book.select(8, &[], start + RETRY_BACKOFF), vec![public(6000)]);
+        // twice as long after the second failure
+        book.failed(public(6000), start);
+        assert!(book.select(8, &[], start + RETRY_BACKOFF).is_empty());
+        assert_eq!(book.select(8, &[], start + RETRY_BACKOFF * 2), vec![public(6000)]);
+        for _ in 2..MAX_FAILURES - 1 {
+            book.failed(public(6000), start);
+        }
+        assert!(book.select(8, &[], start + MAX_RETRY_BACKOFF - Duration::from_millis(1)).is_empty());
+        assert_eq!(book.select(8, &[], start + MAX_RETRY_BACKOFF), vec![public(6000)]);
+
+        // a success wipes the slate
+        book.connected(public(6000), start);
+        assert_eq!(book.select(8, &[], start), vec![public(6000)]);
+        for _ in 0..MAX_FAILURES {
+            book.failed(public(6000), start);
+        }
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn selection_leaves_out_connected_peers() {
+        let mut book = AddrBook::new(false);
+        let now = Instant::now();
+        for port in 1..=20 {
+            book.add(public(port), now);
+        }
+        let connected: Vec<SocketAddr> = (1..=15).map(public).collect();
+        let mut selected = book.select(8, &connected, now);
+        selected.sort();
+        assert_eq!(selected, (16..=20).map(public).collect::<Vec<_>>());
+        assert_eq!(book.select(3, &[], now).len(), 3);
+
+        // a full book learns nothing more
+        for port in 21..=MAX_ADDRS as u16 + 10 {
+            book.add(public(port), now);
+        }
+        assert_eq!(book.len(), MAX_ADDRS);
+    }
+}
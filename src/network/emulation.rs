@@ -0,0 +1,329 @@
+//! Delay and loss injected into what we send, to emulate wide-area links in propagation
+//! experiments on a single host. Compiled in with the `emulation` feature.
+//!
+//! Messages to a peer with link conditions go through a scheduler thread that writes each one
+//! once its delay is over, in the order they were sent, as over a TCP connection; the lost ones
+//! are never written. The decisions come from a seeded RNG, so that a run can be repeated.
+
+use super::peer::PeerId;
+use log::trace;
+use mio_extras::channel;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Seed of the decisions unless configured otherwise
+pub const DEFAULT_SEED: u64 = 0;
+
+/// The link to a peer: every message is held for `delay_ms`, plus up to `jitter_ms` more, and
+/// lost with probability `loss_rate`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    pub delay_ms: u64,
+    pub jitter_ms: u64,
+    pub loss_rate: f32,
+}
+
+impl FromStr for LinkConditions {
+    type Err = String;
+
+    /// Parse `<delay_ms>:<jitter_ms>:<loss_rate>`, say `50:10:0.01`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 3 {
+            return Err(format!("expected <delay_ms>:<jitter_ms>:<loss_rate>, got {}", s));
+        }
+        let delay_ms = parts[0].parse::<u64>().map_err(|e| format!("invalid delay {}: {}", parts[0], e))?;
+        let jitter_ms = parts[1].parse::<u64>().map_err(|e| format!("invalid jitter {}: {}", parts[1], e))?;
+        let loss_rate = parts[2].parse::<f32>().map_err(|e| format!("invalid loss rate {}: {}", parts[2], e))?;
+        if !(0.0..=1.0).contains(&loss_rate) {
+            return Err(format!("loss rate {} is not between 0 and 1", loss_rate));
+        }
+        Ok(LinkConditions { delay_ms, jitter_ms, loss_rate })
+    }
+}
+
+/// What becomes of a message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fate {
+    /// Written at once, the link having no conditions
+    Direct,
+    /// Held for this long
+    Delayed(Duration),
+    Lost,
+}
+
+/// A message to write to a peer's queue once due
+struct Held {
+    due: Instant,
+    /// Order in which messages were held, so that those due at once keep it
+    seq: u64,
+    queue: channel::Sender<Vec<u8>>,
+    bytes: Vec<u8>,
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// The link conditions of each peer, and the scheduler delaying messages to them. Shared by
+/// the threads writing to peers, each taking its locks only to decide and to hand over a message.
+pub struct LinkEmulator {
+    links: Mutex<Links>,
+    /// Started with the first delayed message
+    scheduler: Mutex<Option<mpsc::Sender<Held>>>,
+}
+
+/// What the decisions depend on
+struct Links {
+    conditions: HashMap<PeerId, LinkConditions>,
+    /// For the peers without conditions of their own
+    default: Option<LinkConditions>,
+    rng: StdRng,
+    /// When the last message held for each peer is due, so that none overtakes it
+    last_due: HashMap<PeerId, Instant>,
+    next_seq: u64,
+}
+
+impl Links {
+    fn decide(&mut self, peer: PeerId) -> Fate {
+        let conditions = match self.conditions.get(&peer).or(self.default.as_ref()) {
+            Some(conditions) => *conditions,
+            None => return Fate::Direct,
+        };
+        // drawn for every message, so that the decisions do not depend on the conditions
+        let lost = self.rng.gen::<f32>() < conditions.loss_rate;
+        let jitter = self.rng.gen_range(0, conditions.jitter_ms + 1);
+        if lost {
+            Fate::Lost
+        } else {
+            Fate::Delayed(Duration::from_millis(conditions.delay_ms + jitter))
+        }
+    }
+
+    /// When a message held for `delay` is due, and its place among those due at once
+    fn schedule(&mut self, peer: PeerId, delay: Duration) -> (Instant, u64) {
+        let mut due = Instant::now() + delay;
+        if let Some(last) = self.last_due.get(&peer) {
+            due = due.max(*last);
+        }
+        self.last_due.insert(peer, due);
+        self.next_seq += 1;
+        (due, self.next_seq - 1)
+    }
+}
+
+impl LinkEmulator {
+    pub fn new(seed: u64) -> Self {
+        LinkEmulator {
+            links: Mutex::new(Links {
+                conditions: HashMap::new(),
+                default: None,
+                rng: StdRng::seed_from_u64(seed),
+                last_due: HashMap::new(),
+                next_seq: 0,
+            }),
+            scheduler: Mutex::new(None),
+        }
+    }
+
+    /// Start the decisions over from `seed`
+    pub fn reseed(&self, seed: u64) {
+        self.links.lock().unwrap().rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set(&self, peer: PeerId, conditions: LinkConditions) {
+        self.links.lock().unwrap().conditions.insert(peer, conditions);
+    }
+
+    /// Conditions for every peer without its own, or none
+    pub fn set_default(&self, conditions: Option<LinkConditions>) {
+        self.links.lock().unwrap().default = conditions;
+    }
+
+    /// Lift the conditions of a peer; what is still held for it goes first
+    pub fn clear(&self, peer: PeerId) {
+        self.links.lock().unwrap().conditions.remove(&peer);
+    }
+
+    /// Forget a peer that disconnected
+    pub fn forget(&self, peer: PeerId) {
+        let mut links = self.links.lock().unwrap();
+        links.conditions.remove(&peer);
+        links.last_due.remove(&peer);
+    }
+
+    /// Decide what becomes of the next message to `peer`
+    pub fn decide(&self, peer: PeerId) -> Fate {
+        self.links.lock().unwrap().decide(peer)
+    }
+
+    /// Send `bytes` to `peer` through `queue` as its link decides: at once, later, or never.
+    /// Fails only if a message written at once finds the queue closed.
+    pub fn send(&self, peer: PeerId, bytes: Vec<u8>, queue: &channel::Sender<Vec<u8>>) -> Result<Fate, channel::SendError<Vec<u8>>> {
+        // decided under the lock, but written or held after releasing it
+        let (fate, scheduled) = {
+            let mut links = self.links.lock().unwrap();
+            let fate = links.decide(peer);
+            let delay = match fate {
+                // a peer whose conditions were lifted gets what is still held first
+                Fate::Direct if links.last_due.get(&peer).is_some_and(|due| *due > Instant::now()) => Some(Duration::from_secs(0)),
+                Fate::Delayed(delay) => Some(delay),
+                Fate::Direct | Fate::Lost => None,
+            };
+            (fate, delay.map(|delay| links.schedule(peer, delay)))
+        };
+        match (fate, scheduled) {
+            (_, Some((due, seq))) => self.hold(Held { due, seq, queue: queue.clone(), bytes }),
+            (Fate::Lost, None) => trace!("Dropping a message to peer {}", peer),
+            (_, None) => queue.send(bytes)?,
+        }
+        Ok(fate)
+    }
+
+    fn hold(&self, held: Held) {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        if let Err(mpsc::SendError(held)) = scheduler.get_or_insert_with(start_scheduler).send(held) {
+            // the scheduler is gone only if it panicked; start another
+            let restarted = start_scheduler();
+            restarted.send(held).unwrap();
+            *scheduler = Some(restarted);
+        }
+    }
+}
+
+impl Default for LinkEmulator {
+    fn default() -> Self {
+        LinkEmulator::new(DEFAULT_SEED)
+    }
+}
+
+/// A thread writing held messages to their queues once due, until the emulator is dropped and
+/// nothing is held any more
+fn start_scheduler() -> mpsc::Sender<Held> {
+    let (sender, receiver) = mpsc::channel::<Held>();
+    thread::spawn(move || {
+        let mut held: BinaryHeap<Reverse<Held>> = BinaryHeap::new();
+        loop {
+            let now = Instant::now();
+            while held.peek().is_some_and(|Reverse(next)| next.due <= now) {
+                let Reverse(next) = held.pop().unwrap();
+                // the peer may have disconnected meanwhile
+                next.queue.send(next.bytes).ok();
+            }
+            let received = match held.peek() {
+                Some(Reverse(next)) => receiver.recv_timeout(next.due.saturating_duration_since(now)),
+                None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(message) => held.push(Reverse(message)),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) if held.is_empty() => return,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    let Reverse(next) = held.peek().unwrap();
+                    thread::sleep(next.due.saturating_duration_since(Instant::now()));
+                }
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::peer;
+
+    fn peer_id() -> PeerId {
+        peer::Handle::detached("127.0.0.1:6001".parse().unwrap()).0.id()
+    }
+
+    #[test]
+    fn decisions_repeat_with_the_seed() {
+        let conditions = LinkConditions { delay_ms: 50, jitter_ms: 20, loss_rate: 0.25 };
+        let peer = peer_id();
+        let fates = |seed| {
+            let emulator = LinkEmulator::new(seed);
+            emulator.set(peer, conditions);
+            (0..1000).map(|_| emulator.decide(peer)).collect::<Vec<Fate>>()
+        };
+        let fates_7 = fates(7);
+        assert_eq!(fates_7, fates(7));
+        assert_ne!(fates_7, fates(8));
+
+        let lost = fates_7.iter().filter(|fate| **fate == Fate::Lost).count();
+        assert!((200..300).contains(&lost), "{} lost of 1000", lost);
+        for fate in &fates_7 {
+            if let Fate::Delayed(delay) = fate {
+                assert!(*delay >= Duration::from_millis(50) && *delay <= Duration::from_millis(70), "{:?}", delay);
+            }
+        }
+
+        // a peer without conditions is not held up, unless there are default ones
+        let emulator = LinkEmulator::new(7);
+        let other = peer_id();
+        assert_eq!(emulator.decide(other), Fate::Direct);
+        emulator.set_default(Some(LinkConditions { delay_ms: 5, jitter_ms: 0, loss_rate: 0.0 }));
+        assert_eq!(emulator.decide(other), Fate::Delayed(Duration::from_millis(5)));
+        emulator.set(other, LinkConditions { delay_ms: 0, jitter_ms: 0, loss_rate: 1.0 });
+        assert_eq!(emulator.decide(other), Fate::Lost);
+        emulator.forget(other);
+        assert_eq!(emulator.decide(other), Fate::Delayed(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn delayed_messages_arrive_late_and_in_order() {
+        let emulator = LinkEmulator::new(1);
+        let peer = peer_id();
+        emulator.set(peer, LinkConditions { delay_ms: 100, jitter_ms: 50, loss_rate: 0.0 });
+        let (queue, written) = channel::channel();
+        let start = Instant::now();
+        for i in 0..20u8 {
+            assert!(matches!(emulator.send(peer, vec![i], &queue), Ok(Fate::Delayed(_))));
+        }
+        // lifting the conditions does not let the next message overtake those held
+        emulator.clear(peer);
+        assert!(matches!(emulator.send(peer, vec![20], &queue), Ok(Fate::Direct)));
+        assert!(written.try_recv().is_err());
+
+        let mut received = Vec::new();
+        while received.len() < 21 {
+            assert!(start.elapsed() < Duration::from_secs(10), "held messages not written");
+            match written.try_recv() {
+                Ok(bytes) => received.push(bytes[0]),
+                Err(_) => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(received, (0..21).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn conditions_are_parsed() {
+        assert_eq!("50:10:0.01".parse(), Ok(LinkConditions { delay_ms: 50, jitter_ms: 10, loss_rate: 0.01 }));
+        assert!("50:10".parse::<LinkConditions>().is_err());
+        assert!("50:10:1.5".parse::<LinkConditions>().is_err());
+        assert!("fast:0:0".parse::<LinkConditions>().is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Headers-first synchronization: a node behind a peer first downloads and checks the headers
+//! of the peer's chain, which are small, then fetches the bodies of the valid ones in order,
+//! a window at a time.
+
+use crate::block::Header;
+use crate::blockchain::{Blockchain, MEDIAN_TIME_SPAN};
+use crate::crypto::hash::{Hashable, H256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// Most block bodies requested and not received yet at any time while syncing
+pub const BODY_DOWNLOAD_WINDOW: usize = 512;
+
+/// Why a batch of headers was refused; none of it is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The first header's parent is neither a block nor a header we know: the batch answers
+    /// someone else's locator, or belongs to another network
+    Unconnected,
+    /// Header `index` is not the child of the one before it
+    BrokenLink { index: usize },
+    /// Header `index` is not mined to the chain's difficulty
+    BadPow { index: usize },
+    /// Header `index` is timestamped too far in the future, or not after its ancestors' median
+    BadTimestamp { index: usize },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::Unconnected => write!(f, "the first header's parent is unknown"),
+            HeaderError::BrokenLink { index } => write!(f, "header {} does not follow the one before it", index),
+            HeaderError::BadPow { index } => write!(f, "header {} fails the proof of work check", index),
+            HeaderError::BadTimestamp { index } => write!(f, "header {} has an invalid timestamp", index),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HeaderSync {
+    /// Checked headers whose blocks we do not have yet
+    headers: HashMap<H256, Header>,
+    /// Of those, the ones whose bodies are still to request, parents first
+    to_download: VecDeque<H256>,
+    /// Bodies requested and not received yet
+    downloading: HashSet<H256>,
+    /// The last header accepted, to ask for the ones after it
+    best: Option<H256>,
+    /// Headers accepted since starting, for experiments
+    accepted: u64,
+}
+
+impl HeaderSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check `headers`, a chain each the child of the one before, against the rules a block
+    /// header must follow, and queue the bodies of those we did not know. Headers of blocks we
+    /// have are checked for their links only. Returns how many headers were new.
+    pub fn accept(&mut self, headers: &[Header], blockchain: &Blockchain, now_ms: u128) -> Result<usize, HeaderError> {
+        let first_parent = match headers.first() {
+            Some(header) => header.parent,
+            None => return Ok(0),
+        };
+        if !blockchain.contains_block(&first_parent) && !self.headers.contains_key(&first_parent) {
+            return Err(HeaderError::Unconnected);
+        }
+        let mut added = Vec::new();
+        let mut previous = first_parent;
+        let mut result = Ok(());
+        for (index, header) in headers.iter().enumerate() {
+            let hash = header.hash();
+            if header.parent != previous {
+                result = Err(HeaderError::BrokenLink { index });
+                break;
+            }
+            previous = hash;
+            if blockchain.contains_block(&hash) || self.headers.contains_key(&hash) {
+                continue;
+            }
+            if hash > header.difficulty || header.difficulty != blockchain.difficulty() {
+                result = Err(HeaderError::BadPow { index });
+                break;
+            }
+            let too_late = header.timestamp > now_ms + blockchain.max_future_drift().as_millis();
+            if too_late || header.timestamp <= self.median_time_past(&header.parent, blockchain) {
+                result = Err(HeaderError::BadTimestamp { index });
+                break;
+            }
+            self.headers.insert(hash, header.clone());
+            added.push(hash);
+        }
+        if let Err(e) = result {
+            for hash in &added {
+                self.headers.remove(hash);
+            }
+            return Err(e);
+        }
+        self.best = Some(previous);
+        self.accepted += added.len() as u64;
+        let count = added.len();
+        self.to_download.extend(added);
+        Ok(count)
+    }
+
+    /// Median timestamp of `parent` and its ancestors, through our headers and then the blockchain
+    fn median_time_past(&self, parent: &H256, blockchain: &Blockchain) -> u128 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut hash = *parent;
+        while let Some(header) = self.headers.get(&hash) {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push(header.timestamp);
+            hash = header.parent;
+        }
+        let remaining = MEDIAN_TIME_SPAN - timestamps.len();
+        timestamps.extend(blockchain.iter_from(&hash).take(remaining).map(|(_, header)| header.timestamp));
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len().saturating_sub(1) / 2).copied().unwrap_or(0)
+    }
+
+    /// A locator for the headers after the ones we have: the last header accepted, then our
+    /// chain's own locator in case the peer's chain no longer includes that header
+    pub fn locator(&self, blockchain: &Blockchain) -> Vec<H256> {
+        let mut locator = blockchain.block_locator();
+        if let Some(best) = self.best.filter(|best| self.headers.contains_key(best)) {
+            locator.insert(0, best);
+        }
+        locator
+    }
+
+    /// The bodies to request next, in order, keeping at most `BODY_DOWNLOAD_WINDOW` in flight
+    pub fn next_downloads(&mut self) -> Vec<H256> {
+        let count = BODY_DOWNLOAD_WINDOW.saturating_sub(self.downloading.len()).min(self.to_download.len());
+        let batch: Vec<H256> = self.to_download.drain(..count).collect();
+        self.downloading.extend(batch.iter().cloned());
+        batch
+    }
+
+    /// Forget a header whose block arrived, or that we gave up on fetching
+    pub fn block_done(&mut self, hash: &H256) {
+        if self.headers.remove(hash).is_some() {
+            self.downloading.remove(hash);
+            self.to_download.retain(|queued| queued != hash);
+        }
+    }
+
+    /// Whether there are headers whose bodies are still missing
+    pub fn is_syncing(&self) -> bool {
+        !self.headers.is_empty()
+    }
+
+    pub fn headers_accepted(&self) -> u64 {
+        self.accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_mined_block;
+    use crate::block::Block;
+
+    /// `count` mined blocks, each the child of the one before, the first a child of `parent`
+    fn chain_from(parent: &H256, count: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..count {
+            let parent = blocks.last().map_or(*parent, |block| block.hash());
+            blocks.push(generate_mined_block(&parent));
+        }
+        blocks
+    }
+
+    fn headers(blocks: &[Block]) -> Vec<Header> {
+        blocks.iter().map(|block| block.header.clone()).collect()
+    }
+
+    fn now() -> u128 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis()
+    }
+
+    #[test]
+    fn valid_headers_queue_their_bodies_in_order() {
+        let blockchain = Blockchain::new();
+        let blocks = chain_from(&blockchain.tip(), 600);
+        let mut sync = HeaderSync::new();
+        assert_eq!(sync.accept(&headers(&blocks[..300]), &blockchain, now()), Ok(300));
+        // a batch may continue from headers as well as from blocks, and overlap them
+        assert_eq!(sync.accept(&headers(&blocks[250..]), &blockchain, now()), Ok(300));
+        assert_eq!(sync.headers_accepted(), 600);
+        assert_eq!(sync.locator(&blockchain)[0], blocks[599].hash());
+
+        let first = sync.next_downloads();
+        assert_eq!(first.len(), BODY_DOWNLOAD_WINDOW);
+        assert_eq!(first[0], blocks[0].hash());
+        assert!(sync.next_downloads().is_empty());
+        sync.block_done(&blocks[0].hash());
+        assert_eq!(sync.next_downloads(), vec![blocks[BODY_DOWNLOAD_WINDOW].hash()]);
+        for block in &blocks {
+            sync.block_done(&block.hash());
+        }
+        assert!(!sync.is_syncing());
+        assert!(sync.next_downloads().is_empty());
+    }
+
+    #[test]
+    fn invalid_batches_are_refused_whole() {
+        let blockchain = Blockchain::new();
+        let blocks = chain_from(&blockchain.tip(), 5);
+        let mut sync = HeaderSync::new();
+
+        let orphaned = chain_from(&H256::from([1; 32]), 2);
+        assert_eq!(sync.accept(&headers(&orphaned), &blockchain, now()), Err(HeaderError::Unconnected));
+        // headers arriving out of order are refused until their parents are known
+        assert_eq!(sync.accept(&headers(&blocks[3..]), &blockchain, now()), Err(HeaderError::Unconnected));
+
+        let mut skipping = headers(&blocks);
+        skipping.remove(2);
+        assert_eq!(sync.accept(&skipping, &blockchain, now()), Err(HeaderError::BrokenLink { index: 2 }));
+
+        let mut unmined = headers(&blocks);
+        unmined[4].nonce = unmined[4].nonce.wrapping_add(1);
+        while unmined[4].hash() <= unmined[4].difficulty {
+            unmined[4].nonce = unmined[4].nonce.wrapping_add(1);
+        }
+        assert_eq!(sync.accept(&unmined, &blockchain, now()), Err(HeaderError::BadPow { index: 4 }));
+
+        let mut future = headers(&blocks);
+        future[1].timestamp = now() + 3_600_000;
+        let mut rewound = headers(&blocks);
+        rewound[1].timestamp = 0;
+        for tampered in [future, rewound].iter_mut() {
+            // mined again, so that only the timestamp is wrong
+            while tampered[1].hash() > tampered[1].difficulty {
+                tampered[1].nonce = rand::random();
+            }
+            assert_eq!(sync.accept(&tampered[..2], &blockchain, now()), Err(HeaderError::BadTimestamp { index: 1 }));
+        }
+
+        assert!(!sync.is_syncing());
+        assert_eq!(sync.accept(&headers(&blocks), &blockchain, now()), Ok(5));
+    }
+
+    #[test]
+    fn a_reorg_mid_sync_continues_from_the_fork() {
+        let blockchain = Blockchain::new();
+        let blocks = chain_from(&blockchain.tip(), 10);
+        let mut sync = HeaderSync::new();
+        assert_eq!(sync.accept(&headers(&blocks), &blockchain, now()), Ok(10));
+        // the peer switched to a branch forking after block 4
+        let branch = chain_from(&blocks[4].hash(), 8);
+        assert_eq!(sync.accept(&headers(&branch), &blockchain, now()), Ok(8));
+        assert_eq!(sync.locator(&blockchain)[0], branch[7].hash());
+        let downloads = sync.next_downloads();
+        assert_eq!(downloads.len(), 18);
+        // parents still come before their children
+        assert_eq!(&downloads[10..], &branch.iter().map(|block| block.hash()).collect::<Vec<_>>()[..]);
+    }
+}
@@ -0,0 +1,76 @@
+//! Blocks and transactions some worker is processing, so that another worker receiving the same
+//! one from another peer at nearly the same time skips it instead of validating it again.
+
+use crate::crypto::hash::H256;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The hashes claimed by the workers, shared between them
+#[derive(Clone, Default)]
+pub struct InFlight {
+    hashes: Arc<Mutex<HashSet<H256>>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `hash` for processing; `None` if another worker has it. The claim lasts until the
+    /// returned guard is dropped, which happens on a panic too.
+    pub fn claim(&self, hash: H256) -> Option<Claim> {
+        if !self.lock().insert(hash) {
+            return None;
+        }
+        Some(Claim { hash, hashes: Arc::clone(&self.hashes) })
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.lock().contains(hash)
+    }
+
+    /// A worker panicking while holding the lock leaves the set as consistent as any
+    fn lock(&self) -> MutexGuard<'_, HashSet<H256>> {
+        self.hashes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A hash claimed by a worker, released when dropped
+pub struct Claim {
+    hash: H256,
+    hashes: Arc<Mutex<HashSet<H256>>>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        // not to panic again while unwinding from a panic
+        let mut hashes = self.hashes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        hashes.remove(&self.hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_are_exclusive_until_released() {
+        let in_flight = InFlight::new();
+        let hash = H256::from([1; 32]);
+        let claim = in_flight.claim(hash).unwrap();
+        assert!(in_flight.claim(hash).is_none());
+        assert!(in_flight.claim(H256::from([2; 32])).is_some());
+        drop(claim);
+        assert!(!in_flight.contains(&hash));
+
+        // released by a panicking claimer as well
+        let panicking = in_flight.clone();
+        let result = std::thread::spawn(move || {
+            let _claim = panicking.claim(hash).unwrap();
+            panic!("validation failed unexpectedly");
+        }).join();
+        assert!(result.is_err());
+        assert!(!in_flight.contains(&hash));
+        assert!(in_flight.claim(hash).is_some());
+    }
+}
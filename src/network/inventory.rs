@@ -0,0 +1,91 @@
+//! The block and transaction hashes a peer is known to have, because it announced or sent them
+//! to us or we announced them to it, so that our announcements skip what it has already.
+
+use crate::crypto::hash::H256;
+use std::collections::{HashMap, VecDeque};
+
+/// Most hashes remembered per peer; the least recently seen are forgotten first
+pub const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// A bounded set of hashes, forgetting the least recently seen first
+pub struct KnownInventory {
+    /// When each hash was last seen, as a count of the hashes seen before it
+    seen: HashMap<H256, u64>,
+    /// Hashes in the order seen; an entry older than the hash's last sighting is stale
+    order: VecDeque<(H256, u64)>,
+    capacity: usize,
+    count: u64,
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> Self {
+        KnownInventory { seen: HashMap::new(), order: VecDeque::new(), capacity, count: 0 }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.seen.contains_key(hash)
+    }
+
+    /// Record `hash` as known, or as seen again if it was
+    pub fn insert(&mut self, hash: H256) {
+        self.count += 1;
+        self.seen.insert(hash, self.count);
+        self.order.push_back((hash, self.count));
+        while self.seen.len() > self.capacity {
+            let (oldest, at) = self.order.pop_front().unwrap();
+            if self.seen.get(&oldest) == Some(&at) {
+                self.seen.remove(&oldest);
+            }
+        }
+        // hashes seen over and over leave stale entries behind
+        if self.order.len() > 2 * self.capacity.max(1) {
+            let seen = &self.seen;
+            self.order.retain(|(hash, at)| seen.get(hash) == Some(at));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+impl Default for KnownInventory {
+    fn default() -> Self {
+        KnownInventory::new(MAX_KNOWN_INVENTORY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u32) -> H256 {
+        let mut bytes = [0; 32];
+        bytes[..4].copy_from_slice(&n.to_be_bytes());
+        H256::from(bytes)
+    }
+
+    #[test]
+    fn least_recently_seen_hashes_are_forgotten() {
+        let mut known = KnownInventory::new(3);
+        for n in 0..3 {
+            known.insert(hash(n));
+        }
+        // seen again, so no longer the oldest
+        known.insert(hash(0));
+        known.insert(hash(3));
+        assert!(!known.contains(&hash(1)));
+        assert!(known.contains(&hash(0)) && known.contains(&hash(2)) && known.contains(&hash(3)));
+
+        // however often a hash comes back, memory stays bounded
+        for _ in 0..100 {
+            known.insert(hash(0));
+        }
+        assert_eq!(known.len(), 3);
+        assert!(known.order.len() <= 6);
+    }
+}
@@ -0,0 +1,226 @@
+//! Work queued per peer: each peer's items are taken in the order they came and one at a time,
+//! while items of different peers are taken by different threads side by side. The workers
+//! queue received blocks here, so that a block slow to validate only holds up the blocks of
+//! the peer that sent it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// At most this many items wait in one peer's lane; more are refused
+pub const MAX_LANE_LENGTH: usize = 16;
+
+struct Queues<T> {
+    lanes: HashMap<SocketAddr, VecDeque<T>>,
+    /// Lanes with items waiting and no thread on them, the longest waiting first
+    ready: VecDeque<SocketAddr>,
+    /// Lanes a thread is handling an item of
+    busy: HashSet<SocketAddr>,
+    closed: bool,
+}
+
+struct Shared<T> {
+    queues: Mutex<Queues<T>>,
+    ready: Condvar,
+}
+
+/// The lanes of every peer, shared between the threads filling and those taking them
+pub struct Lanes<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Lanes<T> {
+    fn clone(&self) -> Self {
+        Lanes { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl<T> Default for Lanes<T> {
+    fn default() -> Self {
+        let queues = Queues { lanes: HashMap::new(), ready: VecDeque::new(), busy: HashSet::new(), closed: false };
+        Lanes { shared: Arc::new(Shared { queues: Mutex::new(queues), ready: Condvar::new() }) }
+    }
+}
+
+impl<T> Lanes<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `item` at the back of `lane`. False if the lane is full or the lanes are closed.
+    pub fn push(&self, lane: SocketAddr, item: T) -> bool {
+        let mut queues = self.lock();
+        if queues.closed {
+            return false;
+        }
+        let queue = queues.lanes.entry(lane).or_default();
+        if queue.len() >= MAX_LANE_LENGTH {
+            return false;
+        }
+        queue.push_back(item);
+        if queue.len() == 1 && !queues.busy.contains(&lane) {
+            queues.ready.push_back(lane);
+            self.shared.ready.notify_one();
+        }
+        true
+    }
+
+    /// The next item of a lane no other thread is on, waiting for one if need be, with the turn
+    /// that keeps the lane to the caller until dropped. `None` once closed.
+    pub fn next(&self) -> Option<(T, Turn<T>)> {
+        let mut queues = self.lock();
+        loop {
+            if queues.closed {
+                return None;
+            }
+            if let Some(taken) = self.take(&mut queues) {
+                return Some(taken);
+            }
+            queues = self.shared.ready.wait(queues).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Like `next`, without waiting
+    pub fn try_next(&self) -> Option<(T, Turn<T>)> {
+        let mut queues = self.lock();
+        if queues.closed {
+            return None;
+        }
+        self.take(&mut queues)
+    }
+
+    /// Wake the threads waiting for items, and refuse and drop those still queued
+    pub fn close(&self) {
+        let mut queues = self.lock();
+        queues.closed = true;
+        queues.lanes.clear();
+        queues.ready.clear();
+        self.shared.ready.notify_all();
+    }
+
+    /// How many items wait, over all lanes
+    pub fn len(&self) -> usize {
+        self.lock().lanes.values().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn take(&self, queues: &mut Queues<T>) -> Option<(T, Turn<T>)> {
+        let lane = queues.ready.pop_front()?;
+        let item = queues.lanes.get_mut(&lane).and_then(|queue| queue.pop_front())?;
+        queues.busy.insert(lane);
+        Some((item, Turn { lane, lanes: self.clone() }))
+    }
+
+    /// A thread panicking while holding the lock leaves the queues as consistent as any
+    fn lock(&self) -> MutexGuard<'_, Queues<T>> {
+        self.shared.queues.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A thread's hold on a lane, handing the lane's next item to any thread when dropped, which
+/// happens on a panic too
+pub struct Turn<T> {
+    lane: SocketAddr,
+    lanes: Lanes<T>,
+}
+
+impl<T> Drop for Turn<T> {
+    fn drop(&mut self) {
+        let mut queues = self.lanes.lock();
+        queues.busy.remove(&self.lane);
+        match queues.lanes.get(&self.lane).map(|queue| queue.is_empty()) {
+            Some(false) => {
+                // behind the lanes that waited meanwhile, for a busy peer not to starve the others
+                queues.ready.push_back(self.lane);
+                self.lanes.shared.ready.notify_one();
+            }
+            Some(true) => {
+                queues.lanes.remove(&self.lane);
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn a_lane_is_taken_in_order_and_by_one_thread_at_a_time() {
+        let lanes = Lanes::new();
+        for item in 0..3 {
+            assert!(lanes.push(peer(1), item));
+        }
+        assert!(lanes.push(peer(2), 10));
+
+        let (first, turn) = lanes.try_next().unwrap();
+        assert_eq!(first, 0);
+        // the rest of peer 1's lane waits for the turn to end, peer 2's does not
+        let (other, other_turn) = lanes.try_next().unwrap();
+        assert_eq!(other, 10);
+        assert!(lanes.try_next().is_none());
+        drop(other_turn);
+        drop(turn);
+        let taken: Vec<i32> = std::iter::from_fn(|| lanes.try_next().map(|(item, _)| item)).collect();
+        assert_eq!(taken, vec![1, 2]);
+        assert!(lanes.is_empty());
+    }
+
+    #[test]
+    fn busy_lanes_take_turns_with_the_others() {
+        let lanes = Lanes::new();
+        for item in 0..2 {
+            lanes.push(peer(1), item);
+        }
+        lanes.push(peer(2), 10);
+        lanes.push(peer(3), 20);
+        let taken: Vec<i32> = std::iter::from_fn(|| lanes.try_next().map(|(item, _)| item)).collect();
+        assert_eq!(taken, vec![0, 10, 20, 1]);
+    }
+
+    #[test]
+    fn full_lanes_refuse_items() {
+        let lanes = Lanes::new();
+        for item in 0..MAX_LANE_LENGTH {
+            assert!(lanes.push(peer(1), item));
+        }
+        assert!(!lanes.push(peer(1), MAX_LANE_LENGTH));
+        assert!(lanes.push(peer(2), 0));
+        assert_eq!(lanes.len(), MAX_LANE_LENGTH + 1);
+    }
+
+    #[test]
+    fn closing_wakes_the_waiting_threads() {
+        let lanes: Lanes<i32> = Lanes::new();
+        let waiting = lanes.clone();
+        let thread = thread::spawn(move || waiting.next().is_none());
+        thread::sleep(Duration::from_millis(50));
+        lanes.close();
+        assert!(thread.join().unwrap());
+        assert!(!lanes.push(peer(1), 0));
+    }
+
+    #[test]
+    fn a_panicking_thread_gives_up_its_lane() {
+        let lanes = Lanes::new();
+        lanes.push(peer(1), 0);
+        lanes.push(peer(1), 1);
+        let panicking = lanes.clone();
+        let result = thread::spawn(move || {
+            let (_item, _turn) = panicking.next().unwrap();
+            panic!("validation failed unexpectedly");
+        }).join();
+        assert!(result.is_err());
+        assert_eq!(lanes.try_next().map(|(item, _)| item), Some(1));
+    }
+}
@@ -0,0 +1,25 @@
+//! Caps on what goes over the wire, in one place so that what we send respects the same
+//! limits as what we accept.
+
+/// Largest frame read from a peer unless configured otherwise; a peer announcing a larger one is
+/// disconnected before any of it is read
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+/// Largest message payload we are willing to decode, in bytes, and so the most a frame may be
+/// configured to
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+/// Most hashes in a single announcement or request
+pub const MAX_HASHES_PER_MESSAGE: usize = 4096;
+/// Most blocks in a single `Blocks` message
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 500;
+/// Most bytes of blocks we put in a single `Blocks` message; a block on its own may exceed it
+pub const MAX_BLOCKS_MESSAGE_BYTES: u64 = 2 * 1024 * 1024;
+/// Most headers in a single `Headers` message
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// Most transactions in a single `Transactions` message
+pub const MAX_TRANSACTIONS_PER_MESSAGE: usize = 4096;
+/// Most peer addresses in a single `Addr` message
+pub const MAX_ADDRS_PER_MESSAGE: usize = 1000;
+/// Most bytes in the reason a peer gives for disconnecting
+pub const MAX_DISCONNECT_REASON_LENGTH: usize = 256;
+/// Messages of more bytes than this are sent compressed, to peers that read compressed messages
+pub const COMPRESSION_THRESHOLD: usize = 4 * 1024;
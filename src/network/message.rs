@@ -1,16 +1,368 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 use crate::crypto::hash::H256;
-use crate::block::Block;
+use crate::block::{Block, Header, MAX_TRANSACTIONS_PER_BLOCK};
 use crate::transaction::SignedTransaction;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use super::limits::{COMPRESSION_THRESHOLD, MAX_ADDRS_PER_MESSAGE, MAX_BLOCKS_PER_MESSAGE, MAX_DISCONNECT_REASON_LENGTH, MAX_HASHES_PER_MESSAGE, MAX_HEADERS_PER_MESSAGE, MAX_MESSAGE_SIZE, MAX_TRANSACTIONS_PER_MESSAGE};
+
+/// Version of this protocol, which peers must share to talk
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Service bit of a node that reads `Compressed` messages
+pub const SERVICE_COMPRESSION: u64 = 1;
+/// Names of the message types, in the order of `Message`'s variants
+pub const MESSAGE_KINDS: [&str; 25] = [
+    "Ping", "Pong", "NewBlockHashes", "GetBlocks", "Blocks", "NewTransactionHashes", "GetTransactions",
+    "Transactions", "GetMempool", "GetChain", "StateHash", "Status", "GetHeaders", "Headers", "Version",
+    "Verack", "GetAddr", "Addr", "CompactBlock", "GetBlockTxn", "BlockTxn", "Compressed",
+    "GetTip", "Tip", "Disconnect",
+];
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
-    Ping(String),
-    Pong(String),
+    Ping(u64),
+    Pong(u64),
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
-    Blocks(Vec<Block>),
+    Blocks(Vec<Arc<Block>>),
+    /// Txids, which leave the signatures out
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    GetMempool,
+    /// Ask for the hashes of the longest chain after the first block of this locator we share
+    GetChain(Vec<H256>),
+    /// The hash of the sender's state after a block: (block hash, state hash)
+    StateHash(H256, H256),
+    /// The sender's tip and its height, sent to every peer on completing the handshake
+    Status(H256, u64),
+    /// Ask for the headers of the longest chain after the first block of this locator we share
+    GetHeaders(Vec<H256>),
+    /// Consecutive headers, each the parent of the next
+    Headers(Vec<Header>),
+    /// The first message on a connection, from each side; none but the handshake's are read
+    /// before it. `node_nonce` is random per node, to tell a connection to ourselves, and
+    /// `listen_port` is where the sender accepts connections, zero if it does not. `services`
+    /// are the optional features the sender supports, as `SERVICE_*` bits; it comes last, so
+    /// that nodes from before it read the rest and ignore it, and is zero from those nodes.
+    Version {
+        protocol: u32,
+        genesis: H256,
+        tip_height: u64,
+        node_nonce: u64,
+        listen_port: u16,
+        #[serde(deserialize_with = "zero_if_missing")]
+        services: u64,
+    },
+    /// Acknowledges a peer's `Version`
+    Verack,
+    /// Ask for addresses of other peers to connect to
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    /// A new block by its header, its coinbase, which no mempool has, and the txids of its other
+    /// transactions, for the receiver to rebuild from its mempool
+    CompactBlock { header: Header, coinbase: Box<SignedTransaction>, txids: Vec<H256> },
+    /// Ask for the transactions of a block at these positions
+    GetBlockTxn { block: H256, indexes: Vec<u32> },
+    /// The transactions asked for by a `GetBlockTxn`, in the order asked
+    BlockTxn { block: H256, txs: Vec<SignedTransaction> },
+    /// Another message, encoded and then compressed with snappy, sent only to peers that
+    /// announced `SERVICE_COMPRESSION`
+    Compressed(Vec<u8>),
+    /// Ask for the sender's tip, sent periodically so that a node that missed announcements
+    /// notices it fell behind
+    GetTip,
+    /// The receiver's tip and its height, answering a `GetTip`
+    Tip { hash: H256, height: u64 },
+    /// The sender is closing the connection, say as it shuts down; the receiver drops it
+    /// rather than finding out on its next write
+    Disconnect { reason: String },
+}
+
+/// A trailing field that nodes from before it leave out of the message
+fn zero_if_missing<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    Ok(u64::deserialize(deserializer).unwrap_or(0))
+}
+
+impl Message {
+    /// Decode a message received from a peer, rejecting oversized payloads and
+    /// messages that break the structural caps above.
+    pub fn decode(bytes: &[u8]) -> Result<Message, String> {
+        if bytes.len() as u64 > MAX_MESSAGE_SIZE {
+            return Err(format!("message of {} bytes exceeds the size limit", bytes.len()));
+        }
+        let msg: Message = bincode::config()
+            .limit(MAX_MESSAGE_SIZE)
+            .deserialize(bytes)
+            .map_err(|e| format!("malformed message: {}", e))?;
+        msg.check_limits()?;
+        Ok(msg)
+    }
+
+    /// Compress the encoding of a message over `COMPRESSION_THRESHOLD` bytes into the encoding
+    /// of a `Compressed` message; `None` if it is under the threshold or does not shrink
+    pub fn compress(encoded: &[u8]) -> Option<Vec<u8>> {
+        if encoded.len() <= COMPRESSION_THRESHOLD {
+            return None;
+        }
+        let compressed = snap::raw::Encoder::new().compress_vec(encoded).ok()?;
+        let wrapped = bincode::serialize(&Message::Compressed(compressed)).unwrap();
+        if wrapped.len() < encoded.len() {
+            Some(wrapped)
+        } else {
+            None
+        }
+    }
+
+    /// Decompress and decode the message in a `Compressed` payload, returning it with the size
+    /// of its encoding. The size the payload claims is checked before anything is decompressed,
+    /// so that a small payload cannot make us allocate more than `MAX_MESSAGE_SIZE`; a
+    /// compressed message inside is rejected.
+    pub fn decompress(payload: &[u8]) -> Result<(Message, usize), String> {
+        let size = snap::raw::decompress_len(payload).map_err(|e| format!("malformed compressed message: {}", e))?;
+        if size as u64 > MAX_MESSAGE_SIZE {
+            return Err(format!("compressed message of {} bytes exceeds the size limit", size));
+        }
+        let bytes = snap::raw::Decoder::new().decompress_vec(payload)
+            .map_err(|e| format!("malformed compressed message: {}", e))?;
+        match Message::decode(&bytes)? {
+            Message::Compressed(_) => Err("compressed message inside a compressed message".to_string()),
+            msg => Ok((msg, bytes.len())),
+        }
+    }
+
+    /// Announce `block` compactly, by its header, coinbase and txids; `None` if it has no
+    /// transactions, not even a coinbase
+    pub fn compact_block(block: &Block) -> Option<Message> {
+        let (coinbase, transactions) = block.content.transactions.split_first()?;
+        Some(Message::CompactBlock {
+            header: block.header.clone(),
+            coinbase: Box::new(coinbase.clone()),
+            txids: transactions.iter().map(|tx| tx.txid()).collect(),
+        })
+    }
+
+    /// Whether `bytes` encode an announcement, which can be dropped when the workers fall
+    /// behind: peers announce again, and others announce the same. Tells by the variant alone,
+    /// without decoding the rest.
+    pub fn is_encoded_announcement(bytes: &[u8]) -> bool {
+        let tag = match bytes.get(..4) {
+            Some(tag) => tag,
+            None => return false,
+        };
+        [Message::NewBlockHashes(Vec::new()), Message::NewTransactionHashes(Vec::new())].iter()
+            .any(|announcement| bincode::serialize(announcement).unwrap()[..4] == *tag)
+    }
+
+    /// Whether this answers a request of ours, rather than being sent unasked
+    pub fn is_response(&self) -> bool {
+        matches!(self, Message::Pong(_) | Message::Blocks(_) | Message::Transactions(_) | Message::Headers(_)
+            | Message::Addr(_) | Message::BlockTxn { .. } | Message::Tip { .. })
+    }
+
+    /// The position of the message's type among `MESSAGE_KINDS`, which is also its variant tag
+    /// on the wire
+    pub fn kind_index(&self) -> usize {
+        match self {
+            Message::Ping(_) => 0,
+            Message::Pong(_) => 1,
+            Message::NewBlockHashes(_) => 2,
+            Message::GetBlocks(_) => 3,
+            Message::Blocks(_) => 4,
+            Message::NewTransactionHashes(_) => 5,
+            Message::GetTransactions(_) => 6,
+            Message::Transactions(_) => 7,
+            Message::GetMempool => 8,
+            Message::GetChain(_) => 9,
+            Message::StateHash(..) => 10,
+            Message::Status(..) => 11,
+            Message::GetHeaders(_) => 12,
+            Message::Headers(_) => 13,
+            Message::Version { .. } => 14,
+            Message::Verack => 15,
+            Message::GetAddr => 16,
+            Message::Addr(_) => 17,
+            Message::CompactBlock { .. } => 18,
+            Message::GetBlockTxn { .. } => 19,
+            Message::BlockTxn { .. } => 20,
+            Message::Compressed(_) => 21,
+            Message::GetTip => 22,
+            Message::Tip { .. } => 23,
+            Message::Disconnect { .. } => 24,
+        }
+    }
+
+    /// The name of the message's type, to count traffic by
+    pub fn kind(&self) -> &'static str {
+        MESSAGE_KINDS[self.kind_index()]
+    }
+
+    /// Check the per-type structural caps
+    fn check_limits(&self) -> Result<(), String> {
+        match self {
+            Message::Ping(_) | Message::Pong(_) | Message::GetMempool | Message::StateHash(..) | Message::Status(..)
+            | Message::Version { .. } | Message::Verack | Message::GetAddr | Message::Compressed(_)
+            | Message::GetTip | Message::Tip { .. } => Ok(()),
+            Message::Addr(addrs) => check_count("addresses", addrs.len(), MAX_ADDRS_PER_MESSAGE),
+            Message::NewBlockHashes(hashes)
+            | Message::GetBlocks(hashes)
+            | Message::NewTransactionHashes(hashes)
+            | Message::GetTransactions(hashes)
+            | Message::GetChain(hashes)
+            | Message::GetHeaders(hashes) => {
+                check_count("hashes", hashes.len(), MAX_HASHES_PER_MESSAGE)
+            }
+            Message::Blocks(blocks) => {
+                check_count("blocks", blocks.len(), MAX_BLOCKS_PER_MESSAGE)?;
+                for block in blocks {
+                    check_count("transactions in a block", block.content.transactions.len(), MAX_TRANSACTIONS_PER_BLOCK)?;
+                }
+                Ok(())
+            }
+            Message::Transactions(transactions) => {
+                check_count("transactions", transactions.len(), MAX_TRANSACTIONS_PER_MESSAGE)
+            }
+            Message::Headers(headers) => check_count("headers", headers.len(), MAX_HEADERS_PER_MESSAGE),
+            Message::Disconnect { reason } => check_count("bytes of disconnect reason", reason.len(), MAX_DISCONNECT_REASON_LENGTH),
+            Message::CompactBlock { txids, .. } => check_count("transactions in a block", txids.len(), MAX_TRANSACTIONS_PER_BLOCK),
+            Message::GetBlockTxn { indexes, .. } => check_count("transactions in a block", indexes.len(), MAX_TRANSACTIONS_PER_BLOCK),
+            Message::BlockTxn { txs, .. } => check_count("transactions in a block", txs.len(), MAX_TRANSACTIONS_PER_BLOCK),
+        }
+    }
+}
+
+fn check_count(what: &str, count: usize, max: usize) -> Result<(), String> {
+    if count > max {
+        Err(format!("{} {} exceed the limit of {}", count, what, max))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    /// Encode a variant tag followed by a (possibly huge) length prefix and no data
+    fn length_bomb(variant: u32, length: u64) -> Vec<u8> {
+        let mut bytes = variant.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn length_prefix_bombs_are_rejected() {
+        // every variant but Ping and Pong starts with a length-prefixed vector
+        for variant in 2..8 {
+            for &length in &[u64::MAX, 1 << 40, MAX_MESSAGE_SIZE + 1, 1 << 20] {
+                assert!(Message::decode(&length_bomb(variant, length)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn nested_length_bomb_is_rejected() {
+        // a single block whose transaction list claims billions of entries
+        let block = generate_random_block(&Default::default());
+        let mut bytes = bincode::serialize(&Message::Blocks(vec![Arc::new(block)])).unwrap();
+        let tx_count_offset = 4 + 8 + bincode::serialize(&Block::genesis().header).unwrap().len();
+        bytes[tx_count_offset..tx_count_offset + 8].copy_from_slice(&(1u64 << 35).to_le_bytes());
+        assert!(Message::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn structural_caps_are_enforced() {
+        let hashes = vec![H256::default(); MAX_HASHES_PER_MESSAGE + 1];
+        let bytes = bincode::serialize(&Message::GetBlocks(hashes)).unwrap();
+        assert!(Message::decode(&bytes).is_err());
+
+        let mut block = generate_random_block(&Default::default());
+        block.content.transactions = vec![block.content.transactions[0].clone(); MAX_TRANSACTIONS_PER_BLOCK + 1];
+        let bytes = bincode::serialize(&Message::Blocks(vec![Arc::new(block)])).unwrap();
+        assert!(Message::decode(&bytes).is_err());
+
+        let hashes = vec![H256::default(); MAX_HASHES_PER_MESSAGE];
+        let bytes = bincode::serialize(&Message::NewBlockHashes(hashes)).unwrap();
+        assert!(Message::decode(&bytes).is_ok());
+    }
+
+    #[test]
+    fn announcements_are_told_apart_undecoded() {
+        for announcement in &[Message::NewBlockHashes(vec![H256::default()]), Message::NewTransactionHashes(Vec::new())] {
+            assert!(Message::is_encoded_announcement(&bincode::serialize(announcement).unwrap()));
+        }
+        for other in &[Message::Ping(1), Message::GetBlocks(vec![H256::default()]), Message::Transactions(Vec::new()), Message::Verack] {
+            assert!(!Message::is_encoded_announcement(&bincode::serialize(other).unwrap()));
+        }
+        assert!(!Message::is_encoded_announcement(&[]));
+    }
+
+    #[test]
+    fn large_blocks_round_trip_compressed() {
+        use crate::address::{get_deterministic_keypair, H160};
+        use crate::transaction::{ChainId, RawTransaction};
+        use ring::signature::KeyPair;
+
+        // transfers among ten accounts, as the experiments make
+        let keys: Vec<_> = (0..10).map(get_deterministic_keypair).collect();
+        let mut block = generate_random_block(&Default::default());
+        block.content.transactions.extend((0..500).map(|nonce| {
+            let key = &keys[nonce % keys.len()];
+            let to = H160::from_pubkey(keys[(nonce + 1) % keys.len()].public_key().as_ref());
+            let raw = RawTransaction {
+                from_addr: H160::from_pubkey(key.public_key().as_ref()),
+                outputs: vec![(to, 10)],
+                fee: 1,
+                nonce: nonce as u32,
+                valid_until_block: 0,
+                data: Vec::new(),
+            };
+            SignedTransaction::from_raw(raw, key, &ChainId::default())
+        }));
+        let encoded = bincode::serialize(&Message::Blocks(vec![Arc::new(block)])).unwrap();
+        let compressed = Message::compress(&encoded).unwrap();
+        assert!(compressed.len() < encoded.len() * 3 / 4, "{} bytes compressed from {}", compressed.len(), encoded.len());
+
+        let (msg, size) = match Message::decode(&compressed).unwrap() {
+            Message::Compressed(payload) => Message::decompress(&payload).unwrap(),
+            other => panic!("expected a compressed message, got {:?}", other),
+        };
+        assert_eq!(size, encoded.len());
+        assert!(matches!(&msg, Message::Blocks(blocks) if blocks[0].content.transactions.len() == 501));
+        assert_eq!(bincode::serialize(&msg).unwrap(), encoded);
+
+        // small messages are not worth it
+        assert!(Message::compress(&bincode::serialize(&Message::Ping(1)).unwrap()).is_none());
+    }
+
+    #[test]
+    fn decompression_bombs_are_rejected() {
+        // a snappy payload starts with its decompressed length as a varint: claim 1 GiB
+        let mut bomb = vec![0x80, 0x80, 0x80, 0x80, 0x04];
+        bomb.extend_from_slice(&[0; 64]);
+        let err = Message::decompress(&bomb).unwrap_err();
+        assert!(err.contains("size limit"), "{}", err);
+
+        // just over the limit, however well it compresses
+        let oversized = vec![0; MAX_MESSAGE_SIZE as usize + 1];
+        let payload = snap::raw::Encoder::new().compress_vec(&oversized).unwrap();
+        assert!(Message::decompress(&payload).is_err());
+
+        // nor may compressed messages nest
+        let inner = Message::compress(&bincode::serialize(&Message::GetBlocks(vec![H256::default(); 1000])).unwrap()).unwrap();
+        let nested = snap::raw::Encoder::new().compress_vec(&inner).unwrap();
+        assert!(Message::decompress(&nested).is_err());
+    }
+
+    #[test]
+    fn versions_from_before_services_are_read() {
+        let genesis = H256::from([1; 32]);
+        let current = Message::Version { protocol: PROTOCOL_VERSION, genesis, tip_height: 5, node_nonce: 9, listen_port: 6000, services: SERVICE_COMPRESSION };
+        let encoded = bincode::serialize(&current).unwrap();
+        // an older node sent the same fields but services
+        let older = Message::decode(&encoded[..encoded.len() - 8]).unwrap();
+        assert!(matches!(older, Message::Version { tip_height: 5, listen_port: 6000, services: 0, .. }));
+        assert!(matches!(Message::decode(&encoded).unwrap(), Message::Version { services: SERVICE_COMPRESSION, .. }));
+    }
 }
@@ -1,4 +1,16 @@
+pub mod addr_book;
+#[cfg(feature = "emulation")]
+pub mod emulation;
+pub mod header_sync;
+pub mod in_flight;
+pub mod lanes;
+pub mod inventory;
+pub mod limits;
 pub mod message;
 pub mod peer;
+pub mod peer_manager;
+pub mod request_tracker;
 pub mod server;
+pub mod traffic;
+pub mod transport;
 pub mod worker;
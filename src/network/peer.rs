@@ -1,10 +1,20 @@
+#[cfg(feature = "emulation")]
+use super::emulation::Fate;
+use super::inventory::KnownInventory;
 use super::message;
-use log::{trace, warn};
+use super::peer_manager::PeerManager;
+use super::traffic::NetStats;
+use crate::crypto::hash::H256;
+use log::{debug, trace};
 use mio;
 use mio_extras::channel;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 enum DecodeState {
     Length,
@@ -15,6 +25,8 @@ pub enum ReadResult {
     Continue,
     Message(Vec<u8>),
     EOF,
+    /// The peer announced a frame of this many bytes, over the limit; nothing of it was read
+    Oversized(usize),
 }
 
 pub struct ReadContext {
@@ -23,6 +35,7 @@ pub struct ReadContext {
     msg_length: usize,
     read_length: usize,
     state: DecodeState,
+    max_frame_size: usize,
 }
 
 impl ReadContext {
@@ -45,6 +58,10 @@ impl ReadContext {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            // checked before allocating anything for it
+                            if message_length as usize > self.max_frame_size {
+                                return Ok(ReadResult::Oversized(message_length as usize));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -158,6 +175,9 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    max_frame_size: usize,
+    net_stats: Arc<NetStats>,
+    peer_manager: Arc<Mutex<PeerManager>>,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
@@ -169,6 +189,7 @@ pub fn new(
         msg_length: std::mem::size_of::<u32>(),
         read_length: 0,
         state: DecodeState::Length,
+        max_frame_size,
     };
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
@@ -182,8 +203,15 @@ pub fn new(
         state: WriteState::Payload,
     };
     let handle = Handle {
+        id: PeerId::next(),
         write_queue: write_sender,
         addr,
+        keepalive: Arc::new(Mutex::new(Keepalive::new())),
+        last_mempool_request: Arc::new(Mutex::new(None)),
+        handshake: Arc::new(Mutex::new(Handshake::default())),
+        known_inventory: Arc::new(Mutex::new(KnownInventory::default())),
+        net_stats,
+        peer_manager,
     };
     let ctx = Context {
         addr,
@@ -196,7 +224,38 @@ pub fn new(
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+/// A write to a peer whose connection the server dropped
+#[derive(Debug)]
+pub struct Disconnected {
+    addr: std::net::SocketAddr,
+    kind: &'static str,
+}
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "not sending {} to peer {}, disconnected", self.kind, self.addr)
+    }
+}
+
+/// Tells connections apart for as long as the node runs: unlike an address or a slot in the
+/// server, never reused by a later connection. Later connections get greater ids.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerId(u64);
+
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl PeerId {
+    pub(super) fn next() -> PeerId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        PeerId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -213,16 +272,355 @@ pub struct Context {
 
 #[derive(Clone)]
 pub struct Handle {
+    id: PeerId,
     addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
+    keepalive: Arc<Mutex<Keepalive>>,
+    /// When this peer last asked for our mempool
+    last_mempool_request: Arc<Mutex<Option<Instant>>>,
+    handshake: Arc<Mutex<Handshake>>,
+    /// Hashes the peer has, not to announce to it
+    known_inventory: Arc<Mutex<KnownInventory>>,
+    /// Counts what is written to any peer
+    net_stats: Arc<NetStats>,
+    /// Counts what is written to this peer
+    peer_manager: Arc<Mutex<PeerManager>>,
 }
 
 impl Handle {
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    /// The remote address of the peer
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Queue `msg` for the peer, logging rather than failing if it is disconnected
     pub fn write(&self, msg: message::Message) {
-        // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
-        if self.write_queue.send(buffer).is_err() {
-            warn!("Failed to send write request for peer {}, channel detached", self.addr);
+        if let Err(e) = self.try_write(msg) {
+            debug!("{}", e);
+        }
+    }
+
+    /// Queue `msg` for the peer; fails once the server dropped the connection
+    pub fn try_write(&self, msg: message::Message) -> Result<(), Disconnected> {
+        let mut buffer = bincode::serialize(&msg).unwrap();
+        if self.reads_compressed() {
+            if let Some(compressed) = message::Message::compress(&buffer) {
+                self.net_stats.record_compressed_sent(buffer.len(), compressed.len());
+                buffer = compressed;
+            }
+        }
+        let bytes = buffer.len();
+        #[cfg(feature = "emulation")]
+        {
+            let links = self.peer_manager.lock().unwrap().links();
+            match links.send(self.id, buffer, &self.write_queue) {
+                Err(_) => return Err(Disconnected { addr: self.addr, kind: msg.kind() }),
+                Ok(Fate::Lost) => self.net_stats.record_injected_drop(),
+                // counted once queued, even if the peer disconnects before a delayed one is due
+                Ok(_) => {
+                    self.net_stats.record_sent(&msg, bytes);
+                    self.peer_manager.lock().unwrap().record_sent(self.id, bytes);
+                }
+            }
+            Ok(())
+        }
+        #[cfg(not(feature = "emulation"))]
+        {
+            if self.write_queue.send(buffer).is_err() {
+                return Err(Disconnected { addr: self.addr, kind: msg.kind() });
+            }
+            self.net_stats.record_sent(&msg, bytes);
+            self.peer_manager.lock().unwrap().record_sent(self.id, bytes);
+            Ok(())
+        }
+    }
+
+    /// Send a ping with a fresh nonce that only a matching pong on this connection can answer
+    pub fn ping(&self) {
+        let nonce = self.keepalive.lock().unwrap().new_ping(Instant::now());
+        self.write(message::Message::Ping(nonce));
+    }
+
+    /// Handle a pong; returns the round-trip time, or `None` if the pong is late, duplicated or unsolicited
+    pub fn pong(&self, nonce: u64) -> Option<Duration> {
+        let rtt = self.keepalive.lock().unwrap().on_pong(nonce, Instant::now());
+        if rtt.is_none() {
+            trace!("Ignoring unexpected pong {} from peer {}", nonce, self.addr);
+        }
+        rtt
+    }
+
+    /// Whether to serve this peer's mempool request, allowing at most one per `interval`
+    pub fn allow_mempool_request(&self, interval: Duration) -> bool {
+        let mut last = self.last_mempool_request.lock().unwrap();
+        let now = Instant::now();
+        match *last {
+            Some(previous) if now.duration_since(previous) < interval => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+
+    /// The round-trip time measured by the last answered ping
+    pub fn rtt(&self) -> Option<Duration> {
+        self.keepalive.lock().unwrap().last_rtt
+    }
+
+    /// How many pings in a row went unanswered for `timeout`, as of `now`
+    pub fn missed_pongs(&self, timeout: Duration, now: Instant) -> u32 {
+        self.keepalive.lock().unwrap().expire(timeout, now)
+    }
+
+    /// Record the peer's `Version`, with the services both of us support. Returns `None` if it
+    /// sent one already, else whether this completed the handshake.
+    pub fn record_version(&self, services: u64) -> Option<bool> {
+        let mut handshake = self.handshake.lock().unwrap();
+        if handshake.version_received {
+            return None;
+        }
+        handshake.version_received = true;
+        handshake.services = services;
+        Some(handshake.verack_received)
+    }
+
+    /// Record the peer's `Verack`, as `record_version` does its `Version`
+    pub fn record_verack(&self) -> Option<bool> {
+        let mut handshake = self.handshake.lock().unwrap();
+        if handshake.verack_received {
+            return None;
+        }
+        handshake.verack_received = true;
+        Some(handshake.version_received)
+    }
+
+    /// Whether the peer introduced itself, so that its other messages may be read
+    pub fn version_received(&self) -> bool {
+        self.handshake.lock().unwrap().version_received
+    }
+
+    /// Whether to compress large messages to the peer
+    pub fn reads_compressed(&self) -> bool {
+        self.handshake.lock().unwrap().services & message::SERVICE_COMPRESSION != 0
+    }
+
+    /// Whether the handshake is complete both ways, so that the peer reads what we send
+    pub fn is_ready(&self) -> bool {
+        let handshake = self.handshake.lock().unwrap();
+        handshake.version_received && handshake.verack_received
+    }
+
+    /// Record blocks or transactions the peer announced or sent us
+    pub fn add_known_inventory(&self, hashes: impl IntoIterator<Item = H256>) {
+        let mut known = self.known_inventory.lock().unwrap();
+        for hash in hashes {
+            known.insert(hash);
+        }
+    }
+
+    pub fn knows_inventory(&self, hash: &H256) -> bool {
+        self.known_inventory.lock().unwrap().contains(hash)
+    }
+
+    /// Forget what the peer has, once it is gone
+    pub fn clear_known_inventory(&self) {
+        *self.known_inventory.lock().unwrap() = KnownInventory::default();
+    }
+
+    /// Of `hashes` about to be announced to the peer, those it does not have; all of them are
+    /// known to it afterwards
+    pub fn take_unknown_inventory(&self, hashes: &[H256]) -> Vec<H256> {
+        let mut known = self.known_inventory.lock().unwrap();
+        let unknown = hashes.iter().filter(|hash| !known.contains(hash)).cloned().collect();
+        for hash in hashes {
+            known.insert(*hash);
+        }
+        unknown
+    }
+}
+
+#[cfg(test)]
+impl Handle {
+    /// A handle to no connection, for tests: what is written to the peer comes out of the receiver
+    pub fn detached(addr: std::net::SocketAddr) -> (Handle, channel::Receiver<Vec<u8>>) {
+        let (write_queue, written) = channel::channel();
+        let handle = Handle {
+            id: PeerId::next(),
+            addr,
+            write_queue,
+            keepalive: Arc::new(Mutex::new(Keepalive::new())),
+            last_mempool_request: Arc::new(Mutex::new(None)),
+            handshake: Arc::new(Mutex::new(Handshake::default())),
+            known_inventory: Arc::new(Mutex::new(KnownInventory::default())),
+            net_stats: Arc::new(NetStats::default()),
+            peer_manager: Arc::new(Mutex::new(PeerManager::default())),
+        };
+        (handle, written)
+    }
+
+    /// Skip the handshake, as if the peer had sent its `Version` and acknowledged ours
+    pub fn complete_handshake(&self) {
+        let mut handshake = self.handshake.lock().unwrap();
+        handshake.version_received = true;
+        handshake.verack_received = true;
+    }
+}
+
+/// How far a connection got through the version handshake. Each side sends its `Version` on
+/// connecting and acknowledges the other's with a `Verack`, and sends nothing else until its own
+/// `Version` is acknowledged: the peer has recorded it by then, and reads what follows.
+#[derive(Default)]
+struct Handshake {
+    version_received: bool,
+    verack_received: bool,
+    /// The services of the peer's `Version` that we support as well
+    services: u64,
+}
+
+/// The pings sent on one connection that are still waiting for their pong
+struct Keepalive {
+    /// Nonce of each outstanding ping, and when it was sent
+    pending: HashMap<u64, Instant>,
+    last_rtt: Option<Duration>,
+    /// Pongs that matched no outstanding ping
+    unexpected_pongs: u64,
+    /// Pings that timed out since the last pong
+    missed: u32,
+}
+
+impl Keepalive {
+    fn new() -> Self {
+        Keepalive {
+            pending: HashMap::new(),
+            last_rtt: None,
+            unexpected_pongs: 0,
+            missed: 0,
+        }
+    }
+
+    fn new_ping(&mut self, now: Instant) -> u64 {
+        let nonce = rand::random();
+        self.pending.insert(nonce, now);
+        nonce
+    }
+
+    /// Each nonce is answered at most once; anything else is counted and ignored
+    fn on_pong(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.pending.remove(&nonce) {
+            Some(sent) => {
+                let rtt = now.duration_since(sent);
+                self.last_rtt = Some(rtt);
+                self.missed = 0;
+                Some(rtt)
+            }
+            None => {
+                self.unexpected_pongs += 1;
+                None
+            }
+        }
+    }
+
+    /// Give up on the pings outstanding for `timeout`; returns how many were missed since the last pong
+    fn expire(&mut self, timeout: Duration, now: Instant) -> u32 {
+        let before = self.pending.len();
+        self.pending.retain(|_, sent| now.saturating_duration_since(*sent) < timeout);
+        self.missed += (before - self.pending.len()) as u32;
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replayed_pong_is_ignored() {
+        let mut keepalive = Keepalive::new();
+        let start = Instant::now();
+        let first = keepalive.new_ping(start);
+        assert_eq!(keepalive.on_pong(first, start + Duration::from_millis(30)), Some(Duration::from_millis(30)));
+
+        // a second ping is outstanding when the first pong is replayed
+        let second = keepalive.new_ping(start + Duration::from_millis(100));
+        assert_eq!(keepalive.on_pong(first, start + Duration::from_millis(500)), None);
+        assert_eq!(keepalive.unexpected_pongs, 1);
+        assert!(keepalive.pending.contains_key(&second));
+        assert_eq!(keepalive.last_rtt, Some(Duration::from_millis(30)));
+
+        // a pong meant for another connection cannot match either
+        assert_eq!(keepalive.on_pong(second.wrapping_add(1), start + Duration::from_millis(500)), None);
+        assert!(keepalive.pending.contains_key(&second));
+        assert_eq!(keepalive.on_pong(second, start + Duration::from_millis(110)), Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn unanswered_pings_add_up_until_a_pong() {
+        let mut keepalive = Keepalive::new();
+        let start = Instant::now();
+        let interval = Duration::from_secs(30);
+        let mut nonces = Vec::new();
+        for i in 0..3 {
+            let now = start + interval * i;
+            assert_eq!(keepalive.expire(interval, now), i);
+            nonces.push(keepalive.new_ping(now));
         }
+        // not missed until it times out
+        assert_eq!(keepalive.expire(interval, start + interval * 3 - Duration::from_millis(1)), 2);
+        assert_eq!(keepalive.expire(interval, start + interval * 3), 3);
+        // a pong that comes too late no longer counts
+        assert_eq!(keepalive.on_pong(nonces[0], start + interval * 3), None);
+
+        let nonce = keepalive.new_ping(start + interval * 3);
+        assert!(keepalive.on_pong(nonce, start + interval * 3 + Duration::from_millis(40)).is_some());
+        assert_eq!(keepalive.expire(interval, start + interval * 4), 0);
+    }
+
+    #[test]
+    fn oversized_frame_is_refused_unread() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = mio::net::TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let (mut ctx, _handle) = new(stream, Direction::Incoming, 1024, Arc::new(NetStats::default()), Arc::new(Mutex::new(PeerManager::default()))).unwrap();
+
+        remote.write_all(&(1u32 << 30).to_be_bytes()).unwrap();
+        let start = Instant::now();
+        let result = loop {
+            match ctx.reader.read() {
+                Ok(ReadResult::Continue) => continue,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    assert!(start.elapsed() < Duration::from_secs(10), "nothing read");
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                other => break other,
+            }
+        };
+        assert!(matches!(result, Ok(ReadResult::Oversized(length)) if length == 1 << 30));
+        // nothing was set aside for the frame
+        assert!(ctx.reader.buffer.capacity() < 1024);
+    }
+
+    #[cfg(feature = "emulation")]
+    #[test]
+    fn messages_lost_on_an_emulated_link_are_counted_as_drops() {
+        use crate::network::emulation::LinkConditions;
+
+        let (handle, written) = Handle::detached("127.0.0.1:6001".parse().unwrap());
+        let lossy = LinkConditions { delay_ms: 0, jitter_ms: 0, loss_rate: 1.0 };
+        handle.peer_manager.lock().unwrap().links().set(handle.id, lossy);
+        handle.write(message::Message::Ping(1));
+        assert!(written.try_recv().is_err());
+        let snapshot = handle.net_stats.snapshot();
+        assert_eq!(snapshot.injected_drops, 1);
+        assert!(snapshot.by_type.is_empty());
+
+        handle.peer_manager.lock().unwrap().links().forget(handle.id);
+        handle.write(message::Message::Ping(2));
+        assert!(written.try_recv().is_ok());
+        assert_eq!(handle.net_stats.snapshot().by_type["Ping"].sent, 1);
     }
 }
@@ -0,0 +1,398 @@
+//! The peers connected now, the misbehavior scores of peers, the bans they earn, and the rate
+//! limits they are held to.
+//!
+//! Connections are told apart by their `PeerId`, which no later connection reuses. Scores, bans
+//! and rate limits are kept by address and port, so that they outlive a connection, but not by
+//! IP alone: the nodes of an experiment all run on one host, and banning by IP would cut a node
+//! off from every honest neighbour.
+
+#[cfg(feature = "emulation")]
+use super::emulation::LinkEmulator;
+use super::peer::{self, Direction, PeerId};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(feature = "emulation")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Points for a frame that does not decode into a message
+pub const MALFORMED_MESSAGE_PENALTY: u32 = 20;
+/// Points for a block that breaks a consensus rule, which an honest peer checks before relaying
+pub const INVALID_BLOCK_PENALTY: u32 = 100;
+/// Points for a transaction that no honest node would relay, such as one with a bad signature
+pub const INVALID_TRANSACTION_PENALTY: u32 = 10;
+/// Points for a message before the version handshake completes, or a repeated handshake message
+pub const HANDSHAKE_PENALTY: u32 = 10;
+
+/// Points for every `DROPS_PER_PENALTY` messages dropped for exceeding the rate limits
+pub const RATE_LIMIT_PENALTY: u32 = 10;
+pub const DROPS_PER_PENALTY: u64 = 50;
+
+/// A peer reaching this score is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// Points a score loses per second
+pub const SCORE_DECAY_PER_SEC: u32 = 1;
+/// How long a ban lasts unless configured otherwise
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(3600);
+
+/// How much a single peer may send us; a second's worth can come in a burst
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits { messages_per_sec: 500, bytes_per_sec: 8 * 1024 * 1024 }
+    }
+}
+
+/// Token buckets for a peer's messages and bytes, as of `updated`
+struct Buckets {
+    messages: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+/// A peer's score as of `updated`
+struct Score {
+    points: u32,
+    updated: Instant,
+}
+
+impl Score {
+    fn at(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs();
+        let decay = elapsed.saturating_mul(SCORE_DECAY_PER_SEC as u64);
+        self.points.saturating_sub(decay.min(u32::MAX as u64) as u32)
+    }
+}
+
+/// A connection, from when it was registered until it is dropped
+struct Connection {
+    addr: SocketAddr,
+    direction: Direction,
+    connected_since: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// When the peer's last message was decoded
+    last_seen: Option<Instant>,
+}
+
+/// What we know of a connected peer, as of when it was asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    pub connected_for: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// How long ago its last message came; `None` if none has yet
+    pub last_seen: Option<Duration>,
+}
+
+pub struct PeerManager {
+    connections: HashMap<PeerId, Connection>,
+    scores: HashMap<SocketAddr, Score>,
+    /// When each ban ends
+    bans: HashMap<SocketAddr, Instant>,
+    ban_duration: Duration,
+    limits: RateLimits,
+    buckets: HashMap<SocketAddr, Buckets>,
+    /// Messages dropped for exceeding the rate limits, per peer
+    dropped: HashMap<SocketAddr, u64>,
+    /// Delay and loss injected into what is sent to each peer
+    #[cfg(feature = "emulation")]
+    links: Arc<LinkEmulator>,
+}
+
+impl PeerManager {
+    pub fn new(ban_duration: Duration) -> Self {
+        PeerManager {
+            connections: HashMap::new(),
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            ban_duration,
+            limits: RateLimits::default(),
+            buckets: HashMap::new(),
+            dropped: HashMap::new(),
+            #[cfg(feature = "emulation")]
+            links: Arc::new(LinkEmulator::default()),
+        }
+    }
+
+    /// The link emulator, to use without holding the peer manager's lock
+    #[cfg(feature = "emulation")]
+    pub fn links(&self) -> Arc<LinkEmulator> {
+        Arc::clone(&self.links)
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+        self.buckets.clear();
+    }
+
+    /// Whether a message of `size` bytes from `addr` is within its rate limits, taking it out of
+    /// them if so. A message only needs the buckets not to be empty, so that one larger than a
+    /// second's worth of bytes still gets through, and the peer waits it off afterwards.
+    pub fn allow_message(&mut self, addr: SocketAddr, size: usize, now: Instant) -> bool {
+        let limits = self.limits;
+        let buckets = self.buckets.entry(addr).or_insert(Buckets {
+            messages: limits.messages_per_sec as f64,
+            bytes: limits.bytes_per_sec as f64,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(buckets.updated).as_secs_f64();
+        buckets.messages = (buckets.messages + elapsed * limits.messages_per_sec as f64).min(limits.messages_per_sec as f64);
+        buckets.bytes = (buckets.bytes + elapsed * limits.bytes_per_sec as f64).min(limits.bytes_per_sec as f64);
+        buckets.updated = now;
+        if buckets.messages < 1.0 || buckets.bytes <= 0.0 {
+            return false;
+        }
+        buckets.messages -= 1.0;
+        buckets.bytes -= size as f64;
+        true
+    }
+
+    /// Count a message from `addr` dropped for exceeding the rate limits. Returns true if the
+    /// peer has earned `RATE_LIMIT_PENALTY` points with it.
+    pub fn record_dropped(&mut self, addr: SocketAddr) -> bool {
+        let dropped = self.dropped.entry(addr).or_insert(0);
+        *dropped += 1;
+        dropped.is_multiple_of(DROPS_PER_PENALTY)
+    }
+
+    /// Messages dropped for exceeding the rate limits, per peer that had any
+    pub fn dropped_messages(&self) -> Vec<(SocketAddr, u64)> {
+        self.dropped.iter().map(|(&addr, &count)| (addr, count)).collect()
+    }
+
+    /// Register a new connection
+    pub fn connected(&mut self, peer: &peer::Handle, direction: Direction, now: Instant) {
+        self.connections.insert(peer.id(), Connection {
+            addr: peer.addr(),
+            direction,
+            connected_since: now,
+            bytes_sent: 0,
+            bytes_received: 0,
+            last_seen: None,
+        });
+    }
+
+    /// Forget a connection that was dropped, and the rate limit state of its peer; the peer's
+    /// drop count is kept
+    pub fn disconnected(&mut self, id: PeerId) {
+        if let Some(connection) = self.connections.remove(&id) {
+            self.buckets.remove(&connection.addr);
+        }
+        #[cfg(feature = "emulation")]
+        self.links.forget(id);
+    }
+
+    /// Count `bytes` written to a connection
+    pub fn record_sent(&mut self, id: PeerId, bytes: usize) {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.bytes_sent += bytes as u64;
+        }
+    }
+
+    /// Count a message of `bytes` read from a connection, seen at `now`
+    pub fn record_received(&mut self, id: PeerId, bytes: usize, now: Instant) {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.bytes_received += bytes as u64;
+            connection.last_seen = Some(now);
+        }
+    }
+
+    /// A connected peer by its id, as of `now`
+    pub fn peer(&self, id: PeerId, now: Instant) -> Option<PeerInfo> {
+        self.connections.get(&id).map(|connection| PeerInfo {
+            id,
+            addr: connection.addr,
+            direction: connection.direction,
+            connected_for: now.saturating_duration_since(connection.connected_since),
+            bytes_sent: connection.bytes_sent,
+            bytes_received: connection.bytes_received,
+            last_seen: connection.last_seen.map(|seen| now.saturating_duration_since(seen)),
+        })
+    }
+
+    /// Every connected peer, in the order they connected, as of `now`
+    pub fn peers(&self, now: Instant) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.connections.keys().filter_map(|&id| self.peer(id, now)).collect();
+        peers.sort_by_key(|info| info.id);
+        peers
+    }
+
+    pub fn set_ban_duration(&mut self, ban_duration: Duration) {
+        self.ban_duration = ban_duration;
+    }
+
+    /// Add `points` to the score of `addr`. Returns true if this banned it; the caller should
+    /// then disconnect it.
+    pub fn report(&mut self, addr: SocketAddr, points: u32, now: Instant) -> bool {
+        if self.is_banned(&addr, now) {
+            return false;
+        }
+        let score = self.scores.entry(addr).or_insert(Score { points: 0, updated: now });
+        score.points = score.at(now).saturating_add(points);
+        score.updated = now;
+        if score.points < BAN_THRESHOLD {
+            return false;
+        }
+        self.scores.remove(&addr);
+        self.bans.insert(addr, now + self.ban_duration);
+        true
+    }
+
+    /// The current score of `addr`, decayed since its last offense
+    pub fn score(&self, addr: &SocketAddr, now: Instant) -> u32 {
+        self.scores.get(addr).map_or(0, |score| score.at(now))
+    }
+
+    /// Whether `addr` is banned, forgetting the ban if it has run out
+    pub fn is_banned(&mut self, addr: &SocketAddr, now: Instant) -> bool {
+        match self.bans.get(addr) {
+            Some(&until) if now < until => true,
+            Some(_) => {
+                self.bans.remove(addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The addresses banned as of `now`
+    pub fn banned(&self, now: Instant) -> Vec<SocketAddr> {
+        self.bans.iter().filter(|(_, &until)| now < until).map(|(&addr, _)| addr).collect()
+    }
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        PeerManager::new(DEFAULT_BAN_DURATION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn crossing_the_threshold_bans() {
+        let mut manager = PeerManager::default();
+        let now = Instant::now();
+        for _ in 0..4 {
+            assert!(!manager.report(peer(6001), MALFORMED_MESSAGE_PENALTY, now));
+        }
+        assert_eq!(manager.score(&peer(6001), now), 4 * MALFORMED_MESSAGE_PENALTY);
+        assert!(manager.banned(now).is_empty());
+        assert!(manager.report(peer(6001), MALFORMED_MESSAGE_PENALTY, now));
+        assert!(manager.is_banned(&peer(6001), now));
+        assert_eq!(manager.banned(now), vec![peer(6001)]);
+        // reported once, however much it keeps misbehaving
+        assert!(!manager.report(peer(6001), INVALID_BLOCK_PENALTY, now));
+        // a peer on another port is someone else
+        assert!(!manager.is_banned(&peer(6002), now));
+        assert!(manager.report(peer(6002), INVALID_BLOCK_PENALTY, now));
+    }
+
+    #[test]
+    fn scores_decay() {
+        let mut manager = PeerManager::default();
+        let start = Instant::now();
+        manager.report(peer(6001), 3 * INVALID_TRANSACTION_PENALTY, start);
+        let later = start + Duration::from_secs(5);
+        assert_eq!(manager.score(&peer(6001), later), 3 * INVALID_TRANSACTION_PENALTY - 5 * SCORE_DECAY_PER_SEC);
+        assert_eq!(manager.score(&peer(6001), start + Duration::from_secs(3600)), 0);
+
+        // offenses spread out enough never add up to a ban
+        let interval = Duration::from_secs((INVALID_TRANSACTION_PENALTY / SCORE_DECAY_PER_SEC) as u64);
+        for i in 1..=100 {
+            assert!(!manager.report(peer(6002), INVALID_TRANSACTION_PENALTY, start + interval * i));
+        }
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut manager = PeerManager::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(manager.report(peer(6001), INVALID_BLOCK_PENALTY, start));
+        assert!(manager.is_banned(&peer(6001), start + Duration::from_secs(59)));
+        let expired = start + Duration::from_secs(60);
+        assert!(manager.banned(expired).is_empty());
+        assert!(!manager.is_banned(&peer(6001), expired));
+        // back with a clean score
+        assert_eq!(manager.score(&peer(6001), expired), 0);
+        assert!(!manager.report(peer(6001), MALFORMED_MESSAGE_PENALTY, expired));
+    }
+
+    #[test]
+    fn bursts_beyond_the_limits_are_refused_until_refilled() {
+        let mut manager = PeerManager::default();
+        manager.set_rate_limits(RateLimits { messages_per_sec: 10, bytes_per_sec: 1000 });
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert!(manager.allow_message(peer(6001), 10, start));
+        }
+        assert!(!manager.allow_message(peer(6001), 10, start));
+        // another peer has buckets of its own
+        assert!(manager.allow_message(peer(6002), 10, start));
+        // a tenth of a second refills one message
+        assert!(manager.allow_message(peer(6001), 10, start + Duration::from_millis(100)));
+        assert!(!manager.allow_message(peer(6001), 10, start + Duration::from_millis(100)));
+
+        // a message larger than the byte limit gets through, then the peer waits it off
+        let later = start + Duration::from_secs(10);
+        assert!(manager.allow_message(peer(6001), 3000, later));
+        assert!(!manager.allow_message(peer(6001), 1, later + Duration::from_secs(1)));
+        assert!(manager.allow_message(peer(6001), 1, later + Duration::from_millis(2100)));
+    }
+
+    #[test]
+    fn sustained_drops_are_penalized() {
+        let mut manager = PeerManager::default();
+        let penalties = (0..3 * DROPS_PER_PENALTY).filter(|_| manager.record_dropped(peer(6001))).count();
+        assert_eq!(penalties, 3);
+        assert_eq!(manager.dropped_messages(), vec![(peer(6001), 3 * DROPS_PER_PENALTY)]);
+    }
+
+    #[test]
+    fn connections_are_listed_by_id_until_dropped() {
+        let mut manager = PeerManager::default();
+        let start = Instant::now();
+        let (first, _) = peer::Handle::detached(peer(6001));
+        let (second, _) = peer::Handle::detached(peer(6002));
+        manager.connected(&first, Direction::Outgoing, start);
+        manager.connected(&second, Direction::Incoming, start + Duration::from_secs(1));
+        assert!(manager.peer(second.id(), start).unwrap().last_seen.is_none());
+
+        let now = start + Duration::from_secs(3);
+        manager.record_sent(first.id(), 100);
+        manager.record_received(first.id(), 40, start + Duration::from_secs(2));
+        assert_eq!(manager.peer(first.id(), now), Some(PeerInfo {
+            id: first.id(),
+            addr: peer(6001),
+            direction: Direction::Outgoing,
+            connected_for: Duration::from_secs(3),
+            bytes_sent: 100,
+            bytes_received: 40,
+            last_seen: Some(Duration::from_secs(1)),
+        }));
+        let listed: Vec<PeerId> = manager.peers(now).iter().map(|info| info.id).collect();
+        assert_eq!(listed, vec![first.id(), second.id()]);
+
+        manager.disconnected(first.id());
+        assert!(manager.peer(first.id(), now).is_none());
+        assert_eq!(manager.peers(now).len(), 1);
+        // what a dropped connection is still sent is not counted
+        manager.record_sent(first.id(), 100);
+        assert!(manager.peer(first.id(), now).is_none());
+    }
+}
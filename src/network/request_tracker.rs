@@ -0,0 +1,258 @@
+//! Blocks we asked for and have not received yet, so that a request lost along with its peer is
+//! sent again instead of leaving a gap in our chain until the next announcement covers it.
+
+use super::transport::{Identity, PeerRef};
+use crate::crypto::hash::H256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long a requested block may take to arrive before it is asked for again
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times a block is requested before giving up on it
+pub const MAX_REQUEST_ATTEMPTS: u32 = 4;
+
+struct Request {
+    /// The peer asked last
+    peer: SocketAddr,
+    sent: Instant,
+    attempts: u32,
+    /// Other peers that announced the block, to ask before any peer that may not have it
+    sources: Vec<SocketAddr>,
+}
+
+#[derive(Default)]
+pub struct RequestTracker {
+    requests: HashMap<H256, Request>,
+    /// Every peer we asked for blocks, in the order first asked, to re-request from in turn
+    peers: Vec<PeerRef>,
+}
+
+impl RequestTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Of `hashes` that `peer` announced or that we need from it, the ones to ask it for, which
+    /// are then recorded as requested: those not requested already. For the others, `peer` is
+    /// only remembered as a source to fall back on.
+    pub fn to_request(&mut self, hashes: Vec<H256>, peer: &impl Identity, now: Instant) -> Vec<H256> {
+        self.add_peer(peer);
+        let addr = peer.addr();
+        let mut new = Vec::new();
+        for hash in hashes {
+            match self.requests.get_mut(&hash) {
+                Some(request) => {
+                    if request.peer != addr && !request.sources.contains(&addr) {
+                        request.sources.push(addr);
+                    }
+                }
+                None => new.push(hash),
+            }
+        }
+        self.requested(&new, peer, now);
+        new
+    }
+
+    /// Record that `peer` was asked for `hashes` again, each waiting a full timeout anew
+    pub fn requested(&mut self, hashes: &[H256], peer: &impl Identity, now: Instant) {
+        self.add_peer(peer);
+        let addr = peer.addr();
+        for hash in hashes {
+            let request = self.requests.entry(*hash)
+                .or_insert(Request { peer: addr, sent: now, attempts: 0, sources: Vec::new() });
+            request.sources.retain(|source| *source != addr);
+            request.peer = addr;
+            request.sent = now;
+            request.attempts += 1;
+        }
+    }
+
+    fn add_peer(&mut self, peer: &impl Identity) {
+        if !self.peers.iter().any(|known| known.addr() == peer.addr()) {
+            self.peers.push(PeerRef::of(peer));
+        }
+    }
+
+    /// Stop tracking a block that arrived, whether or not it turned out valid
+    pub fn received(&mut self, hash: &H256) {
+        self.requests.remove(hash);
+    }
+
+    /// Forget a peer that disconnected: the blocks asked of it are due again at once, from the
+    /// others, and it is no longer a source to ask
+    pub fn peer_gone(&mut self, addr: SocketAddr) {
+        self.peers.retain(|known| known.addr() != addr);
+        for request in self.requests.values_mut() {
+            request.sources.retain(|source| *source != addr);
+        }
+    }
+
+    pub fn is_pending(&self, hash: &H256) -> bool {
+        self.requests.contains_key(hash)
+    }
+
+    /// Take the requests that timed out as of `now`, or whose peer is gone. Returns the hashes to ask for again, grouped
+    /// by the peer to ask: the first fallback source if any, else the next peer after the one
+    /// asked last; and the hashes given up
+    /// on after `MAX_REQUEST_ATTEMPTS`, which are no longer tracked. The caller sends the
+    /// requests and records them with `requested`.
+    pub fn take_timed_out(&mut self, now: Instant) -> (Vec<(PeerRef, Vec<H256>)>, Vec<H256>) {
+        let peers = &self.peers;
+        let timed_out: Vec<H256> = self.requests.iter()
+            .filter(|(_, request)| {
+                now.saturating_duration_since(request.sent) >= REQUEST_TIMEOUT
+                    || !peers.iter().any(|known| known.addr() == request.peer)
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+        let mut retries: Vec<(PeerRef, Vec<H256>)> = Vec::new();
+        let mut given_up = Vec::new();
+        for hash in timed_out {
+            let request = &self.requests[&hash];
+            if request.attempts >= MAX_REQUEST_ATTEMPTS || self.peers.is_empty() {
+                self.requests.remove(&hash);
+                given_up.push(hash);
+                continue;
+            }
+            let source = request.sources.first()
+                .and_then(|source| self.peers.iter().find(|known| known.addr() == *source));
+            let peer = match source {
+                Some(peer) => peer,
+                None => {
+                    let next = match self.peers.iter().position(|known| known.addr() == request.peer) {
+                        Some(index) => (index + 1) % self.peers.len(),
+                        None => 0,
+                    };
+                    &self.peers[next]
+                }
+            };
+            match retries.iter_mut().find(|(asked, _)| asked.addr() == peer.addr()) {
+                Some((_, hashes)) => hashes.push(hash),
+                None => retries.push((*peer, vec![hash])),
+            }
+        }
+        (retries, given_up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::peer;
+
+    fn peer(port: u16) -> peer::Handle {
+        peer::Handle::detached(SocketAddr::from(([127, 0, 0, 1], port))).0
+    }
+
+    fn hash(byte: u8) -> H256 {
+        H256::from([byte; 32])
+    }
+
+    fn addrs(retries: &[(PeerRef, Vec<H256>)]) -> Vec<(u16, Vec<H256>)> {
+        retries.iter().map(|(peer, hashes)| (peer.addr().port(), hashes.clone())).collect()
+    }
+
+    #[test]
+    fn timed_out_requests_go_round_robin() {
+        let mut tracker = RequestTracker::new();
+        let (a, b, c) = (peer(6001), peer(6002), peer(6003));
+        let start = Instant::now();
+        tracker.to_request(vec![hash(1)], &a, start);
+        tracker.to_request(vec![hash(2)], &b, start);
+        tracker.to_request(vec![hash(3)], &c, start + Duration::from_secs(1));
+
+        // nothing is due before the timeout
+        let (retries, given_up) = tracker.take_timed_out(start + REQUEST_TIMEOUT - Duration::from_millis(1));
+        assert!(retries.is_empty() && given_up.is_empty());
+
+        let now = start + REQUEST_TIMEOUT;
+        let (retries, _) = tracker.take_timed_out(now);
+        for (peer, hashes) in &retries {
+            tracker.requested(hashes, peer, now);
+        }
+        let mut retries = addrs(&retries);
+        retries.sort();
+        assert_eq!(retries, vec![(6002, vec![hash(1)]), (6003, vec![hash(2)])]);
+
+        // the last peer wraps around to the first
+        let (retries, _) = tracker.take_timed_out(start + Duration::from_secs(1) + REQUEST_TIMEOUT);
+        assert_eq!(addrs(&retries), vec![(6001, vec![hash(3)])]);
+    }
+
+    #[test]
+    fn received_blocks_are_not_requested_again() {
+        let mut tracker = RequestTracker::new();
+        let (a, b) = (peer(6001), peer(6002));
+        let start = Instant::now();
+        tracker.to_request(vec![hash(1), hash(2)], &a, start);
+        tracker.to_request(Vec::new(), &b, start);
+        tracker.received(&hash(1));
+        assert!(!tracker.is_pending(&hash(1)));
+        let (retries, given_up) = tracker.take_timed_out(start + REQUEST_TIMEOUT);
+        assert_eq!(addrs(&retries), vec![(6002, vec![hash(2)])]);
+        assert!(given_up.is_empty());
+        tracker.received(&hash(2));
+        let (retries, _) = tracker.take_timed_out(start + REQUEST_TIMEOUT * 10);
+        assert!(retries.is_empty());
+    }
+
+    #[test]
+    fn requests_are_given_up_after_the_last_attempt() {
+        let mut tracker = RequestTracker::new();
+        let a = peer(6001);
+        let mut now = Instant::now();
+        tracker.to_request(vec![hash(1)], &a, now);
+        for _ in 1..MAX_REQUEST_ATTEMPTS {
+            now += REQUEST_TIMEOUT;
+            let (retries, given_up) = tracker.take_timed_out(now);
+            assert!(given_up.is_empty());
+            // a lone peer is asked again
+            assert_eq!(addrs(&retries), vec![(6001, vec![hash(1)])]);
+            tracker.requested(&retries[0].1, &retries[0].0, now);
+        }
+        // a later announcement is no reason to ask again
+        assert!(tracker.to_request(vec![hash(1)], &a, now + REQUEST_TIMEOUT / 2).is_empty());
+        let (retries, given_up) = tracker.take_timed_out(now + REQUEST_TIMEOUT);
+        assert!(retries.is_empty());
+        assert_eq!(given_up, vec![hash(1)]);
+        assert!(!tracker.is_pending(&hash(1)));
+    }
+
+    #[test]
+    fn blocks_in_flight_are_requested_once() {
+        let mut tracker = RequestTracker::new();
+        let (a, b, c) = (peer(6001), peer(6002), peer(6003));
+        let start = Instant::now();
+        assert_eq!(tracker.to_request(vec![hash(1), hash(2)], &a, start), vec![hash(1), hash(2)]);
+        assert_eq!(tracker.to_request(vec![hash(1), hash(3)], &b, start), vec![hash(3)]);
+        assert!(tracker.to_request(vec![hash(3)], &b, start).is_empty());
+        assert!(tracker.to_request(vec![hash(2)], &c, start).is_empty());
+
+        // a peer that announced the block is asked before the round robin's next peer
+        let (retries, _) = tracker.take_timed_out(start + REQUEST_TIMEOUT);
+        let asked: Vec<(H256, u16)> = retries.iter()
+            .flat_map(|(peer, hashes)| hashes.iter().map(move |hash| (*hash, peer.addr().port())))
+            .collect();
+        assert_eq!(asked.len(), 3);
+        assert!(asked.contains(&(hash(1), 6002)));
+        assert!(asked.contains(&(hash(2), 6003)));
+        assert!(asked.contains(&(hash(3), 6003)));
+    }
+
+    #[test]
+    fn requests_to_a_departed_peer_are_due_at_once() {
+        let mut tracker = RequestTracker::new();
+        let (a, b) = (peer(6001), peer(6002));
+        let start = Instant::now();
+        tracker.to_request(vec![hash(1)], &a, start);
+        tracker.to_request(vec![hash(1), hash(2)], &b, start);
+        assert!(tracker.take_timed_out(start).0.is_empty());
+
+        tracker.peer_gone(b.addr());
+        // asked of the remaining peer without waiting, and only that
+        let (retries, given_up) = tracker.take_timed_out(start);
+        assert!(given_up.is_empty());
+        assert_eq!(addrs(&retries), vec![(6001, vec![hash(2)])]);
+    }
+}
@@ -1,14 +1,53 @@
+#[cfg(feature = "emulation")]
+use super::emulation::LinkConditions;
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use super::limits::{DEFAULT_MAX_FRAME_SIZE, MAX_MESSAGE_SIZE};
+use super::peer_manager::{PeerInfo, PeerManager, RateLimits, MALFORMED_MESSAGE_PENALTY, RATE_LIMIT_PENALTY};
+use super::traffic::{NetStats, NetStatsSnapshot, TrafficStats};
+use super::transport::Identity;
+use crate::crypto::hash::{Hashable, H256};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const MAX_INCOMING_CLIENT: usize = 256;
+/// Tokens of the outgoing connections still being made start here, past those of the peers
+const CONNECTING_TOKENS: usize = MAX_INCOMING_CLIENT * 2;
+/// How long an outgoing connection may take to be made
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to try again handing the workers the messages parked while their queue was full
+const PARKED_RETRY_INTERVAL: Duration = Duration::from_millis(10);
 const MAX_EVENT: usize = 1024;
+/// Wait before the first attempt to reconnect to a configured peer, doubled after each failure
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two attempts to reconnect
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+/// How often every peer is pinged; a ping unanswered by the next one is missed
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// A peer missing this many pongs in a row is taken for dead and disconnected
+const MAX_MISSED_PONGS: u32 = 3;
+/// How many received messages may wait for the workers unless configured otherwise
+pub const DEFAULT_MESSAGE_QUEUE_CAPACITY: usize = 4096;
+
+/// How full the queue of received messages waiting for the workers is
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct QueueStats {
+    pub depth: usize,
+    /// `None` if the queue is unbounded
+    pub capacity: Option<usize>,
+    /// Announcements dropped because the queue was full
+    pub dropped_announcements: u64,
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
@@ -17,30 +56,128 @@ pub fn new(
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
         control_chan: control_signal_sender,
+        msg_queue: msg_sink.clone(),
+        dropped_announcements: Arc::new(AtomicU64::new(0)),
+        peer_manager: Arc::new(Mutex::new(PeerManager::default())),
+        node_nonce: rand::random(),
+        listen_port: addr.port(),
+        services: Arc::new(AtomicU64::new(message::SERVICE_COMPRESSION)),
+        traffic: Arc::new(TrafficStats::default()),
+        net_stats: Arc::new(NetStats::default()),
     };
     let ctx = Context {
         peers: slab::Slab::new(),
         peer_list: vec![],
+        connecting: slab::Slab::new(),
         addr,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        parked: VecDeque::new(),
+        dropped_announcements: Arc::clone(&handle.dropped_announcements),
+        peer_manager: Arc::clone(&handle.peer_manager),
+        on_connect: None,
+        persistent: HashMap::new(),
+        ping_interval: PING_INTERVAL,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        traffic: Arc::clone(&handle.traffic),
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
 }
 
+type OnConnect = Box<dyn Fn(&peer::Handle) + Send>;
+
 pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
+    /// Outgoing connections not made yet, which the event loop waits on with the peers
+    connecting: slab::Slab<Connecting>,
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
+    /// Received messages for the workers. When it is full, announcements are dropped and the
+    /// other messages are parked, and no more is read from their peers until the workers catch up.
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    /// The message each peer not read from waits to hand the workers, by peer id, oldest first
+    parked: VecDeque<(usize, Vec<u8>, peer::Handle)>,
+    dropped_announcements: Arc<AtomicU64>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    /// Called with each new connection, incoming or outgoing, before any of its messages is read
+    on_connect: Option<OnConnect>,
+    /// Peers to stay connected to, and when to next try reconnecting to those we are not
+    persistent: HashMap<SocketAddr, Reconnect>,
+    ping_interval: Duration,
+    /// Largest frame a peer may send
+    max_frame_size: usize,
+    traffic: Arc<TrafficStats>,
     _handle: Handle,
 }
 
+/// An outgoing connection being made, registered once its socket is writable
+struct Connecting {
+    stream: net::TcpStream,
+    addr: SocketAddr,
+    /// Given up on if not made by then
+    deadline: Instant,
+    done: ConnectDone,
+}
+
+/// Who to tell how an outgoing connection went
+enum ConnectDone {
+    /// A `connect` call waiting on its handle
+    Reply(cbchannel::Sender<std::io::Result<peer::Handle>>),
+    /// The reconnection of a configured peer, to try again later if it failed
+    Persistent,
+}
+
+/// Whether and when to reconnect to a configured peer
+struct Reconnect {
+    /// Failed attempts since the last connection
+    failures: u32,
+    /// When to try next; `None` while connected or connecting
+    retry_at: Option<Instant>,
+}
+
 impl Context {
+    /// Have `callback` greet each new peer, say with what our chain looks like
+    pub fn set_on_connect(&mut self, callback: impl Fn(&peer::Handle) + Send + 'static) {
+        self.on_connect = Some(Box::new(callback));
+    }
+
+    /// Disconnect peers sending frames over `size` bytes, at most `MAX_MESSAGE_SIZE`
+    pub fn set_max_frame_size(&mut self, size: usize) -> Result<(), String> {
+        if size as u64 > MAX_MESSAGE_SIZE {
+            return Err(format!("frame size {} exceeds the limit of {}", size, MAX_MESSAGE_SIZE));
+        }
+        self.max_frame_size = size;
+        Ok(())
+    }
+
+    /// Whether to read and send compressed messages, on by default; peers compress to us only
+    /// if we say we read them
+    pub fn set_compression(&mut self, enabled: bool) {
+        let services = if enabled { message::SERVICE_COMPRESSION } else { 0 };
+        self._handle.services.store(services, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    fn set_ping_interval(&mut self, interval: Duration) {
+        self.ping_interval = interval;
+    }
+
+    /// The messages sent with `Handle::send_to` that a server not started has yet to write,
+    /// dropping the other commands
+    #[cfg(test)]
+    pub fn sent_to(&self) -> Vec<(peer::PeerId, message::Message)> {
+        std::iter::from_fn(|| self.control_chan.try_recv().ok())
+            .filter_map(|signal| match signal {
+                ControlSignal::SendTo(id, msg) => Some((id, msg)),
+                _ => None,
+            })
+            .collect()
+    }
+
     /// Start a new server context.
     pub fn start(mut self) -> std::io::Result<()> {
         thread::spawn(move || {
@@ -79,7 +216,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, direction, self.max_frame_size, Arc::clone(&self._handle.net_stats), Arc::clone(&self.peer_manager))?;
 
         // register the writer queue
         self.poll.register(
@@ -90,20 +227,85 @@ impl Context {
         )?;
 
         // insert the context and return the handle
+        self.peer_manager.lock().unwrap().connected(&handle, direction, Instant::now());
         vacant.insert(ctx);
         // record the key of this peer
         self.peer_list.push(key);
         trace!("Registering peer with event token={}", key);
+        debug!("Peer {} is connection {}", handle.addr(), handle.id());
+        if let Some(on_connect) = &self.on_connect {
+            on_connect(&handle);
+        }
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
-        let mio_stream = net::TcpStream::from_stream(stream)?;
-        self.register(mio_stream, peer::Direction::Outgoing)
+    /// Start connecting to a peer without waiting for the connection to be made, for the event
+    /// loop to go on; `done` is told once it is made and the peer registered, or it fails
+    fn start_connect(&mut self, addr: SocketAddr, done: ConnectDone) {
+        let started: std::io::Result<net::TcpStream> = if self.peer_manager.lock().unwrap().is_banned(&addr, Instant::now()) {
+            Err(std::io::Error::other(format!("peer {} is banned", addr)))
+        } else {
+            debug!("Establishing connection to peer {}", addr);
+            net::TcpStream::connect(&addr).and_then(|stream| {
+                let token = mio::Token(CONNECTING_TOKENS + self.connecting.vacant_entry().key());
+                self.poll.register(&stream, token, mio::Ready::writable(), mio::PollOpt::edge())?;
+                Ok(stream)
+            })
+        };
+        match started {
+            Ok(stream) => {
+                self.connecting.insert(Connecting { stream, addr, deadline: Instant::now() + CONNECT_TIMEOUT, done });
+            }
+            Err(e) => self.connect_done(addr, done, Err(e)),
+        }
+    }
+
+    /// Tell whoever asked for an outgoing connection how it went
+    fn connect_done(&mut self, addr: SocketAddr, done: ConnectDone, result: std::io::Result<peer::Handle>) {
+        match done {
+            // the caller may have given up waiting
+            ConnectDone::Reply(result_chan) => {
+                result_chan.send(result).ok();
+            }
+            ConnectDone::Persistent => self.reconnected(addr, result.map(|_| ()), Instant::now()),
+        }
+    }
+
+    /// Register the peer of an outgoing connection whose socket became writable, if it was made
+    fn finish_connect(&mut self, key: usize) {
+        let made = match self.connecting.get(key) {
+            None => return,
+            Some(connecting) => match connecting.stream.take_error() {
+                Ok(Some(e)) | Err(e) => Err(e),
+                Ok(None) => match connecting.stream.peer_addr() {
+                    // an event left over from an earlier connection under the same token
+                    Err(ref e) if e.kind() == std::io::ErrorKind::NotConnected => return,
+                    made => made.map(|_| ()),
+                },
+            },
+        };
+        let Connecting { stream, addr, done, .. } = self.connecting.remove(key);
+        let result = made
+            .and_then(|()| self.poll.deregister(&stream))
+            .and_then(|()| self.register(stream, peer::Direction::Outgoing));
+        if let Err(e) = &result {
+            debug!("Could not connect to peer {}: {}", addr, e);
+        }
+        self.connect_done(addr, done, result);
+    }
+
+    /// Give up on the outgoing connections not made in time as of `now`
+    fn expire_connects(&mut self, now: Instant) {
+        let expired: Vec<usize> = self.connecting.iter()
+            .filter(|(_, connecting)| connecting.deadline <= now)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            // dropping the socket takes it out of the poll
+            let Connecting { addr, done, .. } = self.connecting.remove(key);
+            let error = std::io::Error::new(std::io::ErrorKind::TimedOut, format!("connecting to {} timed out", addr));
+            self.connect_done(addr, done, Err(error));
+        }
     }
 
     /// Accept an incoming peer and register it
@@ -113,6 +315,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.peer_manager.lock().unwrap().is_banned(&addr, Instant::now()) {
+            info!("Refusing connection from banned peer {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -128,21 +334,192 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
-                req.result_chan.send(handle).unwrap();
+                self.start_connect(req.addr, ConnectDone::Reply(req.result_chan));
             }
-            ControlSignal::BroadcastMessage(msg) => {
+            ControlSignal::BroadcastMessage(msg, except) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    let handle = &self.peers[*peer_id].handle;
+                    if !handle.is_ready() || Some(handle.id()) == except {
+                        continue;
+                    }
+                    if let Some(msg) = self.announcement_for(&msg, handle) {
+                        if let Err(e) = handle.try_write(msg) {
+                            debug!("Broadcast: {}", e);
+                        }
+                    }
+                }
+            }
+            ControlSignal::SendTo(id, msg) => {
+                trace!("Processing SendTo command");
+                match self.peer_list.iter().map(|&peer_id| &self.peers[peer_id].handle).find(|handle| handle.id() == id) {
+                    Some(handle) => {
+                        if let Err(e) = handle.try_write(msg) {
+                            debug!("{}", e);
+                        }
+                    }
+                    None => debug!("Not sending {} to peer {:?}, disconnected", msg.kind(), id),
+                }
+            }
+            ControlSignal::PingAll => {
+                trace!("Processing PingAll command");
+                for peer_id in &self.peer_list {
+                    let handle = &self.peers[*peer_id].handle;
+                    if handle.is_ready() {
+                        handle.ping();
+                    }
+                }
+            }
+            ControlSignal::ConnectPersistent(addr) => {
+                trace!("Processing ConnectPersistent command");
+                self.persistent.insert(addr, Reconnect { failures: 0, retry_at: Some(Instant::now()) });
+                self.reconnect_due(Instant::now());
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self.peer_list.iter()
+                    .map(|&peer_id| (self.peers[peer_id].handle.clone(), self.peers[peer_id].direction))
+                    .collect();
+                // the caller may have given up waiting
+                result_chan.send(peers).ok();
+            }
+            ControlSignal::DisconnectAll(reason) => {
+                trace!("Processing DisconnectAll command");
+                // the peers close the connections on reading it, once it is written
+                for peer_id in &self.peer_list {
+                    let handle = &self.peers[*peer_id].handle;
+                    if let Err(e) = handle.try_write(message::Message::Disconnect { reason: reason.clone() }) {
+                        debug!("{}", e);
+                    }
+                }
+            }
+            ControlSignal::Disconnect(addr) => {
+                trace!("Processing Disconnect command");
+                if let Some(&peer_id) = self.peer_list.iter().find(|&&peer_id| self.peers[peer_id].addr == addr) {
+                    info!("Disconnecting peer {}", addr);
+                    self.remove_peer(peer_id);
                 }
             }
         }
         Ok(())
     }
 
+    /// `msg` as it should go to `peer`: an announcement leaves out the hashes the peer has
+    /// already, and is not sent at all if that leaves none; anything else goes as is
+    fn announcement_for(&self, msg: &message::Message, peer: &peer::Handle) -> Option<message::Message> {
+        use message::Message;
+        match msg {
+            Message::NewBlockHashes(hashes) => self.unknown_inventory(hashes, peer).map(Message::NewBlockHashes),
+            Message::NewTransactionHashes(hashes) => self.unknown_inventory(hashes, peer).map(Message::NewTransactionHashes),
+            Message::CompactBlock { header, .. } => self.unknown_inventory(&[header.hash()], peer).map(|_| msg.clone()),
+            _ => Some(msg.clone()),
+        }
+    }
+
+    /// Of `hashes` to announce to `peer`, those it does not have, or `None` if it has them all
+    fn unknown_inventory(&self, hashes: &[H256], peer: &peer::Handle) -> Option<Vec<H256>> {
+        let unknown = peer.take_unknown_inventory(hashes);
+        self.traffic.record_filtered_announcements(hashes.len() - unknown.len());
+        Some(unknown).filter(|unknown| !unknown.is_empty())
+    }
+
+    /// Drop the connection to a peer; closing the socket takes it out of the poll, and its
+    /// writer with it, so that writing to its handle fails. A configured peer we connected to is
+    /// reconnected to after a while.
+    fn remove_peer(&mut self, peer_id: usize) {
+        if !self.peers.contains(peer_id) {
+            return;
+        }
+        let peer = self.peers.remove(peer_id);
+        self.parked.retain(|(parked_id, _, _)| *parked_id != peer_id);
+        self.peer_manager.lock().unwrap().disconnected(peer.handle.id());
+        // the workers may hold on to the handle a while longer
+        peer.handle.clear_known_inventory();
+        if let Some(index) = self.peer_list.iter().position(|&x| x == peer_id) {
+            self.peer_list.swap_remove(index);
+        }
+        if let (peer::Direction::Outgoing, Some(reconnect)) = (peer.direction, self.persistent.get_mut(&peer.addr)) {
+            info!("Lost configured peer {}, reconnecting in {:?}", peer.addr, RECONNECT_BACKOFF);
+            reconnect.retry_at = Some(Instant::now() + RECONNECT_BACKOFF);
+        }
+    }
+
+    /// Start reconnecting to the configured peers whose next attempt is due as of `now`
+    fn reconnect_due(&mut self, now: Instant) {
+        let due: Vec<SocketAddr> = self.persistent.iter()
+            .filter(|(_, reconnect)| reconnect.retry_at.is_some_and(|at| at <= now))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in due {
+            // not due again while the connection is being made
+            self.persistent.get_mut(&addr).unwrap().retry_at = None;
+            self.start_connect(addr, ConnectDone::Persistent);
+        }
+    }
+
+    /// Record how reconnecting to a configured peer went, backing off before the next attempt
+    /// if it failed
+    fn reconnected(&mut self, addr: SocketAddr, result: std::io::Result<()>, now: Instant) {
+        let reconnect = match self.persistent.get_mut(&addr) {
+            Some(reconnect) => reconnect,
+            None => return,
+        };
+        match result {
+            Ok(()) => {
+                info!("Connected to configured peer {}", addr);
+                *reconnect = Reconnect { failures: 0, retry_at: None };
+            }
+            Err(e) => {
+                let backoff = RECONNECT_BACKOFF.checked_mul(1 << reconnect.failures.min(31))
+                    .map_or(MAX_RECONNECT_BACKOFF, |backoff| backoff.min(MAX_RECONNECT_BACKOFF));
+                reconnect.failures += 1;
+                reconnect.retry_at = Some(now + backoff);
+                info!("Error connecting to configured peer {}, attempt {}, retrying in {:?}: {}",
+                    addr, reconnect.failures, backoff, e);
+            }
+        }
+    }
+
+    /// How long the event loop may wait before the next reconnection attempt is due
+    fn reconnect_timeout(&self, now: Instant) -> Option<Duration> {
+        self.persistent.values()
+            .filter_map(|reconnect| reconnect.retry_at)
+            .min()
+            .map(|at| at.saturating_duration_since(now))
+    }
+
+    /// How long the event loop may wait before an outgoing connection times out
+    fn connect_timeout(&self, now: Instant) -> Option<Duration> {
+        self.connecting.iter()
+            .map(|(_, connecting)| connecting.deadline)
+            .min()
+            .map(|at| at.saturating_duration_since(now))
+    }
+
+    /// Disconnect the peers that missed too many pongs, as a crashed host never closes its
+    /// connections, and ping the others. Peers still in the handshake are left alone.
+    fn keepalive(&mut self, now: Instant) {
+        let dead: Vec<usize> = self.peer_list.iter().copied()
+            .filter(|&peer_id| {
+                let handle = &self.peers[peer_id].handle;
+                handle.is_ready() && handle.missed_pongs(self.ping_interval, now) >= MAX_MISSED_PONGS
+            })
+            .collect();
+        for peer_id in dead {
+            warn!("Peer {} missed {} pongs in a row, disconnecting", self.peers[peer_id].addr, MAX_MISSED_PONGS);
+            self.remove_peer(peer_id);
+        }
+        for peer_id in &self.peer_list {
+            let handle = &self.peers[*peer_id].handle;
+            if handle.is_ready() {
+                handle.ping();
+            }
+        }
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
+        let interest = self.socket_interest(peer_id) | mio::Ready::writable();
         let peer = &mut self.peers[peer_id];
         // we have stuff to write at the writer queue
         let socket_token = mio::Token(peer_id * 2);
@@ -150,13 +527,63 @@ impl Context {
         self.poll.reregister(
             &peer.stream,
             socket_token,
-            mio::Ready::readable() | mio::Ready::writable(),
+            interest,
             mio::PollOpt::edge(),
         )?;
         Ok(())
     }
 
+    /// The socket events to wait for from a peer with nothing to write: none readable while its
+    /// parked message waits for room in the worker queue
+    fn socket_interest(&self, peer_id: usize) -> mio::Ready {
+        if self.is_parked(peer_id) {
+            // a writable event has a peer with nothing to write reregistered as it was
+            mio::Ready::writable()
+        } else {
+            mio::Ready::readable()
+        }
+    }
+
+    fn is_parked(&self, peer_id: usize) -> bool {
+        self.parked.iter().any(|(parked_id, _, _)| *parked_id == peer_id)
+    }
+
+    /// Hand the workers the parked messages as long as their queue has room, oldest first, and
+    /// read again from the peers whose message got in
+    fn unpark(&mut self) {
+        while let Some((peer_id, m, handle)) = self.parked.pop_front() {
+            match self.new_msg_chan.try_send((m, handle)) {
+                Ok(()) => {
+                    trace!("Peer {} unparked", peer_id);
+                    let socket_token = mio::Token(peer_id * 2);
+                    // it may have something to write as well
+                    let resumed = self.poll.reregister(
+                        &self.peers[peer_id].stream,
+                        socket_token,
+                        mio::Ready::readable() | mio::Ready::writable(),
+                        mio::PollOpt::edge(),
+                    ).and_then(|()| self.process_readable(peer_id));
+                    if let Err(e) = resumed {
+                        warn!("Error polling peer {}, disconnecting: {}", peer_id, e);
+                        self.remove_peer(peer_id);
+                    }
+                }
+                Err(cbchannel::TrySendError::Full((m, handle))) => {
+                    self.parked.push_front((peer_id, m, handle));
+                    break;
+                }
+                Err(cbchannel::TrySendError::Disconnected(_)) => {
+                    debug!("Workers gone, dropping a message from peer {}", peer_id);
+                }
+            }
+        }
+    }
+
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
+        // an event from before the peer was parked
+        if self.is_parked(peer_id) {
+            return Ok(());
+        }
         // we are using edge-triggered events, loop until block
         let peer = &mut self.peers[peer_id];
         loop {
@@ -164,9 +591,16 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
+                    break;
+                }
+                Ok(ReadResult::Oversized(length)) => {
+                    warn!("Peer {} sent a frame of {} bytes, over the limit, disconnecting", peer.addr, length);
+                    let addr = peer.addr;
+                    self.remove_peer(peer_id);
+                    if self.peer_manager.lock().unwrap().report(addr, MALFORMED_MESSAGE_PENALTY, Instant::now()) {
+                        warn!("Banning peer {} for misbehaving", addr);
+                    }
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -177,7 +611,24 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    match self.new_msg_chan.try_send((m, peer.handle.clone())) {
+                        Ok(()) => {}
+                        Err(cbchannel::TrySendError::Full((m, handle))) => {
+                            if message::Message::is_encoded_announcement(&m) {
+                                debug!("Message queue full, dropping an announcement from peer {}", peer.addr);
+                                self.dropped_announcements.fetch_add(1, Ordering::Relaxed);
+                            } else {
+                                debug!("Message queue full, reading no more from peer {} until the workers catch up", peer.addr);
+                                // the rest stays in the socket, and the reader, until unparked
+                                self.poll.reregister(&peer.stream, mio::Token(peer_id * 2), mio::Ready::writable(), mio::PollOpt::edge())?;
+                                self.parked.push_back((peer_id, m, handle));
+                                break;
+                            }
+                        }
+                        Err(cbchannel::TrySendError::Disconnected(_)) => {
+                            debug!("Workers gone, dropping a message from peer {}", peer.addr);
+                        }
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -187,9 +638,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -199,6 +648,7 @@ impl Context {
     }
 
     fn process_writable(&mut self, peer_id: usize) -> std::io::Result<()> {
+        let interest = self.socket_interest(peer_id);
         let peer = &mut self.peers[peer_id];
         match peer.writer.write() {
             Ok(WriteResult::Complete) => {
@@ -210,7 +660,7 @@ impl Context {
                 self.poll.reregister(
                     &peer.stream,
                     socket_token,
-                    mio::Ready::readable(),
+                    interest,
                     mio::PollOpt::edge(),
                 )?;
                 // we're interested in write queue again.
@@ -224,9 +674,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -235,7 +683,7 @@ impl Context {
                 self.poll.reregister(
                     &peer.stream,
                     socket_token,
-                    mio::Ready::readable(),
+                    interest,
                     mio::PollOpt::edge(),
                 )?;
                 self.poll.deregister(&peer.writer.queue)?;
@@ -246,9 +694,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -283,8 +729,22 @@ impl Context {
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
+        let mut next_ping = Instant::now() + self.ping_interval;
         loop {
-            self.poll.poll(&mut events, None)?;
+            let until_ping = next_ping.saturating_duration_since(Instant::now());
+            let parked_retry = Some(PARKED_RETRY_INTERVAL).filter(|_| !self.parked.is_empty());
+            let timeout = [self.reconnect_timeout(Instant::now()), self.connect_timeout(Instant::now()), parked_retry].iter()
+                .flatten()
+                .fold(until_ping, |until, &timeout| until.min(timeout));
+            self.poll.poll(&mut events, Some(timeout))?;
+            let now = Instant::now();
+            self.reconnect_due(now);
+            self.expire_connects(now);
+            self.unpark();
+            if now >= next_ping {
+                self.keepalive(now);
+                next_ping = now + self.ping_interval;
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -329,6 +789,10 @@ impl Context {
                             }
                         }
                     }
+                    mio::Token(token_id) if token_id >= CONNECTING_TOKENS => {
+                        trace!("Outgoing connection {} writable", token_id - CONNECTING_TOKENS);
+                        self.finish_connect(token_id - CONNECTING_TOKENS);
+                    }
                     mio::Token(token_id) => {
                         // peer id (the index in the peers list) is token_id/2
                         let peer_id = token_id >> 1;
@@ -341,19 +805,32 @@ impl Context {
                                     if !self.peers.contains(peer_id) {
                                         continue;
                                     }
-                                    self.process_readable(peer_id).unwrap();
+                                    if let Err(e) = self.process_readable(peer_id) {
+                                        warn!("Error polling peer {}, disconnecting: {}", peer_id, e);
+                                        self.remove_peer(peer_id);
+                                    }
                                 }
                                 if readiness.is_writable() {
                                     trace!("Peer {} writable", peer_id);
                                     if !self.peers.contains(peer_id) {
                                         continue;
                                     }
-                                    self.process_writable(peer_id).unwrap();
+                                    if let Err(e) = self.process_writable(peer_id) {
+                                        warn!("Error polling peer {}, disconnecting: {}", peer_id, e);
+                                        self.remove_peer(peer_id);
+                                    }
                                 }
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
-                                self.register_write_interest(peer_id)?;
+                                // the peer may have been dropped since the write was queued
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
+                                if let Err(e) = self.register_write_interest(peer_id) {
+                                    warn!("Error polling peer {}, disconnecting: {}", peer_id, e);
+                                    self.remove_peer(peer_id);
+                                }
                             }
                             _ => unreachable!(),
                         }
@@ -367,6 +844,17 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    /// The queue the workers read from, to tell how full it is
+    msg_queue: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    dropped_announcements: Arc<AtomicU64>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    /// Sent in our `Version`, to recognize connections to ourselves
+    node_nonce: u64,
+    listen_port: u16,
+    /// The `SERVICE_*` bits sent in our `Version`
+    services: Arc<AtomicU64>,
+    traffic: Arc<TrafficStats>,
+    net_stats: Arc<NetStats>,
 }
 
 impl Handle {
@@ -384,17 +872,533 @@ impl Handle {
 
     pub fn broadcast(&self, msg: message::Message) {
         self.control_chan
-            .send(ControlSignal::BroadcastMessage(msg))
+            .send(ControlSignal::BroadcastMessage(msg, None))
+            .unwrap();
+    }
+
+    /// Broadcast to every peer but `except`, say the one that sent us what we relay
+    pub fn broadcast_except(&self, msg: message::Message, except: &(impl Identity + ?Sized)) {
+        self.control_chan
+            .send(ControlSignal::BroadcastMessage(msg, Some(except.id())))
+            .unwrap();
+    }
+
+    /// Send to a peer if it is still connected; unlike writing to its handle, a message to a
+    /// peer that is gone is dropped quietly
+    pub fn send_to(&self, peer_id: peer::PeerId, msg: message::Message) {
+        if self.control_chan.send(ControlSignal::SendTo(peer_id, msg)).is_err() {
+            debug!("Could not send to peer {:?}, server detached", peer_id);
+        }
+    }
+
+    /// Ping every peer, each with its own nonce
+    pub fn ping_all(&self) {
+        self.control_chan
+            .send(ControlSignal::PingAll)
             .unwrap();
     }
+
+    /// Connect to `addr` now and again whenever the connection drops, backing off while it is
+    /// unreachable
+    pub fn connect_persistent(&self, addr: SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::ConnectPersistent(addr))
+            .unwrap();
+    }
+
+    /// Add `points` to the misbehavior score of a peer, disconnecting it if that gets it banned
+    pub fn report_misbehavior(&self, addr: SocketAddr, points: u32) {
+        if !self.peer_manager.lock().unwrap().report(addr, points, Instant::now()) {
+            return;
+        }
+        warn!("Banning peer {} for misbehaving", addr);
+        self.disconnect(addr);
+    }
+
+    pub fn disconnect(&self, addr: SocketAddr) {
+        // the server may be gone already when shutting down
+        if self.control_chan.send(ControlSignal::Disconnect(addr)).is_err() {
+            debug!("Could not disconnect peer {}, server detached", addr);
+        }
+    }
+
+    /// Tell every peer we are closing the connection, say as we shut down, for it to drop us
+    pub fn disconnect_all(&self, reason: &str) {
+        if self.control_chan.send(ControlSignal::DisconnectAll(reason.to_string())).is_err() {
+            debug!("Could not say goodbye to the peers, server detached");
+        }
+    }
+
+    pub fn node_nonce(&self) -> u64 {
+        self.node_nonce
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    pub fn services(&self) -> u64 {
+        self.services.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.msg_queue.len(),
+            capacity: self.msg_queue.capacity(),
+            dropped_announcements: self.dropped_announcements.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Messages sent and received by type, since the start or the last reset
+    pub fn net_stats(&self) -> NetStatsSnapshot {
+        self.net_stats.snapshot()
+    }
+
+    /// Count messages from zero again, to tell the steady state from the initial sync
+    pub fn reset_net_stats(&self) {
+        self.net_stats.reset();
+    }
+
+    /// Count a message received compressed into `compressed_bytes`, of `raw_bytes` decompressed
+    pub fn record_decompressed(&self, raw_bytes: usize, compressed_bytes: usize) {
+        self.net_stats.record_compressed_received(raw_bytes, compressed_bytes);
+    }
+
+    /// Count a message received from `peer`, once decoded
+    pub fn record_received(&self, peer: &peer::Handle, msg: &message::Message, bytes: usize) {
+        self.net_stats.record_received(msg, bytes);
+        self.peer_manager.lock().unwrap().record_received(peer.id(), bytes, Instant::now());
+    }
+
+    /// What compact block relay and filtering announcements saved, for experiment reports
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.traffic
+    }
+
+    /// Each connected peer, and which side connected
+    fn peer_handles(&self) -> Vec<(peer::Handle, peer::Direction)> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if self.control_chan.send(ControlSignal::ListPeers(sender)).is_err() {
+            return Vec::new();
+        }
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// Delay and drop what is sent to `peer` from now on, for propagation experiments
+    #[cfg(feature = "emulation")]
+    pub fn set_link_conditions(&self, peer: peer::PeerId, cond: LinkConditions) {
+        self.peer_manager.lock().unwrap().links().set(peer, cond);
+    }
+
+    /// Conditions for the links to the peers without their own, or none
+    #[cfg(feature = "emulation")]
+    pub fn set_default_link_conditions(&self, cond: Option<LinkConditions>) {
+        self.peer_manager.lock().unwrap().links().set_default(cond);
+    }
+
+    /// Seed the delay and drop decisions, so that an experiment can be repeated
+    #[cfg(feature = "emulation")]
+    pub fn seed_link_emulation(&self, seed: u64) {
+        self.peer_manager.lock().unwrap().links().reseed(seed);
+    }
+
+    /// Every connected peer, in the order they connected, with what it sent and was sent
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peer_manager.lock().unwrap().peers(Instant::now())
+    }
+
+    /// A connected peer by the id its messages come with
+    pub fn peer(&self, id: peer::PeerId) -> Option<PeerInfo> {
+        self.peer_manager.lock().unwrap().peer(id, Instant::now())
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peer_handles().len()
+    }
+
+    /// The addresses of the peers we connected to
+    pub fn outbound_peers(&self) -> Vec<SocketAddr> {
+        self.peer_handles().into_iter()
+            .filter(|(_, direction)| *direction == peer::Direction::Outgoing)
+            .map(|(handle, _)| handle.addr())
+            .collect()
+    }
+
+    /// The latest round-trip time to each peer that answered a ping
+    pub fn peer_latencies(&self) -> Vec<(SocketAddr, Duration)> {
+        self.peer_handles().into_iter()
+            .filter_map(|(handle, _)| handle.rtt().map(|rtt| (handle.addr(), rtt)))
+            .collect()
+    }
+
+    /// The peers whose bans have not run out yet
+    pub fn banned_peers(&self) -> Vec<SocketAddr> {
+        self.peer_manager.lock().unwrap().banned(Instant::now())
+    }
+
+    pub fn set_ban_duration(&self, ban_duration: Duration) {
+        self.peer_manager.lock().unwrap().set_ban_duration(ban_duration);
+    }
+
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.peer_manager.lock().unwrap().set_rate_limits(limits);
+    }
+
+    /// Whether a message of `size` bytes from `addr` is within the peer's rate limits
+    pub fn allow_message(&self, addr: SocketAddr, size: usize) -> bool {
+        self.peer_manager.lock().unwrap().allow_message(addr, size, Instant::now())
+    }
+
+    /// Count a message dropped for exceeding the rate limits, penalizing a peer that keeps at it
+    pub fn record_dropped_message(&self, addr: SocketAddr) {
+        if self.peer_manager.lock().unwrap().record_dropped(addr) {
+            self.report_misbehavior(addr, RATE_LIMIT_PENALTY);
+        }
+    }
+
+    /// Messages dropped for exceeding the rate limits, per peer that had any
+    pub fn dropped_messages(&self) -> Vec<(SocketAddr, u64)> {
+        self.peer_manager.lock().unwrap().dropped_messages()
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
-    BroadcastMessage(message::Message),
+    /// A message for every ready peer, but the one given
+    BroadcastMessage(message::Message, Option<peer::PeerId>),
+    SendTo(peer::PeerId, message::Message),
+    PingAll,
+    ConnectPersistent(SocketAddr),
+    ListPeers(cbchannel::Sender<Vec<(peer::Handle, peer::Direction)>>),
+    Disconnect(SocketAddr),
+    /// Send every peer a `Disconnect` with this reason
+    DisconnectAll(String),
 }
 
 struct ConnectRequest {
     addr: std::net::SocketAddr,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Accept a connection on `listener`, failing the test if none comes within ten seconds
+    fn accept_soon(listener: &std::net::TcpListener) -> std::net::TcpStream {
+        listener.set_nonblocking(true).unwrap();
+        let start = Instant::now();
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false).unwrap();
+                    return stream;
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    assert!(start.elapsed() < Duration::from_secs(10), "no connection");
+                    thread::sleep(Duration::from_millis(20));
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    fn read_message(stream: &mut std::net::TcpStream) -> message::Message {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let mut payload = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        message::Message::decode(&payload).unwrap()
+    }
+
+    #[test]
+    fn configured_peer_is_reconnected_after_restarting() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (msg_tx, _msg_rx) = cbchannel::unbounded();
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.set_on_connect(|peer| peer.write(message::Message::Ping(1)));
+        ctx.start().unwrap();
+        server.connect_persistent(addr);
+
+        let mut stream = accept_soon(&listener);
+        assert!(matches!(read_message(&mut stream), message::Message::Ping(1)));
+        // the peer goes down, and is still down at the first attempt to reconnect
+        drop(stream);
+        drop(listener);
+        thread::sleep(RECONNECT_BACKOFF + Duration::from_millis(500));
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+
+        // greeted again once back
+        let mut stream = accept_soon(&listener);
+        assert!(matches!(read_message(&mut stream), message::Message::Ping(1)));
+        let start = Instant::now();
+        while server.outbound_peers() != vec![addr] {
+            assert!(start.elapsed() < Duration::from_secs(10), "reconnected peer not listed");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn refused_connection_fails_without_stopping_the_server() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (msg_tx, _msg_rx) = cbchannel::unbounded();
+        let (ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.start().unwrap();
+
+        // the listener is gone by now, so nothing accepts the connection
+        assert!(server.connect(closed).is_err());
+        assert!(server.peers().is_empty());
+        let peer = server.connect(listener.local_addr().unwrap()).unwrap();
+        let _stream = accept_soon(&listener);
+        assert_eq!(server.outbound_peers(), vec![peer.addr()]);
+    }
+
+    fn write_message(stream: &mut std::net::TcpStream, msg: &message::Message) {
+        use std::io::Write;
+        let payload = bincode::serialize(msg).unwrap();
+        stream.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(&payload).unwrap();
+    }
+
+    #[test]
+    fn peer_that_stops_answering_pings_is_dropped() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (msg_tx, msg_rx) = cbchannel::unbounded();
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.set_ping_interval(Duration::from_millis(300));
+        ctx.set_on_connect(|peer| peer.complete_handshake());
+        ctx.start().unwrap();
+        server.connect(addr).unwrap();
+        let mut stream = accept_soon(&listener);
+
+        // answered pings give a latency; the worker would hand each pong to the peer's handle
+        for _ in 0..2 {
+            match read_message(&mut stream) {
+                message::Message::Ping(nonce) => write_message(&mut stream, &message::Message::Pong(nonce)),
+                other => panic!("expected a ping, got {:?}", other),
+            }
+            let (bytes, peer) = msg_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            if let message::Message::Pong(nonce) = message::Message::decode(&bytes).unwrap() {
+                assert!(peer.pong(nonce).is_some());
+            }
+        }
+        assert_eq!(server.peer_latencies().len(), 1);
+        assert_eq!(server.peer_latencies()[0].0, addr);
+
+        // then the host goes silent without closing the connection
+        let start = Instant::now();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        loop {
+            let mut buffer = [0; 64];
+            if stream.read(&mut buffer).unwrap() == 0 {
+                break;
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        assert!(server.peer_latencies().is_empty());
+    }
+
+    #[test]
+    fn relay_skips_the_excluded_peer() {
+        let listeners: Vec<std::net::TcpListener> = (0..2).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let (msg_tx, _msg_rx) = cbchannel::unbounded();
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.set_on_connect(|peer| peer.complete_handshake());
+        ctx.start().unwrap();
+        let first = server.connect(listeners[0].local_addr().unwrap()).unwrap();
+        let second = server.connect(listeners[1].local_addr().unwrap()).unwrap();
+        let mut first_stream = accept_soon(&listeners[0]);
+        let mut second_stream = accept_soon(&listeners[1]);
+        assert_ne!(first.id(), second.id());
+
+        server.broadcast_except(message::Message::Ping(1), &first);
+        server.send_to(second.id(), message::Message::Ping(2));
+        server.broadcast(message::Message::Ping(3));
+        // the first peer's queue only had the last broadcast
+        assert!(matches!(read_message(&mut first_stream), message::Message::Ping(3)));
+        for nonce in 1..=3 {
+            assert!(matches!(read_message(&mut second_stream), message::Message::Ping(n) if n == nonce));
+        }
+        assert_eq!(server.net_stats().by_type["Ping"].sent, 4);
+        assert_eq!(server.net_stats().by_type["Ping"].sent_bytes, 4 * 12);
+
+        // nor is a peer after it disconnected
+        server.disconnect(second.addr());
+        server.send_to(second.id(), message::Message::Ping(4));
+        server.broadcast(message::Message::Ping(5));
+        assert!(matches!(read_message(&mut first_stream), message::Message::Ping(5)));
+    }
+
+    #[test]
+    fn peer_that_says_goodbye_is_torn_down() {
+        let listeners: Vec<std::net::TcpListener> = (0..2).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let (msg_tx, msg_rx) = cbchannel::unbounded();
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.set_on_connect(|peer| peer.complete_handshake());
+        ctx.start().unwrap();
+        server.connect(listeners[0].local_addr().unwrap()).unwrap();
+        let leaving = server.connect(listeners[1].local_addr().unwrap()).unwrap();
+        let mut staying_stream = accept_soon(&listeners[0]);
+        let mut leaving_stream = accept_soon(&listeners[1]);
+        leaving.add_known_inventory(vec![H256::from([7; 32])]);
+
+        // as the worker does with a `Disconnect` it reads
+        write_message(&mut leaving_stream, &message::Message::Disconnect { reason: "shutting down".to_string() });
+        let (bytes, peer) = msg_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let msg = message::Message::decode(&bytes).unwrap();
+        assert!(matches!(msg, message::Message::Disconnect { .. }));
+        server.record_received(&peer, &msg, bytes.len());
+        assert_eq!(server.peer(peer.id()).unwrap().bytes_received, bytes.len() as u64);
+        server.disconnect(peer.addr());
+        assert_eq!(server.peer_count(), 1);
+        assert!(server.peer(peer.id()).is_none());
+        assert_eq!(server.peers().len(), 1);
+
+        server.broadcast(message::Message::Ping(1));
+        assert!(matches!(read_message(&mut staying_stream), message::Message::Ping(1)));
+        leaving_stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(leaving_stream.read(&mut [0; 16]).unwrap(), 0, "connection still open");
+        assert_eq!(server.net_stats().by_type["Ping"].sent, 1);
+        assert!(leaving.try_write(message::Message::Ping(2)).is_err());
+        assert!(!leaving.knows_inventory(&H256::from([7; 32])));
+
+        // and we say goodbye ourselves when shutting down
+        server.disconnect_all("shutting down");
+        assert!(matches!(read_message(&mut staying_stream), message::Message::Disconnect { reason } if reason == "shutting down"));
+    }
+
+    #[test]
+    fn announcements_skip_peers_that_have_the_block() {
+        let listeners: Vec<std::net::TcpListener> = (0..3).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let (msg_tx, _msg_rx) = cbchannel::unbounded();
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.set_on_connect(|peer| peer.complete_handshake());
+        ctx.start().unwrap();
+        let peers: Vec<peer::Handle> = listeners.iter().map(|listener| server.connect(listener.local_addr().unwrap()).unwrap()).collect();
+        let mut streams: Vec<std::net::TcpStream> = listeners.iter().map(accept_soon).collect();
+
+        // as the worker records a block the first peer sent us
+        let block = H256::from([7; 32]);
+        peers[0].add_known_inventory(vec![block]);
+        server.broadcast(message::Message::NewBlockHashes(vec![block]));
+        // announced once to each of the others, and never again
+        server.broadcast(message::Message::NewBlockHashes(vec![block]));
+        server.broadcast(message::Message::Ping(1));
+        assert!(matches!(read_message(&mut streams[0]), message::Message::Ping(1)));
+        for stream in &mut streams[1..] {
+            assert!(matches!(read_message(stream), message::Message::NewBlockHashes(hashes) if hashes == vec![block]));
+            assert!(matches!(read_message(stream), message::Message::Ping(1)));
+        }
+        // once for the first peer, then once for each peer
+        assert_eq!(server.traffic().snapshot().announcements_filtered, 4);
+    }
+
+    #[test]
+    fn full_queue_drops_announcements_and_holds_back_the_rest() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (msg_tx, msg_rx) = cbchannel::bounded(4);
+        let (ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.start().unwrap();
+        server.connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = accept_soon(&listener);
+
+        // nobody takes from the queue while a peer floods us
+        for _ in 0..10 {
+            write_message(&mut stream, &message::Message::NewBlockHashes(vec![H256::default()]));
+        }
+        write_message(&mut stream, &message::Message::Pong(7));
+        write_message(&mut stream, &message::Message::NewTransactionHashes(vec![H256::default()]));
+        let start = Instant::now();
+        while server.queue_stats().dropped_announcements < 6 {
+            assert!(start.elapsed() < Duration::from_secs(10), "announcements not dropped");
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.queue_stats(), QueueStats { depth: 4, capacity: Some(4), dropped_announcements: 6 });
+
+        // the pong waits for room, and is not dropped
+        let received: Vec<message::Message> = (0..6)
+            .map(|_| message::Message::decode(&msg_rx.recv_timeout(Duration::from_secs(5)).unwrap().0).unwrap())
+            .collect();
+        assert!(received[..4].iter().all(|msg| matches!(msg, message::Message::NewBlockHashes(_))));
+        assert!(matches!(received[4], message::Message::Pong(7)));
+        assert!(matches!(received[5], message::Message::NewTransactionHashes(_)));
+        assert_eq!(server.queue_stats().dropped_announcements, 6);
+    }
+
+    #[test]
+    fn full_queue_stops_reading_one_peer_and_serves_the_others() {
+        let listeners: Vec<std::net::TcpListener> = (0..2).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let (msg_tx, msg_rx) = cbchannel::bounded(1);
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        ctx.set_on_connect(|peer| peer.complete_handshake());
+        ctx.start().unwrap();
+        for listener in &listeners {
+            server.connect(listener.local_addr().unwrap()).unwrap();
+        }
+        let mut streams: Vec<std::net::TcpStream> = listeners.iter().map(accept_soon).collect();
+
+        // the second pong has no room, and waits with its peer
+        write_message(&mut streams[0], &message::Message::Pong(1));
+        write_message(&mut streams[0], &message::Message::Pong(2));
+        let start = Instant::now();
+        while server.queue_stats().depth < 1 {
+            assert!(start.elapsed() < Duration::from_secs(10), "pong not queued");
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        // meanwhile the server goes on with the other peers
+        assert_eq!(server.peers().len(), 2);
+        server.broadcast(message::Message::Ping(3));
+        for stream in &mut streams {
+            assert!(matches!(read_message(stream), message::Message::Ping(3)));
+        }
+        write_message(&mut streams[1], &message::Message::Pong(4));
+
+        // and each peer's messages get in, in order, as the workers catch up
+        let received: Vec<(u64, peer::PeerId)> = (0..3)
+            .map(|_| {
+                let (bytes, peer) = msg_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+                match message::Message::decode(&bytes).unwrap() {
+                    message::Message::Pong(nonce) => (nonce, peer.id()),
+                    other => panic!("expected a pong, got {:?}", other),
+                }
+            })
+            .collect();
+        let from_first: Vec<u64> = received.iter().filter(|(_, id)| *id == received[0].1).map(|(nonce, _)| *nonce).collect();
+        assert_eq!(from_first, vec![1, 2]);
+        assert_eq!(server.queue_stats().dropped_announcements, 0);
+    }
+
+    #[test]
+    fn oversized_frame_drops_the_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (msg_tx, msg_rx) = cbchannel::unbounded();
+        let (mut ctx, server) = new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        assert!(ctx.set_max_frame_size(MAX_MESSAGE_SIZE as usize + 1).is_err());
+        ctx.set_max_frame_size(1024).unwrap();
+        ctx.start().unwrap();
+        server.connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = accept_soon(&listener);
+
+        // a frame at the limit gets through
+        use std::io::Write;
+        let mut padded = bincode::serialize(&message::Message::Ping(1)).unwrap();
+        padded.resize(1024, 0);
+        stream.write_all(&1024u32.to_be_bytes()).unwrap();
+        stream.write_all(&padded).unwrap();
+        let (bytes, _) = msg_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(bytes.len(), 1024);
+
+        // a gigabyte announced, and none of it sent
+        stream.write_all(&(1u32 << 30).to_be_bytes()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0, "connection still open");
+        assert!(msg_rx.try_recv().is_err());
+    }
+}
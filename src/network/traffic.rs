@@ -0,0 +1,253 @@
+//! Counters of the messages sent and received, by type, and of what compact block relay and
+//! filtering announcements by known inventory saved.
+
+use super::message::{Message, MESSAGE_KINDS};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Messages of one type sent and received, and their bytes
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessageStats {
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub received: u64,
+    pub received_bytes: u64,
+}
+
+/// Messages that went over the wire compressed, and their bytes before and after
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionStats {
+    pub messages: u64,
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+/// What `NetStats` counted since it was last reset, for the message types seen. A compressed
+/// message counts as the type of the message inside, with the bytes it took compressed.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct NetStatsSnapshot {
+    pub by_type: BTreeMap<String, MessageStats>,
+    pub compressed_sent: CompressionStats,
+    pub compressed_received: CompressionStats,
+    /// Messages the link emulation dropped instead of sending
+    pub injected_drops: u64,
+}
+
+impl fmt::Display for NetStatsSnapshot {
+    /// A table of the counts and bytes of each type, with the totals last
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<22}{:>10}{:>14}{:>10}{:>14}", "message", "sent", "sent bytes", "received", "recv bytes")?;
+        let mut total = MessageStats::default();
+        for (kind, stats) in &self.by_type {
+            writeln!(f, "{:<22}{:>10}{:>14}{:>10}{:>14}", kind, stats.sent, stats.sent_bytes, stats.received, stats.received_bytes)?;
+            total.sent += stats.sent;
+            total.sent_bytes += stats.sent_bytes;
+            total.received += stats.received;
+            total.received_bytes += stats.received_bytes;
+        }
+        write!(f, "{:<22}{:>10}{:>14}{:>10}{:>14}", "total", total.sent, total.sent_bytes, total.received, total.received_bytes)?;
+        for (direction, stats) in &[("sent", self.compressed_sent), ("received", self.compressed_received)] {
+            if stats.messages > 0 {
+                write!(f, "\n{} {} compressed, {} bytes down from {}", stats.messages, direction, stats.compressed_bytes, stats.raw_bytes)?;
+            }
+        }
+        if self.injected_drops > 0 {
+            write!(f, "\n{} dropped by link emulation", self.injected_drops)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    sent_bytes: AtomicU64,
+    received: AtomicU64,
+    received_bytes: AtomicU64,
+}
+
+#[derive(Default)]
+struct CompressionCounters {
+    messages: AtomicU64,
+    raw_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+impl CompressionCounters {
+    fn record(&self, raw_bytes: usize, compressed_bytes: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.raw_bytes.fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.compressed_bytes.fetch_add(compressed_bytes as u64, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.messages.store(0, Ordering::Relaxed);
+        self.raw_bytes.store(0, Ordering::Relaxed);
+        self.compressed_bytes.store(0, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CompressionStats {
+        CompressionStats {
+            messages: self.messages.load(Ordering::Relaxed),
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Messages sent and received by type, counted where they are serialized and decoded
+#[derive(Default)]
+pub struct NetStats {
+    by_kind: [Counters; MESSAGE_KINDS.len()],
+    compressed_sent: CompressionCounters,
+    compressed_received: CompressionCounters,
+    injected_drops: AtomicU64,
+}
+
+impl NetStats {
+    pub fn record_sent(&self, msg: &Message, bytes: usize) {
+        let counters = &self.by_kind[msg.kind_index()];
+        counters.sent.fetch_add(1, Ordering::Relaxed);
+        counters.sent_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, msg: &Message, bytes: usize) {
+        let counters = &self.by_kind[msg.kind_index()];
+        counters.received.fetch_add(1, Ordering::Relaxed);
+        counters.received_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a message of `raw_bytes` sent compressed into `compressed_bytes`
+    pub fn record_compressed_sent(&self, raw_bytes: usize, compressed_bytes: usize) {
+        self.compressed_sent.record(raw_bytes, compressed_bytes);
+    }
+
+    pub fn record_compressed_received(&self, raw_bytes: usize, compressed_bytes: usize) {
+        self.compressed_received.record(raw_bytes, compressed_bytes);
+    }
+
+    /// Count a message the link emulation dropped, which is not counted as sent
+    pub fn record_injected_drop(&self) {
+        self.injected_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Start counting from zero, say once the initial sync is over
+    pub fn reset(&self) {
+        for counters in &self.by_kind {
+            counters.sent.store(0, Ordering::Relaxed);
+            counters.sent_bytes.store(0, Ordering::Relaxed);
+            counters.received.store(0, Ordering::Relaxed);
+            counters.received_bytes.store(0, Ordering::Relaxed);
+        }
+        self.compressed_sent.reset();
+        self.compressed_received.reset();
+        self.injected_drops.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NetStatsSnapshot {
+        let by_type = MESSAGE_KINDS.iter().zip(&self.by_kind)
+            .map(|(kind, counters)| (kind.to_string(), MessageStats {
+                sent: counters.sent.load(Ordering::Relaxed),
+                sent_bytes: counters.sent_bytes.load(Ordering::Relaxed),
+                received: counters.received.load(Ordering::Relaxed),
+                received_bytes: counters.received_bytes.load(Ordering::Relaxed),
+            }))
+            .filter(|(_, stats)| *stats != MessageStats::default())
+            .collect();
+        NetStatsSnapshot {
+            by_type,
+            compressed_sent: self.compressed_sent.snapshot(),
+            compressed_received: self.compressed_received.snapshot(),
+            injected_drops: self.injected_drops.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What `TrafficStats` counted so far
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct TrafficSnapshot {
+    /// Blocks rebuilt from a compact block
+    pub compact_blocks: u64,
+    /// Compact blocks given up on, fetched whole instead
+    pub compact_fallbacks: u64,
+    /// Bytes the rebuilt blocks would have taken whole, less those their compact blocks and
+    /// missing transactions took
+    pub compact_bytes_saved: u64,
+    /// Hashes left out of announcements to peers that had them already
+    pub announcements_filtered: u64,
+    /// Block requests not sent because the block was already requested from another peer
+    pub suppressed_block_requests: u64,
+}
+
+#[derive(Default)]
+pub struct TrafficStats {
+    compact_blocks: AtomicU64,
+    compact_fallbacks: AtomicU64,
+    compact_bytes_saved: AtomicU64,
+    announcements_filtered: AtomicU64,
+    suppressed_block_requests: AtomicU64,
+}
+
+impl TrafficStats {
+    /// Count a block rebuilt from `compact_bytes` of compact block and missing transactions
+    /// that would have taken `full_bytes` whole
+    pub fn record_compact_block(&self, full_bytes: u64, compact_bytes: u64) {
+        self.compact_blocks.fetch_add(1, Ordering::Relaxed);
+        self.compact_bytes_saved.fetch_add(full_bytes.saturating_sub(compact_bytes), Ordering::Relaxed);
+    }
+
+    pub fn record_compact_fallback(&self) {
+        self.compact_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_filtered_announcements(&self, hashes: usize) {
+        self.announcements_filtered.fetch_add(hashes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_suppressed_block_requests(&self, hashes: usize) {
+        self.suppressed_block_requests.fetch_add(hashes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            compact_blocks: self.compact_blocks.load(Ordering::Relaxed),
+            compact_fallbacks: self.compact_fallbacks.load(Ordering::Relaxed),
+            compact_bytes_saved: self.compact_bytes_saved.load(Ordering::Relaxed),
+            announcements_filtered: self.announcements_filtered.load(Ordering::Relaxed),
+            suppressed_block_requests: self.suppressed_block_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+
+    #[test]
+    fn messages_are_counted_by_type_and_direction() {
+        let stats = NetStats::default();
+        let messages = [Message::Ping(1), Message::Pong(1), Message::Ping(2), Message::NewBlockHashes(vec![H256::default(); 3])];
+        for msg in &messages {
+            let bytes = bincode::serialize(msg).unwrap();
+            // the type is the variant tag that leads the bytes
+            assert_eq!(bytes[..4], (msg.kind_index() as u32).to_le_bytes());
+            stats.record_sent(msg, bytes.len());
+        }
+        stats.record_received(&Message::Pong(1), 12);
+        stats.record_compressed_sent(10_000, 2_000);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.by_type.keys().collect::<Vec<_>>(), vec!["NewBlockHashes", "Ping", "Pong"]);
+        assert_eq!(snapshot.by_type["Ping"], MessageStats { sent: 2, sent_bytes: 24, received: 0, received_bytes: 0 });
+        assert_eq!(snapshot.by_type["Pong"], MessageStats { sent: 1, sent_bytes: 12, received: 1, received_bytes: 12 });
+        assert_eq!(snapshot.by_type["NewBlockHashes"].sent_bytes, 4 + 8 + 3 * 32);
+        assert_eq!(snapshot.compressed_sent, CompressionStats { messages: 1, raw_bytes: 10_000, compressed_bytes: 2_000 });
+        assert!(snapshot.to_string().lines().any(|line| line.starts_with("total")));
+
+        stats.reset();
+        assert_eq!(stats.snapshot(), NetStatsSnapshot::default());
+    }
+}
@@ -0,0 +1,163 @@
+//! What the worker does with a connection to a peer, and with the server to reach every peer, so
+//! that its handlers run as well against a `MockPeer` and a `MockRelay` in tests as against the
+//! `peer::Handle` of a TCP connection and the `server::Handle` of a running server.
+
+use super::message::Message;
+use super::peer::{self, PeerId};
+use super::server;
+use crate::crypto::hash::H256;
+use std::net::SocketAddr;
+
+/// Tells connections apart; all that is kept of a peer to ask it again later
+pub trait Identity {
+    fn id(&self) -> PeerId;
+    /// The remote address, by which scores and requests are kept
+    fn addr(&self) -> SocketAddr;
+}
+
+/// A connection the worker answers and asks through
+pub trait Transport: Identity {
+    /// Queue `msg` for the peer, logging rather than failing if it is disconnected
+    fn write(&self, msg: Message);
+    /// Record blocks or transactions the peer announced or sent us
+    fn add_known_inventory(&self, hashes: &[H256]);
+}
+
+/// What the worker sends to every ready peer at once
+pub trait Relay {
+    fn broadcast(&self, msg: Message);
+    /// Broadcast to every peer but `except`, say the one that sent us what we relay
+    fn broadcast_except(&self, msg: Message, except: &dyn Identity);
+}
+
+/// A peer by its identity alone, say one asked for blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerRef {
+    id: PeerId,
+    addr: SocketAddr,
+}
+
+impl PeerRef {
+    pub fn of(peer: &impl Identity) -> Self {
+        PeerRef { id: peer.id(), addr: peer.addr() }
+    }
+}
+
+impl Identity for PeerRef {
+    fn id(&self) -> PeerId {
+        self.id
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Identity for peer::Handle {
+    fn id(&self) -> PeerId {
+        peer::Handle::id(self)
+    }
+
+    fn addr(&self) -> SocketAddr {
+        peer::Handle::addr(self)
+    }
+}
+
+impl Transport for peer::Handle {
+    fn write(&self, msg: Message) {
+        peer::Handle::write(self, msg)
+    }
+
+    fn add_known_inventory(&self, hashes: &[H256]) {
+        peer::Handle::add_known_inventory(self, hashes.iter().cloned())
+    }
+}
+
+impl Relay for server::Handle {
+    fn broadcast(&self, msg: Message) {
+        server::Handle::broadcast(self, msg)
+    }
+
+    fn broadcast_except(&self, msg: Message, except: &dyn Identity) {
+        server::Handle::broadcast_except(self, msg, except)
+    }
+}
+
+#[cfg(test)]
+pub use mock::{MockPeer, MockRelay};
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use crossbeam::channel;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    /// A peer with no connection behind it: what is written to it comes out of the receiver
+    /// returned with it, as messages
+    #[derive(Clone)]
+    pub struct MockPeer {
+        id: PeerId,
+        addr: SocketAddr,
+        written: channel::Sender<Message>,
+        known: Arc<Mutex<HashSet<H256>>>,
+    }
+
+    impl MockPeer {
+        pub fn new(addr: SocketAddr) -> (MockPeer, channel::Receiver<Message>) {
+            let (written, receiver) = channel::unbounded();
+            let peer = MockPeer { id: PeerId::next(), addr, written, known: Arc::new(Mutex::new(HashSet::new())) };
+            (peer, receiver)
+        }
+
+        /// Whether the peer announced or sent us `hash`
+        pub fn knows(&self, hash: &H256) -> bool {
+            self.known.lock().unwrap().contains(hash)
+        }
+    }
+
+    impl Identity for MockPeer {
+        fn id(&self) -> PeerId {
+            self.id
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.addr
+        }
+    }
+
+    impl Transport for MockPeer {
+        fn write(&self, msg: Message) {
+            // the test may have stopped listening
+            self.written.send(msg).ok();
+        }
+
+        fn add_known_inventory(&self, hashes: &[H256]) {
+            self.known.lock().unwrap().extend(hashes.iter().cloned());
+        }
+    }
+
+    /// A server with no peers behind it: what is broadcast comes out of the receiver returned
+    /// with it, each message with the peer left out if any
+    #[derive(Clone)]
+    pub struct MockRelay {
+        broadcast: channel::Sender<(Message, Option<PeerId>)>,
+    }
+
+    impl MockRelay {
+        pub fn new() -> (MockRelay, channel::Receiver<(Message, Option<PeerId>)>) {
+            let (broadcast, receiver) = channel::unbounded();
+            (MockRelay { broadcast }, receiver)
+        }
+    }
+
+    impl Relay for MockRelay {
+        fn broadcast(&self, msg: Message) {
+            self.broadcast.send((msg, None)).ok();
+        }
+
+        fn broadcast_except(&self, msg: Message, except: &dyn Identity) {
+            self.broadcast.send((msg, Some(except.id()))).ok();
+        }
+    }
+}
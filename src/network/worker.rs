@@ -1,25 +1,180 @@
-use super::message::Message;
+use super::addr_book::AddrBook;
+use super::header_sync::{HeaderError, HeaderSync};
+use super::in_flight::InFlight;
+use super::lanes::Lanes;
+use super::limits::{MAX_ADDRS_PER_MESSAGE, MAX_BLOCKS_MESSAGE_BYTES, MAX_BLOCKS_PER_MESSAGE, MAX_HASHES_PER_MESSAGE, MAX_HEADERS_PER_MESSAGE};
+use super::message::{Message, PROTOCOL_VERSION};
 use super::peer;
-use crate::mempool::Mempool;
+use super::peer_manager::{BAN_THRESHOLD, HANDSHAKE_PENALTY, INVALID_BLOCK_PENALTY, INVALID_TRANSACTION_PENALTY, MALFORMED_MESSAGE_PENALTY};
+use super::request_tracker::{RequestTracker, REQUEST_TIMEOUT};
+use super::transport::{Identity, Relay, Transport};
+use crate::mempool::{batches, Mempool};
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
-use log::{debug, warn};
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::blockchain::Blockchain;
-use crate::crypto::hash::Hashable;
-use crate::blockchain::BlockOrigin;
+use crate::block::{Block, Content, Header, MAX_BLOCK_SIZE};
+use crate::crypto::hash::{H256, Hashable};
+use crate::blockchain::{BlockOrigin, InsertError, InsertOutcome};
+use crate::transaction::{verify_batch, BlockError, SignedTransaction, MAX_DATA_SIZE};
+use crate::validation::RejectReason;
 
 use std::thread;
 
+/// Most transaction hashes we announce in answer to one `GetMempool`
+const MAX_MEMPOOL_ANNOUNCEMENT: usize = 16 * MAX_HASHES_PER_MESSAGE;
+/// How long a block may wait in the orphan buffer for its parent
+const ORPHAN_MAX_AGE: Duration = Duration::from_secs(600);
+/// How often a single peer may ask for our mempool
+const MEMPOOL_REQUEST_INTERVAL: Duration = Duration::from_secs(30);
+/// How many peers we connect to ourselves unless configured otherwise
+pub const DEFAULT_OUTBOUND_TARGET: usize = 8;
+/// How often we check that we have enough outbound peers
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often we ask our peers for addresses
+const ADDR_REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+/// How often we ask our peers for their tips, in case we missed the announcements of blocks
+const TIP_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Most responses held back for exceeding their peer's rate limits, all peers together
+const MAX_DEFERRED_MESSAGES: usize = 1024;
+/// How long a worker waits for a message before looking at the deferred ones again
+const DEFERRED_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+/// Attempts at rebuilding a compact block before fetching it whole
+const MAX_COMPACT_ATTEMPTS: u32 = 2;
+/// Most compact blocks waiting for their missing transactions
+const MAX_PENDING_COMPACT_BLOCKS: usize = 64;
+
+/// A compact block being rebuilt
+struct PartialBlock {
+    header: Header,
+    /// The block's transactions, those not found yet left out
+    txs: Vec<Option<SignedTransaction>>,
+    /// The positions of the transactions last asked for
+    missing: Vec<u32>,
+    /// Rebuilds that did not match the header's merkle root
+    failures: u32,
+    /// Bytes received for the block so far, to tell what fetching it whole would have cost more
+    bytes: u64,
+}
+
+/// Split the blocks answering a `GetBlocks` request into pages of at most
+/// `MAX_BLOCKS_PER_MESSAGE` blocks and `MAX_BLOCKS_MESSAGE_BYTES` bytes, keeping their order
+fn paginate(blocks: Vec<Arc<Block>>) -> Vec<Vec<Arc<Block>>> {
+    let mut pages: Vec<Vec<Arc<Block>>> = Vec::new();
+    let mut page_bytes = 0;
+    for block in blocks {
+        let bytes = bincode::serialized_size(&*block).unwrap();
+        match pages.last_mut() {
+            Some(page) if page.len() < MAX_BLOCKS_PER_MESSAGE && page_bytes + bytes <= MAX_BLOCKS_MESSAGE_BYTES => {
+                page_bytes += bytes;
+                page.push(block);
+            }
+            _ => {
+                page_bytes = bytes;
+                pages.push(vec![block]);
+            }
+        }
+    }
+    pages
+}
+
+/// Introduce ourselves to a newly connected peer, the first message on any connection
+pub fn send_version(peer: &peer::Handle, blockchain: &Blockchain, server: &ServerHandle) {
+    peer.write(Message::Version {
+        protocol: PROTOCOL_VERSION,
+        genesis: blockchain.genesis_hash(),
+        tip_height: blockchain.tip_height(),
+        node_nonce: server.node_nonce(),
+        listen_port: server.listen_port(),
+        services: server.services(),
+    });
+}
+
+/// Once the handshake with a peer is complete, tell it where our chain is, so that whichever of
+/// us is behind catches up, and catch up on the transactions and peers it knows
+fn greet(peer: &peer::Handle, blockchain: &Blockchain) {
+    peer.write(Message::Status(blockchain.tip(), blockchain.tip_height()));
+    peer.write(Message::GetMempool);
+    peer.write(Message::GetAddr);
+}
+
+/// Received blocks waiting to be validated, each message of them with its sender
+type BlockLanes = Lanes<(Vec<Arc<Block>>, peer::Handle)>;
+
+#[cfg(test)]
+type OnValidate = Arc<dyn Fn(&H256) + Send + Sync>;
+
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
+    /// What is sent to every peer goes through here: the server, or a mock in tests
+    relay: Arc<dyn Relay + Send + Sync>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    /// Blocks a worker is validating or inserting, for the others to skip
+    in_flight: InFlight,
+    /// Received blocks, queued by the workers for the validation threads peer by peer
+    block_lanes: BlockLanes,
+    /// Transactions a worker is admitting to the mempool, likewise
+    tx_in_flight: InFlight,
+    /// Blocks asked for with `GetBlocks` and not received yet
+    requests: Arc<Mutex<RequestTracker>>,
+    /// Headers of a longer chain being synced, whose bodies are still to fetch
+    sync: Arc<Mutex<HeaderSync>>,
+    /// Peers we heard of, to connect to
+    addr_book: Arc<Mutex<AddrBook>>,
+    /// How many outbound peers to keep connected to
+    outbound_target: usize,
+    /// How often to ask peers for their tips, zero for never
+    tip_check_interval: Duration,
+    /// Responses over their peer's rate limits, with their size, to handle once it is under them
+    deferred: Arc<Mutex<VecDeque<(Message, usize, peer::Handle)>>>,
+    /// Compact blocks waiting for the transactions we asked for
+    compact: Arc<Mutex<HashMap<H256, PartialBlock>>>,
+    /// Set to have the threads return
+    shutdown: Arc<AtomicBool>,
+    /// Called with each block about to be validated, for tests to count and stall validations
+    #[cfg(test)]
+    on_validate: Option<OnValidate>,
+}
 
+/// Stops the threads of a started worker context
+#[derive(Clone)]
+pub struct Handle {
+    server: ServerHandle,
+    shutdown: Arc<AtomicBool>,
+    block_lanes: BlockLanes,
+    threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+}
+
+impl Handle {
+    /// Tell the peers we are leaving, then stop the worker threads, those validating blocks, and
+    /// those requesting blocks again and connecting to peers, and wait for them; a worker
+    /// finishes the message it is handling first, but gives up verifying a block's signatures,
+    /// and the blocks still queued are dropped
+    pub fn shutdown(&self) {
+        self.server.disconnect_all("shutting down");
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.block_lanes.close();
+        let threads: Vec<thread::JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        // the helpers may be asleep until their next round
+        for thread in &threads {
+            thread.thread().unpark();
+        }
+        for thread in threads {
+            if thread.join().is_err() {
+                warn!("A network thread panicked before shutting down");
+            }
+        }
+        info!("Network workers shut down");
+    }
 }
 
 pub fn new(
@@ -33,90 +188,723 @@ pub fn new(
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
+        relay: Arc::new(server.clone()),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        in_flight: InFlight::new(),
+        block_lanes: Lanes::new(),
+        tx_in_flight: InFlight::new(),
+        requests: Arc::new(Mutex::new(RequestTracker::new())),
+        sync: Arc::new(Mutex::new(HeaderSync::new())),
+        addr_book: Arc::new(Mutex::new(AddrBook::new(false))),
+        outbound_target: DEFAULT_OUTBOUND_TARGET,
+        tip_check_interval: TIP_CHECK_INTERVAL,
+        deferred: Arc::new(Mutex::new(VecDeque::new())),
+        compact: Arc::new(Mutex::new(HashMap::new())),
+        shutdown: Arc::new(AtomicBool::new(false)),
+        #[cfg(test)]
+        on_validate: None,
     }
 }
 
 impl Context {
-    pub fn start(self) {
+    pub fn set_outbound_target(&mut self, target: usize) {
+        self.outbound_target = target;
+    }
+
+    /// Ask peers for their tips this often, or never if zero
+    pub fn set_tip_check_interval(&mut self, interval: Duration) {
+        self.tip_check_interval = interval;
+    }
+
+    /// Learn and gossip loopback and private addresses too, for experiments on one host
+    pub fn set_allow_local_addrs(&self, allow: bool) {
+        self.addr_book.lock().unwrap().set_allow_local(allow);
+    }
+
+    pub fn start(self) -> Handle {
         let num_worker = self.num_worker;
+        let mut threads = Vec::new();
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
+            threads.push(thread::spawn(move || {
                 cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+                if !cloned.is_shutting_down() {
+                    warn!("Worker thread {} exited", i);
+                }
+            }));
+        }
+        // as many validating blocks as taking messages, each on the blocks of another peer
+        for _ in 0..num_worker {
+            let cloned = self.clone();
+            threads.push(thread::spawn(move || {
+                while let Some(((blocks, peer), _turn)) = cloned.block_lanes.next() {
+                    cloned.receive_blocks(blocks, &peer);
+                }
+            }));
+        }
+        let cloned = self.clone();
+        threads.push(thread::spawn(move || {
+            while cloned.sleep(REQUEST_TIMEOUT / 5) {
+                cloned.rerequest_missing(Instant::now());
+            }
+        }));
+        let cloned = self.clone();
+        threads.push(thread::spawn(move || {
+            let mut last_addr_request = Instant::now();
+            while cloned.sleep(CONNECTION_CHECK_INTERVAL) {
+                cloned.maintain_outbound(Instant::now());
+                if last_addr_request.elapsed() >= ADDR_REQUEST_INTERVAL {
+                    cloned.relay.broadcast(Message::GetAddr);
+                    last_addr_request = Instant::now();
+                }
+            }
+        }));
+        if !self.tip_check_interval.is_zero() {
+            let cloned = self.clone();
+            threads.push(thread::spawn(move || {
+                while cloned.sleep(cloned.tip_check_interval) {
+                    cloned.relay.broadcast(Message::GetTip);
+                }
+            }));
+        }
+        Handle {
+            server: self.server.clone(),
+            shutdown: Arc::clone(&self.shutdown),
+            block_lanes: self.block_lanes.clone(),
+            threads: Arc::new(Mutex::new(threads)),
         }
     }
 
-    fn worker_loop(&self) {
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for about `duration`, waking early on shutdown. Returns false if shutting down.
+    fn sleep(&self, duration: Duration) -> bool {
+        if !self.is_shutting_down() {
+            thread::park_timeout(duration);
+        }
+        !self.is_shutting_down()
+    }
+
+    /// Connect to peers from the address book until we have `outbound_target` outbound peers
+    fn maintain_outbound(&self, now: Instant) {
+        let outbound = self.server.outbound_peers();
+        if outbound.len() >= self.outbound_target {
+            return;
+        }
+        let candidates = self.addr_book.lock().unwrap().select(self.outbound_target - outbound.len(), &outbound, now);
+        for addr in candidates {
+            info!("Connecting to peer {} from the address book", addr);
+            let result = self.server.connect(addr);
+            let mut addr_book = self.addr_book.lock().unwrap();
+            match result {
+                Ok(_) => addr_book.connected(addr, now),
+                Err(e) => {
+                    debug!("Could not connect to peer {}: {}", addr, e);
+                    addr_book.failed(addr, now);
+                }
+            }
+        }
+    }
+
+    /// Ask for blocks again whose requests timed out, each from another peer than last time
+    fn rerequest_missing(&self, now: Instant) {
+        let (retries, given_up) = self.requests.lock().unwrap().take_timed_out(now);
+        if !given_up.is_empty() {
+            let mut sync = self.sync.lock().unwrap();
+            for hash in given_up {
+                warn!("Giving up on block {}, requested too many times", hash);
+                sync.block_done(&hash);
+            }
+        }
+        if retries.is_empty() {
+            return;
+        }
+        let blockchain = self.blockchain.lock().unwrap();
+        let mut requests = self.requests.lock().unwrap();
+        for (peer, hashes) in retries {
+            let missing: Vec<H256> = hashes.into_iter().filter(|hash| !blockchain.contains_block(hash)).collect();
+            if missing.is_empty() {
+                continue;
+            }
+            debug!("Requesting {} blocks again from peer {}", missing.len(), peer.addr());
+            requests.requested(&missing, &peer, now);
+            self.server.send_to(peer.id(), Message::GetBlocks(missing));
+        }
+    }
+
+    /// Validate and insert received blocks. Returns the hashes to relay and the missing parents to request.
+    /// Checks that don't need the blockchain run without holding its lock, so workers handling
+    /// blocks from different peers only serialize on the insertion itself.
+    fn process_blocks(&self, blocks: Vec<Arc<Block>>, from: SocketAddr) -> (Vec<H256>, Vec<H256>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let (difficulty, chain, sig_cache, blocks) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            // re-announced blocks, our own echoing back among them, are not validated again
+            let blocks: Vec<Arc<Block>> = blocks.into_iter().filter(|block| !blockchain.contains_block(&block.hash())).collect();
+            (blockchain.difficulty(), blockchain.chain_id(), blockchain.sig_cache(), blocks)
+        };
+        let mut valid_blocks = Vec::new();
+        let mut claims = Vec::new();
+        for block in blocks {
+            // the same block from another peer, which another worker is validating already
+            let claim = match self.in_flight.claim(block.hash()) {
+                Some(claim) => claim,
+                None => {
+                    debug!("Block {} is being processed already", block.hash());
+                    continue;
+                }
+            };
+            #[cfg(test)]
+            {
+                if let Some(on_validate) = &self.on_validate {
+                    on_validate(&block.hash());
+                }
+            }
+            if block.hash() > difficulty || block.header.difficulty != difficulty {
+                warn!("PoW check failed");
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            if !block.verify_merkle_root() {
+                warn!("Merkle root check failed for block {}", block.hash());
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            if block.content.size() > MAX_BLOCK_SIZE {
+                warn!("Block {} has {} bytes of transactions, over the limit of {}", block.hash(), block.content.size(), MAX_BLOCK_SIZE);
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            if let Err((i, e)) = block.check_coinbase() {
+                warn!("Transaction {} of block {} breaks the coinbase rules: {}", i, block.hash(), e);
+                self.blockchain.lock().unwrap().record_block_reject(RejectReason::BadCoinbase);
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            // the coinbase aside, which is unsigned
+            match verify_batch(&block.content.transactions[1..], &chain, &sig_cache, &self.shutdown) {
+                Ok(()) => {}
+                // not the peer's fault: the block is left unprocessed, as are those after it
+                Err(BlockError::Cancelled) => {
+                    info!("Validation of block {} cancelled by shutdown", block.hash());
+                    return (Vec::new(), Vec::new());
+                }
+                Err(BlockError::InvalidTransaction(i)) => {
+                    let tx = &block.content.transactions[i + 1];
+                    let reason = if sig_cache.verify_signature(tx, &chain) { RejectReason::WrongOwner } else { RejectReason::BadSignature };
+                    warn!("Transaction {} of block {} is not signed by its sender: {}", i + 1, block.hash(), reason);
+                    self.blockchain.lock().unwrap().record_block_reject(reason);
+                    self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                    continue;
+                }
+            }
+            if let Some(i) = block.oversized_data() {
+                warn!("Transaction {} of block {} carries more than {} bytes of data", i, block.hash(), MAX_DATA_SIZE);
+                self.blockchain.lock().unwrap().record_block_reject(RejectReason::DataTooLarge);
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            if let Some(i) = block.nonce_conflict() {
+                warn!("Transaction {} of block {} conflicts with an earlier nonce from its sender", i, block.hash());
+                self.blockchain.lock().unwrap().record_block_reject(RejectReason::BadNonce);
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            claims.push(claim);
+            valid_blocks.push(block);
+        }
+        // nor are the state replays under the lock started once shutting down
+        if self.is_shutting_down() && !valid_blocks.is_empty() {
+            info!("Insertion of {} validated blocks cancelled by shutdown", valid_blocks.len());
+            return (Vec::new(), Vec::new());
+        }
+
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let mut relay_hashes = Vec::new();
+        let mut missing_hashes = Vec::new();
+        for block in valid_blocks {
+            // inserted by another worker since, and so not counted towards the delays again
+            if blockchain.contains_block(&block.hash()) {
+                continue;
+            }
+            if !blockchain.timestamp_validity_check(&block, now) {
+                warn!("Timestamp check failed for block {}", block.hash());
+                continue;
+            }
+            // orphans are checked once their parent, and so their height, is known
+            let height = blockchain.get_height(&block.header.parent).map(|height| height + 1);
+            if let Some(i) = height.and_then(|height| block.expired_transaction(height)) {
+                warn!("Transaction {} of block {} has expired", i, block.hash());
+                blockchain.record_block_reject(RejectReason::Expired);
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            // the transactions must apply on top of the parent's state, if known; an orphan is
+            // checked once its parent arrives
+            if let Err((i, e)) = blockchain.state_validity_check(&block) {
+                warn!("Transaction {} of block {} does not apply: {}", i, block.hash(), e);
+                blockchain.record_block_reject(RejectReason::from(&e));
+                self.server.report_misbehavior(from, INVALID_BLOCK_PENALTY);
+                continue;
+            }
+            // For experiment: the block delay, counted only once the block is inserted
+            // (clocks may be skewed, so a block can seem to arrive before it was mined)
+            let origin = BlockOrigin::Received{ delay_ms: now.saturating_sub(block.header.timestamp), from };
+            if !blockchain.parent_check(&block) {
+                let parent = block.header.parent;
+                blockchain.add_received_orphan(block, origin);
+                // a parent still being validated by another worker will pick this block up when inserted
+                if !self.in_flight.contains(&parent) {
+                    missing_hashes.push(parent);
+                }
+                continue;
+            }
+            let hash = block.hash();
+            let resolution = blockchain.insert_recursively(block, &mut relay_hashes);
+            if resolution.outcomes.iter().any(|(inserted, _)| *inserted == hash) {
+                blockchain.hash_to_origin.entry(hash).or_insert(origin);
+            }
+            for (hash, outcome) in &resolution.outcomes {
+                if let InsertOutcome::Reorged { depth } = outcome {
+                    info!("Block {} caused a reorg of depth {}", hash, depth);
+                }
+            }
+            if resolution.orphans_resolved > 0 {
+                debug!("Inserting block {} resolved {} orphans", hash, resolution.orphans_resolved);
+            }
+            for (hash, e) in resolution.rejected {
+                warn!("Dropping block {}: {}", hash, e);
+                if let InsertError::InvalidTransaction { error, .. } = &e {
+                    blockchain.record_block_reject(RejectReason::from(error));
+                }
+            }
+            // orphans are only checked against their parent's state now, so their senders
+            // answer for them now
+            for sender in resolution.invalid_from {
+                self.server.report_misbehavior(sender, INVALID_BLOCK_PENALTY);
+            }
+        }
+        let pruned = blockchain.prune_orphans_older_than(ORPHAN_MAX_AGE);
+        if pruned > 0 {
+            debug!("Pruned {} stale orphan blocks", pruned);
+        }
+        // done while still holding the blockchain lock, so a child never misses its parent's insertion
+        drop(claims);
+        // an adversarial miner may answer competing blocks by releasing its own
+        #[cfg(feature = "adversary")]
+        {
+            let mut released = Vec::new();
+            for hash in &relay_hashes {
+                released.extend(blockchain.adversary_on_received(hash));
+            }
+            relay_hashes.extend(released);
+        }
+        (relay_hashes, missing_hashes)
+    }
+
+    /// The blocks to answer a `GetBlocks` request with, skipping unknown and pruned ones.
+    /// The blocks are shared with the blockchain, and only copied when serialized.
+    fn blocks_for(&self, hashes: &[H256]) -> Vec<Arc<Block>> {
+        let blockchain = self.blockchain.lock().unwrap();
+        hashes.iter()
+            .filter_map(|hash| {
+                if blockchain.is_pruned(hash) {
+                    debug!("GetBlocks: body of block {} was pruned", hash);
+                }
+                blockchain.get_block(hash)
+            })
+            .collect()
+    }
+
+    /// Ask `peer` for the blocks it announced that we lack, unless another peer was asked first
+    fn receive_block_hashes(&self, hashes: Vec<H256>, peer: &impl Transport) {
+        peer.add_known_inventory(&hashes);
+        let blockchain = self.blockchain.lock().unwrap();
+        let missing_hashes: Vec<_> = hashes.into_iter()
+            .filter(|hash| !blockchain.contains_block(hash))
+            .collect();
+        let missing_count = missing_hashes.len();
+        // ask only the first peer to announce a block, keeping the others as fallbacks
+        let missing_hashes = self.requests.lock().unwrap().to_request(missing_hashes, peer, Instant::now());
+        drop(blockchain);
+        self.server.traffic().record_suppressed_block_requests(missing_count - missing_hashes.len());
+        if !missing_hashes.is_empty() {
+            peer.write(Message::GetBlocks(missing_hashes));
+        }
+    }
+
+    /// Answer a `GetBlocks` from `peer`, a page at a time
+    fn send_blocks(&self, hashes: &[H256], peer: &impl Transport) {
+        // the requester crosses off what arrives, and asks again for what does not
+        for page in paginate(self.blocks_for(hashes)) {
+            peer.write(Message::Blocks(page));
+        }
+    }
+
+    /// Check and insert blocks from `peer`, whether sent whole or rebuilt from a compact block,
+    /// then ask for what they showed we lack and relay the new ones
+    /// Leave `blocks` to the validation threads, behind those `peer` sent before. Dropped if too
+    /// many of the peer's are waiting already; those we asked for get asked for again.
+    fn queue_blocks(&self, blocks: Vec<Arc<Block>>, peer: &peer::Handle) {
+        if !self.block_lanes.push(peer.addr(), (blocks, peer.clone())) {
+            debug!("Dropping blocks from peer {}, too many of its blocks wait for validation", peer.addr());
+        }
+    }
+
+    fn receive_blocks(&self, blocks: Vec<Arc<Block>>, peer: &impl Transport) {
+        let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+        peer.add_known_inventory(&hashes);
+        let (relay_hashes, missing_hashes) = self.process_blocks(blocks, peer.addr());
+        let mut downloads = {
+            let mut sync = self.sync.lock().unwrap();
+            for hash in &hashes {
+                sync.block_done(hash);
+            }
+            sync.next_downloads()
+        };
+        let mut requests = self.requests.lock().unwrap();
+        for hash in &hashes {
+            requests.received(hash);
+        }
+        {
+            let mut compact = self.compact.lock().unwrap();
+            for hash in &hashes {
+                compact.remove(hash);
+            }
+        }
+        // the next bodies of a chain being synced
+        downloads = requests.to_request(downloads, peer, Instant::now());
+        if !downloads.is_empty() {
+            peer.write(Message::GetBlocks(downloads));
+        }
+        // the parents of orphans
+        let missing_count = missing_hashes.len();
+        let missing_hashes = requests.to_request(missing_hashes, peer, Instant::now());
+        drop(requests);
+        self.server.traffic().record_suppressed_block_requests(missing_count - missing_hashes.len());
+        if !missing_hashes.is_empty() {
+            peer.write(Message::GetBlocks(missing_hashes));
+        }
+        if !relay_hashes.is_empty() {
+            // let peers check that they agree on the ledger at our new tip
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            let state_hash = blockchain.state_hash(&tip).filter(|_| relay_hashes.contains(&tip));
+            // a single new block, likely just mined, goes out compactly
+            let compact = match &relay_hashes[..] {
+                [hash] => blockchain.get_block(hash).and_then(|block| Message::compact_block(&block)),
+                _ => None,
+            };
+            drop(blockchain);
+            // not back to the peer the blocks came from
+            self.relay.broadcast_except(compact.unwrap_or(Message::NewBlockHashes(relay_hashes)), peer);
+            if let Some(state_hash) = state_hash {
+                self.relay.broadcast(Message::StateHash(tip, state_hash));
+            }
+        }
+    }
+
+    /// Rebuild a compact block from `peer` out of our mempool, asking it for what is missing
+    fn receive_compact_block(&self, header: Header, coinbase: SignedTransaction, txids: Vec<H256>, size: usize, peer: &peer::Handle) {
+        let hash = header.hash();
+        peer.add_known_inventory(std::iter::once(hash).chain(txids.iter().cloned()));
+        if self.in_flight.contains(&hash) {
+            return;
+        }
+        let blockchain = self.blockchain.lock().unwrap();
+        if hash > blockchain.difficulty() || header.difficulty != blockchain.difficulty() {
+            drop(blockchain);
+            warn!("PoW check failed");
+            self.server.report_misbehavior(peer.addr(), INVALID_BLOCK_PENALTY);
+            return;
+        }
+        if blockchain.contains_block(&hash) {
+            return;
+        }
+        // tracked like a block we asked for, so that another peer is asked if this one never
+        // sends the missing transactions; nothing to do if some peer is on it already
+        if self.requests.lock().unwrap().to_request(vec![hash], peer, Instant::now()).is_empty() {
+            return;
+        }
+        let mempool = self.mempool.lock().unwrap();
+        let txs = std::iter::once(Some(coinbase))
+            .chain(txids.iter().map(|txid| mempool.get_transaction(txid).cloned()))
+            .collect();
+        drop(mempool);
+        drop(blockchain);
+        let partial = PartialBlock { header, txs, missing: Vec::new(), failures: 0, bytes: size as u64 };
+        self.complete_compact_block(hash, partial, peer);
+    }
+
+    /// Fill in the transactions of a compact block that `peer` sent as asked
+    fn receive_block_txn(&self, hash: H256, txs: Vec<SignedTransaction>, size: usize, peer: &peer::Handle) {
+        let mut partial = match self.compact.lock().unwrap().remove(&hash) {
+            Some(partial) => partial,
+            None => {
+                debug!("Ignoring transactions of block {} we are not rebuilding", hash);
+                return;
+            }
+        };
+        partial.bytes += size as u64;
+        if txs.len() != partial.missing.len() {
+            warn!("Peer {} sent {} transactions of block {} for {} asked", peer.addr(), txs.len(), hash, partial.missing.len());
+            self.compact_block_failed(hash, partial, peer);
+            return;
+        }
+        for (index, tx) in partial.missing.drain(..).zip(txs) {
+            partial.txs[index as usize] = Some(tx);
+        }
+        self.complete_compact_block(hash, partial, peer);
+    }
+
+    /// Sync from `peer` if its `tip` at `height` is on a longer chain we know nothing of:
+    /// fetch all of it at once, rather than the tip and then its ancestors one by one
+    fn catch_up(&self, tip: H256, height: u64, peer: &peer::Handle) {
+        let blockchain = self.blockchain.lock().unwrap();
+        if !blockchain.contains_block(&tip) && height > blockchain.tip_height() {
+            peer.write(Message::GetHeaders(self.sync.lock().unwrap().locator(&blockchain)));
+        }
+    }
+
+    /// Ask `peer` for the transactions of a compact block we lack, or insert it if none are
+    /// missing and it matches its header
+    fn complete_compact_block(&self, hash: H256, mut partial: PartialBlock, peer: &peer::Handle) {
+        let missing: Vec<u32> = partial.txs.iter().enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect();
+        if !missing.is_empty() {
+            let mut compact = self.compact.lock().unwrap();
+            if compact.len() >= MAX_PENDING_COMPACT_BLOCKS {
+                drop(compact);
+                partial.failures = MAX_COMPACT_ATTEMPTS;
+                self.compact_block_failed(hash, partial, peer);
+                return;
+            }
+            peer.write(Message::GetBlockTxn { block: hash, indexes: missing.clone() });
+            partial.missing = missing;
+            compact.insert(hash, partial);
+            return;
+        }
+        let transactions = partial.txs.iter().flatten().cloned().collect();
+        let block = Block { header: partial.header.clone(), content: Content { transactions } };
+        if !block.verify_merkle_root() {
+            self.compact_block_failed(hash, partial, peer);
+            return;
+        }
+        let block = Arc::new(block);
+        let full_bytes = bincode::serialized_size(&Message::Blocks(vec![Arc::clone(&block)])).unwrap();
+        self.server.traffic().record_compact_block(full_bytes, partial.bytes);
+        self.queue_blocks(vec![block], peer);
+    }
+
+    /// A compact block did not match its header: perhaps our copies of its transactions are
+    /// signed differently, so ask for all of them the first time, and for the whole block after
+    fn compact_block_failed(&self, hash: H256, mut partial: PartialBlock, peer: &peer::Handle) {
+        partial.failures += 1;
+        if partial.failures >= MAX_COMPACT_ATTEMPTS {
+            info!("Could not rebuild compact block {}, fetching it whole from peer {}", hash, peer.addr());
+            self.server.traffic().record_compact_fallback();
+            self.requests.lock().unwrap().requested(&[hash], peer, Instant::now());
+            peer.write(Message::GetBlocks(vec![hash]));
+            return;
+        }
+        debug!("Compact block {} does not match its merkle root, asking for all its transactions", hash);
+        let coinbase = partial.txs[0].take();
+        partial.txs = vec![None; partial.txs.len()];
+        partial.txs[0] = coinbase;
+        self.complete_compact_block(hash, partial, peer);
+    }
+
+    /// The next message to handle, with its size and sender: a deferred response whose peer is
+    /// under its rate limits again, else the next one received. `None` once the server is gone or on shutdown.
+    fn next_message(&self) -> Option<(Message, usize, peer::Handle)> {
         loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            if self.is_shutting_down() {
+                return None;
+            }
+            {
+                let mut deferred = self.deferred.lock().unwrap();
+                let ready = deferred.iter().position(|(_, size, peer)| self.server.allow_message(peer.addr(), *size));
+                if let Some(index) = ready {
+                    return deferred.remove(index);
+                }
+            }
+            let (bytes, peer) = match self.msg_chan.recv_timeout(DEFERRED_RETRY_INTERVAL) {
+                Ok(msg) => msg,
+                Err(channel::RecvTimeoutError::Timeout) => continue,
+                // every sender dropping, as in tests, means there is nothing more to handle
+                Err(channel::RecvTimeoutError::Disconnected) => return None,
+            };
+            let msg = match Message::decode(&bytes) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Rejected {}-byte message from peer {}: {}", bytes.len(), peer.addr(), e);
+                    self.server.report_misbehavior(peer.addr(), MALFORMED_MESSAGE_PENALTY);
+                    continue;
+                }
+            };
+            let msg = match msg {
+                Message::Compressed(payload) => match Message::decompress(&payload) {
+                    Ok((msg, size)) => {
+                        self.server.record_decompressed(size, bytes.len());
+                        msg
+                    }
+                    Err(e) => {
+                        warn!("Rejected {}-byte compressed message from peer {}: {}", bytes.len(), peer.addr(), e);
+                        self.server.report_misbehavior(peer.addr(), MALFORMED_MESSAGE_PENALTY);
+                        continue;
+                    }
+                },
+                msg => msg,
+            };
+            self.server.record_received(&peer, &msg, bytes.len());
+            // the handshake is never held up, so that a peer is not dropped for a slow greeting,
+            // nor is a goodbye
+            let handshake = matches!(msg, Message::Version { .. } | Message::Verack | Message::Disconnect { .. });
+            if handshake || self.server.allow_message(peer.addr(), bytes.len()) {
+                return Some((msg, bytes.len(), peer));
+            }
+            let mut deferred = self.deferred.lock().unwrap();
+            if msg.is_response() && deferred.len() < MAX_DEFERRED_MESSAGES {
+                debug!("Deferring {}-byte response from peer {} over its rate limits", bytes.len(), peer.addr());
+                deferred.push_back((msg, bytes.len(), peer));
+            } else {
+                drop(deferred);
+                debug!("Dropping {}-byte message from peer {} over its rate limits", bytes.len(), peer.addr());
+                self.server.record_dropped_message(peer.addr());
+            }
+        }
+    }
+
+    fn worker_loop(&self) {
+        while let Some((msg, size, peer)) = self.next_message() {
             match msg {
+                Message::Version { protocol, genesis, tip_height, node_nonce, listen_port, services } => {
+                    debug!("Version: protocol {}, genesis {}, height {}", protocol, genesis, tip_height);
+                    // where the peer accepts connections, whichever side connected
+                    let listen_addr = SocketAddr::new(peer.addr().ip(), listen_port);
+                    if node_nonce == self.server.node_nonce() {
+                        info!("Dropping connection {} to ourselves", peer.addr());
+                        self.addr_book.lock().unwrap().ignore(listen_addr);
+                        self.server.disconnect(peer.addr());
+                        continue;
+                    }
+                    let blockchain = self.blockchain.lock().unwrap();
+                    if genesis != blockchain.genesis_hash() {
+                        warn!("Peer {} is on another network, with genesis {}", peer.addr(), genesis);
+                        drop(blockchain);
+                        // banned rather than only disconnected, so that it does not keep reconnecting
+                        self.server.report_misbehavior(peer.addr(), BAN_THRESHOLD);
+                        continue;
+                    }
+                    if protocol != PROTOCOL_VERSION {
+                        warn!("Peer {} speaks protocol {}, not {}", peer.addr(), protocol, PROTOCOL_VERSION);
+                        self.server.disconnect(peer.addr());
+                        continue;
+                    }
+                    match peer.record_version(services & self.server.services()) {
+                        Some(complete) => {
+                            self.addr_book.lock().unwrap().add(listen_addr, Instant::now());
+                            peer.write(Message::Verack);
+                            if complete {
+                                greet(&peer, &blockchain);
+                            }
+                        }
+                        None => {
+                            drop(blockchain);
+                            warn!("Duplicate Version from peer {}", peer.addr());
+                            self.server.report_misbehavior(peer.addr(), HANDSHAKE_PENALTY);
+                        }
+                    }
+                }
+                Message::Verack => {
+                    debug!("Verack");
+                    match peer.record_verack() {
+                        Some(true) => greet(&peer, &self.blockchain.lock().unwrap()),
+                        Some(false) => {}
+                        None => {
+                            warn!("Duplicate Verack from peer {}", peer.addr());
+                            self.server.report_misbehavior(peer.addr(), HANDSHAKE_PENALTY);
+                        }
+                    }
+                }
+                Message::Disconnect { reason } => {
+                    info!("Peer {} is disconnecting: {}", peer.addr(), reason);
+                    // blocks asked of it are asked of others without waiting for the timeout
+                    self.requests.lock().unwrap().peer_gone(peer.addr());
+                    self.server.disconnect(peer.addr());
+                }
+                _ if !peer.version_received() => {
+                    warn!("Ignoring message from peer {} before its Version", peer.addr());
+                    self.server.report_misbehavior(peer.addr(), HANDSHAKE_PENALTY);
+                }
+                Message::GetAddr => {
+                    debug!("GetAddr");
+                    let addrs = self.addr_book.lock().unwrap().sample(MAX_ADDRS_PER_MESSAGE);
+                    if !addrs.is_empty() {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+                Message::Addr(addrs) => {
+                    debug!("Addr: {:?}", addrs);
+                    let now = Instant::now();
+                    let mut addr_book = self.addr_book.lock().unwrap();
+                    let learned = addrs.into_iter().filter(|addr| addr_book.add(*addr, now)).count();
+                    debug!("Learned {} new addresses from peer {}", learned, peer.addr());
+                }
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+                    peer.write(Message::Pong(nonce));
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    if let Some(rtt) = peer.pong(nonce) {
+                        debug!("Round-trip time: {:?}", rtt);
+                    }
                 }
                 Message::NewBlockHashes(hashes) => {
                     debug!("NewBlockHashes: {:?}", hashes);
-                    let blockchain = self.blockchain.lock().unwrap();
-                    let missing_hashes: Vec<_> = hashes.into_iter()
-                        .filter(|hash| !blockchain.contains_block(hash))
-                        .collect();
-                    if !missing_hashes.is_empty() {
-                        peer.write(Message::GetBlocks(missing_hashes));
-                    }
+                    self.receive_block_hashes(hashes, &peer);
                 }
                 Message::GetBlocks(hashes) => {
                     debug!("GetBlocks: {:?}", hashes);
-                    let blockchain = self.blockchain.lock().unwrap();
-                    let blocks: Vec<_> = hashes.iter()
-                        .filter(|hash| blockchain.contains_block(hash))
-                        .map(|hash| blockchain.get_block(hash).clone())
-                        .collect();
-                    if !blocks.is_empty() {
-                        peer.write(Message::Blocks(blocks));
-                    }
+                    self.send_blocks(&hashes, &peer);
                 }
                 Message::Blocks(blocks) => {
                     debug!("Blocks: {:?}", blocks);
-                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut relay_hashes = Vec::new();
-                    let mut missing_hashes = Vec::new();
-                    for block in blocks {
-                        // For experiment: record the block delay; don't count redundant or self-mined blocks:
-                        blockchain.hash_to_origin.entry(block.hash())
-                            .or_insert(BlockOrigin::Received{ delay_ms: now - block.header.timestamp });
-                        // Regular processing:
-                        if blockchain.contains_block(&block.hash()) {
-                            continue;
-                        }
-                        if !blockchain.pow_validity_check(&block) {
-                            warn!("PoW check failed");
-                            continue;
-                        }
-                        if !blockchain.parent_check(&block) {
-                            blockchain.add_to_orphan_buffer(&block);
-                            missing_hashes.push(block.header.parent);
-                            continue;
+                    self.queue_blocks(blocks, &peer);
+                }
+                Message::CompactBlock { header, coinbase, txids } => {
+                    debug!("CompactBlock: {} with {} transactions", header.hash(), txids.len() + 1);
+                    self.receive_compact_block(header, *coinbase, txids, size, &peer);
+                }
+                Message::GetBlockTxn { block, indexes } => {
+                    debug!("GetBlockTxn: {} transactions of block {}", indexes.len(), block);
+                    let body = match self.blockchain.lock().unwrap().get_block(&block) {
+                        Some(body) => body,
+                        None => continue,
+                    };
+                    let txs: Option<Vec<_>> = indexes.iter()
+                        .map(|&index| body.content.transactions.get(index as usize).cloned())
+                        .collect();
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTxn { block, txs }),
+                        None => {
+                            warn!("Peer {} asked for transactions past the end of block {}", peer.addr(), block);
+                            self.server.report_misbehavior(peer.addr(), MALFORMED_MESSAGE_PENALTY);
                         }
-                        blockchain.insert_recursively(&block, &mut relay_hashes);
                     }
-                    if !missing_hashes.is_empty() {
-                        peer.write(Message::GetBlocks(missing_hashes));
-                    }
-                    if !relay_hashes.is_empty() {
-                        self.server.broadcast(Message::NewBlockHashes(relay_hashes));
-                    }
-                },
+                }
+                Message::BlockTxn { block, txs } => {
+                    debug!("BlockTxn: {} transactions of block {}", txs.len(), block);
+                    self.receive_block_txn(block, txs, size, &peer);
+                }
                 Message::NewTransactionHashes(hashes) => {
+                    peer.add_known_inventory(hashes.iter().cloned());
                     let mempool = self.mempool.lock().unwrap();
                     let missing_hashes: Vec<_> = hashes.into_iter()
                         .filter(|hash| !mempool.get_transaction(hash).is_some())
@@ -135,20 +923,1317 @@ impl Context {
                     }
                 }
                 Message::Transactions(transactions) => {
+                    let hashes: Vec<H256> = transactions.iter().map(|tx| tx.txid()).collect();
+                    peer.add_known_inventory(hashes.iter().cloned());
+                    // skipping those from another peer that another worker is admitting already
+                    let mut claims = Vec::new();
+                    let (hashes, transactions): (Vec<H256>, Vec<SignedTransaction>) = hashes.into_iter().zip(transactions)
+                        .filter(|(hash, _)| match self.tx_in_flight.claim(*hash) {
+                            Some(claim) => {
+                                claims.push(claim);
+                                true
+                            }
+                            None => false,
+                        })
+                        .unzip();
+                    let mut blockchain = self.blockchain.lock().unwrap();
                     let mut mempool = self.mempool.lock().unwrap();
-                    for transaction in transactions {
-                        if transaction.verify_signature() {
-                            mempool.insert(transaction);
+                    let results = blockchain.admit_transactions(&mut mempool, transactions);
+                    drop(blockchain);
+                    drop(claims);
+                    drop(mempool);
+                    let mut admitted = Vec::new();
+                    for (hash, result) in hashes.into_iter().zip(results) {
+                        match result {
+                            Ok(()) => admitted.push(hash),
+                            Err(reason) => {
+                                debug!("Transaction {} rejected: {}", hash, reason);
+                                if reason.is_misbehavior() {
+                                    self.server.report_misbehavior(peer.addr(), INVALID_TRANSACTION_PENALTY);
+                                }
+                            }
                         }
                     }
-                    self.server.broadcast(Message::NewTransactionHashes(
-                        mempool.get_keys()
-                    ));
+                    // only what is new to us, and never more hashes in a message than peers accept
+                    for batch in batches(&admitted, MAX_HASHES_PER_MESSAGE) {
+                        self.relay.broadcast_except(Message::NewTransactionHashes(batch), &peer);
+                    }
+                }
+                Message::GetMempool => {
+                    if !peer.allow_mempool_request(MEMPOOL_REQUEST_INTERVAL) {
+                        debug!("GetMempool: ignoring repeated request");
+                        continue;
+                    }
+                    let mempool = self.mempool.lock().unwrap();
+                    for batch in mempool.announcement_batches(MAX_MEMPOOL_ANNOUNCEMENT, MAX_HASHES_PER_MESSAGE) {
+                        peer.write(Message::NewTransactionHashes(batch));
+                    }
+                }
+                Message::StateHash(block_hash, state_hash) => {
+                    match self.blockchain.lock().unwrap().state_hash(&block_hash) {
+                        Some(ours) if ours != state_hash => {
+                            warn!("!!! STATE MISMATCH after block {}: peer {} has state {}, we have {}",
+                                block_hash, peer.addr(), state_hash, ours);
+                        }
+                        Some(_) => debug!("StateHash: agree on the state after block {}", block_hash),
+                        None => debug!("StateHash: no state for block {}", block_hash),
+                    }
+                }
+                Message::Status(tip, height) => {
+                    debug!("Status: tip {} at height {}", tip, height);
+                    self.catch_up(tip, height, &peer);
+                }
+                Message::GetTip => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    peer.write(Message::Tip { hash: blockchain.tip(), height: blockchain.tip_height() });
                 }
+                Message::Tip { hash, height } => {
+                    debug!("Tip: {} at height {}", hash, height);
+                    self.catch_up(hash, height, &peer);
+                }
+                Message::GetHeaders(locator) => {
+                    debug!("GetHeaders: {:?}", locator);
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let headers: Vec<_> = blockchain.blocks_after_locator(&locator, MAX_HEADERS_PER_MESSAGE).iter()
+                        .filter_map(|hash| blockchain.get_header(hash).cloned())
+                        .collect();
+                    drop(blockchain);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+                Message::Headers(headers) => {
+                    debug!("Headers: {} headers", headers.len());
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let mut sync = self.sync.lock().unwrap();
+                    match sync.accept(&headers, &blockchain, now) {
+                        Ok(count) => {
+                            debug!("Accepted {} new headers from peer {}", count, peer.addr());
+                            // a full batch: the peer likely has more
+                            if headers.len() == MAX_HEADERS_PER_MESSAGE {
+                                peer.write(Message::GetHeaders(sync.locator(&blockchain)));
+                            }
+                        }
+                        // out of order, or answering another locator: its parents come in another batch
+                        Err(HeaderError::Unconnected) => debug!("Headers from peer {} do not connect", peer.addr()),
+                        Err(e) => {
+                            warn!("Invalid headers from peer {}: {}", peer.addr(), e);
+                            self.server.report_misbehavior(peer.addr(), INVALID_BLOCK_PENALTY);
+                        }
+                    }
+                    let downloads = sync.next_downloads();
+                    drop(sync);
+                    drop(blockchain);
+                    let downloads = self.requests.lock().unwrap().to_request(downloads, &peer, Instant::now());
+                    if !downloads.is_empty() {
+                        peer.write(Message::GetBlocks(downloads));
+                    }
+                }
+                Message::GetChain(locator) => {
+                    debug!("GetChain: {:?}", locator);
+                    let hashes = self.blockchain.lock().unwrap()
+                        .blocks_after_locator(&locator, MAX_HASHES_PER_MESSAGE);
+                    if !hashes.is_empty() {
+                        peer.write(Message::NewBlockHashes(hashes));
+                    }
+                }
+                // decompressed by next_message, which rejects one compressed twice
+                Message::Compressed(_) => unreachable!(),
+            }
+        }
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{get_deterministic_keypair, H160};
+    use crate::block::test::generate_mined_block;
+    use crate::block::MAX_TRANSACTIONS_PER_BLOCK;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::{ChainId, RawTransaction, SignedTransaction};
+    use ring::signature::KeyPair;
+    use crate::network::server;
+    use crate::network::message::SERVICE_COMPRESSION;
+    use crate::network::peer_manager::{RateLimits, BAN_THRESHOLD};
+    use crate::network::transport::{MockPeer, MockRelay};
+
+    fn test_context() -> Context {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        new(1, msg_rx, &server, &blockchain, &mempool)
+    }
 
+    /// A context whose server, though not running, is kept to take the blocks it relays
+    fn relaying_context() -> (Context, server::Context) {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_tx).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        (new(1, msg_rx, &server, &blockchain, &mempool), server_ctx)
+    }
+
+    /// A context broadcasting through a mock, each message with the peer left out if any
+    fn mock_context() -> (Context, channel::Receiver<(Message, Option<peer::PeerId>)>) {
+        let mut ctx = test_context();
+        let (relay, broadcasts) = MockRelay::new();
+        ctx.relay = Arc::new(relay);
+        (ctx, broadcasts)
+    }
 
+    /// `count` blocks, each the child of the one before and the first a child of `parent`,
+    /// valid on top of `blockchain`'s state and inserted into it
+    fn valid_chain(blockchain: &mut Blockchain, parent: &H256, count: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..count {
+            let parent = blocks.last().map_or(*parent, |block| block.hash());
+            let height = blockchain.get_height(&parent).unwrap() + 1;
+            let mut block = generate_mined_block(&parent);
+            block.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, height)];
+            block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+            block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+            while block.hash() > block.header.difficulty {
+                block.header.nonce = rand::random();
             }
+            blockchain.insert(&block);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn test_peer() -> SocketAddr {
+        "127.0.0.1:6001".parse().unwrap()
+    }
+
+    #[test]
+    fn child_of_in_flight_parent_waits_instead_of_requesting() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 2);
+        let child = chain.pop().unwrap();
+        let parent = chain.pop().unwrap();
+
+        // another worker is still validating the parent
+        let claim = ctx.in_flight.claim(parent.hash()).unwrap();
+        let (relay_hashes, missing_hashes) = ctx.process_blocks(vec![Arc::new(child.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        assert!(missing_hashes.is_empty());
+        // nor does the parent from another peer get validated meanwhile
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(parent.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+
+        drop(claim);
+        let (relay_hashes, missing_hashes) = ctx.process_blocks(vec![Arc::new(parent.clone())], test_peer());
+        assert_eq!(relay_hashes, vec![parent.hash(), child.hash()]);
+        assert!(missing_hashes.is_empty());
+        assert!(!ctx.in_flight.contains(&parent.hash()) && !ctx.in_flight.contains(&child.hash()));
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), child.hash());
+    }
+
+    #[test]
+    fn a_block_arriving_twice_at_once_is_validated_once() {
+        let validations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&validations);
+        let on_validate = move |_: &H256| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            // long enough for the other worker to receive the block meanwhile
+            thread::sleep(Duration::from_millis(200));
+        };
+        let ctx = Context { on_validate: Some(Arc::new(on_validate)), ..test_context() };
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = Arc::new(valid_chain(&mut Blockchain::new(), &genesis_hash, 1).remove(0));
+
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let workers: Vec<thread::JoinHandle<Vec<H256>>> = [6001, 6002].iter().map(|&port| {
+            let (ctx, block, barrier) = (ctx.clone(), Arc::clone(&block), Arc::clone(&barrier));
+            thread::spawn(move || {
+                barrier.wait();
+                ctx.process_blocks(vec![block], SocketAddr::from(([127, 0, 0, 1], port))).0
+            })
+        }).collect();
+        let relayed: Vec<H256> = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+
+        assert_eq!(validations.load(Ordering::SeqCst), 1);
+        assert_eq!(relayed, vec![block.hash()]);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+        assert!(!ctx.in_flight.contains(&block.hash()));
+    }
+
+    #[test]
+    fn a_known_block_is_not_validated_again() {
+        let validations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&validations);
+        let on_validate = move |_: &H256| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        };
+        let ctx = Context { on_validate: Some(Arc::new(on_validate)), ..test_context() };
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = Arc::new(valid_chain(&mut Blockchain::new(), &genesis_hash, 1).remove(0));
+
+        assert_eq!(ctx.process_blocks(vec![Arc::clone(&block)], test_peer()).0, vec![block.hash()]);
+        assert_eq!(ctx.process_blocks(vec![Arc::clone(&block)], test_peer()), (vec![], vec![]));
+        assert_eq!(validations.load(Ordering::SeqCst), 1);
+        assert_eq!(ctx.blockchain.lock().unwrap().block_delays_ms().len(), 1);
+    }
+
+    /// A mined block of ICO account 0's transfers, committing to the state it leads to
+    fn mined_transfer_block(ctx: &Context, parent: &H256, nonces: &[u32]) -> Block {
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let mut block = generate_mined_block(parent);
+        let height = ctx.blockchain.lock().unwrap().get_height(parent).unwrap() + 1;
+        block.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, height)];
+        block.content.transactions.extend(nonces.iter().map(|&nonce| {
+            let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 10)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &key, &ChainId::default())
+        }));
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        block.header.state_root = ctx.blockchain.lock().unwrap().expected_state_root(&block).unwrap_or_default();
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = rand::random();
         }
+        block
+    }
+
+    #[test]
+    fn block_with_repeated_nonce_is_rejected() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &[1, 1]);
+        let (relay_hashes, missing_hashes) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        assert!(missing_hashes.is_empty());
+        assert!(!ctx.blockchain.lock().unwrap().contains_block(&block.hash()));
+    }
+
+    #[test]
+    fn block_with_forged_sender_is_rejected() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut block = mined_transfer_block(&ctx, &genesis_hash, &[1]);
+        // account 1 signs a transaction spending account 0's coins
+        let raw = block.content.transactions[1].raw.clone();
+        block.content.transactions[1] = SignedTransaction::from_raw(raw, &get_deterministic_keypair(1), &ChainId::default());
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = rand::random();
+        }
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert!(!blockchain.contains_block(&block.hash()));
+        assert_eq!(blockchain.validation_stats().blocks.wrong_owner, 1);
+    }
+
+    #[test]
+    fn block_spending_coins_its_sender_lacks_is_neither_inserted_nor_relayed() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let key = get_deterministic_keypair(0);
+        let mut block = mined_transfer_block(&ctx, &genesis_hash, &[1]);
+        // signed properly, but for more than the sender has
+        let mut raw = block.content.transactions[1].raw.clone();
+        raw.outputs = vec![(Default::default(), 1 << 62)];
+        block.content.transactions[1] = SignedTransaction::from_raw(raw, &key, &ChainId::default());
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        block.header.state_root = ctx.blockchain.lock().unwrap().expected_state_root(&block).unwrap_or_default();
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = rand::random();
+        }
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        assert!(!ctx.blockchain.lock().unwrap().contains_block(&block.hash()));
+        assert_eq!(ctx.blockchain.lock().unwrap().validation_stats().blocks.insufficient_balance, 1);
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    #[test]
+    fn orphan_spending_coins_its_sender_lacks_is_rejected_once_its_parent_arrives() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut scratch = Blockchain::new();
+        let parent = valid_chain(&mut scratch, &genesis_hash, 1).remove(0);
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let mut child = generate_mined_block(&parent.hash());
+        // signed properly, but for more than the sender has
+        let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 1 << 62)], fee: 0, nonce: 1, valid_until_block: 0, data: Vec::new() };
+        child.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, 2), SignedTransaction::from_raw(raw, &key, &ChainId::default())];
+        child.header.merkle_root = MerkleTree::new(&child.content.transactions).root();
+        assert_eq!(scratch.expected_state_root(&child), None);
+        while child.hash() > child.header.difficulty {
+            child.header.nonce = rand::random();
+        }
+        let honest: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+
+        let (relay_hashes, missing) = ctx.process_blocks(vec![Arc::new(child.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        assert_eq!(missing, vec![parent.hash()]);
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(parent.clone())], honest);
+        assert_eq!(relay_hashes, vec![parent.hash()]);
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert_eq!(blockchain.tip(), parent.hash());
+        assert!(!blockchain.contains_block(&child.hash()));
+        assert_eq!(blockchain.orphan_count(), 0);
+        assert_eq!(blockchain.validation_stats().blocks.insufficient_balance, 1);
+        drop(blockchain);
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    #[test]
+    fn block_with_bad_signature_is_rejected() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut block = mined_transfer_block(&ctx, &genesis_hash, &[1, 2, 3]);
+        block.content.transactions[2].signature[0] ^= 1;
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = rand::random();
+        }
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert!(!blockchain.contains_block(&block.hash()));
+        assert_eq!(blockchain.validation_stats().blocks.bad_signature, 1);
+    }
+
+    #[test]
+    fn sender_of_an_invalid_block_is_banned() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut block = generate_mined_block(&genesis_hash);
+        block.header.merkle_root = H256::from([1; 32]);
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = rand::random();
+        }
+        let honest: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let valid = valid_chain(&mut Blockchain::new(), &genesis_hash, 1).remove(0);
+        ctx.process_blocks(vec![Arc::new(valid)], honest);
+        assert!(ctx.server.banned_peers().is_empty());
+        ctx.process_blocks(vec![Arc::new(block)], test_peer());
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    #[test]
+    fn block_of_only_a_coinbase_is_accepted_and_relayed() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        // what the miner builds from an empty mempool
+        let block = mined_transfer_block(&ctx, &genesis_hash, &[]);
+        assert_eq!(block.content.transactions.len(), 1);
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert_eq!(relay_hashes, vec![block.hash()]);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    fn unsigned_transaction_is_rejected() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let unsigned = |block: &mut Block, from_addr: H160| {
+            let tx = &mut block.content.transactions[1];
+            tx.raw.from_addr = from_addr;
+            tx.pub_key.clear();
+            tx.signature.clear();
+            block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+            while block.hash() > block.header.difficulty {
+                block.header.nonce = rand::random();
+            }
+        };
+        // from a funded account
+        let mut block = mined_transfer_block(&ctx, &genesis_hash, &[1]);
+        unsigned(&mut block, H160::from_pubkey(get_deterministic_keypair(0).public_key().as_ref()));
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        // from the zero address, like a coinbase out of place
+        let mut block = mined_transfer_block(&ctx, &genesis_hash, &[1]);
+        unsigned(&mut block, H160::default());
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert_eq!(blockchain.validation_stats().blocks.bad_signature, 1);
+        assert_eq!(blockchain.validation_stats().blocks.bad_coinbase, 1);
+    }
+
+    #[test]
+    fn oversized_block_is_rejected() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let nonces: Vec<u32> = (1..=400).collect();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &nonces);
+        assert!(block.content.size() > MAX_BLOCK_SIZE);
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert!(relay_hashes.is_empty());
+        assert!(!ctx.blockchain.lock().unwrap().contains_block(&block.hash()));
+    }
+
+    #[test]
+    fn block_with_nonce_chain_is_accepted() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &[1, 2, 3]);
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(block.clone())], test_peer());
+        assert_eq!(relay_hashes, vec![block.hash()]);
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(blockchain.nonce_of(&H160::from_pubkey(get_deterministic_keypair(0).public_key().as_ref())), 3);
+    }
+
+    #[test]
+    fn unknown_parent_is_requested() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 2);
+        let child = chain.pop().unwrap();
+        let parent = chain.pop().unwrap();
+        let (relay_hashes, missing_hashes) = ctx.process_blocks(vec![Arc::new(child)], test_peer());
+        assert!(relay_hashes.is_empty());
+        assert_eq!(missing_hashes, vec![parent.hash()]);
+    }
+
+    #[test]
+    fn origin_records_the_sending_peer() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 2);
+        let (mined, received) = (&chain[0], &chain[1]);
+        {
+            let mut blockchain = ctx.blockchain.lock().unwrap();
+            blockchain.insert(mined);
+            blockchain.hash_to_origin.insert(mined.hash(), BlockOrigin::Mined);
+        }
+        ctx.process_blocks(vec![Arc::new(received.clone())], test_peer());
+        // our own block echoing back from a peer stays mined
+        ctx.process_blocks(vec![Arc::new(mined.clone())], test_peer());
+
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert!(matches!(blockchain.hash_to_origin[&mined.hash()], BlockOrigin::Mined));
+        match blockchain.hash_to_origin[&received.hash()] {
+            BlockOrigin::Received { from, .. } => assert_eq!(from, test_peer()),
+            BlockOrigin::Mined => panic!("received block recorded as mined"),
+        }
+        let delays = blockchain.delays_by_peer();
+        assert_eq!(delays.len(), 1);
+        assert_eq!(delays[&test_peer()].len(), 1);
+    }
+
+    #[test]
+    fn only_inserted_received_blocks_count_towards_delays() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 4);
+        {
+            let mut blockchain = ctx.blockchain.lock().unwrap();
+            blockchain.insert(&chain[0]);
+            blockchain.hash_to_origin.insert(chain[0].hash(), BlockOrigin::Mined);
+        }
+        let mut invalid = chain[1].clone();
+        invalid.header.difficulty = [0xff; 32].into();
+        // an orphan whose parent never arrives
+        let orphan = chain[3].clone();
+        let blocks = vec![invalid, chain[0].clone(), orphan.clone(), chain[1].clone()];
+        ctx.process_blocks(blocks.into_iter().map(Arc::new).collect(), test_peer());
+
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert_eq!(blockchain.block_delays_ms().len(), 1);
+        assert!(matches!(blockchain.hash_to_origin[&chain[1].hash()], BlockOrigin::Received { .. }));
+        assert!(!blockchain.hash_to_origin.contains_key(&orphan.hash()));
+    }
+
+    #[test]
+    fn orphan_delay_is_recorded_once_it_is_inserted() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 2);
+        ctx.process_blocks(vec![Arc::new(chain[1].clone())], test_peer());
+        assert!(ctx.blockchain.lock().unwrap().block_delays_ms().is_empty());
+
+        ctx.process_blocks(vec![Arc::new(chain[0].clone())], test_peer());
+        let blockchain = ctx.blockchain.lock().unwrap();
+        assert_eq!(blockchain.tip(), chain[1].hash());
+        assert_eq!(blockchain.block_delays_ms().len(), 2);
+        assert!(matches!(blockchain.hash_to_origin[&chain[1].hash()], BlockOrigin::Received { from, .. } if from == test_peer()));
+    }
+
+    #[test]
+    fn pruned_blocks_are_skipped_when_requested() {
+        let ctx = test_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 4);
+        let next = chain.pop().unwrap();
+        ctx.process_blocks(chain.iter().cloned().map(Arc::new).collect(), test_peer());
+        assert_eq!(ctx.blockchain.lock().unwrap().prune_below(2), 1);
+
+        let hashes: Vec<H256> = chain.iter().map(|block| block.hash()).collect();
+        let served: Vec<H256> = ctx.blocks_for(&hashes).iter().map(|block| block.hash()).collect();
+        assert_eq!(served, hashes[1..].to_vec());
+
+        let (relay_hashes, _) = ctx.process_blocks(vec![Arc::new(next.clone())], test_peer());
+        assert_eq!(relay_hashes, vec![next.hash()]);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip_height(), 4);
+    }
+
+    /// A chain of `count` mined blocks on top of genesis, each with `txs` transactions, inserted into `ctx`
+    fn insert_chain(ctx: &Context, count: usize, txs: usize) -> Vec<H256> {
+        let mut blockchain = ctx.blockchain.lock().unwrap();
+        let mut hashes = Vec::new();
+        let mut parent = blockchain.tip();
+        for _ in 0..count {
+            let mut block = generate_mined_block(&parent);
+            block.content.transactions = vec![block.content.transactions[0].clone(); txs];
+            parent = block.hash();
+            blockchain.insert(&block);
+            hashes.push(parent);
+        }
+        hashes
+    }
+
+    #[test]
+    fn served_blocks_are_shared_not_copied() {
+        let ctx = test_context();
+        let hashes = insert_chain(&ctx, 100, 1);
+        let served = ctx.blocks_for(&hashes);
+        assert_eq!(served.len(), 100);
+        let blockchain = ctx.blockchain.lock().unwrap();
+        for (block, hash) in served.iter().zip(&hashes) {
+            assert!(Arc::ptr_eq(block, &blockchain.get_block(hash).unwrap()));
+        }
+    }
+
+    #[test]
+    fn garbled_messages_do_not_stop_the_worker() {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server_tx, _server_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_tx).unwrap();
+        let ctx = new(1, msg_rx, &server, &Arc::new(Mutex::new(Blockchain::new())), &Arc::new(Mutex::new(Mempool::new())));
+        let (peer, written) = ready_peer(test_peer());
+
+        msg_tx.send((rand::random::<[u8; 32]>().to_vec(), peer.clone())).unwrap();
+        msg_tx.send((Vec::new(), peer.clone())).unwrap();
+        // a valid ping, cut short
+        let ping = bincode::serialize(&Message::Ping(7)).unwrap();
+        msg_tx.send((ping[..ping.len() - 1].to_vec(), peer.clone())).unwrap();
+        msg_tx.send((ping, peer)).unwrap();
+        drop(msg_tx);
+        // returns once the channel is drained and closed, rather than panicking
+        ctx.worker_loop();
+
+        let replies: Vec<Message> = std::iter::from_fn(|| written.try_recv().ok())
+            .map(|bytes| bincode::deserialize(&bytes).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 1);
+        assert!(matches!(replies[0], Message::Pong(7)));
+    }
+
+    #[test]
+    fn oversized_vectors_are_misbehavior() {
+        let ctx = test_context();
+        let (peer, written) = ready_peer(test_peer());
+        let too_many = bincode::serialize(&Message::GetBlocks(vec![H256::default(); MAX_HASHES_PER_MESSAGE + 1])).unwrap();
+        let (msg_tx, msg_rx) = channel::unbounded();
+        for _ in 0..BAN_THRESHOLD / MALFORMED_MESSAGE_PENALTY {
+            msg_tx.send((too_many.clone(), peer.clone())).unwrap();
+        }
+        drop(msg_tx);
+        Context { msg_chan: msg_rx, ..ctx.clone() }.worker_loop();
+        assert!(written.try_recv().is_err());
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    /// A detached peer through the handshake already
+    fn ready_peer(addr: SocketAddr) -> (peer::Handle, mio_extras::channel::Receiver<Vec<u8>>) {
+        let (peer, written) = peer::Handle::detached(addr);
+        peer.complete_handshake();
+        (peer, written)
+    }
+
+    /// Have `ctx` handle `messages` from `peer`, as a worker thread would, then the blocks
+    /// among them as the validation threads would
+    fn deliver(ctx: &Context, messages: Vec<Message>, peer: &peer::Handle) {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        for msg in messages {
+            msg_tx.send((bincode::serialize(&msg).unwrap(), peer.clone())).unwrap();
+        }
+        drop(msg_tx);
+        Context { msg_chan: msg_rx, ..ctx.clone() }.worker_loop();
+        while let Some(((blocks, peer), _turn)) = ctx.block_lanes.try_next() {
+            ctx.receive_blocks(blocks, &peer);
+        }
+    }
+
+    #[test]
+    fn parent_of_an_orphan_is_requested_until_it_arrives() {
+        let (server_tx, _server_rx) = channel::unbounded();
+        let (server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_tx).unwrap();
+        let ctx = new(1, channel::never(), &server, &Arc::new(Mutex::new(Blockchain::new())), &Arc::new(Mutex::new(Mempool::new())));
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let mut chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 2);
+        let child = chain.pop().unwrap();
+        let parent = chain.pop().unwrap();
+        let (first, first_written) = ready_peer(test_peer());
+        let (second, second_written) = ready_peer("127.0.0.1:6002".parse().unwrap());
+        let written = |receiver: &mio_extras::channel::Receiver<Vec<u8>>| -> Vec<Message> {
+            std::iter::from_fn(|| receiver.try_recv().ok()).map(|bytes| bincode::deserialize(&bytes).unwrap()).collect()
+        };
+
+        deliver(&ctx, vec![Message::NewBlockHashes(vec![child.hash()])], &second);
+        assert!(ctx.requests.lock().unwrap().is_pending(&child.hash()));
+        assert!(matches!(&written(&second_written)[..], [Message::GetBlocks(hashes)] if hashes == &vec![child.hash()]));
+        deliver(&ctx, vec![Message::Blocks(vec![Arc::new(child.clone())])], &first);
+        assert!(!ctx.requests.lock().unwrap().is_pending(&child.hash()));
+        assert!(ctx.requests.lock().unwrap().is_pending(&parent.hash()));
+        assert!(matches!(&written(&first_written)[..], [Message::GetBlocks(hashes)] if hashes == &vec![parent.hash()]));
+
+        // the first peer never answers, so the second is asked, through the server
+        ctx.rerequest_missing(Instant::now() + REQUEST_TIMEOUT / 2);
+        assert!(server_ctx.sent_to().is_empty());
+        ctx.rerequest_missing(Instant::now() + REQUEST_TIMEOUT);
+        assert!(matches!(&server_ctx.sent_to()[..],
+            [(id, Message::GetBlocks(hashes))] if *id == second.id() && hashes == &vec![parent.hash()]));
+
+        deliver(&ctx, vec![Message::Blocks(vec![Arc::new(parent.clone())])], &second);
+        assert!(!ctx.requests.lock().unwrap().is_pending(&parent.hash()));
+        assert!(ctx.blockchain.lock().unwrap().contains_block(&child.hash()));
+    }
+
+    #[test]
+    fn block_announced_twice_is_requested_once() {
+        let (server_tx, _server_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_tx).unwrap();
+        let ctx = new(1, channel::never(), &server, &Arc::new(Mutex::new(Blockchain::new())), &Arc::new(Mutex::new(Mempool::new())));
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = generate_mined_block(&genesis_hash);
+        let (first, first_written) = ready_peer(test_peer());
+        let (second, second_written) = ready_peer("127.0.0.1:6002".parse().unwrap());
+
+        deliver(&ctx, vec![Message::NewBlockHashes(vec![block.hash()])], &first);
+        deliver(&ctx, vec![Message::NewBlockHashes(vec![block.hash()])], &second);
+        let requests: Vec<Message> = std::iter::from_fn(|| first_written.try_recv().ok())
+            .chain(std::iter::from_fn(|| second_written.try_recv().ok()))
+            .map(|bytes| bincode::deserialize(&bytes).unwrap())
+            .collect();
+        assert_eq!(requests.len(), 1);
+        assert!(matches!(&requests[0], Message::GetBlocks(hashes) if hashes == &vec![block.hash()]));
+        assert_eq!(ctx.server.traffic().snapshot().suppressed_block_requests, 1);
+    }
+
+    #[test]
+    fn unknown_announced_block_is_requested() {
+        let (ctx, _) = mock_context();
+        let block = generate_mined_block(&ctx.blockchain.lock().unwrap().tip());
+        let (peer, written) = MockPeer::new(test_peer());
+
+        ctx.receive_block_hashes(vec![block.hash()], &peer);
+        assert!(peer.knows(&block.hash()));
+        assert!(ctx.requests.lock().unwrap().is_pending(&block.hash()));
+        assert!(matches!(&written.try_iter().collect::<Vec<_>>()[..], [Message::GetBlocks(hashes)] if hashes == &vec![block.hash()]));
+    }
+
+    #[test]
+    fn known_announced_block_is_not_requested() {
+        let (ctx, _) = mock_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let known = valid_chain(&mut ctx.blockchain.lock().unwrap(), &genesis_hash, 1).remove(0);
+        let (peer, written) = MockPeer::new(test_peer());
+
+        ctx.receive_block_hashes(vec![known.hash()], &peer);
+        assert!(written.try_recv().is_err());
+        assert!(!ctx.requests.lock().unwrap().is_pending(&known.hash()));
+    }
+
+    #[test]
+    fn requested_blocks_we_have_are_sent() {
+        let (ctx, _) = mock_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let known = valid_chain(&mut ctx.blockchain.lock().unwrap(), &genesis_hash, 1).remove(0);
+        let (peer, written) = MockPeer::new(test_peer());
+
+        ctx.send_blocks(&[known.hash(), generate_mined_block(&known.hash()).hash()], &peer);
+        match &written.try_iter().collect::<Vec<_>>()[..] {
+            [Message::Blocks(blocks)] => assert_eq!(blocks.iter().map(|block| block.hash()).collect::<Vec<_>>(), vec![known.hash()]),
+            other => panic!("unexpected messages {:?}", other),
+        }
+    }
+
+    #[test]
+    fn orphan_gets_its_parent_requested() {
+        let (ctx, broadcasts) = mock_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let chain = valid_chain(&mut Blockchain::new(), &genesis_hash, 2);
+        let (peer, written) = MockPeer::new(test_peer());
+
+        ctx.receive_blocks(vec![Arc::new(chain[1].clone())], &peer);
+        assert!(!ctx.blockchain.lock().unwrap().contains_block(&chain[1].hash()));
+        assert!(matches!(&written.try_iter().collect::<Vec<_>>()[..], [Message::GetBlocks(hashes)] if hashes == &vec![chain[0].hash()]));
+        assert!(broadcasts.try_recv().is_err());
+    }
+
+    #[test]
+    fn inserted_block_is_broadcast_to_the_other_peers() {
+        let (ctx, broadcasts) = mock_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = valid_chain(&mut Blockchain::new(), &genesis_hash, 1).remove(0);
+        let (peer, written) = MockPeer::new(test_peer());
+
+        ctx.receive_blocks(vec![Arc::new(block.clone())], &peer);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+        assert!(written.try_recv().is_err());
+        let state_hash = ctx.blockchain.lock().unwrap().state_hash(&block.hash()).unwrap();
+        let broadcasts: Vec<_> = broadcasts.try_iter().collect();
+        match &broadcasts[..] {
+            [(Message::CompactBlock { header, .. }, Some(except)), (Message::StateHash(tip, state), None)] => {
+                assert_eq!(header.hash(), block.hash());
+                assert_eq!(*except, peer.id());
+                assert_eq!((*tip, *state), (block.hash(), state_hash));
+            }
+            other => panic!("unexpected broadcasts {:?}", other),
+        }
+    }
+
+    /// A node listening on a free local port, with its server and workers running; asking its
+    /// peers for their tips every `tip_check_interval`, unless zero
+    fn spawn_node(blockchain: Blockchain, tip_check_interval: Duration) -> (server::Handle, Context, SocketAddr) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (mut server_ctx, server) = server::new(addr, msg_tx).unwrap();
+        let greeter = Arc::clone(&blockchain);
+        let greeting_server = server.clone();
+        server_ctx.set_on_connect(move |peer| send_version(peer, &greeter.lock().unwrap(), &greeting_server));
+        server_ctx.start().unwrap();
+        let mut ctx = new(2, msg_rx, &server, &blockchain, &Arc::new(Mutex::new(Mempool::new())));
+        ctx.set_tip_check_interval(tip_check_interval);
+        ctx.clone().start();
+        (server, ctx, addr)
+    }
+
+    /// Connect a fresh node to one with a chain of `length` blocks, and wait for it to sync.
+    /// Returns the fresh node's worker context.
+    fn sync_fresh_node(length: usize) -> Context {
+        let mut chain = Blockchain::new();
+        let genesis_hash = chain.tip();
+        let parent = valid_chain(&mut chain, &genesis_hash, length).last().unwrap().hash();
+        let (_, _, synced_addr) = spawn_node(chain, Duration::from_secs(0));
+        let (fresh, fresh_ctx, _) = spawn_node(Blockchain::new(), Duration::from_secs(0));
+
+        // until the synced node's listener is up
+        let start = std::time::Instant::now();
+        while fresh.connect(synced_addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "could not connect");
+            thread::sleep(Duration::from_millis(20));
+        }
+        while fresh_ctx.blockchain.lock().unwrap().tip() != parent {
+            assert!(start.elapsed() < Duration::from_secs(60), "stuck at height {}", fresh_ctx.blockchain.lock().unwrap().tip_height());
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(fresh_ctx.blockchain.lock().unwrap().tip_height(), length as u64);
+        fresh_ctx
+    }
+
+    #[test]
+    fn fresh_node_syncs_the_chain_on_connecting() {
+        sync_fresh_node(1000);
+    }
+
+    #[test]
+    fn fresh_node_syncs_headers_first() {
+        let ctx = sync_fresh_node(300);
+        // the worker inserting the last blocks may not have crossed them off yet
+        let start = std::time::Instant::now();
+        while ctx.sync.lock().unwrap().is_syncing() {
+            assert!(start.elapsed() < Duration::from_secs(10), "headers left without bodies");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // every block came in answer to its header
+        assert_eq!(ctx.sync.lock().unwrap().headers_accepted(), 300);
+    }
+
+    /// How many messages of type `kind` a node received
+    fn received(server: &server::Handle, kind: &str) -> u64 {
+        server.net_stats().by_type.get(kind).map_or(0, |stats| stats.received)
+    }
+
+    #[test]
+    fn node_that_missed_announcements_catches_up_on_asking_for_tips() {
+        let (_, ahead_ctx, ahead_addr) = spawn_node(Blockchain::new(), Duration::from_secs(0));
+        let (laggard, laggard_ctx, _) = spawn_node(Blockchain::new(), Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        while laggard.connect(ahead_addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "could not connect");
+            thread::sleep(Duration::from_millis(20));
+        }
+        // past the handshake, so the status exchanged on completing it is of the same chain
+        while received(&laggard, "Status") == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "no handshake");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        // blocks the laggard hears nothing of, as if it missed their announcements
+        let tip = {
+            let mut blockchain = ahead_ctx.blockchain.lock().unwrap();
+            let genesis_hash = blockchain.tip();
+            valid_chain(&mut blockchain, &genesis_hash, 5).last().unwrap().hash()
+        };
+        while laggard_ctx.blockchain.lock().unwrap().tip() != tip {
+            assert!(start.elapsed() < Duration::from_secs(10), "stuck at height {}", laggard_ctx.blockchain.lock().unwrap().tip_height());
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(received(&laggard, "Tip") > 0);
+    }
+
+    /// The `Blocks` messages `ctx` answers a `GetBlocks` request for `hashes` with
+    fn blocks_answering(ctx: &Context, hashes: Vec<H256>) -> Vec<Vec<Arc<Block>>> {
+        let (peer, written) = ready_peer(test_peer());
+        deliver(ctx, vec![Message::GetBlocks(hashes)], &peer);
+        std::iter::from_fn(|| written.try_recv().ok())
+            .map(|bytes| match Message::decode(&bytes).unwrap() {
+                Message::Blocks(blocks) => blocks,
+                _ => panic!("expected blocks"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn large_block_requests_are_answered_in_pages() {
+        let ctx = test_context();
+        let hashes = insert_chain(&ctx, 1200, 1);
+        let pages = blocks_answering(&ctx, hashes.clone());
+        let sizes: Vec<usize> = pages.iter().map(|page| page.len()).collect();
+        assert_eq!(sizes, vec![MAX_BLOCKS_PER_MESSAGE, MAX_BLOCKS_PER_MESSAGE, 1200 - 2 * MAX_BLOCKS_PER_MESSAGE]);
+        // each block exactly once, in the order asked
+        let served: Vec<H256> = pages.iter().flatten().map(|block| block.hash()).collect();
+        assert_eq!(served, hashes);
+
+        // big blocks fill a page by bytes before they fill it by count
+        let ctx = test_context();
+        let hashes = insert_chain(&ctx, 300, 100);
+        let pages = blocks_answering(&ctx, hashes.clone());
+        assert!(pages.len() > 1);
+        for page in &pages {
+            assert!(bincode::serialized_size(page).unwrap() <= MAX_BLOCKS_MESSAGE_BYTES + 8);
+        }
+        let served: Vec<H256> = pages.iter().flatten().map(|block| block.hash()).collect();
+        assert_eq!(served, hashes);
+    }
+
+    fn version(genesis: H256, node_nonce: u64) -> Message {
+        Message::Version { protocol: PROTOCOL_VERSION, genesis, tip_height: 0, node_nonce, listen_port: 0, services: 0 }
+    }
+
+    fn written(receiver: &mio_extras::channel::Receiver<Vec<u8>>) -> Vec<Message> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).map(|bytes| Message::decode(&bytes).unwrap()).collect()
+    }
+
+    #[test]
+    fn handshake_comes_before_other_messages() {
+        let ctx = test_context();
+        let genesis = ctx.blockchain.lock().unwrap().genesis_hash();
+        let (peer, out) = peer::Handle::detached(test_peer());
+
+        deliver(&ctx, vec![Message::Ping(1), Message::GetMempool], &peer);
+        assert!(written(&out).is_empty());
+        deliver(&ctx, vec![version(genesis, rand::random())], &peer);
+        assert!(matches!(&written(&out)[..], [Message::Verack]));
+        // the peer may talk now, but is not told anything until it acknowledges our version
+        assert!(!peer.is_ready());
+        deliver(&ctx, vec![Message::Ping(2)], &peer);
+        assert!(matches!(&written(&out)[..], [Message::Pong(2)]));
+        deliver(&ctx, vec![Message::Verack], &peer);
+        assert!(peer.is_ready());
+        assert!(matches!(&written(&out)[..], [Message::Status(..), Message::GetMempool, Message::GetAddr]));
+        assert!(ctx.server.banned_peers().is_empty());
+    }
+
+    #[test]
+    fn messages_before_the_handshake_are_misbehavior() {
+        let ctx = test_context();
+        let (peer, out) = peer::Handle::detached(test_peer());
+        deliver(&ctx, vec![Message::Ping(1); (BAN_THRESHOLD / HANDSHAKE_PENALTY) as usize], &peer);
+        assert!(written(&out).is_empty());
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    #[test]
+    fn duplicate_version_is_misbehavior() {
+        let ctx = test_context();
+        let genesis = ctx.blockchain.lock().unwrap().genesis_hash();
+        let (peer, out) = peer::Handle::detached(test_peer());
+        let versions = vec![version(genesis, rand::random()); 1 + (BAN_THRESHOLD / HANDSHAKE_PENALTY) as usize];
+        deliver(&ctx, versions, &peer);
+        // acknowledged once only
+        assert!(matches!(&written(&out)[..], [Message::Verack]));
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    #[test]
+    fn peer_of_another_network_is_banned() {
+        let ctx = test_context();
+        let (peer, out) = peer::Handle::detached(test_peer());
+        deliver(&ctx, vec![version(H256::from([7; 32]), rand::random()), Message::Ping(1)], &peer);
+        assert!(written(&out).is_empty());
+        assert!(!peer.version_received());
+        assert_eq!(ctx.server.banned_peers(), vec![test_peer()]);
+    }
+
+    #[test]
+    fn connection_to_ourselves_is_dropped() {
+        let ctx = test_context();
+        let genesis = ctx.blockchain.lock().unwrap().genesis_hash();
+        let (peer, out) = peer::Handle::detached(test_peer());
+        deliver(&ctx, vec![version(genesis, ctx.server.node_nonce())], &peer);
+        assert!(written(&out).is_empty());
+        assert!(!peer.version_received());
+        // dropped, not banned: it is our own address
+        assert!(ctx.server.banned_peers().is_empty());
+    }
+
+    #[test]
+    fn compression_is_used_with_peers_that_support_it() {
+        let (ctx, _server) = relaying_context();
+        let genesis = ctx.blockchain.lock().unwrap().genesis_hash();
+        let (older, older_out) = peer::Handle::detached(test_peer());
+        let (newer, newer_out) = peer::Handle::detached("127.0.0.1:6002".parse().unwrap());
+        deliver(&ctx, vec![version(genesis, 1), Message::Verack], &older);
+        let hello = Message::Version { protocol: PROTOCOL_VERSION, genesis, tip_height: 0, node_nonce: 2, listen_port: 0, services: SERVICE_COMPRESSION };
+        deliver(&ctx, vec![hello, Message::Verack], &newer);
+        assert!(!older.reads_compressed());
+        assert!(newer.reads_compressed());
+        written(&older_out);
+        written(&newer_out);
+
+        // handled as the message inside
+        let nonces: Vec<u32> = (1..=40).collect();
+        let block = mined_transfer_block(&ctx, &genesis, &nonces);
+        let compressed = Message::compress(&bincode::serialize(&Message::Blocks(vec![Arc::new(block.clone())])).unwrap()).unwrap();
+        deliver(&ctx, vec![Message::decode(&compressed).unwrap()], &newer);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+        let stats = ctx.server.net_stats();
+        assert_eq!(stats.by_type["Blocks"].received, 1);
+        assert_eq!(stats.by_type["Blocks"].received_bytes, compressed.len() as u64);
+        assert_eq!(stats.compressed_received.messages, 1);
+
+        // and sent compressed only to the peer that reads it
+        deliver(&ctx, vec![Message::GetBlocks(vec![block.hash()])], &older);
+        deliver(&ctx, vec![Message::GetBlocks(vec![block.hash()])], &newer);
+        assert!(matches!(&written(&older_out)[..], [Message::Blocks(blocks)] if blocks[0].hash() == block.hash()));
+        match &written(&newer_out)[..] {
+            [Message::Compressed(payload)] => {
+                assert!(matches!(Message::decompress(payload).unwrap().0, Message::Blocks(blocks) if blocks[0].hash() == block.hash()));
+            }
+            other => panic!("expected a compressed block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn addresses_are_learned_and_gossiped() {
+        let ctx = test_context();
+        ctx.set_allow_local_addrs(true);
+        let genesis = ctx.blockchain.lock().unwrap().genesis_hash();
+        // an inbound peer is known by the port it listens on, not the one it connected from
+        let (peer, out) = peer::Handle::detached("127.0.0.1:51234".parse().unwrap());
+        let hello = Message::Version { protocol: PROTOCOL_VERSION, genesis, tip_height: 0, node_nonce: 1, listen_port: 6001, services: 0 };
+        deliver(&ctx, vec![hello, Message::Verack], &peer);
+        written(&out);
+        let gossiped: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        deliver(&ctx, vec![Message::Addr(vec![gossiped, gossiped]), Message::GetAddr], &peer);
+        match &written(&out)[..] {
+            [Message::Addr(addrs)] => {
+                let mut addrs = addrs.clone();
+                addrs.sort();
+                assert_eq!(addrs, vec![test_peer(), gossiped]);
+            }
+            other => panic!("expected addresses, got {:?}", other),
+        }
+
+        // loopback addresses stay out of the book by default
+        let ctx = test_context();
+        let (peer, out) = ready_peer(test_peer());
+        deliver(&ctx, vec![Message::Addr(vec![gossiped]), Message::GetAddr], &peer);
+        assert!(written(&out).is_empty());
+    }
+
+    #[test]
+    fn a_flooding_peer_does_not_starve_the_others() {
+        let ctx = test_context();
+        ctx.server.set_rate_limits(RateLimits { messages_per_sec: 10, bytes_per_sec: 1 << 20 });
+        let (flooder, flooder_out) = ready_peer(test_peer());
+        let (other, other_out) = ready_peer("127.0.0.1:6002".parse().unwrap());
+        let gossiped: SocketAddr = "8.8.8.8:6000".parse().unwrap();
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let send = |msg: Message, peer: &peer::Handle| msg_tx.send((bincode::serialize(&msg).unwrap(), peer.clone())).unwrap();
+        for nonce in 0..100 {
+            send(Message::Ping(nonce), &flooder);
+        }
+        // answering a request of ours, so held back rather than dropped
+        send(Message::Addr(vec![gossiped]), &flooder);
+        send(Message::Ping(1000), &other);
+        drop(msg_tx);
+        let worker = Context { msg_chan: msg_rx, ..ctx.clone() };
+        worker.worker_loop();
+
+        // a second's worth, and whatever refilled while the worker was at it
+        let answered = written(&flooder_out).len() as u64;
+        assert!((10..15).contains(&answered), "{} pings answered", answered);
+        assert!(matches!(&written(&other_out)[..], [Message::Pong(1000)]));
+        assert_eq!(ctx.server.dropped_messages(), vec![(test_peer(), 100 - answered)]);
+        assert_eq!(ctx.deferred.lock().unwrap().len(), 1);
+
+        // handled once the flooder's limit refills
+        thread::sleep(Duration::from_millis(150));
+        deliver(&ctx, vec![Message::GetAddr], &other);
+        assert!(ctx.deferred.lock().unwrap().is_empty());
+        assert!(matches!(&written(&other_out)[..], [Message::Addr(addrs)] if addrs == &vec![gossiped]));
+    }
+
+    /// `ctx`'s mempool holding the transactions of `block` at `indexes`
+    fn pool_transactions(ctx: &Context, block: &Block, indexes: &[usize]) {
+        let mut mempool = ctx.mempool.lock().unwrap();
+        for &index in indexes {
+            mempool.insert(block.content.transactions[index].clone());
+        }
+    }
+
+    /// A transfer from ICO account 0 with `nonce`, valid on top of the genesis state
+    fn transfer(nonce: u32) -> SignedTransaction {
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 10)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+        SignedTransaction::from_raw(raw, &key, &ChainId::default())
+    }
+
+    /// `ctx`'s mempool holding `count` more transactions than fit in an announcement; they are
+    /// never validated, so they need not be signed
+    fn crowd_mempool(ctx: &Context, count: usize) {
+        let mut mempool = ctx.mempool.lock().unwrap();
+        for nonce in 0..count as u32 {
+            let raw = RawTransaction { from_addr: Default::default(), outputs: Vec::new(), fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+            mempool.insert(SignedTransaction { raw, pub_key: Vec::new(), signature: Vec::new() });
+        }
+    }
+
+    #[test]
+    fn only_admitted_transactions_are_announced() {
+        let (ctx, broadcasts) = mock_context();
+        crowd_mempool(&ctx, MAX_HASHES_PER_MESSAGE + 1);
+        let (peer, _) = ready_peer(test_peer());
+        let (first, second) = (transfer(1), transfer(2));
+        let mut unsigned = transfer(3);
+        unsigned.signature = vec![0; 64];
+
+        deliver(&ctx, vec![Message::Transactions(vec![first.clone(), second.clone(), unsigned])], &peer);
+        let broadcasts: Vec<_> = broadcasts.try_iter().collect();
+        match &broadcasts[..] {
+            [(Message::NewTransactionHashes(hashes), Some(except))] => {
+                assert_eq!(*hashes, vec![first.txid(), second.txid()]);
+                assert_eq!(*except, peer.id());
+            }
+            other => panic!("unexpected broadcasts {:?}", other),
+        }
+    }
+
+    #[test]
+    fn relaying_with_a_crowded_mempool_does_not_get_honest_peers_banned() {
+        let (sender, sender_broadcasts) = mock_context();
+        crowd_mempool(&sender, MAX_HASHES_PER_MESSAGE + 1);
+        let receiver = test_context();
+        let (origin, _) = ready_peer(test_peer());
+        // the sender as the receiver sees it
+        let (relay, relay_out) = ready_peer("127.0.0.1:6002".parse().unwrap());
+
+        // as many relays as it takes malformed messages to get banned
+        let relays = BAN_THRESHOLD / MALFORMED_MESSAGE_PENALTY;
+        let transactions: Vec<SignedTransaction> = (1..=relays).map(transfer).collect();
+        for tx in &transactions {
+            deliver(&sender, vec![Message::Transactions(vec![tx.clone()])], &origin);
+            let announcements = sender_broadcasts.try_iter().map(|(msg, _)| msg).collect();
+            deliver(&receiver, announcements, &relay);
+        }
+        assert!(receiver.server.banned_peers().is_empty());
+        let requested: Vec<H256> = written(&relay_out).into_iter()
+            .flat_map(|msg| match msg {
+                Message::GetTransactions(hashes) => hashes,
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect();
+        assert_eq!(requested, transactions.iter().map(|tx| tx.txid()).collect::<Vec<H256>>());
+    }
+
+    #[test]
+    fn compact_block_is_rebuilt_from_the_mempool() {
+        let (ctx, _server) = relaying_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &[1, 2, 3]);
+        pool_transactions(&ctx, &block, &[1, 2, 3]);
+        let (peer, out) = ready_peer(test_peer());
+        deliver(&ctx, vec![Message::compact_block(&block).unwrap()], &peer);
+
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+        assert!(written(&out).is_empty());
+        // not to be announced back to the peer
+        assert!(peer.knows_inventory(&block.hash()));
+        assert!(peer.knows_inventory(&block.content.transactions[1].txid()));
+        let traffic = ctx.server.traffic().snapshot();
+        assert_eq!(traffic.compact_blocks, 1);
+        assert!(traffic.compact_bytes_saved > 0);
+        assert_eq!(ctx.server.net_stats().by_type["CompactBlock"].received, 1);
+    }
+
+    #[test]
+    fn missing_transactions_are_asked_for() {
+        let (ctx, _server) = relaying_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &[1, 2, 3]);
+        pool_transactions(&ctx, &block, &[2]);
+        let (peer, out) = ready_peer(test_peer());
+        deliver(&ctx, vec![Message::compact_block(&block).unwrap()], &peer);
+        assert!(matches!(&written(&out)[..],
+            [Message::GetBlockTxn { block: hash, indexes }] if *hash == block.hash() && indexes == &vec![1, 3]));
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), genesis_hash);
+
+        let txs = vec![block.content.transactions[1].clone(), block.content.transactions[3].clone()];
+        deliver(&ctx, vec![Message::BlockTxn { block: block.hash(), txs }], &peer);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+        assert!(ctx.compact.lock().unwrap().is_empty());
+        assert_eq!(ctx.server.traffic().snapshot().compact_blocks, 1);
+    }
+
+    #[test]
+    fn compact_block_that_cannot_be_rebuilt_is_fetched_whole() {
+        let (ctx, _server) = relaying_context();
+        let genesis_hash = ctx.blockchain.lock().unwrap().tip();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &[1, 2]);
+        let other = mined_transfer_block(&ctx, &genesis_hash, &[1, 3]);
+        pool_transactions(&ctx, &block, &[1]);
+        let (peer, out) = ready_peer(test_peer());
+        deliver(&ctx, vec![Message::compact_block(&block).unwrap()], &peer);
+        assert!(matches!(&written(&out)[..], [Message::GetBlockTxn { indexes, .. }] if indexes == &vec![2]));
+
+        // the wrong transaction breaks the merkle root, so all of them are asked for again
+        let wrong = vec![other.content.transactions[2].clone()];
+        deliver(&ctx, vec![Message::BlockTxn { block: block.hash(), txs: wrong }], &peer);
+        assert!(matches!(&written(&out)[..], [Message::GetBlockTxn { indexes, .. }] if indexes == &vec![1, 2]));
+
+        let wrong = other.content.transactions[1..].to_vec();
+        deliver(&ctx, vec![Message::BlockTxn { block: block.hash(), txs: wrong }], &peer);
+        assert!(matches!(&written(&out)[..], [Message::GetBlocks(hashes)] if hashes == &vec![block.hash()]));
+        assert_eq!(ctx.server.traffic().snapshot().compact_fallbacks, 1);
+
+        deliver(&ctx, vec![Message::Blocks(vec![Arc::new(block.clone())])], &peer);
+        assert_eq!(ctx.blockchain.lock().unwrap().tip(), block.hash());
+        assert!(ctx.compact.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_joins_every_thread() {
+        let (server_tx, _server_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_tx).unwrap();
+        // the server is still up, so nothing but the shutdown stops the workers
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let ctx = new(4, msg_rx.clone(), &server, &Arc::new(Mutex::new(Blockchain::new())), &Arc::new(Mutex::new(Mempool::new())));
+        let workers = ctx.start();
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        workers.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(1), "shutdown took {:?}", start.elapsed());
+        assert!(workers.threads.lock().unwrap().is_empty());
+        // nobody is left to take messages
+        let (peer, _) = ready_peer(test_peer());
+        msg_tx.send((bincode::serialize(&Message::Ping(1)).unwrap(), peer)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(msg_rx.len(), 1);
+    }
+
+    #[test]
+    fn shutdown_cancels_a_block_validation_without_blaming_its_sender() {
+        let (server_tx, _server_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_tx).unwrap();
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut ctx = new(1, msg_rx, &server, &blockchain, &Arc::new(Mutex::new(Mempool::new())));
+        let (started_tx, started_rx) = channel::unbounded();
+        let shutdown = Arc::clone(&ctx.shutdown);
+        ctx.on_validate = Some(Arc::new(move |_: &H256| {
+            started_tx.send(()).unwrap();
+            // the shutdown comes while the block is being validated
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        // as many transactions as a block may hold
+        let nonces: Vec<u32> = (1..=300).collect();
+        let block = mined_transfer_block(&ctx, &genesis_hash, &nonces);
+        assert!(block.content.size() <= MAX_BLOCK_SIZE);
+        assert!(blockchain.lock().unwrap().expected_state_root(&block).is_some());
+
+        let workers = ctx.clone().start();
+        let (peer, _out) = ready_peer(test_peer());
+        msg_tx.send((bincode::serialize(&Message::Blocks(vec![Arc::new(block.clone())])).unwrap(), peer)).unwrap();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let start = Instant::now();
+        workers.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(1), "shutdown took {:?}", start.elapsed());
+
+        // neither processed nor held against the peer
+        assert!(!blockchain.lock().unwrap().contains_block(&block.hash()));
+        assert_eq!(blockchain.lock().unwrap().orphan_count(), 0);
+        assert!(!ctx.in_flight.contains(&block.hash()));
+        assert!(server.banned_peers().is_empty());
+    }
+
+    #[test]
+    fn a_heavy_block_from_one_peer_does_not_hold_up_the_blocks_of_another() {
+        let (server_tx, _server_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_tx).unwrap();
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut ctx = new(2, msg_rx, &server, &blockchain, &Arc::new(Mutex::new(Mempool::new())));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        // blocks of as many transactions as a message may carry, 5000 being over the limit, twice
+        // as many as there are workers; and a light one
+        let heavy: Vec<Block> = (0..2).map(|_| {
+            let mut block = mined_transfer_block(&ctx, &genesis_hash, &[1]);
+            let transfer = block.content.transactions[1].clone();
+            block.content.transactions.resize(MAX_TRANSACTIONS_PER_BLOCK, transfer);
+            block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+            while block.hash() > block.header.difficulty {
+                block.header.nonce = rand::random();
+            }
+            block
+        }).collect();
+        let light = valid_chain(&mut Blockchain::new(), &genesis_hash, 1).remove(0);
+
+        // validating a heavy block takes until the light one is in, or long enough to tell
+        let heavy_hashes: Vec<H256> = heavy.iter().map(|block| block.hash()).collect();
+        let (light_hash, chain) = (light.hash(), Arc::clone(&blockchain));
+        let (waited_tx, waited_rx) = channel::unbounded();
+        ctx.on_validate = Some(Arc::new(move |hash: &H256| {
+            if heavy_hashes.contains(hash) {
+                let start = Instant::now();
+                while !chain.lock().unwrap().contains_block(&light_hash) && start.elapsed() < Duration::from_secs(5) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                waited_tx.send(start.elapsed()).unwrap();
+            }
+        }));
+        let workers = ctx.start();
+
+        let (heavy_peer, _) = ready_peer(test_peer());
+        let (light_peer, _) = ready_peer("127.0.0.1:6002".parse().unwrap());
+        for block in heavy {
+            msg_tx.send((bincode::serialize(&Message::Blocks(vec![Arc::new(block)])).unwrap(), heavy_peer.clone())).unwrap();
+        }
+        msg_tx.send((bincode::serialize(&Message::Blocks(vec![Arc::new(light.clone())])).unwrap(), light_peer)).unwrap();
+
+        let waited = waited_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(waited < Duration::from_secs(5), "the light block waited for the heavy one");
+        assert_eq!(blockchain.lock().unwrap().tip(), light_hash);
+        workers.shutdown();
     }
 }
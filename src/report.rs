@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::blockchain::{BlockOrigin, Blockchain};
+use crate::mempool::Mempool;
+use crate::network::traffic::{NetStatsSnapshot, TrafficSnapshot};
+use crate::sig_cache::SigCacheStats;
+
+/// Summary of the block propagation delays, in milliseconds
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct DelayStats {
+    pub count: usize,
+    pub mean: f64,
+    pub median: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+/// Everything the end-of-run statistics cover, in one serializable struct
+#[derive(Serialize, Debug, Clone)]
+pub struct ExperimentReport {
+    pub run_seconds: f64,
+    pub blocks_mined: u64,
+    pub mining_rate: f64,
+    pub blocks_received: usize,
+    pub total_blocks: usize,
+    pub longest_chain_length: u64,
+    pub stale_blocks: usize,
+    pub fork_count: usize,
+    pub fork_length_histogram: HashMap<u64, usize>,
+    pub average_block_size: usize,
+    pub block_delays_ms: DelayStats,
+    pub confirmation_latency_ms: DelayStats,
+    pub mempool_size: usize,
+    pub sig_cache: SigCacheStats,
+    /// What compact block relay, filtering announcements and asking only the first peer to
+    /// announce each block saved
+    pub traffic: TrafficSnapshot,
+    /// Messages sent and received by type, and their bytes
+    pub net_stats: NetStatsSnapshot,
+}
+
+/// One block of the longest chain, for post-processing outside of Rust.
+/// Times are in milliseconds; hashes are hex strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockSummary {
+    pub hash: String,
+    pub parent: String,
+    pub height: u64,
+    pub timestamp: u64,
+    pub nonce: u32,
+    pub difficulty: String,
+    /// Transaction count and serialized size, unless the block's body was pruned
+    pub tx_count: Option<usize>,
+    pub size: Option<usize>,
+    /// `mined`, `received`, or `unknown` for genesis and blocks loaded from disk
+    pub origin: String,
+    /// Propagation delay, for received blocks only
+    pub delay_ms: Option<u64>,
+    /// The peer the block was received from
+    pub from: Option<String>,
+}
+
+/// The longest chain, from genesis to the tip
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainSummary {
+    pub blocks: Vec<BlockSummary>,
+}
+
+impl ChainSummary {
+    pub fn collect(blockchain: &Blockchain) -> Self {
+        let blocks = blockchain.iter_longest_chain().map(|(hash, header)| {
+            let (origin, delay_ms, from) = match blockchain.hash_to_origin.get(hash) {
+                Some(BlockOrigin::Mined) => ("mined", None, None),
+                Some(BlockOrigin::Received { delay_ms, from }) => {
+                    ("received", Some(*delay_ms as u64), Some(from.to_string()))
+                }
+                None => ("unknown", None, None),
+            };
+            BlockSummary {
+                hash: hash.to_string(),
+                parent: header.parent.to_string(),
+                height: blockchain.get_height(hash).unwrap(),
+                timestamp: header.timestamp as u64,
+                nonce: header.nonce,
+                difficulty: header.difficulty.to_string(),
+                tx_count: blockchain.get_block(hash).map(|block| block.content.transactions.len()),
+                size: blockchain.get_block(hash).map(|block| block.size()),
+                origin: origin.to_string(),
+                delay_ms,
+                from,
+            }
+        }).collect();
+        ChainSummary { blocks }
+    }
+
+    /// Write the summary as pretty-printed JSON
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+impl ExperimentReport {
+    /// Assemble the report from the miner's counters and the blockchain and mempool stats
+    pub fn collect(run_seconds: f64, blocks_mined: u64, blockchain: &Blockchain, mempool: &Mempool, traffic: TrafficSnapshot, net_stats: NetStatsSnapshot) -> Self {
+        let blocks_received = blockchain.hash_to_origin.values()
+            .filter(|origin| matches!(origin, BlockOrigin::Received{..}))
+            .count();
+        ExperimentReport {
+            run_seconds,
+            blocks_mined,
+            mining_rate: blocks_mined as f64 / run_seconds,
+            blocks_received,
+            total_blocks: blockchain.block_count(),
+            longest_chain_length: blockchain.tip_height() + 1,
+            stale_blocks: blockchain.stale_block_count(),
+            fork_count: blockchain.fork_count(),
+            fork_length_histogram: blockchain.fork_length_histogram(),
+            average_block_size: blockchain.average_block_size(),
+            block_delays_ms: blockchain.block_delay_stats(),
+            confirmation_latency_ms: blockchain.confirmation_latency_stats(),
+            mempool_size: mempool.get_keys().len(),
+            sig_cache: blockchain.sig_cache().stats(),
+            traffic,
+            net_stats,
+        }
+    }
+
+    /// Write the report as pretty-printed JSON
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+impl DelayStats {
+    /// Summarize delays that are already sorted in ascending order; all zero if there are none.
+    /// Percentiles are the nearest rank at or below, e.g. the median of 4 delays is the 2nd one.
+    pub fn from_sorted(delays: &[u128]) -> Self {
+        if delays.is_empty() {
+            return Default::default();
+        }
+        let percentile = |p: usize| delays[(delays.len() - 1) * p / 100] as u64;
+        DelayStats {
+            count: delays.len(),
+            mean: delays.iter().sum::<u128>() as f64 / delays.len() as f64,
+            median: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            min: delays[0] as u64,
+            max: delays[delays.len() - 1] as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+
+    #[test]
+    fn report_serializes_with_stats() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        blockchain.hash_to_origin.insert(block_1.hash(), BlockOrigin::Mined);
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        blockchain.hash_to_origin.insert(fork_1.hash(), BlockOrigin::Received { delay_ms: 40, from: "127.0.0.1:6001".parse().unwrap() });
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        blockchain.hash_to_origin.insert(block_2.hash(), BlockOrigin::Received { delay_ms: 120, from: "127.0.0.1:6001".parse().unwrap() });
+
+        let report = ExperimentReport::collect(2.0, 1, &blockchain, &Mempool::new(), TrafficSnapshot::default(), NetStatsSnapshot::default());
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["blocks_mined"], 1);
+        assert_eq!(json["mining_rate"], 0.5);
+        assert_eq!(json["blocks_received"], 2);
+        assert_eq!(json["total_blocks"], 4);
+        assert_eq!(json["longest_chain_length"], 3);
+        assert_eq!(json["stale_blocks"], 1);
+        assert_eq!(json["fork_count"], 1);
+        assert_eq!(json["fork_length_histogram"]["1"], 1);
+        assert_eq!(json["block_delays_ms"]["count"], 2);
+        assert_eq!(json["block_delays_ms"]["max"], 120);
+        assert!(json["average_block_size"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn delay_percentiles() {
+        let delays: Vec<u128> = (1..=100).collect();
+        let stats = DelayStats::from_sorted(&delays);
+        assert_eq!(stats, DelayStats { count: 100, mean: 50.5, median: 50, p90: 90, p99: 99, min: 1, max: 100 });
+
+        let delays = [3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 377];
+        let stats = DelayStats::from_sorted(&delays);
+        assert_eq!(stats, DelayStats { count: 11, mean: 89.27272727272727, median: 34, p90: 233, p99: 233, min: 3, max: 377 });
+
+        assert_eq!(DelayStats::from_sorted(&[]), DelayStats::default());
+        assert_eq!(DelayStats::from_sorted(&[7]).p99, 7);
+    }
+
+    #[test]
+    fn chain_summary_round_trip() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        blockchain.hash_to_origin.insert(block_1.hash(), BlockOrigin::Mined);
+        let fork_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        blockchain.hash_to_origin.insert(block_2.hash(), BlockOrigin::Received { delay_ms: 120, from: "127.0.0.1:6001".parse().unwrap() });
+
+        let path = std::env::temp_dir().join(format!("chain-test-{}.json", rand::random::<u64>()));
+        blockchain.export_json_to_file(&path).unwrap();
+        let summary: ChainSummary = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary, blockchain.export_json());
+
+        let heights: Vec<u64> = summary.blocks.iter().map(|block| block.height).collect();
+        assert_eq!(heights, vec![0, 1, 2]);
+        assert_eq!(summary.blocks[2].parent, summary.blocks[1].hash);
+        assert_eq!(summary.blocks[2].hash, block_2.hash().to_string());
+        let origins: Vec<&str> = summary.blocks.iter().map(|block| block.origin.as_str()).collect();
+        assert_eq!(origins, vec!["unknown", "mined", "received"]);
+        assert_eq!(summary.blocks[2].delay_ms, Some(120));
+        assert_eq!(summary.blocks[2].from.as_deref(), Some("127.0.0.1:6001"));
+    }
+}
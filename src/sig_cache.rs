@@ -0,0 +1,160 @@
+//! Results of signature verifications, so that a transaction verified when admitted into the
+//! mempool is not verified again when it is selected for a template or received in a block.
+
+use crate::crypto::hash::H256;
+use crate::transaction::{ChainId, SignedTransaction};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// How many verification results are kept unless configured otherwise
+pub const DEFAULT_SIG_CACHE_CAPACITY: usize = 100_000;
+
+/// How often the cache answered, and how often it had to verify
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Verification results keyed by the chain and the wtxid, which covers the public key and the
+/// signature: a copy of a transaction signed differently has another key and is verified anew.
+/// Once full, the oldest results make room for new ones.
+pub struct SigCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entries {
+    results: HashMap<(ChainId, H256), bool>,
+    /// Keys in insertion order, oldest first
+    order: VecDeque<(ChainId, H256)>,
+    capacity: usize,
+}
+
+impl SigCache {
+    pub fn new(capacity: usize) -> Self {
+        SigCache {
+            entries: Mutex::new(Entries { results: HashMap::new(), order: VecDeque::new(), capacity }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Same as `SignedTransaction::verify_signature`, verifying only on a miss. The lock is not
+    /// held while verifying, so threads verifying a batch do not wait on each other.
+    pub fn verify_signature(&self, tx: &SignedTransaction, chain: &ChainId) -> bool {
+        let key = (*chain, tx.wtxid());
+        if let Some(&valid) = self.entries.lock().unwrap().results.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return valid;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let valid = tx.verify_signature(chain);
+        self.entries.lock().unwrap().insert(key, valid);
+        valid
+    }
+
+    /// Same as `SignedTransaction::verify`, with the signature checked through the cache
+    pub fn verify(&self, tx: &SignedTransaction, chain: &ChainId) -> bool {
+        self.verify_signature(tx, chain) && tx.verify_owner()
+    }
+
+    pub fn stats(&self) -> SigCacheStats {
+        SigCacheStats { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+}
+
+impl Default for SigCache {
+    fn default() -> Self {
+        SigCache::new(DEFAULT_SIG_CACHE_CAPACITY)
+    }
+}
+
+impl Entries {
+    fn insert(&mut self, key: (ChainId, H256), valid: bool) {
+        if self.capacity == 0 || self.results.insert(key, valid).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.results.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{get_deterministic_keypair, H160};
+    use crate::blockchain::Blockchain;
+    use crate::mempool::Mempool;
+    use crate::transaction::{verify_batch, RawTransaction};
+    use std::sync::atomic::AtomicBool;
+    use ring::signature::KeyPair;
+
+    fn transaction(nonce: u32) -> SignedTransaction {
+        let raw = RawTransaction { nonce, outputs: vec![(Default::default(), 1)], ..Default::default() };
+        SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+    }
+
+    #[test]
+    fn resigned_copy_does_not_hit_a_cached_result() {
+        let cache = SigCache::default();
+        let chain = ChainId::default();
+        let genuine = transaction(1);
+        assert!(cache.verify_signature(&genuine, &chain));
+        assert!(cache.verify_signature(&genuine, &chain));
+        assert_eq!(cache.stats(), SigCacheStats { hits: 1, misses: 1 });
+
+        // same txid, another signer's key and signature: verified, and rejected
+        let mut forged = genuine.clone();
+        let other = SignedTransaction::from_raw(genuine.raw.clone(), &get_deterministic_keypair(1), &chain);
+        forged.signature = other.signature;
+        assert_eq!(forged.txid(), genuine.txid());
+        assert!(!cache.verify_signature(&forged, &chain));
+        assert!(!cache.verify_signature(&forged, &chain));
+        // nor does another chain share the result
+        assert!(!cache.verify_signature(&genuine, &ChainId(H256::from([1; 32]))));
+        assert_eq!(cache.stats(), SigCacheStats { hits: 2, misses: 3 });
+    }
+
+    #[test]
+    fn oldest_results_are_evicted() {
+        let cache = SigCache::new(2);
+        let chain = ChainId::default();
+        for nonce in 1..=3 {
+            cache.verify_signature(&transaction(nonce), &chain);
+        }
+        // the first result made room for the third
+        cache.verify_signature(&transaction(3), &chain);
+        cache.verify_signature(&transaction(1), &chain);
+        assert_eq!(cache.stats(), SigCacheStats { hits: 1, misses: 4 });
+    }
+
+    /// Transfers from the first ICO account, signed for the default chain
+    fn transfers(count: u32) -> Vec<SignedTransaction> {
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        (1..=count).map(|nonce| {
+            let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 1)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &key, &ChainId::default())
+        }).collect()
+    }
+
+    #[test]
+    fn block_of_admitted_transactions_is_not_verified_again() {
+        let mut blockchain = Blockchain::new();
+        let txs = transfers(10);
+        let results = blockchain.admit_transactions(&mut Mempool::new(), txs.clone());
+        assert!(results.iter().all(|result| result.is_ok()));
+        let sig_cache = blockchain.sig_cache();
+        assert_eq!(sig_cache.stats(), SigCacheStats { hits: 0, misses: 10 });
+        assert_eq!(verify_batch(&txs, &blockchain.chain_id(), &sig_cache, &AtomicBool::new(false)), Ok(()));
+        assert_eq!(sig_cache.stats(), SigCacheStats { hits: 10, misses: 10 });
+    }
+}
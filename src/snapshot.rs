@@ -0,0 +1,112 @@
+//! Consistent reads across the blockchain and the mempool.
+//! Anything that locks both must take the blockchain lock first, then the mempool lock
+//! (the miner does the same), or two threads can deadlock.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use crate::mempool::Mempool;
+use std::sync::{Arc, Mutex};
+
+/// Identifies the chain tip and mempool version an answer was computed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsistentView {
+    pub tip: H256,
+    pub height: u64,
+    pub mempool_generation: u64,
+}
+
+/// Hands out consistent views of the blockchain and the mempool
+#[derive(Clone)]
+pub struct SnapshotCoordinator {
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+}
+
+impl SnapshotCoordinator {
+    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> Self {
+        SnapshotCoordinator {
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+        }
+    }
+
+    /// Run `f` while holding both locks, taken in the fixed order, together with the view it sees
+    pub fn with_both<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Blockchain, &Mempool, ConsistentView) -> T,
+    {
+        let blockchain = self.blockchain.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
+        let view = ConsistentView {
+            tip: blockchain.tip(),
+            height: blockchain.tip_height(),
+            mempool_generation: mempool.generation(),
+        };
+        f(&blockchain, &mempool, view)
+    }
+
+    /// The current view, for answers that need nothing else
+    pub fn view(&self) -> ConsistentView {
+        self.with_both(|_, _, view| view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::get_deterministic_keypair;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+    use crate::transaction::{ChainId, RawTransaction, SignedTransaction};
+    use std::collections::HashMap;
+    use std::thread;
+
+    #[test]
+    fn views_never_mix_tips_and_generations() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let coordinator = SnapshotCoordinator::new(&blockchain, &mempool);
+        // mempool generation right after each tip was set, written under both locks
+        let generation_at_tip = Arc::new(Mutex::new(HashMap::new()));
+        generation_at_tip.lock().unwrap().insert(blockchain.lock().unwrap().tip(), 0);
+
+        // a "miner" that adds transactions and then confirms them in a block
+        let miner = {
+            let blockchain = Arc::clone(&blockchain);
+            let mempool = Arc::clone(&mempool);
+            let generation_at_tip = Arc::clone(&generation_at_tip);
+            thread::spawn(move || {
+                let key = get_deterministic_keypair(0);
+                for nonce in 1..=200 {
+                    let raw = RawTransaction { nonce, outputs: vec![(Default::default(), 1)], ..Default::default() };
+                    mempool.lock().unwrap().insert(SignedTransaction::from_raw(raw, &key, &ChainId::default()));
+                    let mut blockchain = blockchain.lock().unwrap();
+                    let mut mempool = mempool.lock().unwrap();
+                    mempool.pop();
+                    let block = generate_random_block(&blockchain.tip());
+                    blockchain.insert(&block);
+                    generation_at_tip.lock().unwrap().insert(block.hash(), mempool.generation());
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let coordinator = coordinator.clone();
+            let generation_at_tip = Arc::clone(&generation_at_tip);
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    let view = coordinator.view();
+                    let at_tip = generation_at_tip.lock().unwrap()[&view.tip];
+                    // the view cannot predate its own tip, nor see a mempool from after the next block
+                    assert!(view.mempool_generation >= at_tip);
+                    assert!(view.mempool_generation <= at_tip + 1);
+                }
+            })
+        }).collect();
+        miner.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(coordinator.view().height, 200);
+    }
+}
@@ -1,24 +1,94 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use crate::{address::H160, crypto::hash::{Hashable, H256}};
+use crate::block::Block;
+use crate::blockchain::GenesisConfig;
+use crate::sig_cache::SigCache;
+use crate::validation::MAX_OUTPUTS_PER_TRANSACTION;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Prefixed to every signed message, so bytes signed for any other purpose never verify as a transaction
+const SIGNING_DOMAIN: &[u8] = b"PART5-TX-V1";
+
+/// Transactions whose `data` is longer than this are neither admitted nor valid in a block
+pub const MAX_DATA_SIZE: usize = 256;
+
+/// The chain transactions are signed for, named by the hash of its genesis block, so that a
+/// transaction signed for one chain does not verify on another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChainId(pub H256);
+
+impl Default for ChainId {
+    /// The chain of the default genesis block, as `Blockchain::new` builds it
+    fn default() -> Self {
+        let config = GenesisConfig::default();
+        ChainId(Block::genesis_with(config.difficulty, config.timestamp as u128).hash())
+    }
+}
 
 /// Account-based transaction
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct RawTransaction {
     pub from_addr: H160,
-    pub to_addr: H160,
-    pub value: u64,
+    /// Recipients and the value each receives, credited in order. A recipient may appear more
+    /// than once, and may be the sender.
+    pub outputs: Vec<(H160, u64)>,
+    /// Paid to the miner of the block including the transaction
+    pub fee: u64,
     pub nonce: u32,
+    /// Height of the last block that may include the transaction, inclusive; 0 for no expiry
+    pub valid_until_block: u64,
+    /// Free-form memo, signed along with the rest; at most `MAX_DATA_SIZE` bytes
+    pub data: Vec<u8>,
+}
+
+impl RawTransaction {
+    /// A transaction with a single output, paying `value` to `to`
+    pub fn transfer(from: H160, to: H160, value: u64, fee: u64, nonce: u32) -> Self {
+        RawTransaction { from_addr: from, outputs: vec![(to, value)], fee, nonce, valid_until_block: 0, data: Vec::new() }
+    }
+
+    /// Sum of the outputs' values, `None` if it overflows
+    pub fn total_value(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |total, (_, value)| total.checked_add(*value))
+    }
+
+    /// The bytes hashed and signed, independent of how bincode is configured: `from_addr` as its
+    /// 20 raw bytes, the number of outputs as a little-endian u32, each output's address and
+    /// value, then `fee`, `nonce`, `valid_until_block`, and `data` after its length as a u32.
+    /// Integers are fixed-width little-endian.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(48 + 28 * self.outputs.len() + self.data.len());
+        bytes.extend_from_slice(self.from_addr.as_ref());
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_le_bytes());
+        for (address, value) in &self.outputs {
+            bytes.extend_from_slice(address.as_ref());
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.fee.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend_from_slice(&self.valid_until_block.to_le_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Whether the transaction may no longer be included in a block at `height`
+    pub fn is_expired_at(&self, height: u64) -> bool {
+        self.valid_until_block != 0 && height > self.valid_until_block
+    }
 }
+
 impl Hashable for RawTransaction {
     fn hash(&self) -> H256 {
-        let bytes = bincode::serialize(&self).unwrap();
-        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+        ring::digest::digest(&ring::digest::SHA256, &self.canonical_bytes()).into()
     }
 }
 
-/// A signed transaction
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+/// A signed transaction. It has no `Default`: an unsigned transaction from the zero address
+/// would pass for a coinbase.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SignedTransaction {
     // to avoid name confusion, we recommend renaming `Transaction` to `RawTransaction`:
     pub raw: RawTransaction,  
@@ -33,51 +103,453 @@ impl Hashable for SignedTransaction {
     }
 }
 
+/// Why `SignedTransaction::try_from_raw` refused to sign a raw transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxBuildError {
+    /// Paying no one
+    NoOutputs,
+    /// More than `MAX_OUTPUTS_PER_TRANSACTION` outputs
+    TooManyOutputs { count: usize },
+    /// `data` longer than `MAX_DATA_SIZE`
+    DataTooLarge { size: usize },
+    /// The outputs and the fee add up to more than a u64 holds
+    Overflow,
+    /// The key does not own `from_addr`, so the signature would not authorize the spend
+    WrongSigner,
+}
+
+impl fmt::Display for TxBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxBuildError::NoOutputs => write!(f, "the transaction has no outputs"),
+            TxBuildError::TooManyOutputs { count } => {
+                write!(f, "{} outputs, more than the {} allowed", count, MAX_OUTPUTS_PER_TRANSACTION)
+            }
+            TxBuildError::DataTooLarge { size } => write!(f, "{} bytes of data, more than the {} allowed", size, MAX_DATA_SIZE),
+            TxBuildError::Overflow => write!(f, "the outputs and the fee add up to more than the maximum amount"),
+            TxBuildError::WrongSigner => write!(f, "the key does not own the sending address"),
+        }
+    }
+}
+
 impl SignedTransaction {
-    /// Create a new transaction from a raw transaction and a key pair
-    pub fn from_raw(raw: RawTransaction, key: &Ed25519KeyPair) -> SignedTransaction {
+    /// The coinbase of the block at `height`, paying `value` to `to`. It is unsigned, sent
+    /// from the zero address, and has the height as its nonce so that coinbases paying the
+    /// same miner the same amount still have different hashes.
+    pub fn coinbase(to: H160, value: u64, height: u64) -> SignedTransaction {
+        let raw = RawTransaction::transfer(H160::default(), to, value, 0, height as u32);
+        SignedTransaction { raw, pub_key: Vec::new(), signature: Vec::new() }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.raw.from_addr == H160::default() && self.pub_key.is_empty() && self.signature.is_empty()
+    }
+
+    /// Identifies the payment: the hash of `raw` alone, so copies signed differently share it.
+    /// The mempool, the transaction index and relay messages all go by it.
+    pub fn txid(&self) -> H256 {
+        self.raw.hash()
+    }
+
+    /// The hash of the whole transaction, signature and public key included, as the merkle
+    /// root commits to
+    pub fn wtxid(&self) -> H256 {
+        self.hash()
+    }
+
+    /// Sign `raw` for `chain` after checking it is a transaction the mempool could admit as far
+    /// as its own contents go: balance, nonce and expiry depend on the chain and are not checked
+    pub fn try_from_raw(raw: RawTransaction, key: &Ed25519KeyPair, chain: &ChainId) -> Result<SignedTransaction, TxBuildError> {
+        if H160::from_pubkey(key.public_key().as_ref()) != raw.from_addr {
+            return Err(TxBuildError::WrongSigner);
+        }
+        if raw.outputs.is_empty() {
+            return Err(TxBuildError::NoOutputs);
+        }
+        if raw.outputs.len() > MAX_OUTPUTS_PER_TRANSACTION {
+            return Err(TxBuildError::TooManyOutputs { count: raw.outputs.len() });
+        }
+        if raw.data.len() > MAX_DATA_SIZE {
+            return Err(TxBuildError::DataTooLarge { size: raw.data.len() });
+        }
+        raw.total_value().and_then(|value| value.checked_add(raw.fee)).ok_or(TxBuildError::Overflow)?;
+        Ok(SignedTransaction::from_raw(raw, key, chain))
+    }
+
+    /// Sign `raw` for `chain` as it is, even if no node would accept the result: tests use it to
+    /// build invalid transactions. Everything else should go through `try_from_raw`.
+    pub fn from_raw(raw: RawTransaction, key: &Ed25519KeyPair, chain: &ChainId) -> SignedTransaction {
         let pub_key = key.public_key().as_ref().to_vec();
-        let signature = sign(&raw, key).as_ref().to_vec();
+        let signature = sign(&raw, key, chain).as_ref().to_vec();
         SignedTransaction { raw, pub_key, signature }
     }
 
-    /// Verify the signature of this transaction
-    pub fn verify_signature(&self) -> bool {
-        let serialized_raw = bincode::serialize(&self.raw).unwrap();
+    /// The address owned by the embedded public key, which a valid transaction sends from
+    pub fn signer_address(&self) -> H160 {
+        H160::from_pubkey(&self.pub_key)
+    }
+
+    /// Verify the signature of this transaction, which must have been made for `chain`
+    pub fn verify_signature(&self, chain: &ChainId) -> bool {
         let public_key = ring::signature::UnparsedPublicKey::new(
             &ring::signature::ED25519, &self.pub_key[..]);
-        public_key.verify(&serialized_raw, self.signature.as_ref()).is_ok()
+        public_key.verify(&signing_message(&self.raw, chain), self.signature.as_ref()).is_ok()
+    }
+
+    /// Length in bytes once serialized, counted without serializing
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    /// Check that the embedded public key owns the sending address
+    pub fn verify_owner(&self) -> bool {
+        self.signer_address() == self.raw.from_addr
+    }
+
+    /// Check both the signature and that the signer owns the sending address
+    pub fn verify(&self, chain: &ChainId) -> bool {
+        self.verify_signature(chain) && self.verify_owner()
+    }
+}
+
+/// Below this many transactions, verifying on the calling thread beats spawning threads
+const PARALLEL_VERIFY_THRESHOLD: usize = 64;
+
+/// Transactions verified between looks at the cancellation flag
+const CANCEL_CHECK_INTERVAL: usize = 64;
+
+/// Why the transactions of a block were not all verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The index of the first transaction that does not verify
+    InvalidTransaction(usize),
+    /// Stopped short because the cancellation flag was set, say on shutdown
+    Cancelled,
+}
+
+/// Verify the signature and owner of every transaction, spread over the available cores, and
+/// skipping the signatures `sig_cache` already verified. Gives up with `Cancelled` soon after
+/// `cancel` is set.
+pub fn verify_batch(txs: &[SignedTransaction], chain: &ChainId, sig_cache: &SigCache, cancel: &AtomicBool) -> Result<(), BlockError> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    if txs.len() < PARALLEL_VERIFY_THRESHOLD || threads == 1 {
+        return verify_chunk(txs, 0, chain, sig_cache, cancel);
+    }
+    let chunk_size = txs.len().div_ceil(threads);
+    let results: Vec<Result<(), BlockError>> = crossbeam::scope(|scope| {
+        let handles: Vec<_> = txs.chunks(chunk_size).enumerate().map(|(c, chunk)| {
+            scope.spawn(move |_| verify_chunk(chunk, c * chunk_size, chain, sig_cache, cancel))
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }).unwrap();
+    if results.contains(&Err(BlockError::Cancelled)) {
+        return Err(BlockError::Cancelled);
+    }
+    let first_invalid = results.into_iter().filter_map(|result| match result {
+        Err(BlockError::InvalidTransaction(i)) => Some(i),
+        _ => None,
+    }).min();
+    first_invalid.map_or(Ok(()), |i| Err(BlockError::InvalidTransaction(i)))
+}
+
+/// `verify_batch` on one thread, for the transactions starting at index `offset`
+fn verify_chunk(txs: &[SignedTransaction], offset: usize, chain: &ChainId, sig_cache: &SigCache, cancel: &AtomicBool) -> Result<(), BlockError> {
+    for (i, tx) in txs.iter().enumerate() {
+        if i % CANCEL_CHECK_INTERVAL == 0 && cancel.load(Ordering::SeqCst) {
+            return Err(BlockError::Cancelled);
+        }
+        if !sig_cache.verify(tx, chain) {
+            return Err(BlockError::InvalidTransaction(offset + i));
+        }
     }
+    Ok(())
+}
+
+/// The message actually signed: the domain tag, the chain's genesis hash, then the transaction
+fn signing_message(t: &RawTransaction, chain: &ChainId) -> Vec<u8> {
+    let mut message = SIGNING_DOMAIN.to_vec();
+    message.extend_from_slice(chain.0.as_ref());
+    message.extend_from_slice(&t.canonical_bytes());
+    message
 }
 
-/// Create digital signature of a transaction
-pub fn sign(t: &RawTransaction, key: &Ed25519KeyPair) -> Signature {
-    key.sign(bincode::serialize(&t).unwrap().as_ref())
+/// Create digital signature of a transaction for `chain`
+pub fn sign(t: &RawTransaction, key: &Ed25519KeyPair, chain: &ChainId) -> Signature {
+    key.sign(&signing_message(t, chain))
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &RawTransaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
+pub fn verify(t: &RawTransaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature, chain: &ChainId) -> bool {
     ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key.as_ref())
-        .verify(bincode::serialize(&t).unwrap().as_ref(), signature.as_ref())
+        .verify(&signing_message(t, chain), signature.as_ref())
         .is_ok()
 }
 
-// #[cfg(any(test, test_utilities))]
-// mod tests {
-//     use super::*;
-//     use crate::crypto::key_pair;
-
-//     pub fn generate_random_transaction() -> RawTransaction {
-//         RawTransaction {
-//             foo: rand::random(),
-//         }
-//     }
-
-//     #[test]
-//     fn sign_verify() {
-//         let t = generate_random_transaction();
-//         let key = key_pair::random();
-//         let signature = sign(&t, &key);
-//         assert!(verify(&t, &(key.public_key()), &signature));
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::get_deterministic_keypair;
+    use crate::crypto::key_pair;
+    use proptest::prelude::*;
+
+    pub fn generate_random_transaction() -> RawTransaction {
+        RawTransaction {
+            from_addr: rand::random::<[u8; 20]>().into(),
+            outputs: vec![(rand::random::<[u8; 20]>().into(), rand::random())],
+            fee: rand::random(),
+            nonce: rand::random(),
+            valid_until_block: rand::random(),
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn sign_verify() {
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        let chain = ChainId::default();
+        let signature = sign(&t, &key, &chain);
+        assert!(verify(&t, key.public_key(), &signature, &chain));
+        assert!(!verify(&t, key.public_key(), &signature, &ChainId(H256::from([1; 32]))));
+    }
+
+    fn arb_h160() -> impl Strategy<Value = H160> {
+        any::<[u8; 20]>().prop_map(H160::from)
+    }
+
+    fn arb_h256() -> impl Strategy<Value = H256> {
+        any::<[u8; 32]>().prop_map(H256::from)
+    }
+
+    /// Amounts with the extremes drawn far more often than chance would
+    fn arb_value() -> impl Strategy<Value = u64> {
+        prop_oneof![Just(0), Just(1), Just(u64::MAX), any::<u64>()]
+    }
+
+    fn arb_raw_transaction() -> impl Strategy<Value = RawTransaction> {
+        (
+            arb_h160(),
+            prop::collection::vec((arb_h160(), arb_value()), 0..=4),
+            arb_value(),
+            prop_oneof![Just(0), Just(u32::MAX), any::<u32>()],
+            any::<u64>(),
+            prop::collection::vec(any::<u8>(), 0..=MAX_DATA_SIZE),
+        ).prop_map(|(from_addr, outputs, fee, nonce, valid_until_block, data)| {
+            RawTransaction { from_addr, outputs, fee, nonce, valid_until_block, data }
+        })
+    }
+
+    /// One of a few deterministic key pairs, or a fresh random one
+    fn arb_keypair() -> impl Strategy<Value = Option<u8>> {
+        prop::option::of(0u8..4)
+    }
+
+    fn keypair(choice: Option<u8>) -> Ed25519KeyPair {
+        choice.map_or_else(key_pair::random, get_deterministic_keypair)
+    }
+
+    proptest! {
+        #[test]
+        fn signed_transactions_verify(raw in arb_raw_transaction(), key in arb_keypair(), chain in arb_h256()) {
+            let chain = ChainId(chain);
+            let signed = SignedTransaction::from_raw(raw, &keypair(key), &chain);
+            prop_assert!(signed.verify_signature(&chain));
+        }
+
+        #[test]
+        fn flipping_a_byte_of_the_raw_transaction_breaks_the_signature(
+            raw in arb_raw_transaction(), key in arb_keypair(), index in any::<prop::sample::Index>(), mask in 1u8..,
+        ) {
+            let chain = ChainId::default();
+            let signed = SignedTransaction::from_raw(raw, &keypair(key), &chain);
+            let mut bytes = bincode::serialize(&signed.raw).unwrap();
+            let i = index.index(bytes.len());
+            bytes[i] ^= mask;
+            // bytes that no longer decode cannot be verified at all
+            if let Ok(tampered) = bincode::deserialize::<RawTransaction>(&bytes) {
+                let tampered = SignedTransaction { raw: tampered, ..signed };
+                prop_assert!(!tampered.verify_signature(&chain));
+            }
+        }
+
+        #[test]
+        fn flipping_a_byte_of_the_signature_or_public_key_breaks_verification(
+            raw in arb_raw_transaction(), key in arb_keypair(), index in any::<prop::sample::Index>(), mask in 1u8..,
+        ) {
+            let chain = ChainId::default();
+            let signed = SignedTransaction::from_raw(raw, &keypair(key), &chain);
+
+            let mut tampered = signed.clone();
+            let i = index.index(tampered.signature.len());
+            tampered.signature[i] ^= mask;
+            prop_assert!(!tampered.verify_signature(&chain));
+
+            let mut tampered = signed;
+            let i = index.index(tampered.pub_key.len());
+            tampered.pub_key[i] ^= mask;
+            prop_assert!(!tampered.verify_signature(&chain));
+        }
+
+        #[test]
+        fn hashes_survive_a_serialization_round_trip(raw in arb_raw_transaction(), key in arb_keypair()) {
+            let signed = SignedTransaction::from_raw(raw, &keypair(key), &ChainId::default());
+            let decoded: SignedTransaction = bincode::deserialize(&bincode::serialize(&signed).unwrap()).unwrap();
+            prop_assert_eq!(decoded.raw.canonical_bytes(), signed.raw.canonical_bytes());
+            prop_assert_eq!(decoded.txid(), signed.txid());
+            prop_assert_eq!(decoded.wtxid(), signed.wtxid());
+            prop_assert_eq!(decoded.size(), signed.size());
+        }
+    }
+}
+
+#[cfg(test)]
+mod owner_tests {
+    use super::*;
+    use crate::address::get_deterministic_keypair;
+
+    /// A fixed transaction, whose encoding and hash must never change
+    fn golden_transaction() -> RawTransaction {
+        RawTransaction {
+            from_addr: [0x11; 20].into(),
+            outputs: vec![([0x22; 20].into(), 1_000_000)],
+            fee: 25,
+            nonce: 7,
+            valid_until_block: 300,
+            data: b"memo".to_vec(),
+        }
+    }
+
+    #[test]
+    fn canonical_bytes_golden_vector() {
+        let raw = golden_transaction();
+        assert_eq!(
+            hex::encode(raw.canonical_bytes()),
+            concat!(
+                "1111111111111111111111111111111111111111", "01000000",
+                "2222222222222222222222222222222222222222", "40420f0000000000", "1900000000000000", "07000000", "2c01000000000000",
+                "04000000", "6d656d6f",
+            )
+        );
+        assert_eq!(hex::encode(raw.hash()), "99f77330d676f55b0a10c853a66e2afde9baf3e8502ad9ea0ccbfff3ff92be8d");
+    }
+
+    #[test]
+    fn signatures_are_bound_to_the_domain_and_chain() {
+        let raw = golden_transaction();
+        let key = get_deterministic_keypair(0);
+        let chain_a = ChainId::default();
+        let chain_b = ChainId(crate::block::Block::genesis_with(Default::default(), 1).hash());
+        assert_ne!(chain_a, chain_b);
+
+        let signed = SignedTransaction::from_raw(raw.clone(), &key, &chain_a);
+        assert!(signed.verify_signature(&chain_a));
+        assert!(!signed.verify_signature(&chain_b));
+        assert_eq!(verify_batch(&[signed], &chain_b, &SigCache::default(), &AtomicBool::new(false)), Err(BlockError::InvalidTransaction(0)));
+        let mut message = b"PART5-TX-V1".to_vec();
+        message.extend_from_slice(chain_a.0.as_ref());
+        message.extend_from_slice(&raw.canonical_bytes());
+        assert_eq!(sign(&raw, &key, &chain_a).as_ref(), key.sign(&message).as_ref());
+
+        // the bare transaction bytes, signed for some other purpose, don't verify
+        let mut bare = SignedTransaction::from_raw(raw.clone(), &key, &chain_a);
+        bare.signature = key.sign(&raw.canonical_bytes()).as_ref().to_vec();
+        assert!(!bare.verify_signature(&chain_a));
+    }
+
+    #[test]
+    fn verify_batch_finds_the_first_bad_transaction() {
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let chain = ChainId::default();
+        let running = AtomicBool::new(false);
+        for &count in &[10, 300] {
+            let mut txs: Vec<SignedTransaction> = (1..=count).map(|nonce| {
+                let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 1)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+                SignedTransaction::from_raw(raw, &key, &chain)
+            }).collect();
+            assert_eq!(verify_batch(&txs, &chain, &SigCache::default(), &running), Ok(()));
+            txs[count as usize - 3].raw.outputs[0].1 = 2;
+            assert_eq!(verify_batch(&txs, &chain, &SigCache::default(), &running), Err(BlockError::InvalidTransaction(count as usize - 3)));
+            txs[7].signature[0] ^= 1;
+            assert_eq!(verify_batch(&txs, &chain, &SigCache::default(), &running), Err(BlockError::InvalidTransaction(7)));
+        }
+        assert_eq!(verify_batch(&[], &chain, &SigCache::default(), &running), Ok(()));
+    }
+
+    #[test]
+    fn verify_batch_stops_once_cancelled() {
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let chain = ChainId::default();
+        let mut txs: Vec<SignedTransaction> = (1..=300).map(|nonce| {
+            let raw = RawTransaction { from_addr, outputs: vec![(Default::default(), 1)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &key, &chain)
+        }).collect();
+        txs[250].signature[0] ^= 1;
+        let sig_cache = SigCache::default();
+        // cancelled takes precedence over a bad transaction, which it may not have reached
+        assert_eq!(verify_batch(&txs, &chain, &sig_cache, &AtomicBool::new(true)), Err(BlockError::Cancelled));
+        assert_eq!(verify_batch(&txs[..10], &chain, &sig_cache, &AtomicBool::new(true)), Err(BlockError::Cancelled));
+        assert_eq!(sig_cache.stats().misses, 0);
+    }
+
+    #[test]
+    fn forged_sender_fails_the_owner_check() {
+        let (key_a, key_b) = (get_deterministic_keypair(0), get_deterministic_keypair(1));
+        let raw = RawTransaction {
+            from_addr: H160::from_pubkey(key_b.public_key().as_ref()),
+            outputs: vec![(H160::from_pubkey(key_a.public_key().as_ref()), 1000)],
+            fee: 0,
+            nonce: 1,
+            valid_until_block: 0,
+            data: Vec::new(),
+        };
+        // key A validly signs a transaction spending B's coins
+        let forged = SignedTransaction::from_raw(raw.clone(), &key_a, &ChainId::default());
+        assert!(forged.verify_signature(&ChainId::default()));
+        assert!(!forged.verify_owner());
+        assert!(!forged.verify(&ChainId::default()));
+
+        let genuine = SignedTransaction::from_raw(raw, &key_b, &ChainId::default());
+        assert!(genuine.verify(&ChainId::default()));
+    }
+
+    #[test]
+    fn try_from_raw_refuses_what_no_node_would_admit() {
+        let key = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let chain = ChainId::default();
+        let valid = RawTransaction { from_addr, outputs: vec![(Default::default(), 1)], fee: 1, nonce: 1, valid_until_block: 0, data: vec![0; MAX_DATA_SIZE] };
+        let build = |raw: RawTransaction| SignedTransaction::try_from_raw(raw, &key, &chain);
+        let refusal = |raw: RawTransaction| build(raw).err();
+
+        let signed = build(valid.clone()).unwrap();
+        assert_eq!(signed.hash(), SignedTransaction::from_raw(valid.clone(), &key, &chain).hash());
+        assert!(signed.verify(&chain));
+        // zero-value sends are allowed
+        assert!(build(RawTransaction { outputs: vec![(Default::default(), 0)], fee: 0, ..valid.clone() }).is_ok());
+
+        assert_eq!(refusal(RawTransaction { outputs: Vec::new(), ..valid.clone() }), Some(TxBuildError::NoOutputs));
+        let count = MAX_OUTPUTS_PER_TRANSACTION + 1;
+        assert_eq!(
+            refusal(RawTransaction { outputs: vec![(Default::default(), 1); count], ..valid.clone() }),
+            Some(TxBuildError::TooManyOutputs { count })
+        );
+        assert_eq!(
+            refusal(RawTransaction { data: vec![0; MAX_DATA_SIZE + 1], ..valid.clone() }),
+            Some(TxBuildError::DataTooLarge { size: MAX_DATA_SIZE + 1 })
+        );
+        assert_eq!(refusal(RawTransaction { fee: u64::MAX, ..valid.clone() }), Some(TxBuildError::Overflow));
+        assert_eq!(refusal(RawTransaction { from_addr: Default::default(), ..valid }), Some(TxBuildError::WrongSigner));
+    }
+
+    #[test]
+    fn signer_address_is_the_address_of_the_public_key() {
+        let key = get_deterministic_keypair(3);
+        let raw = RawTransaction { outputs: vec![(Default::default(), 1)], ..Default::default() };
+        let signed = SignedTransaction::from_raw(raw, &key, &ChainId::default());
+        assert_eq!(signed.signer_address(), H160::from_pubkey(key.public_key().as_ref()));
+        assert_eq!(signed.signer_address(), H160::from_pubkey(&signed.pub_key));
+        assert!(!signed.verify_owner());
+    }
+}
@@ -1,7 +1,6 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use crate::address::H160;
-use crate::crypto::hash::{H256, Hashable};
 
 use crate::network::server::Handle as ServerHandle;
 use crate::transaction::{RawTransaction, SignedTransaction};
@@ -11,12 +10,48 @@ use std::sync::{Arc, Mutex};
 use crate::mempool::Mempool;
 use crate::network::message::Message;
 use crate::blockchain::{Blockchain};
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Starts the data of every generated transaction, ahead of its `Stamp`
+const STAMP_TAG: &[u8] = b"GEN1";
+
+/// What a generated transaction carries in its data: which generator sent it, and when, in
+/// milliseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub generator: u32,
+    pub sent_ms: u64,
+}
+
+impl Stamp {
+    /// The tag, then the generator and the send time as little-endian integers
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = STAMP_TAG.to_vec();
+        bytes.extend_from_slice(&self.generator.to_le_bytes());
+        bytes.extend_from_slice(&self.sent_ms.to_le_bytes());
+        bytes
+    }
+
+    /// `None` unless `data` is exactly a stamp
+    pub fn parse(data: &[u8]) -> Option<Stamp> {
+        let rest = data.strip_prefix(STAMP_TAG)?;
+        if rest.len() != 12 {
+            return None;
+        }
+        let generator = u32::from_le_bytes(rest[..4].try_into().ok()?);
+        let sent_ms = u64::from_le_bytes(rest[4..].try_into().ok()?);
+        Some(Stamp { generator, sent_ms })
+    }
+}
 
 pub struct TransactionGenerator {
     server: ServerHandle,
     mempool: Arc<Mutex<Mempool>>,
     blockchain: Arc<Mutex<Blockchain>>,
     controlled_keypair: Ed25519KeyPair,
+    /// Stamped into the generated transactions, to tell generators apart
+    id: u32,
 }
 
 impl TransactionGenerator {
@@ -24,13 +59,15 @@ impl TransactionGenerator {
         server: &ServerHandle,
         mempool: &Arc<Mutex<Mempool>>,
         blockchain: &Arc<Mutex<Blockchain>>,
-        controlled_keypair: Ed25519KeyPair
+        controlled_keypair: Ed25519KeyPair,
+        id: u32,
     ) -> TransactionGenerator {
         TransactionGenerator {
             server: server.clone(),
             mempool: Arc::clone(mempool),
             blockchain: Arc::clone(blockchain),
             controlled_keypair,
+            id,
         }
     }
 
@@ -50,21 +87,31 @@ impl TransactionGenerator {
             let interval = time::Duration::from_millis(INTERVAL_MILLISECONDS);
             thread::sleep(interval);
 
-            // 1. generate some random transactions:
+            // 1. generate some random transactions, stamped to measure how long they take to confirm:
+            let sent_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
             let raw_transaction = RawTransaction {
                 from_addr: H160::from_pubkey(self.controlled_keypair.public_key().as_ref()),
-                to_addr: H160::from_pubkey(self.controlled_keypair.public_key().as_ref()), // for example, send to self
-                value: 10,
+                outputs: vec![(H160::from_pubkey(self.controlled_keypair.public_key().as_ref()), 10)], // for example, send to self
+                fee: 0,
                 nonce: 0, // update as needed
+                valid_until_block: 0,
+                data: Stamp { generator: self.id, sent_ms }.to_bytes(),
+            };
+            let chain = self.blockchain.lock().unwrap().chain_id();
+            let signed_transaction = match SignedTransaction::try_from_raw(raw_transaction, &self.controlled_keypair, &chain) {
+                Ok(tx) => tx,
+                Err(e) => {
+                    log::warn!("Transaction Generator built an invalid transaction: {}", e);
+                    continue;
+                }
             };
-            let signed_transaction = SignedTransaction::from_raw(raw_transaction, &self.controlled_keypair);
 
             // 2. add these transactions to the mempool:
             let mut mempool = self.mempool.lock().unwrap();
             mempool.insert(signed_transaction.clone());
 
             // 3. broadcast them using `self.server.broadcast(Message::NewTransactionHashes(...))`:
-            self.server.broadcast(Message::NewTransactionHashes(vec![signed_transaction.hash()]));
+            self.server.broadcast(Message::NewTransactionHashes(vec![signed_transaction.txid()]));
         }
     }
 }
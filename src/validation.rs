@@ -0,0 +1,337 @@
+//! Why transactions get rejected, counted for experiments.
+
+use crate::blockchain::TxApplyError;
+use std::fmt;
+
+/// Transactions serializing to more bytes than this are not admitted into the mempool
+pub const MAX_TRANSACTION_SIZE: usize = 1024;
+
+/// Transactions paying more outputs than this are not admitted into the mempool
+pub const MAX_OUTPUTS_PER_TRANSACTION: usize = 16;
+
+/// Why a transaction was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    BadSignature,
+    /// Signed by a key that does not own the sending address
+    WrongOwner,
+    BadNonce,
+    InsufficientBalance,
+    Overflow,
+    /// Already pending or confirmed
+    Duplicate,
+    Oversized,
+    /// Pays less than the minimum fee
+    FeeTooLow,
+    /// A coinbase outside of its place, or a block whose coinbase is missing or wrong
+    BadCoinbase,
+    /// Past its `valid_until_block`
+    Expired,
+    /// More than `MAX_OUTPUTS_PER_TRANSACTION` outputs
+    TooManyOutputs,
+    /// Has the sender and nonce of a pending transaction without paying enough more to replace it
+    ReplacementUnderpriced,
+    /// More than `MAX_DATA_SIZE` bytes of data
+    DataTooLarge,
+}
+
+impl RejectReason {
+    /// Whether only a misbehaving peer relays a transaction rejected for this reason. Honest
+    /// peers check signatures and sizes too, while nonces, balances, fees and expiry depend on
+    /// their view of the chain.
+    pub fn is_misbehavior(&self) -> bool {
+        match self {
+            RejectReason::BadSignature
+            | RejectReason::WrongOwner
+            | RejectReason::Oversized
+            | RejectReason::BadCoinbase
+            | RejectReason::TooManyOutputs
+            | RejectReason::DataTooLarge => true,
+            RejectReason::BadNonce
+            | RejectReason::InsufficientBalance
+            | RejectReason::Overflow
+            | RejectReason::Duplicate
+            | RejectReason::FeeTooLow
+            | RejectReason::Expired
+            | RejectReason::ReplacementUnderpriced => false,
+        }
+    }
+}
+
+impl From<&TxApplyError> for RejectReason {
+    fn from(e: &TxApplyError) -> Self {
+        match e {
+            TxApplyError::BadSignature => RejectReason::BadSignature,
+            TxApplyError::WrongOwner => RejectReason::WrongOwner,
+            TxApplyError::BadNonce { .. } | TxApplyError::NonceExhausted => RejectReason::BadNonce,
+            TxApplyError::InsufficientBalance { .. } => RejectReason::InsufficientBalance,
+            TxApplyError::Overflow => RejectReason::Overflow,
+            TxApplyError::MissingCoinbase
+            | TxApplyError::MisplacedCoinbase
+            | TxApplyError::CoinbaseTooLarge { .. }
+            | TxApplyError::CoinbaseHeight { .. } => RejectReason::BadCoinbase,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            RejectReason::BadSignature => "bad signature",
+            RejectReason::WrongOwner => "signer does not own the sending address",
+            RejectReason::BadNonce => "bad nonce",
+            RejectReason::InsufficientBalance => "insufficient balance",
+            RejectReason::Overflow => "overflow",
+            RejectReason::Duplicate => "duplicate",
+            RejectReason::Oversized => "oversized",
+            RejectReason::FeeTooLow => "fee too low",
+            RejectReason::BadCoinbase => "bad coinbase",
+            RejectReason::Expired => "expired",
+            RejectReason::TooManyOutputs => "too many outputs",
+            RejectReason::ReplacementUnderpriced => "replacement underpriced",
+            RejectReason::DataTooLarge => "data too large",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// How many transactions were rejected, by reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RejectCounts {
+    pub bad_signature: u64,
+    pub wrong_owner: u64,
+    pub bad_nonce: u64,
+    pub insufficient_balance: u64,
+    pub overflow: u64,
+    pub duplicate: u64,
+    pub oversized: u64,
+    pub fee_too_low: u64,
+    pub bad_coinbase: u64,
+    pub expired: u64,
+    pub too_many_outputs: u64,
+    pub replacement_underpriced: u64,
+    pub data_too_large: u64,
+}
+
+impl RejectCounts {
+    pub fn record(&mut self, reason: RejectReason) {
+        let counter = match reason {
+            RejectReason::BadSignature => &mut self.bad_signature,
+            RejectReason::WrongOwner => &mut self.wrong_owner,
+            RejectReason::BadNonce => &mut self.bad_nonce,
+            RejectReason::InsufficientBalance => &mut self.insufficient_balance,
+            RejectReason::Overflow => &mut self.overflow,
+            RejectReason::Duplicate => &mut self.duplicate,
+            RejectReason::Oversized => &mut self.oversized,
+            RejectReason::FeeTooLow => &mut self.fee_too_low,
+            RejectReason::BadCoinbase => &mut self.bad_coinbase,
+            RejectReason::Expired => &mut self.expired,
+            RejectReason::TooManyOutputs => &mut self.too_many_outputs,
+            RejectReason::ReplacementUnderpriced => &mut self.replacement_underpriced,
+            RejectReason::DataTooLarge => &mut self.data_too_large,
+        };
+        *counter += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.bad_signature + self.wrong_owner + self.bad_nonce + self.insufficient_balance
+            + self.overflow + self.duplicate + self.oversized + self.fee_too_low + self.bad_coinbase + self.expired
+            + self.too_many_outputs + self.replacement_underpriced + self.data_too_large
+    }
+}
+
+impl fmt::Display for RejectCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (bad signature {}, wrong owner {}, bad nonce {}, insufficient balance {}, overflow {}, duplicate {}, oversized {}, fee too low {}, bad coinbase {}, expired {}, too many outputs {}, replacement underpriced {}, data too large {})",
+            self.total(), self.bad_signature, self.wrong_owner, self.bad_nonce,
+            self.insufficient_balance, self.overflow, self.duplicate, self.oversized, self.fee_too_low, self.bad_coinbase,
+            self.expired, self.too_many_outputs, self.replacement_underpriced, self.data_too_large
+        )
+    }
+}
+
+/// Rejections when admitting transactions into the mempool and when validating blocks,
+/// counted separately
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValidationStats {
+    pub mempool: RejectCounts,
+    pub blocks: RejectCounts,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{get_deterministic_keypair, H160};
+    use crate::blockchain::Blockchain;
+    use crate::mempool::Mempool;
+    use crate::transaction::{ChainId, RawTransaction, SignedTransaction, MAX_DATA_SIZE};
+    use ring::signature::KeyPair;
+
+    fn ico_address(i: u8) -> H160 {
+        H160::from_pubkey(get_deterministic_keypair(i).public_key().as_ref())
+    }
+
+    /// Account 0 of the default ICO sending to account 1
+    fn transaction(value: u64, nonce: u32) -> SignedTransaction {
+        let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), value)], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+        SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+    }
+
+    #[test]
+    fn each_rejection_moves_its_own_counter() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let mut expected = RejectCounts::default();
+        let mut admit = |tx: SignedTransaction, reason: Option<RejectReason>, counter: fn(&mut RejectCounts) -> &mut u64| {
+            assert_eq!(blockchain.admit_transactions(&mut mempool, vec![tx]), vec![reason.map_or(Ok(()), Err)]);
+            if reason.is_some() {
+                *counter(&mut expected) += 1;
+            }
+            assert_eq!(blockchain.validation_stats().mempool, expected);
+            assert_eq!(blockchain.validation_stats().blocks, RejectCounts::default());
+        };
+
+        admit(transaction(100, 1), None, |c| &mut c.duplicate);
+        admit(transaction(100, 1), Some(RejectReason::Duplicate), |c| &mut c.duplicate);
+        // the same payment, whatever its signature
+        let mut resigned = transaction(100, 1);
+        resigned.signature[0] ^= 1;
+        admit(resigned, Some(RejectReason::Duplicate), |c| &mut c.duplicate);
+        let mut tampered = transaction(100, 2);
+        tampered.raw.outputs[0].1 = 200;
+        admit(tampered, Some(RejectReason::BadSignature), |c| &mut c.bad_signature);
+        let mut stolen = transaction(100, 2);
+        stolen.raw.from_addr = ico_address(1);
+        stolen = SignedTransaction::from_raw(stolen.raw, &get_deterministic_keypair(0), &ChainId::default());
+        admit(stolen, Some(RejectReason::WrongOwner), |c| &mut c.wrong_owner);
+        admit(transaction(100, 0), Some(RejectReason::BadNonce), |c| &mut c.bad_nonce);
+        admit(transaction(10001, 2), Some(RejectReason::InsufficientBalance), |c| &mut c.insufficient_balance);
+        let mut oversized = transaction(100, 2);
+        oversized.signature = vec![0; MAX_TRANSACTION_SIZE];
+        admit(oversized, Some(RejectReason::Oversized), |c| &mut c.oversized);
+        // a nonce beyond the next one may follow pending transactions
+        admit(transaction(100, 3), None, |c| &mut c.duplicate);
+
+        assert_eq!(expected.total(), 7);
+        assert_eq!(mempool.get_keys().len(), 2);
+    }
+
+    #[test]
+    fn transactions_signed_for_another_chain_are_rejected() {
+        let mut other_chain = Blockchain::new_with_genesis(crate::block::default_difficulty().into(), 1);
+        assert_ne!(other_chain.chain_id(), ChainId::default());
+        let results = other_chain.admit_transactions(&mut Mempool::new(), vec![transaction(100, 1)]);
+        assert_eq!(results, vec![Err(RejectReason::BadSignature)]);
+        assert_eq!(Blockchain::new().admit_transactions(&mut Mempool::new(), vec![transaction(100, 1)]), vec![Ok(())]);
+    }
+
+    #[test]
+    fn min_fee_is_enforced_on_admission() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_min_fee(5);
+        let mut mempool = Mempool::new();
+        let with_fee = |fee: u64, nonce: u32| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 100)], fee, nonce, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let results = blockchain.admit_transactions(&mut mempool, vec![
+            with_fee(4, 1), with_fee(5, 1), with_fee(9901, 2), with_fee(u64::MAX, 3),
+        ]);
+        assert_eq!(results, vec![
+            Err(RejectReason::FeeTooLow), Ok(()), Err(RejectReason::InsufficientBalance), Err(RejectReason::Overflow),
+        ]);
+        assert_eq!(blockchain.validation_stats().mempool.fee_too_low, 1);
+    }
+
+    #[test]
+    fn expired_transactions_are_not_admitted() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let valid_until = |valid_until_block: u64, nonce: u32| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 100)], fee: 0, nonce, valid_until_block, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        // at genesis, the next block is at height 1
+        let mut extended = valid_until(1, 3);
+        extended.raw.valid_until_block = 5;
+        let results = blockchain.admit_transactions(&mut mempool, vec![valid_until(1, 1), valid_until(0, 2), extended]);
+        assert_eq!(results, vec![Ok(()), Ok(()), Err(RejectReason::BadSignature)]);
+
+        let mut next = crate::block::test::generate_mined_block(&blockchain.tip());
+        next.content.transactions.clear();
+        blockchain.insert(&next);
+        assert_eq!(blockchain.admit_transactions(&mut mempool, vec![valid_until(1, 3)]), vec![Err(RejectReason::Expired)]);
+        assert_eq!(blockchain.validation_stats().mempool.expired, 1);
+        assert_eq!(mempool.evict_expired(blockchain.tip_height() + 1), 1);
+        assert_eq!(mempool.get_keys().len(), 1);
+    }
+
+    #[test]
+    fn replacement_must_outbid_the_pending_transaction() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        mempool.set_replacement_fee_increment(3);
+        let with_fee = |fee: u64| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 100)], fee, nonce: 1, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let results = blockchain.admit_transactions(&mut mempool, vec![with_fee(1), with_fee(0), with_fee(3), with_fee(4)]);
+        assert_eq!(results, vec![
+            Ok(()), Err(RejectReason::ReplacementUnderpriced), Err(RejectReason::ReplacementUnderpriced), Ok(()),
+        ]);
+        assert_eq!(blockchain.validation_stats().mempool.replacement_underpriced, 2);
+        assert_eq!(mempool.get_keys(), vec![with_fee(4).txid()]);
+        // the replaced transaction relayed back is rejected again
+        assert_eq!(blockchain.admit_transactions(&mut mempool, vec![with_fee(1)]), vec![Err(RejectReason::ReplacementUnderpriced)]);
+    }
+
+    #[test]
+    fn extreme_values_are_rejected_on_admission() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let paying = |outputs: Vec<(H160, u64)>, fee: u64| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs, fee, nonce: 1, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let results = blockchain.admit_transactions(&mut mempool, vec![
+            paying(vec![(ico_address(1), u64::MAX)], 1),
+            paying(vec![(ico_address(1), u64::MAX), (ico_address(2), 1)], 0),
+            paying(vec![(ico_address(1), 1)], u64::MAX),
+            paying(vec![(ico_address(1), u64::MAX)], 0),
+        ]);
+        assert_eq!(results, vec![
+            Err(RejectReason::Overflow), Err(RejectReason::Overflow), Err(RejectReason::Overflow), Err(RejectReason::InsufficientBalance),
+        ]);
+        assert!(mempool.get_keys().is_empty());
+    }
+
+    #[test]
+    fn data_is_capped_on_admission() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let with_data = |size: usize, nonce: u32| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 1)], fee: 0, nonce, valid_until_block: 0, data: vec![0xab; size] };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let results = blockchain.admit_transactions(&mut mempool, vec![with_data(MAX_DATA_SIZE + 1, 1), with_data(MAX_DATA_SIZE, 1)]);
+        assert_eq!(results, vec![Err(RejectReason::DataTooLarge), Ok(())]);
+        assert_eq!(blockchain.validation_stats().mempool.data_too_large, 1);
+    }
+
+    #[test]
+    fn too_many_outputs_are_not_admitted() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let paying = |count: usize, nonce: u32| {
+            let raw = RawTransaction { from_addr: ico_address(0), outputs: vec![(ico_address(1), 1); count], fee: 0, nonce, valid_until_block: 0, data: Vec::new() };
+            SignedTransaction::from_raw(raw, &get_deterministic_keypair(0), &ChainId::default())
+        };
+        let results = blockchain.admit_transactions(&mut mempool, vec![
+            paying(MAX_OUTPUTS_PER_TRANSACTION + 1, 1), paying(MAX_OUTPUTS_PER_TRANSACTION, 1),
+        ]);
+        assert_eq!(results, vec![Err(RejectReason::TooManyOutputs), Ok(())]);
+        assert_eq!(blockchain.validation_stats().mempool.too_many_outputs, 1);
+    }
+}
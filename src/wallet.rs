@@ -0,0 +1,364 @@
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::address::{get_deterministic_keypair, H160};
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::transaction::{RawTransaction, SignedTransaction, TxBuildError};
+
+/// Keystore files hold one PKCS#8-encoded key each, named `<wallet name>.pk8`
+const KEY_FILE_EXTENSION: &str = "pk8";
+
+/// Why a wallet could not build a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    /// The wallet's address has no account as of the tip: it was never funded
+    UnknownAccount(H160),
+    /// Sending `cost`, value and fee together, with a balance of only `balance`
+    InsufficientFunds { balance: u64, cost: u64 },
+    /// The transaction itself is malformed, whatever the balance
+    Invalid(TxBuildError),
+    /// The account already sent a transaction with the highest nonce, so it can send no more
+    NonceExhausted,
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalletError::UnknownAccount(address) => write!(f, "no account for address {}", address),
+            WalletError::InsufficientFunds { balance, cost } => {
+                write!(f, "sending {} with a balance of {}", cost, balance)
+            }
+            WalletError::Invalid(e) => write!(f, "{}", e),
+            WalletError::NonceExhausted => write!(f, "the account has used up its nonces"),
+        }
+    }
+}
+
+/// A named account controlled by this node
+pub struct Wallet {
+    name: String,
+    keypair: Ed25519KeyPair,
+    /// The key as saved to the keystore; deterministic keys are never saved
+    pkcs8: Option<Vec<u8>>,
+    address: H160,
+    blockchain: Arc<Mutex<Blockchain>>,
+    /// The nonce to use for the next transaction this wallet sends, unless the chain is already
+    /// past it; wider than a nonce, so it can count past the last one
+    next_nonce: u64,
+}
+
+impl Wallet {
+    fn from_keypair(name: &str, keypair: Ed25519KeyPair, pkcs8: Option<Vec<u8>>, blockchain: &Arc<Mutex<Blockchain>>) -> Self {
+        let address = H160::from_pubkey(keypair.public_key().as_ref());
+        // a wallet restored over an account that already sent carries on from its nonce
+        let next_nonce = blockchain.lock().unwrap().account_info(&address).map_or(1, |account| account.nonce as u64 + 1);
+        Wallet { name: name.to_string(), keypair, pkcs8, address, blockchain: Arc::clone(blockchain), next_nonce }
+    }
+
+    /// Create a wallet from a PKCS#8-encoded key
+    pub fn from_pkcs8(name: &str, pkcs8: Vec<u8>, blockchain: &Arc<Mutex<Blockchain>>) -> Result<Self, String> {
+        let keypair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| format!("invalid key for wallet {}: {}", name, e))?;
+        Ok(Wallet::from_keypair(name, keypair, Some(pkcs8), blockchain))
+    }
+
+    /// Create a wallet with a fresh random key
+    pub fn generate(name: &str, blockchain: &Arc<Mutex<Blockchain>>) -> Self {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        Wallet::from_pkcs8(name, pkcs8.as_ref().to_vec(), blockchain).unwrap()
+    }
+
+    /// Create a wallet with the i-th deterministic key, such as those funded by the default ICO
+    pub fn deterministic(name: &str, i: u8, blockchain: &Arc<Mutex<Blockchain>>) -> Self {
+        Wallet::from_keypair(name, get_deterministic_keypair(i), None, blockchain)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address(&self) -> H160 {
+        self.address
+    }
+
+    /// Balance as of the tip, 0 if the account does not exist
+    pub fn balance(&self) -> u64 {
+        self.blockchain.lock().unwrap().balance_of(&self.address)
+    }
+
+    /// Coinbase rewards as of the tip that cannot be spent yet, not counted in `balance`
+    pub fn immature_balance(&self) -> u64 {
+        self.blockchain.lock().unwrap().immature_balance_of(&self.address)
+    }
+
+    /// Build and sign a transaction paying `value` to `to`, see `create_payment`
+    pub fn create_transaction(&mut self, to: H160, value: u64, fee: u64) -> Result<SignedTransaction, WalletError> {
+        self.create_payment(vec![(to, value)], fee)
+    }
+
+    /// Build and sign a transaction paying every output, for the blockchain's chain. The nonce
+    /// follows both the account's nonce as of the tip and this wallet's earlier sends, so
+    /// back-to-back sends get consecutive nonces before any block confirms them. Funds are
+    /// checked against the tip's balance only, not counting what pending sends will spend.
+    pub fn create_payment(&mut self, outputs: Vec<(H160, u64)>, fee: u64) -> Result<SignedTransaction, WalletError> {
+        let (account, chain) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.account_info(&self.address), blockchain.chain_id())
+        };
+        let account = account.ok_or(WalletError::UnknownAccount(self.address))?;
+        let cost = outputs.iter().try_fold(fee, |cost, (_, value)| cost.checked_add(*value))
+            .ok_or(WalletError::Invalid(TxBuildError::Overflow))?;
+        if cost > account.balance {
+            return Err(WalletError::InsufficientFunds { balance: account.balance, cost });
+        }
+        self.next_nonce = self.next_nonce.max(account.nonce as u64 + 1);
+        let nonce = u32::try_from(self.next_nonce).map_err(|_| WalletError::NonceExhausted)?;
+        let raw = RawTransaction {
+            from_addr: self.address,
+            outputs,
+            fee,
+            nonce,
+            valid_until_block: 0,
+            data: Vec::new(),
+        };
+        let signed = SignedTransaction::try_from_raw(raw, &self.keypair, &chain).map_err(WalletError::Invalid)?;
+        self.next_nonce += 1;
+        Ok(signed)
+    }
+}
+
+/// All the wallets of this node, optionally backed by a keystore directory
+pub struct WalletManager {
+    keystore: Option<PathBuf>,
+    wallets: BTreeMap<String, Wallet>,
+    /// Where the wallets look up their accounts
+    blockchain: Arc<Mutex<Blockchain>>,
+}
+
+impl WalletManager {
+    /// Create a manager whose wallets only live in memory
+    pub fn new(blockchain: &Arc<Mutex<Blockchain>>) -> Self {
+        WalletManager { keystore: None, wallets: BTreeMap::new(), blockchain: Arc::clone(blockchain) }
+    }
+
+    /// Load every wallet in the keystore directory, creating the directory if needed
+    pub fn load(keystore: &Path, blockchain: &Arc<Mutex<Blockchain>>) -> Result<Self, String> {
+        std::fs::create_dir_all(keystore)
+            .map_err(|e| format!("error creating keystore {}: {}", keystore.display(), e))?;
+        let entries = std::fs::read_dir(keystore)
+            .map_err(|e| format!("error reading keystore {}: {}", keystore.display(), e))?;
+        let mut manager = WalletManager::new(blockchain);
+        manager.keystore = Some(keystore.to_path_buf());
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(KEY_FILE_EXTENSION) {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let pkcs8 = std::fs::read(&path)
+                .map_err(|e| format!("error reading key file {}: {}", path.display(), e))?;
+            let wallet = Wallet::from_pkcs8(&name, pkcs8, blockchain)?;
+            manager.wallets.insert(name, wallet);
+        }
+        Ok(manager)
+    }
+
+    /// Generate a new wallet, saving its key to the keystore if there is one
+    pub fn generate(&mut self, name: &str) -> Result<H160, String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("invalid wallet name: {}", name));
+        }
+        if self.wallets.contains_key(name) {
+            return Err(format!("wallet {} already exists", name));
+        }
+        let wallet = Wallet::generate(name, &self.blockchain);
+        if let (Some(keystore), Some(pkcs8)) = (&self.keystore, &wallet.pkcs8) {
+            let path = keystore.join(name).with_extension(KEY_FILE_EXTENSION);
+            write_key_file(&path, pkcs8)
+                .map_err(|e| format!("error writing key file {}: {}", path.display(), e))?;
+        }
+        let address = wallet.address();
+        self.wallets.insert(name.to_string(), wallet);
+        Ok(address)
+    }
+
+    /// The name and address of every wallet, sorted by name
+    pub fn list(&self) -> Vec<(String, H160)> {
+        self.wallets.values().map(|w| (w.name.clone(), w.address)).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Wallet> {
+        self.wallets.get(name)
+    }
+
+    /// Send `value` from the named wallet to `to`, paying `fee`, inserting the transaction into
+    /// the mempool. The nonce also skips whatever is already pending in the mempool for the
+    /// wallet's address. The mempool is never locked while the blockchain is being read.
+    pub fn send(&mut self, name: &str, to: H160, value: u64, fee: u64, mempool: &Mutex<Mempool>) -> Result<SignedTransaction, String> {
+        let wallet = self.wallets.get_mut(name).ok_or(format!("unknown wallet: {}", name))?;
+        if let Some(pending) = mempool.lock().unwrap().max_nonce_of(&wallet.address) {
+            wallet.next_nonce = wallet.next_nonce.max(pending as u64 + 1);
+        }
+        let transaction = wallet.create_transaction(to, value, fee).map_err(|e| e.to_string())?;
+        mempool.lock().unwrap().insert(transaction.clone());
+        Ok(transaction)
+    }
+}
+
+/// Write a new key file that only its owner can read
+fn write_key_file(path: &Path, pkcs8: &[u8]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(pkcs8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::blockchain::InsertOutcome;
+    use std::collections::HashMap;
+    use std::thread;
+
+    #[test]
+    fn concurrent_sends_get_sequential_nonces() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut manager = WalletManager::new(&blockchain);
+        for (i, name) in ["merchant", "customer"].iter().enumerate() {
+            manager.wallets.insert(name.to_string(), Wallet::deterministic(name, i as u8, &blockchain));
+        }
+        let merchant = manager.get("merchant").unwrap().address();
+        let customer = manager.get("customer").unwrap().address();
+        let manager = Arc::new(Mutex::new(manager));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+
+        let senders: Vec<_> = vec![("merchant", customer), ("customer", merchant)].into_iter().map(|(name, to)| {
+            let manager = Arc::clone(&manager);
+            let mempool = Arc::clone(&mempool);
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    manager.lock().unwrap().send(name, to, 1, 0, &mempool).unwrap();
+                }
+            })
+        }).collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+
+        // every transaction is pending, and each sender's nonces are 1, 2, ..., 10
+        let chain = blockchain.lock().unwrap().chain_id();
+        let mut mempool = mempool.lock().unwrap();
+        let mut nonces: HashMap<H160, Vec<u32>> = HashMap::new();
+        while let Some(tx) = mempool.pop() {
+            assert!(tx.verify_signature(&chain));
+            nonces.entry(tx.raw.from_addr).or_default().push(tx.raw.nonce);
+        }
+        for address in &[merchant, customer] {
+            let mut sent = nonces.remove(address).unwrap();
+            sent.sort();
+            assert_eq!(sent, (1..=10).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn back_to_back_sends_follow_the_confirmed_nonce() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut wallet = Wallet::deterministic("alice", 0, &blockchain);
+        let bob = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+
+        // confirm a transaction sent by another instance of the same wallet
+        let confirmed = Wallet::deterministic("alice", 0, &blockchain).create_transaction(bob, 100, 0).unwrap();
+        {
+            let mut blockchain = blockchain.lock().unwrap();
+            let mut block = generate_random_block(&blockchain.tip());
+            block.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, 1), confirmed];
+            block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+            assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+        }
+        assert_eq!(wallet.balance(), 9900);
+
+        let nonces: Vec<u32> = (0..3).map(|_| wallet.create_transaction(bob, 10, 1).unwrap().raw.nonce).collect();
+        assert_eq!(nonces, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn restored_wallet_starts_from_the_account_nonce() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let bob = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let mut first = Wallet::deterministic("alice", 0, &blockchain);
+        let sent: Vec<SignedTransaction> = (0..2).map(|_| first.create_transaction(bob, 100, 0).unwrap()).collect();
+        {
+            let mut blockchain = blockchain.lock().unwrap();
+            let mut block = generate_random_block(&blockchain.tip());
+            block.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, 1)];
+            block.content.transactions.extend(sent);
+            block.header.state_root = blockchain.expected_state_root(&block).unwrap();
+            assert_eq!(blockchain.try_insert(&block), Ok(InsertOutcome::ExtendedTip));
+        }
+
+        let restored = Wallet::deterministic("alice", 0, &blockchain);
+        assert_eq!(restored.next_nonce, 3);
+        assert_eq!(Wallet::generate("stranger", &blockchain).next_nonce, 1);
+    }
+
+    #[test]
+    fn unfunded_and_overspending_sends_fail() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut stranger = Wallet::generate("stranger", &blockchain);
+        assert_eq!(stranger.balance(), 0);
+        let address = stranger.address();
+        assert_eq!(stranger.create_transaction(H160::default(), 1, 0).unwrap_err(), WalletError::UnknownAccount(address));
+
+        let mut alice = Wallet::deterministic("alice", 0, &blockchain);
+        assert_eq!(
+            alice.create_transaction(H160::default(), 10000, 1).unwrap_err(),
+            WalletError::InsufficientFunds { balance: 10000, cost: 10001 }
+        );
+        assert_eq!(
+            alice.create_payment(vec![(H160::default(), u64::MAX), (H160::default(), 1)], 0).unwrap_err(),
+            WalletError::Invalid(TxBuildError::Overflow)
+        );
+        // a failed send does not use up a nonce
+        assert_eq!(alice.create_transaction(H160::default(), 10000, 0).unwrap().raw.nonce, 1);
+    }
+
+    #[test]
+    fn no_send_past_the_last_nonce() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut alice = Wallet::deterministic("alice", 0, &blockchain);
+        alice.next_nonce = u32::MAX as u64;
+        assert_eq!(alice.create_transaction(H160::default(), 1, 0).unwrap().raw.nonce, u32::MAX);
+        assert_eq!(alice.create_transaction(H160::default(), 1, 0).unwrap_err(), WalletError::NonceExhausted);
+    }
+
+    #[test]
+    fn keystore_round_trip() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let keystore = std::env::temp_dir().join(format!("keystore-test-{}", rand::random::<u64>()));
+        let treasury = WalletManager::load(&keystore, &blockchain).unwrap().generate("treasury").unwrap();
+        let reloaded = WalletManager::load(&keystore, &blockchain).unwrap();
+        assert_eq!(reloaded.list(), vec![("treasury".to_string(), treasury)]);
+        let metadata = std::fs::metadata(keystore.join("treasury.pk8")).unwrap();
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777, 0o600);
+        std::fs::remove_dir_all(&keystore).unwrap();
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        let mut manager = WalletManager::new(&Arc::new(Mutex::new(Blockchain::new())));
+        assert!(manager.generate("../escape").is_err());
+        manager.generate("merchant").unwrap();
+        assert!(manager.generate("merchant").is_err());
+    }
+}